@@ -0,0 +1,55 @@
+// Capture some information about the build, which is exposed at runtime via
+// `common::build_info!`. This is shared by the build script of each binary, which
+// `include!`s it.
+
+use std::process::Command;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+fn main() {
+    // The git hash can be given explicitly (for instance when the git repository isn't
+    // available, as in our Dockerfile). Otherwise, we ask git for it.
+    println!("cargo:rerun-if-env-changed=GIT_HASH");
+    if std::env::var_os("GIT_HASH").is_none() {
+        if let Some(hash) = git_hash() {
+            println!("cargo:rustc-env=GIT_HASH={hash}");
+        }
+        // HEAD changes on checkout, and the ref it points to lives in either 'refs' or, once
+        // git has packed its refs (for instance in 'git gc'), 'packed-refs'. Cargo always
+        // reruns us if told to watch a file that doesn't exist, so only watch what's there.
+        for path in ["HEAD", "refs", "packed-refs"] {
+            let path = format!("../../.git/{path}");
+            if std::path::Path::new(&path).exists() {
+                println!("cargo:rerun-if-changed={path}");
+            }
+        }
+    }
+
+    // The build time is only updated when this script reruns, so rerun it whenever
+    // the source changes. 'SOURCE_DATE_EPOCH' can be set for reproducible builds.
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rustc-env=BUILD_TIME={}", build_time());
+}
+
+fn git_hash() -> Option<String> {
+    let output = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let hash = String::from_utf8(output.stdout).ok()?;
+    let hash = hash.trim();
+    (!hash.is_empty()).then(|| hash.to_owned())
+}
+
+fn build_time() -> String {
+    std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .and_then(|secs| OffsetDateTime::from_unix_timestamp(secs).ok())
+        .unwrap_or_else(OffsetDateTime::now_utc)
+        .format(&Rfc3339)
+        .expect("timestamps should be formattable")
+}
@@ -0,0 +1,85 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Information about how a binary was built, so that we can tell which version of it is
+//! deployed. The git hash and build time are captured by each binary's build script.
+
+use crate::prometheus::escape_label_value;
+use serde::Serialize;
+
+/// Information about how a binary was built. Use [`build_info!`] to obtain this for the
+/// crate that it's called from.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BuildInfo {
+    /// The version of the crate.
+    pub version: &'static str,
+    /// The git hash that the binary was built from, if known.
+    pub git_hash: Option<&'static str>,
+    /// When the binary was built (RFC3339 formatted), if known.
+    pub build_time: Option<&'static str>,
+}
+
+/// Obtain the [`BuildInfo`] for the crate that this is called from.
+#[macro_export]
+macro_rules! build_info {
+    () => {
+        $crate::build_info::BuildInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            git_hash: option_env!("GIT_HASH"),
+            build_time: option_env!("BUILD_TIME"),
+        }
+    };
+}
+
+impl BuildInfo {
+    /// The build information as a JSON object.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("build info should be serializable")
+    }
+
+    /// A line in the prometheus text format for a gauge with the name given, which is always
+    /// 1 and is labelled with the version and git hash.
+    pub fn prometheus_metric(&self, name: &str) -> String {
+        format!(
+            "{name}{{version=\"{}\",git_hash=\"{}\"}} 1\n",
+            escape_label_value(self.version),
+            escape_label_value(self.git_hash.unwrap_or(""))
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn build_info_formats() {
+        let info = BuildInfo {
+            version: "0.1.0",
+            git_hash: Some("abc123"),
+            build_time: None,
+        };
+
+        assert_eq!(
+            info.to_json(),
+            r#"{"version":"0.1.0","git_hash":"abc123","build_time":null}"#
+        );
+        assert_eq!(
+            info.prometheus_metric("foo_build_info"),
+            "foo_build_info{version=\"0.1.0\",git_hash=\"abc123\"} 1\n"
+        );
+    }
+}
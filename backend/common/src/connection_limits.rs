@@ -0,0 +1,220 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Limit how many connections can be open to each of our websocket endpoints at once. Limits
+//! are given as a comma separated list of `endpoint=max` pairs, for example
+//! `feed=5000,shard_submit=10`. Endpoints that aren't given have no limit.
+
+use anyhow::{anyhow, Error};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// The endpoints that we can limit connections to.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Endpoint {
+    /// The core's `/feed` endpoint.
+    Feed,
+    /// The shard's `/submit` endpoint.
+    Submit,
+    /// The core's `/shard_submit` endpoint.
+    ShardSubmit,
+}
+
+impl Endpoint {
+    pub const ALL: [Endpoint; 3] = [Endpoint::Feed, Endpoint::Submit, Endpoint::ShardSubmit];
+
+    /// The name of the endpoint, as given in connection limits and metric labels.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Endpoint::Feed => "feed",
+            Endpoint::Submit => "submit",
+            Endpoint::ShardSubmit => "shard_submit",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl std::str::FromStr for Endpoint {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Endpoint::ALL
+            .into_iter()
+            .find(|e| e.as_str() == s)
+            .ok_or_else(|| {
+                anyhow!("Unknown endpoint '{s}'; expecting one of feed, submit, shard_submit")
+            })
+    }
+}
+
+/// The maximum number of connections allowed to each endpoint, and how many are currently open.
+#[derive(Debug, Default)]
+pub struct ConnectionLimits {
+    limits: [Option<usize>; Endpoint::ALL.len()],
+    counts: [AtomicUsize; Endpoint::ALL.len()],
+}
+
+impl ConnectionLimits {
+    /// The maximum number of connections allowed to an endpoint, or `None` if there's no limit.
+    pub fn limit(&self, endpoint: Endpoint) -> Option<usize> {
+        self.limits[endpoint.index()]
+    }
+
+    /// Lower the maximum number of connections allowed to an endpoint to `max`, unless
+    /// its limit is already lower than that.
+    pub fn limit_to(&mut self, endpoint: Endpoint, max: usize) {
+        let limit = &mut self.limits[endpoint.index()];
+        *limit = Some(limit.map_or(max, |limit| limit.min(max)));
+    }
+
+    /// How many connections to an endpoint are currently open.
+    pub fn count(&self, endpoint: Endpoint) -> usize {
+        self.counts[endpoint.index()].load(Ordering::Relaxed)
+    }
+
+    /// Make a note of a new connection to an endpoint, returning a guard which should be held
+    /// onto for as long as the connection is open. Returns `None` if the endpoint is at capacity.
+    pub fn try_acquire(self: &Arc<Self>, endpoint: Endpoint) -> Option<ConnectionGuard> {
+        let limit = self.limit(endpoint).unwrap_or(usize::MAX);
+        self.counts[endpoint.index()]
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                (count < limit).then_some(count + 1)
+            })
+            .ok()?;
+        Some(ConnectionGuard {
+            limits: Arc::clone(self),
+            endpoint,
+        })
+    }
+
+    /// The number of open connections (and the limit, if there is one) for each of the
+    /// endpoints given, in the text format that prometheus expects.
+    pub fn prometheus_metrics(&self, prefix: &str, endpoints: &[Endpoint]) -> String {
+        let mut s = String::new();
+        for &endpoint in endpoints {
+            let name = endpoint.as_str();
+            s.push_str(&format!(
+                "{prefix}_connections{{endpoint=\"{name}\"}} {}\n",
+                self.count(endpoint)
+            ));
+            if let Some(limit) = self.limit(endpoint) {
+                s.push_str(&format!(
+                    "{prefix}_connection_limit{{endpoint=\"{name}\"}} {limit}\n"
+                ));
+            }
+        }
+        s
+    }
+}
+
+impl std::str::FromStr for ConnectionLimits {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut limits = ConnectionLimits::default();
+        for pair in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (endpoint, max) = pair
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Expecting 'endpoint=max', but got '{pair}'"))?;
+            let endpoint: Endpoint = endpoint.trim().parse()?;
+            let max = max
+                .trim()
+                .parse()
+                .map_err(|e| anyhow!("Invalid connection limit for {}: {e}", endpoint.as_str()))?;
+            limits.limits[endpoint.index()] = Some(max);
+        }
+        Ok(limits)
+    }
+}
+
+/// The response to send when an endpoint is at capacity.
+pub fn at_capacity_response(endpoint: Endpoint) -> hyper::Response<hyper::Body> {
+    hyper::Response::builder()
+        .status(503)
+        .body(
+            format!(
+                "Too many connections to /{}; try again later",
+                endpoint.as_str()
+            )
+            .into(),
+        )
+        .unwrap()
+}
+
+/// This is handed back when a connection is allowed, and frees up its slot when dropped.
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    limits: Arc<ConnectionLimits>,
+    endpoint: Endpoint,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.limits.counts[self.endpoint.index()].fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn limits_are_parsed() {
+        let limits: ConnectionLimits = "feed=100, shard_submit=2".parse().unwrap();
+        assert_eq!(limits.limit(Endpoint::Feed), Some(100));
+        assert_eq!(limits.limit(Endpoint::Submit), None);
+        assert_eq!(limits.limit(Endpoint::ShardSubmit), Some(2));
+
+        let limits: ConnectionLimits = "".parse().unwrap();
+        assert!(Endpoint::ALL.iter().all(|&e| limits.limit(e).is_none()));
+
+        assert!("feed".parse::<ConnectionLimits>().is_err());
+        assert!("feed=lots".parse::<ConnectionLimits>().is_err());
+        assert!("metrics=1".parse::<ConnectionLimits>().is_err());
+    }
+
+    #[test]
+    fn limits_can_only_be_lowered() {
+        let mut limits: ConnectionLimits = "feed=100, shard_submit=2".parse().unwrap();
+        limits.limit_to(Endpoint::Feed, 10);
+        limits.limit_to(Endpoint::ShardSubmit, 10);
+        limits.limit_to(Endpoint::Submit, 10);
+        assert_eq!(limits.limit(Endpoint::Feed), Some(10));
+        assert_eq!(limits.limit(Endpoint::ShardSubmit), Some(2));
+        assert_eq!(limits.limit(Endpoint::Submit), Some(10));
+    }
+
+    #[test]
+    fn connections_are_limited_until_guards_are_dropped() {
+        let limits = Arc::new("submit=2".parse::<ConnectionLimits>().unwrap());
+
+        let a = limits.try_acquire(Endpoint::Submit).unwrap();
+        let _b = limits.try_acquire(Endpoint::Submit).unwrap();
+        assert!(limits.try_acquire(Endpoint::Submit).is_none());
+        assert_eq!(limits.count(Endpoint::Submit), 2);
+
+        // Other endpoints aren't affected:
+        let _feed = limits.try_acquire(Endpoint::Feed).unwrap();
+        assert_eq!(limits.count(Endpoint::Feed), 1);
+
+        drop(a);
+        assert_eq!(limits.count(Endpoint::Submit), 1);
+        assert!(limits.try_acquire(Endpoint::Submit).is_some());
+    }
+}
@@ -14,18 +14,33 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-use futures::io::{BufReader, BufWriter};
+use futures::io::{
+    AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter, ReadHalf, WriteHalf,
+};
 use hyper::server::conn::AddrStream;
 use hyper::{Body, Request, Response, Server};
+use soketto::extension::deflate::Deflate;
+use soketto::extension::{Extension, Param};
 use std::future::Future;
 use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
 
-/// A convenience function to start up a Hyper server and handle requests.
-pub async fn start_server<H, F>(addr: SocketAddr, handler: H) -> Result<(), anyhow::Error>
+/// A convenience function to start up a Hyper server and handle requests. Once `shutdown`
+/// resolves, we stop accepting new connections and wait for in-flight requests to complete.
+pub async fn start_server<H, F, S>(
+    addr: SocketAddr,
+    shutdown: S,
+    handler: H,
+) -> Result<(), anyhow::Error>
 where
     H: Clone + Send + Sync + 'static + FnMut(SocketAddr, Request<Body>) -> F,
     F: Send + 'static + Future<Output = Result<Response<Body>, anyhow::Error>>,
+    S: Future<Output = ()>,
 {
     let service = hyper::service::make_service_fn(move |addr: &AddrStream| {
         let mut handler = handler.clone();
@@ -35,20 +50,317 @@ where
     let server = Server::bind(&addr).serve(service);
 
     log::info!("listening on http://{}", server.local_addr());
-    server.await?;
+    server.with_graceful_shutdown(shutdown).await?;
 
     Ok(())
 }
 
-type WsStream = BufReader<BufWriter<Compat<hyper::upgrade::Upgraded>>>;
 pub type WsSender = soketto::connection::Sender<WsStream>;
 pub type WsReceiver = soketto::connection::Receiver<WsStream>;
 
+type Upgraded = Compat<hyper::upgrade::Upgraded>;
+
+/// The stream that websocket connections are built on.
+pub struct WsStream {
+    reader: BufReader<ReadHalf<Upgraded>>,
+    writer: WsWriter,
+    activity: WsActivity,
+}
+
+impl AsyncRead for WsStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.reader).poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            if n > 0 {
+                this.activity.record_read();
+            }
+        }
+        res
+    }
+}
+
+impl AsyncWrite for WsStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().writer).poll_write(cx, buf)
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().writer).poll_flush(cx)
+    }
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().writer).poll_close(cx)
+    }
+}
+
+/// Writes to a websocket connection go via a [`SharedWriter`] if it was handed a [`WsCloser`],
+/// so that the closer can write to the connection alongside the [`WsSender`]. Other connections
+/// avoid the locking that this needs.
+enum WsWriter {
+    Owned(BufWriter<ActivityWriter>),
+    Shared(SharedWriter),
+}
+
+impl AsyncWrite for WsWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            WsWriter::Owned(w) => Pin::new(w).poll_write(cx, buf),
+            WsWriter::Shared(w) => Pin::new(w).poll_write(cx, buf),
+        }
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            WsWriter::Owned(w) => Pin::new(w).poll_flush(cx),
+            WsWriter::Shared(w) => Pin::new(w).poll_flush(cx),
+        }
+    }
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            WsWriter::Owned(w) => Pin::new(w).poll_close(cx),
+            WsWriter::Shared(w) => Pin::new(w).poll_close(cx),
+        }
+    }
+}
+
+/// A buffered writer to a connection which can be cloned, so that more than one thing can
+/// write to it. Only one thing should write to it at a time, or their bytes will be interleaved.
+#[derive(Clone)]
+struct SharedWriter(Arc<Mutex<BufWriter<ActivityWriter>>>);
+
+impl AsyncWrite for SharedWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut *self.0.lock().unwrap()).poll_write(cx, buf)
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.0.lock().unwrap()).poll_flush(cx)
+    }
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.0.lock().unwrap()).poll_close(cx)
+    }
+}
+
+/// Writes to the underlying connection, making a note in a [`WsActivity`] of when bytes were
+/// last written to it. This sits beneath any buffering, so that it sees bytes as they're
+/// actually handed to the connection.
+struct ActivityWriter {
+    inner: WriteHalf<Upgraded>,
+    activity: WsActivity,
+}
+
+impl AsyncWrite for ActivityWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            if n > 0 {
+                this.activity.record_write();
+            }
+        }
+        res
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+
+/// Keeps track of when we last read or wrote any bytes at all on a websocket connection. Unlike
+/// [`WsReceiver::receive_data`], which only hands back complete messages, this also notices
+/// control frames such as pings, and partial messages that are still arriving. Likewise, writes
+/// are noticed as each chunk of a message is handed to the connection.
+#[derive(Clone)]
+pub struct WsActivity {
+    opened_at: Instant,
+    // Milliseconds after `opened_at` that bytes were last read:
+    last_read_ms: Arc<AtomicU64>,
+    // Milliseconds after `opened_at` that bytes were last written:
+    last_write_ms: Arc<AtomicU64>,
+}
+
+impl WsActivity {
+    fn new() -> Self {
+        WsActivity {
+            opened_at: Instant::now(),
+            last_read_ms: Arc::new(AtomicU64::new(0)),
+            last_write_ms: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    fn record_read(&self) {
+        let ms = self.opened_at.elapsed().as_millis() as u64;
+        self.last_read_ms.store(ms, Ordering::Relaxed);
+    }
+
+    fn record_write(&self) {
+        let ms = self.opened_at.elapsed().as_millis() as u64;
+        self.last_write_ms.store(ms, Ordering::Relaxed);
+    }
+
+    /// When bytes were last written to the connection (or when it was opened, if none have been).
+    pub fn last_write(&self) -> Instant {
+        self.opened_at + Duration::from_millis(self.last_write_ms.load(Ordering::Relaxed))
+    }
+
+    /// Resolves once no bytes have been written to the connection for the duration given,
+    /// counting from no earlier than when this is called. Use it alongside a write to find out
+    /// if the write has stopped making progress.
+    pub async fn no_writes_for(&self, duration: Duration) {
+        let started = Instant::now();
+        loop {
+            let deadline = self.last_write().max(started) + duration;
+            if deadline <= Instant::now() {
+                return;
+            }
+            tokio::time::sleep_until(deadline.into()).await;
+        }
+    }
+
+    /// When bytes were last read from the connection (or when it was opened, if none have been).
+    pub fn last_read(&self) -> Instant {
+        self.opened_at + Duration::from_millis(self.last_read_ms.load(Ordering::Relaxed))
+    }
+
+    /// Resolves once no bytes have been read from the connection for the duration given.
+    pub async fn idle_for(&self, duration: Duration) {
+        loop {
+            let deadline = self.last_read() + duration;
+            if deadline <= Instant::now() {
+                return;
+            }
+            tokio::time::sleep_until(deadline.into()).await;
+        }
+    }
+}
+
+/// Close a websocket connection with a specific status code and reason. [`WsSender::close`]
+/// always closes connections with a "normal closure" status code, which doesn't let the other
+/// side know that something went wrong.
+pub struct WsCloser(SharedWriter);
+
+impl WsCloser {
+    /// Send a close frame with the status code and reason given. The connection is left open
+    /// so that the other end can acknowledge it, and is closed once the [`WsSender`] and
+    /// [`WsReceiver`] are dropped. This should only be called once the [`WsSender`] is no
+    /// longer being used to send messages. The reason can be at most 123 bytes long.
+    pub async fn close(&mut self, code: u16, reason: &str) -> std::io::Result<()> {
+        self.0.write_all(&close_frame(code, reason)).await?;
+        self.0.flush().await
+    }
+}
+
+/// An unmasked (as all frames sent from a server are) close frame, as described in RFC 6455.
+fn close_frame(code: u16, reason: &str) -> Vec<u8> {
+    // Control frames can have at most 125 bytes of payload, 2 of which are the status code:
+    assert!(reason.len() <= 123, "websocket close reason is too long");
+    let mut frame = Vec::with_capacity(4 + reason.len());
+    // The FIN bit, and the opcode of a close frame:
+    frame.push(0x88);
+    frame.push(2 + reason.len() as u8);
+    frame.extend_from_slice(&code.to_be_bytes());
+    frame.extend_from_slice(reason.as_bytes());
+    frame
+}
+
 /// A convenience function to upgrade a Hyper request into a Soketto Websocket.
 pub fn upgrade_to_websocket<H, F>(req: Request<Body>, on_upgrade: H) -> hyper::Response<Body>
 where
     H: 'static + Send + FnOnce(WsSender, WsReceiver) -> F,
     F: Send + Future<Output = ()>,
+{
+    upgrade(req, None, false, None, false, |sender, receiver, _, _| {
+        on_upgrade(sender, receiver)
+    })
+}
+
+/// Like [`upgrade_to_websocket`], but if `allow_deflate` is true and the client offers the
+/// permessage-deflate extension (RFC 7692), messages sent and received on the connection are
+/// compressed with it. Otherwise, they're sent uncompressed as usual. If a `protocol` is given,
+/// we tell the client that we'll speak it; this should be one of the subprotocols that the
+/// client offered (see [`negotiate_protocol`]). The handler is also given a [`WsCloser`], to
+/// close the connection with a specific status code if need be, and a [`WsActivity`], to see
+/// how sending to the connection is progressing.
+pub fn upgrade_to_websocket_with_deflate<H, F>(
+    req: Request<Body>,
+    allow_deflate: bool,
+    protocol: Option<&'static str>,
+    on_upgrade: H,
+) -> hyper::Response<Body>
+where
+    H: 'static + Send + FnOnce(WsSender, WsReceiver, WsCloser, WsActivity) -> F,
+    F: Send + Future<Output = ()>,
+{
+    upgrade(
+        req,
+        None,
+        allow_deflate,
+        protocol,
+        true,
+        |sender, receiver, closer, activity| {
+            on_upgrade(
+                sender,
+                receiver,
+                closer.expect("closer asked for"),
+                activity,
+            )
+        },
+    )
+}
+
+/// Like [`upgrade_to_websocket`], but if a max message size is given, the [`WsReceiver`] will
+/// return an error as soon as a message (which may be fragmented over many frames) exceeds
+/// this size, rather than continuing to accumulate bytes until the soketto default is reached.
+/// The handler is also given a [`WsActivity`], to see when bytes last arrived on the connection.
+pub fn upgrade_to_websocket_with_max_message_size<H, F>(
+    req: Request<Body>,
+    max_message_size: Option<usize>,
+    on_upgrade: H,
+) -> hyper::Response<Body>
+where
+    H: 'static + Send + FnOnce(WsSender, WsReceiver, WsActivity) -> F,
+    F: Send + Future<Output = ()>,
+{
+    upgrade(
+        req,
+        max_message_size,
+        false,
+        None,
+        false,
+        |sender, receiver, _, activity| on_upgrade(sender, receiver, activity),
+    )
+}
+
+fn upgrade<H, F>(
+    req: Request<Body>,
+    max_message_size: Option<usize>,
+    allow_deflate: bool,
+    protocol: Option<&'static str>,
+    with_closer: bool,
+    on_upgrade: H,
+) -> hyper::Response<Body>
+where
+    H: 'static + Send + FnOnce(WsSender, WsReceiver, Option<WsCloser>, WsActivity) -> F,
+    F: Send + Future<Output = ()>,
 {
     if !is_upgrade_request(&req) {
         return basic_response(400, "Expecting WebSocket upgrade headers");
@@ -80,12 +392,24 @@ where
     let mut accept_key_buf = [0; 32];
     let accept_key = generate_websocket_accept_key(key.as_bytes(), &mut accept_key_buf);
 
+    // Accept the permessage-deflate extension if we're allowed to and it's on offer:
+    let deflate = allow_deflate
+        .then(|| negotiate_deflate(req.headers()))
+        .flatten();
+
     // Tell the client that we accept the upgrade-to-WS request:
-    let response = Response::builder()
+    let mut response = Response::builder()
         .status(hyper::StatusCode::SWITCHING_PROTOCOLS)
         .header(hyper::header::CONNECTION, "upgrade")
         .header(hyper::header::UPGRADE, "websocket")
-        .header("Sec-WebSocket-Accept", accept_key)
+        .header("Sec-WebSocket-Accept", accept_key);
+    if let Some(deflate) = &deflate {
+        response = response.header("Sec-WebSocket-Extensions", extension_header(deflate));
+    }
+    if let Some(protocol) = protocol {
+        response = response.header("Sec-WebSocket-Protocol", protocol);
+    }
+    let response = response
         .body(Body::empty())
         .expect("bug: failed to build response");
 
@@ -100,20 +424,105 @@ where
             }
         };
 
-        // Start a Soketto server with it:
-        let server =
-            soketto::handshake::Server::new(BufReader::new(BufWriter::new(stream.compat())));
+        // Start a Soketto server with it, holding onto a way to write to it ourselves too
+        // if we've been asked for one:
+        let (reader, writer) = stream.compat().split();
+        let activity = WsActivity::new();
+        let writer = ActivityWriter {
+            inner: writer,
+            activity: activity.clone(),
+        };
+        let (writer, closer) = if with_closer {
+            let writer = SharedWriter(Arc::new(Mutex::new(BufWriter::new(writer))));
+            let closer = WsCloser(writer.clone());
+            (WsWriter::Shared(writer), Some(closer))
+        } else {
+            (WsWriter::Owned(BufWriter::new(writer)), None)
+        };
+        let mut server = soketto::handshake::Server::new(WsStream {
+            reader: BufReader::new(reader),
+            writer,
+            activity: activity.clone(),
+        });
+        if let Some(deflate) = deflate {
+            server.add_extension(Box::new(deflate));
+        }
 
         // Get hold of a way to send and receive messages:
-        let (sender, receiver) = server.into_builder().finish();
+        let mut builder = server.into_builder();
+        if let Some(max_message_size) = max_message_size {
+            builder.set_max_message_size(max_message_size);
+        }
+        let (sender, receiver) = builder.finish();
 
         // Pass these to our when-upgraded handler:
-        on_upgrade(sender, receiver).await;
+        on_upgrade(sender, receiver, closer, activity).await;
     });
 
     response
 }
 
+/// If the client offers the permessage-deflate extension in its Sec-WebSocket-Extensions
+/// header(s), return the extension configured according to the first offer that we can accept.
+fn negotiate_deflate(headers: &hyper::HeaderMap) -> Option<Deflate> {
+    headers
+        .get_all("Sec-WebSocket-Extensions")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .find_map(|offer| {
+            let mut parts = offer.split(';').map(str::trim);
+            if parts.next()? != "permessage-deflate" {
+                return None;
+            }
+            let params: Vec<Param> = parts
+                .filter(|part| !part.is_empty())
+                .map(|part| {
+                    let (name, value) = match part.split_once('=') {
+                        Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                        None => (part, None),
+                    };
+                    let mut param = Param::new(name);
+                    param.set_value(value);
+                    param
+                })
+                .collect();
+
+            let mut deflate = Deflate::new(soketto::connection::Mode::Server);
+            deflate.configure(&params).ok()?;
+            deflate.is_enabled().then_some(deflate)
+        })
+}
+
+/// Pick the subprotocol to speak from those that the client offers in its Sec-WebSocket-Protocol
+/// header(s), preferring whichever it lists first. Returns `None` if the client doesn't offer any
+/// of the subprotocols that we support.
+pub fn negotiate_protocol(
+    headers: &hyper::HeaderMap,
+    supported: &[&'static str],
+) -> Option<&'static str> {
+    headers
+        .get_all("Sec-WebSocket-Protocol")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .find_map(|offer| supported.iter().find(|&&p| p == offer.trim()).copied())
+}
+
+/// The value of the Sec-WebSocket-Extensions response header that accepts an extension.
+fn extension_header(extension: &dyn Extension) -> String {
+    let mut header = extension.name().to_owned();
+    for param in extension.params() {
+        header.push_str("; ");
+        header.push_str(param.name());
+        if let Some(value) = param.value() {
+            header.push('=');
+            header.push_str(value);
+        }
+    }
+    header
+}
+
 /// A helper to return a basic HTTP response with a code and text body.
 fn basic_response(code: u16, msg: impl AsRef<str>) -> Response<Body> {
     Response::builder()
@@ -173,3 +582,52 @@ fn header_contains_value(
     }
     false
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn headers(extensions: &str) -> hyper::HeaderMap {
+        let mut headers = hyper::HeaderMap::new();
+        headers.insert("Sec-WebSocket-Extensions", extensions.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn deflate_is_accepted_when_offered() {
+        let deflate = negotiate_deflate(&headers("permessage-deflate")).unwrap();
+        assert!(extension_header(&deflate).starts_with("permessage-deflate"));
+
+        // Browsers typically offer something like this:
+        let deflate =
+            negotiate_deflate(&headers("permessage-deflate; client_max_window_bits")).unwrap();
+        assert!(extension_header(&deflate).starts_with("permessage-deflate"));
+
+        // Other extensions can be offered alongside it:
+        assert!(negotiate_deflate(&headers("x-foo, permessage-deflate")).is_some());
+    }
+
+    #[test]
+    fn deflate_is_not_accepted_when_not_offered() {
+        assert!(negotiate_deflate(&hyper::HeaderMap::new()).is_none());
+        assert!(negotiate_deflate(&headers("x-foo; bar=1")).is_none());
+    }
+
+    #[test]
+    fn protocol_is_negotiated_in_client_preference_order() {
+        let mut headers = hyper::HeaderMap::new();
+        headers.insert("Sec-WebSocket-Protocol", "foo, b, a".parse().unwrap());
+        assert_eq!(negotiate_protocol(&headers, &["a", "b"]), Some("b"));
+        assert_eq!(negotiate_protocol(&headers, &["c"]), None);
+        assert_eq!(negotiate_protocol(&hyper::HeaderMap::new(), &["a"]), None);
+    }
+
+    #[test]
+    fn close_frame_contains_code_and_reason() {
+        assert_eq!(
+            close_frame(1011, "too slow"),
+            [&[0x88, 10, 0x03, 0xF3][..], b"too slow"].concat()
+        );
+        assert_eq!(close_frame(1000, ""), vec![0x88, 2, 0x03, 0xE8]);
+    }
+}
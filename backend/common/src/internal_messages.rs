@@ -23,6 +23,15 @@ use crate::node_message::Payload;
 use crate::node_types::{BlockHash, NodeDetails};
 use serde::{Deserialize, Serialize};
 
+/// The version of the messages that shards and the core exchange. Shards say which version they
+/// speak in a `protocol` query parameter when connecting, and the core replies with the version
+/// that it speaks in [`FromTelemetryCore::Ready`]. Shards and cores that don't say are treated as
+/// speaking version 0, and are only sent messages that version 0 understands.
+///
+/// Version 1 adds the `SystemIntervalV2` form of [`Payload`], the [`MuteReason::TooManyChains`] and
+/// [`MuteReason::GlobalQuota`] reasons for muting nodes, and [`FromShardAggregator::Identify`].
+pub const PROTOCOL_VERSION: u32 = 1;
+
 id_type! {
     /// The shard-local ID of a given node, where a single connection
     /// might send data on behalf of more than one chain.
@@ -48,6 +57,10 @@ pub enum FromShardAggregator {
     },
     /// Inform the telemetry core that a node has been removed
     RemoveNode { local_id: ShardNodeId },
+    /// Tell the telemetry core which shard this is, so that it can tell where nodes are
+    /// coming from. This is sent before anything else, and only to cores that speak protocol
+    /// version 1 or above, since older cores won't know how to deserialize it.
+    Identify { shard_id: String },
 }
 
 /// Message sent form the telemetry core to a telemetry shard
@@ -57,11 +70,64 @@ pub enum FromTelemetryCore {
         local_id: ShardNodeId,
         reason: MuteReason,
     },
+    /// The core has finished setting up the shard connection and is ready to receive
+    /// node data. This is only sent to shards that ask for it when connecting, since
+    /// older shards won't know how to deserialize it.
+    Initialized,
+    /// This is sent instead of [`FromTelemetryCore::Initialized`] to shards that say which
+    /// [`PROTOCOL_VERSION`] they speak when connecting, and tells them the version that we speak.
+    Ready { protocol_version: u32 },
 }
 
 /// Why is the thing being muted?
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub enum MuteReason {
     Overquota,
     ChainNotAllowed,
+    /// Only sent to shards that speak protocol version 1 or above.
+    TooManyChains,
+    /// Only sent to shards that speak protocol version 1 or above.
+    GlobalQuota,
+}
+
+impl MuteReason {
+    /// The closest reason that a shard speaking the [`PROTOCOL_VERSION`] given understands.
+    /// Shards that don't know about a reason would fail to deserialize it.
+    pub fn for_protocol(self, protocol_version: u32) -> MuteReason {
+        match self {
+            MuteReason::TooManyChains | MuteReason::GlobalQuota if protocol_version < 1 => {
+                MuteReason::Overquota
+            }
+            reason => reason,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn older_shards_are_sent_mute_reasons_they_understand() {
+        assert_eq!(
+            MuteReason::TooManyChains.for_protocol(0),
+            MuteReason::Overquota
+        );
+        assert_eq!(
+            MuteReason::TooManyChains.for_protocol(1),
+            MuteReason::TooManyChains
+        );
+        assert_eq!(
+            MuteReason::GlobalQuota.for_protocol(0),
+            MuteReason::Overquota
+        );
+        assert_eq!(
+            MuteReason::GlobalQuota.for_protocol(1),
+            MuteReason::GlobalQuota
+        );
+        assert_eq!(
+            MuteReason::ChainNotAllowed.for_protocol(0),
+            MuteReason::ChainNotAllowed
+        );
+    }
 }
@@ -14,14 +14,20 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+pub mod build_info;
 pub mod byte_size;
+pub mod connection_limits;
 pub mod http_utils;
 pub mod id_type;
 pub mod internal_messages;
 pub mod node_message;
 pub mod node_types;
+pub mod prometheus;
 pub mod ready_chunks_all;
+pub mod real_ip;
 pub mod rolling_total;
+pub mod self_test;
+pub mod shutdown;
 pub mod time;
 pub mod ws_client;
 
@@ -38,6 +44,6 @@ pub use assign_id::AssignId;
 pub use dense_map::DenseMap;
 pub use either_sink::EitherSink;
 pub use mean_list::MeanList;
-pub use most_seen::MostSeen;
+pub use most_seen::{MostSeen, TieBreak};
 pub use multi_map_unique::MultiMapUnique;
 pub use num_stats::NumStats;
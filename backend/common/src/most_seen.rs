@@ -24,6 +24,20 @@ pub struct MostSeen<T> {
     current_best: T,
     current_count: usize,
     others: HashMap<T, usize>,
+    tie_break: TieBreak,
+}
+
+/// How to choose between items that have been seen the same number of times.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TieBreak {
+    /// Keep the current best item until another item is seen strictly more often than it.
+    /// If the current best is overtaken by several items that are tied with each other, the
+    /// smallest of them (the lexicographically first, for strings) becomes the new best.
+    #[default]
+    KeepCurrent,
+    /// Always prefer the smallest of the items that are tied for being the most seen, even
+    /// if this means replacing the current best.
+    Smallest,
 }
 
 impl<T: Default> Default for MostSeen<T> {
@@ -34,6 +48,7 @@ impl<T: Default> Default for MostSeen<T> {
             current_best: T::default(),
             current_count: 0,
             others: HashMap::new(),
+            tie_break: TieBreak::default(),
         }
     }
 }
@@ -47,8 +62,17 @@ impl<T> MostSeen<T> {
             current_best: item,
             current_count: 1,
             others: HashMap::new(),
+            tie_break: TieBreak::default(),
         }
     }
+    /// Choose how to break ties between items that have been seen the same number of times.
+    pub fn with_tie_break(mut self, tie_break: TieBreak) -> Self {
+        self.tie_break = tie_break;
+        self
+    }
+    pub fn tie_break(&self) -> TieBreak {
+        self.tie_break
+    }
     pub fn best(&self) -> &T {
         &self.current_best
     }
@@ -57,7 +81,18 @@ impl<T> MostSeen<T> {
     }
 }
 
-impl<T: Hash + Eq + Clone> MostSeen<T> {
+impl<T: Hash + Ord + Clone> MostSeen<T> {
+    /// Should an item seen `count` times replace the current best?
+    fn beats_best(&self, item: &T, count: usize) -> bool {
+        match self.tie_break {
+            TieBreak::KeepCurrent => count > self.current_count,
+            TieBreak::Smallest => {
+                count > self.current_count
+                    || (count == self.current_count && item < &self.current_best)
+            }
+        }
+    }
+
     pub fn insert(&mut self, item: &T) -> ChangeResult {
         if &self.current_best == item {
             // Item already the best one; bump count.
@@ -70,7 +105,8 @@ impl<T: Hash + Eq + Clone> MostSeen<T> {
         *item_count += 1;
 
         // Is item now the best?
-        if *item_count > self.current_count {
+        let item_count = *item_count;
+        if self.beats_best(item, item_count) {
             let (mut item, mut count) = self.others.remove_entry(item).expect("item added above");
 
             // Swap the current best for the new best:
@@ -90,15 +126,19 @@ impl<T: Hash + Eq + Clone> MostSeen<T> {
             // Item already the best one; reduce count (don't allow to drop below 0)
             self.current_count = self.current_count.saturating_sub(1);
 
-            // Is there a new best?
-            let other_best = self.others.iter().max_by_key(|f| f.1);
+            // Is there a new best? Of the items seen the most, prefer the smallest, so
+            // that the result doesn't depend on the order of items in the map.
+            let other_best = self
+                .others
+                .iter()
+                .max_by(|a, b| a.1.cmp(b.1).then_with(|| b.0.cmp(a.0)));
 
             let (other_item, &other_count) = match other_best {
                 Some(item) => item,
                 None => return ChangeResult::NoChange,
             };
 
-            if other_count > self.current_count {
+            if self.beats_best(other_item, other_count) {
                 // Clone item to unborrow self.others so that we can remove
                 // the item from it. We could pre-emptively remove and reinsert
                 // instead, but most of the time there is no change, so I'm
@@ -223,6 +263,79 @@ mod test {
         assert_eq!(*a.best(), "First", "5");
     }
 
+    #[test]
+    fn ties_dont_cause_best_to_oscillate() {
+        // Which of several tied items takes over from the best used to depend on the order
+        // of items in the map, which is different for every `MostSeen`, so try a few:
+        let mut bests = std::collections::HashSet::new();
+        for _ in 0..20 {
+            let mut a: MostSeen<&str> = MostSeen::default();
+            for item in ["Best", "Best", "Best", "C", "C", "B", "B", "A", "A"] {
+                a.insert(&item);
+            }
+
+            // As the old best comes and goes, the same one of the tied items takes over:
+            for _ in 0..5 {
+                a.remove(&"Best");
+                assert!(a.remove(&"Best").has_changed());
+                bests.insert(*a.best());
+
+                a.insert(&"Best");
+                assert!(a.insert(&"Best").has_changed());
+                assert_eq!(*a.best(), "Best");
+            }
+
+            // Items tied with the best coming and going doesn't change it:
+            a.remove(&"Best");
+            for _ in 0..10 {
+                assert!(!a.remove(&"B").has_changed());
+                assert_eq!(*a.best(), "Best");
+                assert!(!a.insert(&"B").has_changed());
+                assert_eq!(*a.best(), "Best");
+            }
+        }
+        assert_eq!(bests, std::collections::HashSet::from(["A"]));
+    }
+
+    #[test]
+    fn ties_for_new_best_are_broken_deterministically() {
+        // The order of items in the map differs each time, so try a few times:
+        for _ in 0..20 {
+            let mut a: MostSeen<&str> = MostSeen::default();
+            for item in ["Best", "Best", "Best", "C", "C", "A", "A", "B", "B"] {
+                a.insert(&item);
+            }
+            assert_eq!(*a.best(), "Best");
+
+            // "A", "B" and "C" are now tied and all ahead of "Best"; "A" wins:
+            a.remove(&"Best");
+            a.remove(&"Best");
+            assert_eq!(*a.best(), "A");
+            assert_eq!(a.best_count(), 2);
+        }
+    }
+
+    #[test]
+    fn smallest_tie_break_prefers_smallest_item() {
+        let mut a: MostSeen<&str> = MostSeen::default().with_tie_break(TieBreak::Smallest);
+        assert_eq!(a.tie_break(), TieBreak::Smallest);
+
+        a.insert(&"Second");
+        a.insert(&"First");
+        assert_eq!(*a.best(), "First");
+
+        a.insert(&"Second");
+        assert_eq!(*a.best(), "Second");
+
+        // Tied again, so the smallest wins:
+        a.insert(&"First");
+        assert_eq!(*a.best(), "First");
+
+        a.remove(&"Second");
+        a.remove(&"First");
+        assert_eq!(*a.best(), "First");
+    }
+
     #[test]
     fn it_tracks_best() {
         let mut a: MostSeen<&str> = MostSeen::default();
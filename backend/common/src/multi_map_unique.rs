@@ -41,6 +41,14 @@ impl<K, V> MultiMapUnique<K, V> {
         self.key_to_values.get(key)
     }
 
+    /// Return the key associated with a value, if any.
+    pub fn get_key(&self, value: &V) -> Option<&K>
+    where
+        V: Eq + Hash,
+    {
+        self.value_to_key.get(value)
+    }
+
     /// Remove a value from the MultiMap, returning the key it was found
     /// under, if it was found at all.
     ///
@@ -163,4 +171,17 @@ mod test {
         assert!(b_vals.contains(&3));
         assert!(b_vals.contains(&4));
     }
+
+    #[test]
+    fn key_can_be_looked_up_from_value() {
+        let mut m = MultiMapUnique::new();
+
+        m.insert("a", 1);
+        m.insert("b", 2);
+        m.insert("b", 1);
+
+        assert_eq!(m.get_key(&1), Some(&"b"));
+        assert_eq!(m.get_key(&2), Some(&"b"));
+        assert_eq!(m.get_key(&3), None);
+    }
 }
@@ -18,6 +18,9 @@
 //! There is a separate JSON representation of these types, because internally we want to be
 //! able to serialize these messages to bincode, and various serde attributes aren't compatible
 //! with this, hence this separate internal representation.
+//!
+//! Nodes can also send these types directly, bincode encoded, to a shard's `/submit_bin`
+//! endpoint, so changes to them should be made with care.
 
 use crate::node_types::{Block, BlockHash, BlockNumber, NodeDetails};
 use serde::{Deserialize, Serialize};
@@ -53,7 +56,8 @@ impl From<NodeMessage> for Payload {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone)]
+#[serde(from = "WirePayload")]
 pub enum Payload {
     SystemConnected(SystemConnected),
     SystemInterval(SystemInterval),
@@ -63,6 +67,62 @@ pub enum Payload {
     HwBench(NodeHwBench),
 }
 
+/// The form that a [`Payload`] is (de)serialized in. Bincode is positional, and so intervals that
+/// carry any of the [`SystemInterval`] fields added since the first release are sent as a separate
+/// `SystemIntervalV2` variant on the end. Intervals that don't are sent exactly as before, so that
+/// older cores and `/submit_bin` nodes are still understood.
+#[derive(Deserialize)]
+enum WirePayload {
+    SystemConnected(SystemConnected),
+    SystemInterval(SystemIntervalV1),
+    BlockImport(Block),
+    NotifyFinalized(Finalized),
+    AfgAuthoritySet(AfgAuthoritySet),
+    HwBench(NodeHwBench),
+    SystemIntervalV2(SystemInterval),
+}
+
+/// A borrowed [`WirePayload`], so that we don't need to clone payloads to serialize them.
+#[derive(Serialize)]
+enum WirePayloadRef<'a> {
+    SystemConnected(&'a SystemConnected),
+    SystemInterval(SystemIntervalV1),
+    BlockImport(&'a Block),
+    NotifyFinalized(&'a Finalized),
+    AfgAuthoritySet(&'a AfgAuthoritySet),
+    HwBench(&'a NodeHwBench),
+    SystemIntervalV2(&'a SystemInterval),
+}
+
+impl From<WirePayload> for Payload {
+    fn from(payload: WirePayload) -> Payload {
+        match payload {
+            WirePayload::SystemConnected(m) => Payload::SystemConnected(m),
+            WirePayload::SystemInterval(m) => Payload::SystemInterval(m.into()),
+            WirePayload::BlockImport(m) => Payload::BlockImport(m),
+            WirePayload::NotifyFinalized(m) => Payload::NotifyFinalized(m),
+            WirePayload::AfgAuthoritySet(m) => Payload::AfgAuthoritySet(m),
+            WirePayload::HwBench(m) => Payload::HwBench(m),
+            WirePayload::SystemIntervalV2(m) => Payload::SystemInterval(m),
+        }
+    }
+}
+
+impl Serialize for Payload {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let payload = match self {
+            Payload::SystemConnected(m) => WirePayloadRef::SystemConnected(m),
+            Payload::SystemInterval(m) if m.has_v2_fields() => WirePayloadRef::SystemIntervalV2(m),
+            Payload::SystemInterval(m) => WirePayloadRef::SystemInterval(m.into()),
+            Payload::BlockImport(m) => WirePayloadRef::BlockImport(m),
+            Payload::NotifyFinalized(m) => WirePayloadRef::NotifyFinalized(m),
+            Payload::AfgAuthoritySet(m) => WirePayloadRef::AfgAuthoritySet(m),
+            Payload::HwBench(m) => WirePayloadRef::HwBench(m),
+        };
+        payload.serialize(serializer)
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SystemConnected {
     pub genesis_hash: BlockHash,
@@ -79,6 +139,67 @@ pub struct SystemInterval {
     pub finalized_hash: Option<BlockHash>,
     pub block: Option<Block>,
     pub used_state_cache_size: Option<f32>,
+    pub database_size: Option<u64>,
+    pub error_count: Option<u64>,
+    pub warning_count: Option<u64>,
+    pub major_syncing: Option<bool>,
+}
+
+impl SystemInterval {
+    /// Does this interval carry any of the fields that only `SystemIntervalV2` can send?
+    fn has_v2_fields(&self) -> bool {
+        self.database_size.is_some()
+            || self.error_count.is_some()
+            || self.warning_count.is_some()
+            || self.major_syncing.is_some()
+    }
+}
+
+/// The fields that a [`SystemInterval`] was first released with.
+#[derive(Serialize, Deserialize)]
+struct SystemIntervalV1 {
+    peers: Option<u64>,
+    txcount: Option<u64>,
+    bandwidth_upload: Option<f64>,
+    bandwidth_download: Option<f64>,
+    finalized_height: Option<BlockNumber>,
+    finalized_hash: Option<BlockHash>,
+    block: Option<Block>,
+    used_state_cache_size: Option<f32>,
+}
+
+impl From<&SystemInterval> for SystemIntervalV1 {
+    fn from(m: &SystemInterval) -> SystemIntervalV1 {
+        SystemIntervalV1 {
+            peers: m.peers,
+            txcount: m.txcount,
+            bandwidth_upload: m.bandwidth_upload,
+            bandwidth_download: m.bandwidth_download,
+            finalized_height: m.finalized_height,
+            finalized_hash: m.finalized_hash,
+            block: m.block,
+            used_state_cache_size: m.used_state_cache_size,
+        }
+    }
+}
+
+impl From<SystemIntervalV1> for SystemInterval {
+    fn from(m: SystemIntervalV1) -> SystemInterval {
+        SystemInterval {
+            peers: m.peers,
+            txcount: m.txcount,
+            bandwidth_upload: m.bandwidth_upload,
+            bandwidth_download: m.bandwidth_download,
+            finalized_height: m.finalized_height,
+            finalized_hash: m.finalized_hash,
+            block: m.block,
+            used_state_cache_size: m.used_state_cache_size,
+            database_size: None,
+            error_count: None,
+            warning_count: None,
+            major_syncing: None,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -122,6 +243,17 @@ impl Payload {
             _ => None,
         }
     }
+
+    /// Drop any [`SystemInterval`] fields that only `SystemIntervalV2` can send, so that the
+    /// payload is serialized in the form that older cores understand.
+    pub fn clear_v2_fields(&mut self) {
+        if let Payload::SystemInterval(interval) = self {
+            interval.database_size = None;
+            interval.error_count = None;
+            interval.warning_count = None;
+            interval.major_syncing = None;
+        }
+    }
 }
 
 #[cfg(test)]
@@ -160,6 +292,7 @@ mod tests {
                     startup_time: None,
                     sysinfo: None,
                     ip: Some("127.0.0.1".into()),
+                    shard: None,
                 },
             }),
         });
@@ -177,6 +310,10 @@ mod tests {
                 finalized_hash: None,
                 block: None,
                 used_state_cache_size: None,
+                database_size: None,
+                error_count: None,
+                warning_count: None,
+                major_syncing: None,
             }),
         });
     }
@@ -210,6 +347,73 @@ mod tests {
         });
     }
 
+    fn system_interval() -> SystemInterval {
+        SystemInterval {
+            peers: Some(3),
+            txcount: None,
+            bandwidth_upload: None,
+            bandwidth_download: None,
+            finalized_height: Some(10),
+            finalized_hash: None,
+            block: None,
+            used_state_cache_size: None,
+            database_size: None,
+            error_count: None,
+            warning_count: None,
+            major_syncing: None,
+        }
+    }
+
+    #[test]
+    fn system_interval_without_new_fields_keeps_the_original_layout() {
+        // How payloads were laid out before intervals grew any fields:
+        #[derive(Deserialize)]
+        enum OriginalPayload {
+            #[allow(dead_code)]
+            SystemConnected(SystemConnected),
+            SystemInterval(SystemIntervalV1),
+        }
+
+        let bytes = bincode::options()
+            .serialize(&Payload::SystemInterval(system_interval()))
+            .unwrap();
+        let original: OriginalPayload = bincode::options().deserialize(&bytes).unwrap();
+        match original {
+            OriginalPayload::SystemInterval(interval) => {
+                assert_eq!(interval.peers, Some(3));
+                assert_eq!(interval.finalized_height, Some(10));
+            }
+            _ => panic!("expected an interval"),
+        }
+    }
+
+    #[test]
+    fn system_interval_with_new_fields_round_trips() {
+        let mut payload = Payload::SystemInterval(SystemInterval {
+            error_count: Some(2),
+            major_syncing: Some(true),
+            ..system_interval()
+        });
+
+        let bytes = bincode::options().serialize(&payload).unwrap();
+        match bincode::options().deserialize(&bytes).unwrap() {
+            Payload::SystemInterval(interval) => {
+                assert_eq!(interval.peers, Some(3));
+                assert_eq!(interval.error_count, Some(2));
+                assert_eq!(interval.major_syncing, Some(true));
+            }
+            p => panic!("expected an interval, got {p:?}"),
+        }
+
+        // Once the new fields are cleared, it's sent in the original form again:
+        payload.clear_v2_fields();
+        let cleared = bincode::options().serialize(&payload).unwrap();
+        let original = bincode::options()
+            .serialize(&Payload::SystemInterval(system_interval()))
+            .unwrap();
+        assert_eq!(cleared, original);
+    }
+
     #[test]
     fn bincode_block_zero() {
         let raw = Block::zero();
@@ -29,7 +29,7 @@ pub use primitive_types::H256 as BlockHash;
 pub type NetworkId = ArrayString<64>;
 
 /// Basic node details.
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct NodeDetails {
     pub chain: Box<str>,
     pub name: Box<str>,
@@ -43,6 +43,10 @@ pub struct NodeDetails {
     pub target_env: Option<Box<str>>,
     pub sysinfo: Option<NodeSysInfo>,
     pub ip: Option<Box<str>>,
+    /// The shard that the node is connected through. This is filled in by the
+    /// core, and so is never sent from shards.
+    #[serde(skip)]
+    pub shard: Option<Box<str>>,
 }
 
 /// Hardware and software information for the node.
@@ -178,6 +182,9 @@ pub struct NodeLocation {
     pub latitude: f32,
     pub longitude: f32,
     pub city: Box<str>,
+    /// The ISO 3166-1 code of the country that the node is in, if known. This
+    /// isn't sent to feeds.
+    pub country: Option<Box<str>>,
 }
 
 impl Serialize for NodeLocation {
@@ -203,6 +210,7 @@ impl<'de> Deserialize<'de> for NodeLocation {
             latitude,
             longitude,
             city,
+            country: None,
         })
     }
 }
@@ -19,7 +19,7 @@ use std::convert::TryFrom;
 use std::iter::Sum;
 
 /// Keep track of last N numbers pushed onto internal stack.
-/// Provides means to get an average of said numbers.
+/// Provides means to get an average or percentiles of said numbers.
 pub struct NumStats<T> {
     stack: Box<[T]>,
     index: usize,
@@ -67,6 +67,41 @@ impl<T: NumOps + Zero + Bounded + Copy + Sum + TryFrom<usize>> NumStats<T> {
     }
 }
 
+impl<T: NumOps + Zero + Bounded + Copy + Sum + TryFrom<usize> + PartialOrd> NumStats<T> {
+    /// The `p`th percentile (from 0 to 100) of the numbers we're keeping track of, using
+    /// the nearest-rank method. `p` of 0 gives the smallest number, and 100 the largest.
+    /// Returns zero if no numbers have been pushed.
+    pub fn percentile(&self, p: f64) -> T {
+        let [val] = self.percentiles([p]);
+        val
+    }
+
+    /// Several percentiles at once, as with [`NumStats::percentile`], sorting the numbers
+    /// we're keeping track of only once to work them all out.
+    pub fn percentiles<const N: usize>(&self, ps: [f64; N]) -> [T; N] {
+        let cap = std::cmp::min(self.index, self.stack.len());
+
+        if cap == 0 {
+            return [T::zero(); N];
+        }
+
+        // Work on a copy so that the order of the stack is left alone:
+        let mut vals = self.stack[..cap].to_vec();
+        vals.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+
+        ps.map(|p| {
+            let rank = (p.clamp(0.0, 100.0) * cap as f64 / 100.0).ceil() as usize;
+            vals[rank.saturating_sub(1).min(cap - 1)]
+        })
+    }
+
+    /// The median of the numbers we're keeping track of. Returns zero if no
+    /// numbers have been pushed.
+    pub fn median(&self) -> T {
+        self.percentile(50.0)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -98,6 +133,69 @@ mod tests {
         assert_eq!(stats.average(), 0);
     }
 
+    #[test]
+    fn percentiles_of_nothing_are_zero() {
+        let stats: NumStats<u64> = NumStats::new(10);
+
+        assert_eq!(stats.percentile(0.0), 0);
+        assert_eq!(stats.median(), 0);
+        assert_eq!(stats.percentile(100.0), 0);
+    }
+
+    #[test]
+    fn percentiles_of_one_number_are_that_number() {
+        let mut stats: NumStats<u64> = NumStats::new(10);
+
+        stats.push(42);
+
+        assert_eq!(stats.percentile(0.0), 42);
+        assert_eq!(stats.median(), 42);
+        assert_eq!(stats.percentile(95.0), 42);
+        assert_eq!(stats.percentile(100.0), 42);
+    }
+
+    #[test]
+    fn calculates_correct_percentiles() {
+        let mut stats: NumStats<u64> = NumStats::new(100);
+
+        // Push 1..=100 in a jumbled up order:
+        for n in 0..100 {
+            stats.push((n * 37) % 100 + 1);
+        }
+
+        assert_eq!(stats.percentile(0.0), 1);
+        assert_eq!(stats.percentile(1.0), 1);
+        assert_eq!(stats.percentile(25.0), 25);
+        assert_eq!(stats.median(), 50);
+        assert_eq!(stats.percentile(95.0), 95);
+        assert_eq!(stats.percentile(100.0), 100);
+
+        // Out of range percentiles are clamped:
+        assert_eq!(stats.percentile(-10.0), 1);
+        assert_eq!(stats.percentile(110.0), 100);
+
+        // Asking for several at once gives the same answers:
+        assert_eq!(stats.percentiles([50.0, 95.0, 1.0]), [50, 95, 1]);
+
+        // The order that numbers were pushed in is left alone, so the oldest is replaced next:
+        stats.push(1000);
+        assert_eq!(stats.percentile(100.0), 1000);
+        assert_eq!(stats.percentile(0.0), 2);
+    }
+
+    #[test]
+    fn percentiles_only_consider_the_retained_window() {
+        let mut stats: NumStats<u64> = NumStats::new(3);
+
+        stats.push(100);
+        stats.push(1);
+        stats.push(2);
+        stats.push(3);
+
+        assert_eq!(stats.percentile(100.0), 3);
+        assert_eq!(stats.median(), 2);
+    }
+
     #[test]
     fn resets_properly() {
         let mut stats: NumStats<u64> = NumStats::new(10);
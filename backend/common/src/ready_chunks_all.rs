@@ -22,6 +22,9 @@
 //! better when the amount of items varies a bunch (and we don't want to allocate a fixed capacity every time),
 //! and can help ensure that we process as many items as possible each time (rather than only up to capacity items).
 //!
+//! An optional maximum chunk size can be given, in which case no more than that many items will be returned at once,
+//! and any remaining ready items will be returned on the next poll.
+//!
 //! Code is adapted from the futures implementation
 //! (see [ready_chunks.rs](https://docs.rs/futures-util/0.3.15/src/futures_util/stream/stream/ready_chunks.rs.html)).
 
@@ -43,6 +46,7 @@ pin_project! {
         #[pin]
         stream: Fuse<St>,
         items: Vec<St::Item>,
+        max_chunk_size: Option<usize>,
     }
 }
 
@@ -50,10 +54,13 @@ impl<St: Stream> ReadyChunksAll<St>
 where
     St: Stream,
 {
-    pub fn new(stream: St) -> Self {
+    /// Wrap a stream. If `max_chunk_size` is `None`, every ready item is returned
+    /// at once. Otherwise, at most `max_chunk_size` items are returned at a time.
+    pub fn new(stream: St, max_chunk_size: Option<usize>) -> Self {
         Self {
             stream: stream.fuse(),
             items: Vec::new(),
+            max_chunk_size,
         }
     }
 }
@@ -76,9 +83,15 @@ impl<St: Stream> Stream for ReadyChunksAll<St> {
                     }
                 }
 
-                // Push the ready item into the buffer
+                // Push the ready item into the buffer, returning the buffer
+                // if it's now as large as we allow it to get.
                 Poll::Ready(Some(item)) => {
                     this.items.push(item);
+                    if let Some(max_chunk_size) = *this.max_chunk_size {
+                        if this.items.len() >= max_chunk_size {
+                            return Poll::Ready(Some(mem::take(this.items)));
+                        }
+                    }
                 }
 
                 // Since the underlying stream ran out of values, return what we
@@ -119,3 +132,35 @@ impl<St: FusedStream> FusedStream for ReadyChunksAll<St> {
         self.stream.is_terminated() && self.items.is_empty()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::stream;
+
+    #[test]
+    fn returns_all_ready_items_by_default() {
+        let chunks: Vec<Vec<u32>> =
+            block_on(ReadyChunksAll::new(stream::iter(1..=5), None).collect());
+        assert_eq!(chunks, vec![vec![1, 2, 3, 4, 5]]);
+    }
+
+    #[test]
+    fn returns_at_most_max_chunk_size_items() {
+        let chunks: Vec<Vec<u32>> =
+            block_on(ReadyChunksAll::new(stream::iter(1..=5), Some(2)).collect());
+        assert_eq!(chunks, vec![vec![1, 2], vec![3, 4], vec![5]]);
+
+        let chunks: Vec<Vec<u32>> =
+            block_on(ReadyChunksAll::new(stream::iter(1..=4), Some(2)).collect());
+        assert_eq!(chunks, vec![vec![1, 2], vec![3, 4]]);
+    }
+
+    #[test]
+    fn max_chunk_size_of_one_returns_single_items() {
+        let chunks: Vec<Vec<u32>> =
+            block_on(ReadyChunksAll::new(stream::iter(1..=3), Some(1)).collect());
+        assert_eq!(chunks, vec![vec![1], vec![2], vec![3]]);
+    }
+}
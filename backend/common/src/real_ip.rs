@@ -0,0 +1,364 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use hyper::header::HeaderName;
+use std::net::{IpAddr, SocketAddr};
+
+/**
+Extract the "real" IP address of the connection by looking at headers
+set by proxies (this is inspired by Actix Web's implementation of the feature).
+
+First, check for the standardised "Forwarded" header. This looks something like:
+
+"Forwarded: for=12.34.56.78;host=example.com;proto=https, for=23.45.67.89"
+
+Each proxy can append to this comma separated list of forwarded-details. We'll look for
+the first "for" address and try to decode that.
+
+If this doesn't yield a result, look for the non-standard but common X-Forwarded-For header,
+which contains a comma separated list of addresses; each proxy in the potential chain possibly
+appending one to the end. So, take the first of these if it exists.
+
+If still no luck, look for the X-Real-IP header, which we expect to contain a single IP address.
+
+If that _still_ doesn't work, fall back to the socket address of the connection.
+
+If `trusted_headers` is not empty, then we ignore the above and instead look only at those
+headers, in the order given, using the first one that yields an address. The "Forwarded" and
+"X-Forwarded-For" headers are decoded as above, and any other header is expected to contain a
+single IP address or a comma separated list of them (in which case we take the first). If none
+of them yield an address, we fall back to the socket address of the connection.
+
+Any of these headers can be set by whoever is making the request, and so they should only be
+trusted if the server sits behind a trusted proxy which sets (or strips) them.
+*/
+pub fn real_ip(
+    addr: SocketAddr,
+    headers: &hyper::HeaderMap,
+    trusted_headers: &[HeaderName],
+) -> (IpAddr, Source) {
+    if !trusted_headers.is_empty() {
+        return pick_ip_from_trusted_headers(headers, trusted_headers, addr);
+    }
+
+    let forwarded = headers.get("forwarded").and_then(header_as_str);
+    let forwarded_for = headers.get("x-forwarded-for").and_then(header_as_str);
+    let real_ip = headers.get("x-real-ip").and_then(header_as_str);
+    pick_best_ip_from_options(forwarded, forwarded_for, real_ip, addr)
+}
+
+/**
+Like [`real_ip`] given some `trusted_headers`, but for when the address is used to enforce limits,
+and so must not be something that the client can choose.
+
+Proxies append the address that they received a request from to the end of the "Forwarded" and
+"X-Forwarded-For" headers, leaving anything already in them (which the client could have set)
+alone. So, if the server sits behind `trusted_proxies` proxies, we take the address that many
+entries from the end of the list (the rightmost entry given one proxy). If there are fewer entries
+than that, we take the first. Any other header is expected to be set by a proxy rather than
+appended to, and is used as is (taking the last entry of a comma separated list).
+*/
+pub fn real_ip_behind_proxies(
+    addr: SocketAddr,
+    headers: &hyper::HeaderMap,
+    trusted_headers: &[HeaderName],
+    trusted_proxies: usize,
+) -> (IpAddr, Source) {
+    for name in trusted_headers {
+        let value = match headers.get(name).and_then(header_as_str) {
+            Some(value) => value,
+            None => continue,
+        };
+
+        let hops = if name == "forwarded" || name == "x-forwarded-for" {
+            trusted_proxies.max(1)
+        } else {
+            1
+        };
+        let ip = get_nth_last_entry(value, hops).and_then(|entry| {
+            if name == "forwarded" {
+                get_first_addr_from_forwarded_header(entry)
+            } else {
+                Some(entry.trim())
+            }
+        });
+
+        if let Some(ip) = ip.and_then(parse_ip) {
+            return (ip, Source::TrustedHeader(name.clone()));
+        }
+    }
+
+    // Fall back to local IP address if none of the headers give us one
+    (addr.ip(), Source::SocketAddr)
+}
+
+/// The source of the address returned
+pub enum Source {
+    ForwardedHeader,
+    XForwardedForHeader,
+    XRealIpHeader,
+    /// One of the headers that we were explicitly told to trust.
+    TrustedHeader(HeaderName),
+    SocketAddr,
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::ForwardedHeader => write!(f, "'Forwarded' header"),
+            Source::XForwardedForHeader => write!(f, "'X-Forwarded-For' header"),
+            Source::XRealIpHeader => write!(f, "'X-Real-Ip' header"),
+            Source::TrustedHeader(name) => write!(f, "'{}' header", name),
+            Source::SocketAddr => write!(f, "Socket address"),
+        }
+    }
+}
+
+fn header_as_str(value: &hyper::header::HeaderValue) -> Option<&str> {
+    std::str::from_utf8(value.as_bytes()).ok()
+}
+
+fn pick_best_ip_from_options(
+    // Forwarded header value (if present)
+    forwarded: Option<&str>,
+    // X-Forwarded-For header value (if present)
+    forwarded_for: Option<&str>,
+    // X-Real-IP header value (if present)
+    real_ip: Option<&str>,
+    // socket address (if known)
+    addr: SocketAddr,
+) -> (IpAddr, Source) {
+    let realip = forwarded
+        .as_ref()
+        .and_then(|val| {
+            let addr = get_first_addr_from_forwarded_header(val)?;
+            Some((addr, Source::ForwardedHeader))
+        })
+        .or_else(|| {
+            // fall back to X-Forwarded-For
+            forwarded_for.as_ref().and_then(|val| {
+                let addr = get_first_addr_from_x_forwarded_for_header(val)?;
+                Some((addr, Source::XForwardedForHeader))
+            })
+        })
+        .or_else(|| {
+            // fall back to X-Real-IP
+            real_ip.as_ref().and_then(|val| {
+                let addr = val.trim();
+                Some((addr, Source::XRealIpHeader))
+            })
+        })
+        .and_then(|(ip, source)| {
+            let addr = parse_ip(ip)?;
+            Some((addr, source))
+        })
+        // Fall back to local IP address if the above fails
+        .unwrap_or((addr.ip(), Source::SocketAddr));
+
+    realip
+}
+
+fn pick_ip_from_trusted_headers(
+    headers: &hyper::HeaderMap,
+    trusted_headers: &[HeaderName],
+    addr: SocketAddr,
+) -> (IpAddr, Source) {
+    for name in trusted_headers {
+        let value = match headers.get(name).and_then(header_as_str) {
+            Some(value) => value,
+            None => continue,
+        };
+
+        let ip = if name == "forwarded" {
+            get_first_addr_from_forwarded_header(value)
+        } else {
+            get_first_addr_from_x_forwarded_for_header(value)
+        };
+
+        if let Some(ip) = ip.and_then(parse_ip) {
+            return (ip, Source::TrustedHeader(name.clone()));
+        }
+    }
+
+    // Fall back to local IP address if none of the headers give us one
+    (addr.ip(), Source::SocketAddr)
+}
+
+/// Try parsing assuming the address may have a port first,
+/// and then assuming it doesn't. IPv6 addresses without a port
+/// may still be surrounded in square brackets.
+fn parse_ip(ip: &str) -> Option<IpAddr> {
+    let unbracketed = ip
+        .strip_prefix('[')
+        .and_then(|ip| ip.strip_suffix(']'))
+        .unwrap_or(ip);
+    ip.parse::<SocketAddr>()
+        .map(|s| s.ip())
+        .or_else(|_| unbracketed.parse::<IpAddr>())
+        .ok()
+}
+
+/// Follow <https://datatracker.ietf.org/doc/html/rfc7239> to decode the Forwarded header value.
+/// Roughly, proxies can add new sets of values by appending a comma to the existing list
+/// (so we have something like "values1, values2, values3" from proxy1, proxy2 and proxy3 for
+/// instance) and then the values themselves are ';' separated name=value pairs. The value in each
+/// pair may or may not be surrounded in double quotes.
+///
+/// Examples from the RFC:
+///
+/// ```text
+/// Forwarded: for="_gazonk"
+/// Forwarded: For="[2001:db8:cafe::17]:4711"
+/// Forwarded: for=192.0.2.60;proto=http;by=203.0.113.43
+/// Forwarded: for=192.0.2.43, for=198.51.100.17
+/// ```
+fn get_first_addr_from_forwarded_header(value: &str) -> Option<&str> {
+    let first_values = value.split(',').next()?;
+
+    for pair in first_values.split(';') {
+        let mut parts = pair.trim().splitn(2, '=');
+        let key = parts.next()?;
+        let value = parts.next()?;
+
+        if key.to_lowercase() == "for" {
+            // trim double quotes if they surround the value:
+            let value = if value.starts_with('"') && value.ends_with('"') {
+                &value[1..value.len() - 1]
+            } else {
+                value
+            };
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+fn get_first_addr_from_x_forwarded_for_header(value: &str) -> Option<&str> {
+    value.split(",").map(|val| val.trim()).next()
+}
+
+/// Return the entry `n` from the end of a comma separated list (1 being the last entry), or the
+/// first entry if there are fewer than `n` of them.
+fn get_nth_last_entry(value: &str, n: usize) -> Option<&str> {
+    let entries: Vec<&str> = value.split(',').collect();
+    let idx = entries.len().saturating_sub(n);
+    entries.get(idx).copied()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn get_addr_from_forwarded_rfc_examples() {
+        let examples = vec![
+            (r#"for="_gazonk""#, "_gazonk"),
+            (
+                r#"For="[2001:db8:cafe::17]:4711""#,
+                "[2001:db8:cafe::17]:4711",
+            ),
+            (r#"for=192.0.2.60;proto=http;by=203.0.113.43"#, "192.0.2.60"),
+            (r#"for=192.0.2.43, for=198.51.100.17"#, "192.0.2.43"),
+        ];
+
+        for (value, expected) in examples {
+            assert_eq!(
+                get_first_addr_from_forwarded_header(value),
+                Some(expected),
+                "Header value: {}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn trusted_headers_are_used_in_order() {
+        let socket_addr: SocketAddr = "10.0.0.1:1234".parse().unwrap();
+        let mut headers = hyper::HeaderMap::new();
+        headers.insert("x-real-ip", "1.1.1.1".parse().unwrap());
+        headers.insert("cf-connecting-ip", "2.2.2.2".parse().unwrap());
+        headers.insert("forwarded", "for=3.3.3.3:80, for=4.4.4.4".parse().unwrap());
+
+        let ip = |trusted: &[&str]| {
+            let trusted: Vec<HeaderName> = trusted.iter().map(|h| h.parse().unwrap()).collect();
+            real_ip(socket_addr, &headers, &trusted).0.to_string()
+        };
+
+        // With no trusted headers we use the built in precedence:
+        assert_eq!(ip(&[]), "3.3.3.3");
+        // Otherwise, the first configured header that's present is used:
+        assert_eq!(ip(&["cf-connecting-ip", "x-real-ip"]), "2.2.2.2");
+        assert_eq!(ip(&["true-client-ip", "x-real-ip"]), "1.1.1.1");
+        assert_eq!(ip(&["Forwarded"]), "3.3.3.3");
+        // And if none are present, we fall back to the socket address:
+        assert_eq!(ip(&["true-client-ip"]), "10.0.0.1");
+    }
+
+    #[test]
+    fn addresses_behind_proxies_are_taken_from_the_right() {
+        let socket_addr: SocketAddr = "10.0.0.1:1234".parse().unwrap();
+        let mut headers = hyper::HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            "6.6.6.6, 1.1.1.1, 10.0.0.2".parse().unwrap(),
+        );
+        headers.insert(
+            "forwarded",
+            "for=6.6.6.6, for=2.2.2.2;proto=https".parse().unwrap(),
+        );
+        headers.insert("x-real-ip", "3.3.3.3".parse().unwrap());
+
+        let ip = |trusted: &str, proxies: usize| {
+            let trusted: HeaderName = trusted.parse().unwrap();
+            real_ip_behind_proxies(socket_addr, &headers, &[trusted], proxies)
+                .0
+                .to_string()
+        };
+
+        // The address that the client made up on the left is ignored:
+        assert_eq!(ip("x-forwarded-for", 1), "10.0.0.2");
+        assert_eq!(ip("x-forwarded-for", 2), "1.1.1.1");
+        assert_eq!(ip("forwarded", 1), "2.2.2.2");
+        // With more proxies than entries, we take the first entry:
+        assert_eq!(ip("forwarded", 5), "6.6.6.6");
+        // Other headers are used as is:
+        assert_eq!(ip("x-real-ip", 2), "3.3.3.3");
+        // And if none are present, we fall back to the socket address:
+        assert_eq!(ip("true-client-ip", 1), "10.0.0.1");
+    }
+
+    #[test]
+    fn ipv6_addresses_are_retained() {
+        let socket_addr: SocketAddr = "10.0.0.1:1234".parse().unwrap();
+        let ip = |name: &str, value: &str| {
+            let mut headers = hyper::HeaderMap::new();
+            headers.insert(name.parse::<HeaderName>().unwrap(), value.parse().unwrap());
+            real_ip(socket_addr, &headers, &[]).0.to_string()
+        };
+
+        assert_eq!(
+            ip("x-forwarded-for", "2001:db8::1, 10.0.0.2"),
+            "2001:db8::1"
+        );
+        assert_eq!(ip("x-real-ip", "[2001:db8::2]:4711"), "2001:db8::2");
+        assert_eq!(ip("forwarded", r#"for="[2001:db8::3]""#), "2001:db8::3");
+        assert_eq!(
+            ip("forwarded", r#"for="[2001:db8:cafe::17]:4711""#),
+            "2001:db8:cafe::17"
+        );
+    }
+}
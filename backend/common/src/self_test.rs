@@ -0,0 +1,121 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Helpers for a telemetry process to check that it's working properly, by connecting to
+//! its own endpoints and making sure that a node shows up on a feed.
+
+use crate::node_types::BlockHash;
+use crate::ws_client;
+use futures::StreamExt;
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Connect to the websocket endpoint given, retrying until the deadline if the connection
+/// fails (for instance because the server hasn't started listening yet).
+pub async fn connect(
+    uri: &http::Uri,
+    deadline: Instant,
+) -> anyhow::Result<(ws_client::Sender, ws_client::Receiver)> {
+    loop {
+        match ws_client::connect(uri).await {
+            Ok(connection) => return Ok(connection.into_channels()),
+            Err(e) if Instant::now() >= deadline => {
+                return Err(anyhow::anyhow!("Could not connect to {uri}: {e}"))
+            }
+            Err(_) => tokio::time::sleep(Duration::from_millis(250)).await,
+        }
+    }
+}
+
+/// Subscribe to the chain with the genesis hash given on the feed endpoint given, and wait
+/// for the node with the name given to show up on it. Fails if this doesn't happen before
+/// the deadline. The feed endpoint should be a '/feed/v2' one, which names the action of
+/// each message rather than just giving its ID.
+pub async fn wait_for_node_on_feed(
+    feed_uri: &http::Uri,
+    genesis_hash: BlockHash,
+    node_name: &str,
+    deadline: Instant,
+) -> anyhow::Result<()> {
+    let (feed_tx, mut feed_rx) = connect(feed_uri, deadline).await?;
+    let subscribe = format!("subscribe:{genesis_hash:0x}");
+
+    // The chain won't exist until the node has been added, and subscribing to a chain that
+    // doesn't exist does nothing, so we keep subscribing until we see the node:
+    while Instant::now() < deadline {
+        feed_tx.unbounded_send(ws_client::SentMessage::Text(subscribe.clone()))?;
+        let msg = match tokio::time::timeout(Duration::from_secs(1), feed_rx.next()).await {
+            Err(_) => continue,
+            Ok(msg) => msg.ok_or_else(|| anyhow::anyhow!("Feed connection closed"))??,
+        };
+        let bytes = match &msg {
+            ws_client::RecvMessage::Binary(data) => &data[..],
+            ws_client::RecvMessage::Text(text) => text.as_bytes(),
+        };
+        if contains_added_node(bytes, node_name)? {
+            return Ok(());
+        }
+    }
+
+    Err(anyhow::anyhow!(
+        "Node '{node_name}' did not show up on the feed at {feed_uri}"
+    ))
+}
+
+/// A message sent to '/feed/v2' connections.
+#[derive(Deserialize)]
+struct FeedMessage {
+    action: Box<str>,
+    payload: serde_json::Value,
+}
+
+/// Does this batch of feed messages tell us that the node with the name given was added?
+fn contains_added_node(bytes: &[u8], node_name: &str) -> anyhow::Result<bool> {
+    for line in bytes.split(|&b| b == b'\n').filter(|line| !line.is_empty()) {
+        let message: FeedMessage = serde_json::from_slice(line)?;
+        if &*message.action != "AddedNode" {
+            continue;
+        }
+        // The payload starts with the node ID, followed by the node details, which
+        // start with the node name:
+        if message.payload[1][0].as_str() == Some(node_name) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn finds_added_node_by_name() {
+        let bytes = br#"{"action":"Version","payload":32}
+{"action":"AddedNode","payload":[1,["Alice","Substrate Node","2.0.0"]]}
+{"action":"RemovedNode","payload":2}
+"#;
+        assert!(contains_added_node(bytes, "Alice").unwrap());
+        assert!(!contains_added_node(bytes, "Bob").unwrap());
+    }
+
+    #[test]
+    fn ignores_other_actions_mentioning_the_name() {
+        let bytes = br#"{"action":"LocatedNode","payload":[1,["Alice"]]}"#;
+        assert!(!contains_added_node(bytes, "Alice").unwrap());
+    }
+}
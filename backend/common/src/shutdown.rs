@@ -0,0 +1,137 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Coordinate a graceful shutdown: connections are told when it's time to stop, and
+//! we wait (for a while) for them to finish up before exiting.
+
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+/// Hands out [`ShutdownHandle`]s, and tells them all when it's time to shut down.
+pub struct Shutdown {
+    token: CancellationToken,
+    alive_tx: mpsc::Sender<()>,
+    alive_rx: mpsc::Receiver<()>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (alive_tx, alive_rx) = mpsc::channel(1);
+        Shutdown {
+            token: CancellationToken::new(),
+            alive_tx,
+            alive_rx,
+        }
+    }
+
+    /// Hand out a new handle. When shutting down, we wait for every handle
+    /// to be dropped (up to some grace period).
+    pub fn handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            token: self.token.clone(),
+            _alive: self.alive_tx.clone(),
+        }
+    }
+
+    /// Tell every [`ShutdownHandle`] that we're shutting down, and then wait up to
+    /// `grace` for them all to be dropped. Returns false if some were still around
+    /// when the grace period ended.
+    pub async fn shutdown(self, grace: Duration) -> bool {
+        let Shutdown {
+            token,
+            alive_tx,
+            mut alive_rx,
+        } = self;
+
+        token.cancel();
+        drop(alive_tx);
+
+        // Nothing is ever sent on this channel, so this resolves once every sender
+        // (ie every handle) has been dropped:
+        tokio::time::timeout(grace, alive_rx.recv()).await.is_ok()
+    }
+}
+
+/// This is told when it's time to shut down. We won't finish shutting down until this is
+/// dropped (or the grace period ends), so hold onto it until everything has been tidied up.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    token: CancellationToken,
+    _alive: mpsc::Sender<()>,
+}
+
+impl ShutdownHandle {
+    /// Resolves once we've been asked to shut down.
+    pub async fn wait(&self) {
+        self.token.cancelled().await
+    }
+}
+
+/// Resolves once the process is asked to stop via SIGTERM or SIGINT (Ctrl+C).
+#[cfg(unix)]
+pub async fn signal() -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        _ = sigterm.recv() => {},
+        _ = sigint.recv() => {},
+    }
+    Ok(())
+}
+
+/// Resolves once the process is asked to stop via Ctrl+C.
+#[cfg(not(unix))]
+pub async fn signal() -> std::io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn waits_for_handles_to_be_dropped() {
+        let shutdown = Shutdown::new();
+        let handle = shutdown.handle();
+
+        let (finished_tx, finished_rx) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            handle.wait().await;
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let _ = finished_tx.send(());
+        });
+
+        assert!(shutdown.shutdown(Duration::from_secs(5)).await);
+        assert!(finished_rx.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_grace_period() {
+        let shutdown = Shutdown::new();
+        let _handle = shutdown.handle();
+
+        assert!(!shutdown.shutdown(Duration::from_millis(100)).await);
+    }
+}
@@ -23,6 +23,10 @@ mod receiver;
 /// The channel based send interface
 mod sender;
 
-pub use connect::{connect, ConnectError, Connection, RawReceiver, RawSender};
+pub use connect::{
+    connect, connect_with_deflate, connect_with_headers, connect_with_headers_and_ca,
+    connect_with_protocols, connect_with_timeout, CaCertificates, ConnectError, Connection,
+    RawReceiver, RawSender,
+};
 pub use receiver::{Receiver, RecvError, RecvMessage};
 pub use sender::{SendError, Sender, SentMessage};
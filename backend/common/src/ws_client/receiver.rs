@@ -24,10 +24,14 @@ pub struct Receiver {
     pub(super) closer: Arc<OnClose>,
 }
 
+/// The ways in which receiving messages from a connection can fail.
 #[derive(thiserror::Error, Debug)]
 pub enum RecvError {
     #[error("Text message contains invalid UTF8: {0}")]
     InvalidUtf8(#[from] std::string::FromUtf8Error),
+    /// Something went wrong with the underlying connection, which has now been closed.
+    #[error("Failed to receive data: {0}")]
+    Connection(#[from] soketto::connection::Error),
     #[error("Stream finished")]
     StreamFinished,
     #[error("Failed to send close message")]
@@ -46,7 +46,7 @@ pub struct Sender {
 
 impl Sender {
     /// Ask the underlying Websocket connection to close.
-    pub async fn close(&mut self) -> Result<(), SendError<SentMessage>> {
+    pub async fn close(&mut self) -> Result<(), SendError> {
         self.closer.0.send(()).map_err(|_| SendError::CloseError)?;
         Ok(())
     }
@@ -56,11 +56,10 @@ impl Sender {
     }
     /// Unbounded send will always queue the message and doesn't
     /// need to be awaited.
-    pub fn unbounded_send(&self, msg: SentMessage) -> Result<(), channel::mpsc::SendError> {
+    pub fn unbounded_send(&self, msg: SentMessage) -> Result<(), SendError> {
         self.inner
             .unbounded_send(msg)
-            .map_err(|e| e.into_send_error())?;
-        Ok(())
+            .map_err(|_| SendError::Closed)
     }
     /// Convert this sender into a Sink
     pub fn into_sink(
@@ -70,10 +69,36 @@ impl Sender {
     }
 }
 
-#[derive(thiserror::Error, Debug, Clone)]
-pub enum SendError<T: std::fmt::Debug + 'static> {
-    #[error("Failed to send message: {0}")]
-    ChannelError(#[from] flume::SendError<T>),
+/// The ways in which sending messages into a connection can fail.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum SendError {
+    /// The connection has closed, and so can't be sent any more messages.
+    #[error("Failed to send message: the connection is closed")]
+    Closed,
+    /// The connection couldn't be asked to close, because it's already closed.
     #[error("Failed to send close message")]
     CloseError,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::sync::broadcast;
+
+    #[test]
+    fn sending_into_a_closed_connection_fails() {
+        let (inner, rx) = channel::mpsc::unbounded();
+        let (closer, _) = broadcast::channel(1);
+        let sender = Sender {
+            inner,
+            closer: Arc::new(OnClose(closer)),
+        };
+        assert!(sender.unbounded_send(SentMessage::StaticText("hi")).is_ok());
+
+        drop(rx);
+        assert_eq!(
+            sender.unbounded_send(SentMessage::StaticText("hi")),
+            Err(SendError::Closed)
+        );
+    }
+}
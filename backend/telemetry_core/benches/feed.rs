@@ -0,0 +1,277 @@
+//! Benchmarks covering the hot paths involved in serializing and sending messages to feeds:
+//!
+//! - `feed: single node update`: how long it takes a node update to reach a subscribed feed.
+//! - `feed: subscribe burst`: how long it takes to send a feed the state of a chain with
+//!   10k and 50k nodes on it when it subscribes.
+//! - `feed: broadcast fan-out`: how long it takes a node update to reach every one of many
+//!   feeds subscribed to the chain.
+//!
+//! The node data is generated using [`FakeTelemetry`], with fixed names and timestamps so that
+//! each run does the same work. Run them from the `backend` folder with:
+//!
+//! ```text
+//! cargo bench -p telemetry_core --bench feed
+//! ```
+//!
+//! Append a filter like `-- "subscribe burst"` to run just some of them. As with the `subscribe`
+//! benchmark, the timings include the overhead of the websocket connections, so compare them
+//! against a baseline run rather than reading too much into their absolute values.
+
+use common::node_types::{BlockHash, BlockNumber};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use std::time::{Duration, Instant};
+use test_utils::fake_telemetry::FakeTelemetry;
+use test_utils::feed_message_de::FeedMessage;
+use test_utils::server::channels::{FeedReceiver, FeedSender, ShardSender};
+use test_utils::server::{ProcessId, Server};
+use test_utils::workspace::{start_server, CoreOpts, ServerOpts, ShardOpts};
+use tokio::runtime::Runtime;
+
+/// Every message that we generate is given this timestamp.
+const TIMESTAMP: &str = "2021-07-12T10:37:47.714666+01:00";
+/// Every node that we generate is on this chain.
+const CHAIN: &str = "Polkadot"; // No limit to #nodes on this network.
+
+/// Time how long it takes for a single node update to be serialized and sent to a feed.
+pub fn benchmark_single_node_update(c: &mut Criterion) {
+    let rt = Runtime::new().expect("tokio runtime should start");
+
+    c.bench_function("feed: single node update", move |b| {
+        b.to_async(&rt).iter_custom(|iters| async move {
+            let (server, shard_id) = start_bench_server().await;
+            let (mut node_tx, node) = add_nodes(&server, shard_id, 1).await;
+            let mut feeds = subscribe_feeds(&server, 1).await;
+
+            let mut total_time = Duration::ZERO;
+            for n in 0..iters {
+                // Height 1 was imported when the node was added:
+                let height = n + 2;
+                let start = Instant::now();
+                node_tx
+                    .send_json_text(node.block_import_message(height, TIMESTAMP))
+                    .unwrap();
+                wait_for_best_block(&mut feeds[0].1, height).await;
+                total_time += start.elapsed();
+            }
+
+            server.shutdown().await;
+            total_time
+        })
+    });
+}
+
+/// Time how long it takes to send the state of a chain to a feed that subscribes to it.
+pub fn benchmark_subscribe_burst(c: &mut Criterion) {
+    let rt = Runtime::new().expect("tokio runtime should start");
+
+    let mut group = c.benchmark_group("feed: subscribe burst");
+    group.sample_size(10);
+    for number_of_nodes in [10_000, 50_000] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(number_of_nodes),
+            &number_of_nodes,
+            |b, &number_of_nodes| {
+                b.to_async(&rt).iter_custom(|iters| async move {
+                    let (server, shard_id) = start_bench_server().await;
+                    let (_node_tx, _) = add_nodes(&server, shard_id, number_of_nodes).await;
+
+                    let mut total_time = Duration::ZERO;
+                    for _n in 0..iters {
+                        let (feed_tx, mut feed_rx) = server
+                            .get_core()
+                            .connect_feed()
+                            .await
+                            .expect("feed can connect");
+
+                        let start = Instant::now();
+                        feed_tx.send_command("subscribe", CHAIN).unwrap();
+                        wait_for_pong(&feed_tx, &mut feed_rx).await;
+                        total_time += start.elapsed();
+                    }
+
+                    server.shutdown().await;
+                    total_time
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+/// Time how long it takes for a single node update to be sent to every feed subscribed to
+/// the chain.
+pub fn benchmark_broadcast_fan_out(c: &mut Criterion) {
+    let rt = Runtime::new().expect("tokio runtime should start");
+
+    let mut group = c.benchmark_group("feed: broadcast fan-out");
+    for number_of_feeds in [100, 500] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(number_of_feeds),
+            &number_of_feeds,
+            |b, &number_of_feeds| {
+                b.to_async(&rt).iter_custom(|iters| async move {
+                    let (server, shard_id) = start_bench_server().await;
+                    let (mut node_tx, node) = add_nodes(&server, shard_id, 1).await;
+                    let mut feeds = subscribe_feeds(&server, number_of_feeds).await;
+
+                    let mut total_time = Duration::ZERO;
+                    for n in 0..iters {
+                        // Height 1 was imported when the node was added:
+                        let height = n + 2;
+                        let start = Instant::now();
+                        node_tx
+                            .send_json_text(node.block_import_message(height, TIMESTAMP))
+                            .unwrap();
+                        for (_, feed_rx) in &mut feeds {
+                            wait_for_best_block(feed_rx, height).await;
+                        }
+                        total_time += start.elapsed();
+                    }
+
+                    server.shutdown().await;
+                    total_time
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+/// Start a server with a single aggregator and shard, configured to accept as much node data
+/// as we throw at it. The ID of the shard is handed back along with the server.
+async fn start_bench_server() -> (Server, ProcessId) {
+    let mut server = start_server(
+        ServerOpts {
+            release_mode: true,
+            log_output: false,
+        },
+        CoreOpts {
+            worker_threads: Some(16),
+            num_aggregators: Some(1),
+            ..Default::default()
+        },
+        ShardOpts {
+            max_nodes_per_connection: Some(usize::MAX),
+            max_node_data_per_second: Some(usize::MAX),
+            worker_threads: Some(2),
+            ..Default::default()
+        },
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+    (server, shard_id)
+}
+
+/// Add the given number of nodes (which have each imported block 1) to the same chain, over a
+/// single connection, and wait for the core to know about all of them. The connection must
+/// be kept alive to keep the nodes around. The last node added is also handed back.
+async fn add_nodes(
+    server: &Server,
+    shard_id: ProcessId,
+    number_of_nodes: usize,
+) -> (ShardSender, FakeTelemetry) {
+    let (mut node_tx, _) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .expect("node can connect");
+
+    let mut last_node = None;
+    for n in 0..number_of_nodes {
+        let node = FakeTelemetry {
+            block_time: Duration::from_secs(6),
+            node_name: format!("Node {n}"),
+            chain: CHAIN.to_owned(),
+            genesis_hash: BlockHash::from_low_u64_ne(1),
+            message_id: n,
+        };
+        node_tx
+            .send_json_text(node.connect_message(TIMESTAMP))
+            .unwrap();
+        node_tx
+            .send_json_text(node.block_import_message(1, TIMESTAMP))
+            .unwrap();
+        last_node = Some(node);
+    }
+
+    // Rather than waiting for some arbitrary amount of time, wait until a feed is told that
+    // every node has been added, so that adding them doesn't skew the results.
+    let (_feed_tx, mut feed_rx) = server
+        .get_core()
+        .connect_feed()
+        .await
+        .expect("feed can connect");
+    loop {
+        let msgs = feed_rx.recv_feed_messages_once().await.unwrap();
+        let all_added = msgs.iter().any(|m| {
+            matches!(m, FeedMessage::AddedChain { node_count, .. } if *node_count == number_of_nodes)
+        });
+        if all_added {
+            break;
+        }
+    }
+
+    (
+        node_tx,
+        last_node.expect("at least one node should be added"),
+    )
+}
+
+/// Connect the given number of feeds and subscribe them to the chain, waiting for each one to
+/// have received the state of the chain.
+async fn subscribe_feeds(
+    server: &Server,
+    number_of_feeds: usize,
+) -> Vec<(FeedSender, FeedReceiver)> {
+    let mut feeds = server
+        .get_core()
+        .connect_multiple_feeds(number_of_feeds)
+        .await
+        .expect("feeds can connect");
+
+    for (feed_tx, _) in feeds.iter() {
+        feed_tx.send_command("subscribe", CHAIN).unwrap();
+    }
+    for (feed_tx, feed_rx) in feeds.iter_mut() {
+        wait_for_pong(feed_tx, feed_rx).await;
+    }
+
+    feeds
+}
+
+/// Ping a feed, and wait for it to receive the pong back. Since messages are sent to a feed
+/// in order, everything that was sent to it before the pong will have been received, too.
+async fn wait_for_pong(feed_tx: &FeedSender, feed_rx: &mut FeedReceiver) {
+    feed_tx.send_command("ping", "Finished!").unwrap();
+    let finished = FeedMessage::Pong {
+        msg: "Finished!".to_owned(),
+    };
+    loop {
+        let msgs = feed_rx.recv_feed_messages_once().await.unwrap();
+        if msgs.contains(&finished) {
+            break;
+        }
+    }
+}
+
+/// Wait for a feed to be told about a new best block at the given height.
+async fn wait_for_best_block(feed_rx: &mut FeedReceiver, height: BlockNumber) {
+    loop {
+        let msgs = feed_rx.recv_feed_messages_once().await.unwrap();
+        let seen = msgs.iter().any(
+            |m| matches!(m, FeedMessage::BestBlock { block_number, .. } if *block_number == height),
+        );
+        if seen {
+            break;
+        }
+    }
+}
+
+criterion_group!(
+    benches,
+    benchmark_single_node_update,
+    benchmark_subscribe_burst,
+    benchmark_broadcast_fan_out
+);
+criterion_main!(benches);
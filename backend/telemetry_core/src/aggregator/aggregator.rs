@@ -15,13 +15,25 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 use super::inner_loop;
-use crate::find_location::find_location;
-use crate::state::NodeId;
+use crate::chain_metadata::ChainMetadata;
+use crate::feed_recorder::FeedRecorder;
+use crate::find_location::{find_location, GeoIpDatabase, LocatorMetrics};
+use crate::memory_monitor::MemoryMonitor;
+use crate::state::{NodeId, QualityScoreWeights, StateSnapshot};
+use crate::uptime::{Uptime, UptimeEvent};
 use common::id_type;
+use common::node_types::{BlockHash, NetworkId};
+use common::ws_client::CaCertificates;
 use futures::{future, Sink, SinkExt};
+use std::collections::HashSet;
 use std::net::IpAddr;
 use std::sync::atomic::AtomicU64;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How long to wait for more node location requests to arrive so that they can be looked up
+/// together, rather than one at a time.
+const LOCATION_BATCH_WINDOW: Duration = Duration::from_millis(50);
 
 id_type! {
     /// A unique Id is assigned per websocket connection (or more accurately,
@@ -38,15 +50,122 @@ pub struct Aggregator(Arc<AggregatorInternal>);
 pub struct AggregatorOpts {
     /// Any node from these chains is muted
     pub denylist: Vec<String>,
+    /// If not empty, only nodes on these chains are allowed to connect.
+    pub allowlist: Vec<String>,
     /// If our incoming message queue exceeds this length, we start
     /// dropping non-essential messages.
     pub max_queue_len: usize,
     /// How many nodes from third party chains are allowed to connect
     /// before we prevent connections from them.
     pub max_third_party_nodes: usize,
+    /// If set, nodes on new third party chains are muted once we're keeping track
+    /// of this many chains.
+    pub max_chains: Option<usize>,
+    /// If set, nodes are muted once we're keeping track of this many nodes
+    /// across all chains.
+    pub max_total_nodes: Option<usize>,
     /// Flag to expose the node's details (IP address, SysInfo, HwBench) of all connected
     /// nodes to the feed subscribers.
     pub expose_node_details: bool,
+    /// If set, `BestBlock` feed messages for a chain are sent at most
+    /// once per this interval, always reflecting the latest best block.
+    pub best_block_coalesce_interval: Option<Duration>,
+    /// If set, feed messages about changes to a node's hardware, stats and IO are sent
+    /// at most once per this interval for each node, always reflecting the latest values.
+    pub node_update_interval: Option<Duration>,
+    /// If set, `ImportedBlock` feed messages are sent at most once per this interval
+    /// for each node, always reflecting the latest imported block.
+    pub imported_block_interval: Option<Duration>,
+    /// If set, nodes that shards tell us have gone are shown as stale for this long before
+    /// being removed, and are recovered if they're added again in the meantime.
+    pub node_removal_grace: Option<Duration>,
+    /// If set, `LocatedNode` feed messages are batched up for each chain and sent out
+    /// once per this interval.
+    pub location_broadcast_interval: Option<Duration>,
+    /// If set, `NodeUptime` and `NodeProcessUptime` feed messages for every node are sent out
+    /// once per this interval.
+    pub node_uptime_broadcast_interval: Option<Duration>,
+    /// If set, chains that have no nodes left are kept (with a node count of 0) for this
+    /// long before being removed.
+    pub empty_chain_ttl: Option<Duration>,
+    /// Nodes that haven't had a new best block in this long are marked as stale, and
+    /// are no longer considered when working out the best block of their chain.
+    pub stale_timeout: Duration,
+    /// The stats of each chain are regenerated (and sent to feeds if they've changed) at
+    /// most once per this interval.
+    pub stats_interval: Duration,
+    /// If set, each node is given a quality score, calculated using these weights.
+    pub quality_score_weights: Option<QualityScoreWeights>,
+    /// How many recent best and finalized block events each chain keeps
+    /// hold of, to replay to feeds when they subscribe to it.
+    pub max_recent_blocks: usize,
+    /// The maximum number of node location lookups to perform at once.
+    pub max_location_lookups_in_flight: usize,
+    /// The maximum number of node location lookups to queue up before we start dropping the oldest.
+    pub location_lookup_queue_len: usize,
+    /// The database used to find the locations of nodes.
+    pub geoip_database: GeoIpDatabase,
+    /// If set, a postgres connection string for the database that we record node uptime in.
+    pub uptime_db: Option<String>,
+    /// Certificate authorities to trust when connecting to the uptime database over TLS, on
+    /// top of the usual web PKI ones.
+    pub uptime_db_ca: CaCertificates,
+    /// If set, and the aggregator queue grows beyond this length, node updates sent to feeds are
+    /// batched up and sent out once every `degraded_feed_flush_interval` until the queue shrinks.
+    pub degraded_feed_queue_len: Option<usize>,
+    /// How often to send batched node updates to feeds while in degraded feed mode.
+    pub degraded_feed_flush_interval: Duration,
+    /// How many updates to hold onto for a node that hasn't been added yet, to be
+    /// applied if it's added shortly afterwards.
+    pub max_pending_updates_per_node: usize,
+    /// If set, every message broadcast to feeds is recorded using this.
+    pub feed_recorder: Option<FeedRecorder>,
+    /// If set, feeds which have opted in are sent a fresh snapshot of the chain they're
+    /// subscribed to, rather than the backlog, once they have more than this many messages queued.
+    pub feed_resync_queue_len: Option<usize>,
+    /// If set, this many of the most recent batches of messages sent to the feeds of each
+    /// chain are held onto, so that feeds which reconnect can resume from where they left off.
+    pub feed_resume_buffer_len: Option<usize>,
+    /// If not empty, only nodes on these chains are geolocated.
+    pub geolocate_chains: Vec<BlockHash>,
+    /// If true, nodes with private, loopback or otherwise reserved IP addresses aren't geolocated.
+    pub skip_private_ip_lookups: bool,
+    /// Chains with fewer than this many nodes aren't listed to feeds.
+    pub min_chain_node_count: usize,
+    /// Display metadata to send to feeds along with each chain.
+    pub chain_metadata: Arc<ChainMetadata>,
+    /// Chains (by genesis hash, with the label to use for each) that are always listed to
+    /// feeds, even when they have no nodes.
+    pub pinned_chains: Vec<(BlockHash, String)>,
+    /// A snapshot of chains and nodes to start with (see '--load-snapshot').
+    pub snapshot: Option<Arc<StateSnapshot>>,
+    /// Tells us when to shed load because we're running low on memory.
+    pub memory_monitor: Arc<MemoryMonitor>,
+}
+
+/// What to do when a new feed connects but we already have the maximum
+/// number of feeds connected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaxFeedsPolicy {
+    /// Close the new feed connection.
+    Reject,
+    /// Close the feed connection that was least recently active to make room for the new one.
+    EvictOldest,
+}
+
+impl std::str::FromStr for MaxFeedsPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "reject" => Ok(MaxFeedsPolicy::Reject),
+            "evict-oldest" => Ok(MaxFeedsPolicy::EvictOldest),
+            other => Err(anyhow::anyhow!(
+                "Cannot parse max feeds policy '{}'; expecting one of 'reject' or 'evict-oldest'",
+                other
+            )),
+        }
+    }
 }
 
 struct AggregatorInternal {
@@ -54,8 +173,6 @@ struct AggregatorInternal {
     /// This helps us know who to send messages back to (especially in
     /// conjunction with the `ShardNodeId` that messages will come with).
     shard_conn_id: AtomicU64,
-    /// Feeds that connect have their own unique connection ID, too.
-    feed_conn_id: AtomicU64,
     /// Send messages in to the aggregator from the outside via this. This is
     /// stored here so that anybody holding an `Aggregator` handle can
     /// make use of it.
@@ -63,29 +180,39 @@ struct AggregatorInternal {
 }
 
 impl Aggregator {
-    /// Spawn a new Aggregator. This connects to the telemetry backend
-    pub async fn spawn(opts: AggregatorOpts) -> anyhow::Result<Aggregator> {
+    /// Spawn a new Aggregator. This connects to the telemetry backend. If given, the events
+    /// to record in the uptime database are sent to `tx_to_uptime`.
+    pub async fn spawn(
+        opts: AggregatorOpts,
+        tx_to_uptime: Option<flume::Sender<UptimeEvent<NodeId>>>,
+    ) -> anyhow::Result<Aggregator> {
         let (tx_to_aggregator, rx_from_external) = flume::unbounded();
 
         // Kick off a locator task to locate nodes, which hands back a channel to make location requests
-        let tx_to_locator =
-            find_location(tx_to_aggregator.clone().into_sink().with(|(node_id, msg)| {
+        let (tx_to_locator, locator_metrics) = find_location(
+            tx_to_aggregator.clone().into_sink().with(|(node_id, msg)| {
                 future::ok::<_, flume::SendError<_>>(inner_loop::ToAggregator::FromFindLocation(
                     node_id, msg,
                 ))
-            }));
+            }),
+            opts.geoip_database.clone(),
+            opts.max_location_lookups_in_flight,
+            opts.location_lookup_queue_len,
+            LOCATION_BATCH_WINDOW,
+        );
 
         // Handle any incoming messages in our handler loop:
         tokio::spawn(Aggregator::handle_messages(
             rx_from_external,
             tx_to_locator,
+            locator_metrics,
+            tx_to_uptime,
             opts,
         ));
 
         // Return a handle to our aggregator:
         Ok(Aggregator(Arc::new(AggregatorInternal {
             shard_conn_id: AtomicU64::new(1),
-            feed_conn_id: AtomicU64::new(1),
             tx_to_aggregator,
         })))
     }
@@ -96,9 +223,11 @@ impl Aggregator {
     async fn handle_messages(
         rx_from_external: flume::Receiver<inner_loop::ToAggregator>,
         tx_to_aggregator: flume::Sender<(NodeId, IpAddr)>,
+        locator_metrics: Arc<LocatorMetrics>,
+        tx_to_uptime: Option<flume::Sender<UptimeEvent<NodeId>>>,
         opts: AggregatorOpts,
     ) {
-        inner_loop::InnerLoop::new(tx_to_aggregator, opts)
+        inner_loop::InnerLoop::new(tx_to_aggregator, locator_metrics, tx_to_uptime, opts)
             .handle(rx_from_external)
             .await;
     }
@@ -114,6 +243,87 @@ impl Aggregator {
         Ok(metrics)
     }
 
+    /// How many messages are queued up waiting for the aggregator loop to handle them.
+    pub fn queue_len(&self) -> usize {
+        self.0.tx_to_aggregator.len()
+    }
+
+    /// Return some details about the node with the given ID (as given in feed messages) on
+    /// the chain with the given genesis hash, or `None` if there's no such node.
+    pub async fn node_info(
+        &self,
+        genesis_hash: BlockHash,
+        node_id: usize,
+    ) -> anyhow::Result<Option<serde_json::Value>> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::GetNodeInfo(genesis_hash, node_id, tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        let info = rx.recv_async().await?;
+        Ok(info)
+    }
+
+    /// Return some details about each of the feeds connected to this aggregator.
+    pub async fn feeds(&self) -> anyhow::Result<Vec<serde_json::Value>> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::GetFeeds(tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        let feeds = rx.recv_async().await?;
+        Ok(feeds)
+    }
+
+    /// Return a copy of the chains and nodes that this aggregator knows about.
+    pub async fn state(&self) -> anyhow::Result<StateSnapshot> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::GetState(tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        let snapshot = rx.recv_async().await?;
+        Ok(snapshot)
+    }
+
+    /// Return when the least recently active feed connected to this aggregator was last
+    /// heard from, or `None` if no feeds are connected.
+    pub async fn oldest_feed_activity(&self) -> anyhow::Result<Option<Instant>> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::GetOldestFeedActivity(tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        let last_activity = rx.recv_async().await?;
+        Ok(last_activity)
+    }
+
+    /// Close the least recently active feed connected to this aggregator.
+    pub async fn evict_oldest_feed(&self) -> anyhow::Result<()> {
+        let msg = inner_loop::ToAggregator::EvictOldestFeed;
+        self.0.tx_to_aggregator.send_async(msg).await?;
+        Ok(())
+    }
+
+    /// Tell the aggregator how long the node with the given network ID has been up for.
+    pub async fn update_node_uptime(
+        &self,
+        node_id: NodeId,
+        network_id: NetworkId,
+        uptime: Uptime,
+    ) -> anyhow::Result<()> {
+        let msg = inner_loop::ToAggregator::FromUptime(node_id, network_id, uptime);
+        self.0.tx_to_aggregator.send_async(msg).await?;
+        Ok(())
+    }
+
+    /// Replace the names of the chains that nodes aren't allowed to connect from.
+    pub async fn update_denylist(&self, denylist: HashSet<String>) -> anyhow::Result<()> {
+        let msg = inner_loop::ToAggregator::UpdateDenylist(denylist);
+        self.0.tx_to_aggregator.send_async(msg).await?;
+        Ok(())
+    }
+
     /// Return a sink that a shard can send messages into to be handled by the aggregator.
     pub fn subscribe_shard(
         &self,
@@ -137,31 +347,23 @@ impl Aggregator {
         }))
     }
 
-    /// Return a sink that a feed can send messages into to be handled by the aggregator.
+    /// Return a sink that a feed can send messages into to be handled by the aggregator. The
+    /// ID given must be unique to this feed connection, and is passed along with every message
+    /// to the aggregator loop.
     pub fn subscribe_feed(
         &self,
-    ) -> (
-        u64,
-        impl Sink<inner_loop::FromFeedWebsocket, Error = anyhow::Error> + Send + Sync + Unpin + 'static,
-    ) {
-        // Assign a unique aggregator-local ID to each connection that subscribes, and pass
-        // that along with every message to the aggregator loop:
-        let feed_conn_id = self
-            .0
-            .feed_conn_id
-            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        feed_conn_id: u64,
+    ) -> impl Sink<inner_loop::FromFeedWebsocket, Error = anyhow::Error> + Send + Sync + Unpin + 'static
+    {
         let tx_to_aggregator = self.0.tx_to_aggregator.clone();
 
         // Calling `send` on this Sink requires Unpin. There may be a nicer way than this,
         // but pinning by boxing is the easy solution for now:
-        (
-            feed_conn_id,
-            Box::pin(tx_to_aggregator.into_sink().with(move |msg| async move {
-                Ok(inner_loop::ToAggregator::FromFeedWebsocket(
-                    feed_conn_id.into(),
-                    msg,
-                ))
-            })),
-        )
+        Box::pin(tx_to_aggregator.into_sink().with(move |msg| async move {
+            Ok(inner_loop::ToAggregator::FromFeedWebsocket(
+                feed_conn_id.into(),
+                msg,
+            ))
+        }))
     }
 }
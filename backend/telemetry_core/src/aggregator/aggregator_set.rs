@@ -1,17 +1,30 @@
 use super::aggregator::{Aggregator, AggregatorOpts};
 use super::inner_loop;
+use crate::state::StateSnapshot;
+use crate::uptime::track_uptime;
+use common::node_types::BlockHash;
 use common::EitherSink;
 use futures::{Sink, SinkExt};
 use inner_loop::{FromShardWebsocket, Metrics};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// The maximum number of node connect and disconnect events to queue up for the uptime
+/// database before we start dropping them.
+const UPTIME_EVENT_QUEUE_LEN: usize = 10_000;
 
 #[derive(Clone)]
 pub struct AggregatorSet(Arc<AggregatorSetInner>);
 
 pub struct AggregatorSetInner {
     aggregators: Vec<Aggregator>,
-    next_idx: AtomicUsize,
+    /// Feeds are given IDs that are unique across all of the aggregators, and
+    /// the ID of a feed determines which aggregator handles it.
+    next_feed_id: AtomicU64,
+    /// Shard connections are given IDs too, which prefix every log line about them.
+    next_shard_conn_id: AtomicU64,
     metrics: Mutex<Vec<Metrics>>,
 }
 
@@ -23,16 +36,58 @@ impl AggregatorSet {
     ) -> anyhow::Result<AggregatorSet> {
         assert_ne!(num_aggregators, 0, "You must have 1 or more aggregator");
 
-        let aggregators = futures::future::try_join_all(
-            (0..num_aggregators).map(|_| Aggregator::spawn(opts.clone())),
-        )
+        // If we're recording node uptime, kick off a task to do so, which hands back how long
+        // each node that connects has been up for:
+        let (tx_uptime, rx_uptime) = flume::unbounded();
+        let tx_to_uptime = match &opts.uptime_db {
+            Some(connection_string) => Some(
+                track_uptime(
+                    tx_uptime.into_sink(),
+                    connection_string,
+                    &opts.uptime_db_ca,
+                    UPTIME_EVENT_QUEUE_LEN,
+                )
+                .await?,
+            ),
+            None => None,
+        };
+
+        let aggregators = futures::future::try_join_all((0..num_aggregators).map(|idx| {
+            let mut opts = opts.clone();
+            let mut tx_to_uptime = tx_to_uptime.clone();
+            // Every aggregator broadcasts the same messages (just to different feeds), and
+            // sees the same nodes connect and disconnect, so we only need to record them
+            // from one of them.
+            if idx != 0 {
+                opts.feed_recorder = None;
+                tx_to_uptime = None;
+            }
+            Aggregator::spawn(opts, tx_to_uptime)
+        }))
         .await?;
 
+        // Every aggregator is told how long nodes have been up for, to tell its feeds:
+        let uptime_aggregators = aggregators.clone();
+        tokio::spawn(async move {
+            while let Ok((node_id, network_id, uptime)) = rx_uptime.recv_async().await {
+                for aggregator in &uptime_aggregators {
+                    if let Err(e) = aggregator
+                        .update_node_uptime(node_id, network_id, uptime)
+                        .await
+                    {
+                        log::error!("Aggregator connection has failed: {}", e);
+                        return;
+                    }
+                }
+            }
+        });
+
         let initial_metrics = (0..num_aggregators).map(|_| Metrics::default()).collect();
 
         let this = AggregatorSet(Arc::new(AggregatorSetInner {
             aggregators,
-            next_idx: AtomicUsize::new(0),
+            next_feed_id: AtomicU64::new(1),
+            next_shard_conn_id: AtomicU64::new(1),
             metrics: Mutex::new(initial_metrics),
         }));
 
@@ -82,16 +137,26 @@ impl AggregatorSet {
         self.0.metrics.lock().unwrap().clone()
     }
 
-    /// Return a sink that a shard can send messages into to be handled by all aggregators.
+    /// How many messages are queued up waiting to be handled, across all aggregators.
+    pub fn queue_len(&self) -> usize {
+        self.0.aggregators.iter().map(|a| a.queue_len()).sum()
+    }
+
+    /// Return an ID for a new shard connection, along with a sink that the shard can send
+    /// messages into to be handled by all aggregators.
     pub fn subscribe_shard(
         &self,
-    ) -> impl Sink<inner_loop::FromShardWebsocket, Error = anyhow::Error> + Send + Sync + Unpin + 'static
-    {
+    ) -> (
+        u64,
+        impl Sink<inner_loop::FromShardWebsocket, Error = anyhow::Error> + Send + Sync + Unpin + 'static,
+    ) {
+        let shard_conn_id = self.0.next_shard_conn_id.fetch_add(1, Ordering::Relaxed);
+
         // Special case 1 aggregator to avoid the extra indirection and so on
         // if we don't actually need it.
         if self.0.aggregators.len() == 1 {
             let sub = self.0.aggregators[0].subscribe_shard();
-            return EitherSink::a(sub);
+            return (shard_conn_id, EitherSink::a(sub));
         }
 
         let mut conns: Vec<_> = self
@@ -117,7 +182,10 @@ impl AggregatorSet {
             }
         });
 
-        EitherSink::b(tx.into_sink().sink_map_err(|e| anyhow::anyhow!("{}", e)))
+        (
+            shard_conn_id,
+            EitherSink::b(tx.into_sink().sink_map_err(|e| anyhow::anyhow!("{}", e))),
+        )
     }
 
     /// Return a sink that a feed can send messages into to be handled by a single aggregator.
@@ -127,9 +195,102 @@ impl AggregatorSet {
         u64,
         impl Sink<inner_loop::FromFeedWebsocket, Error = anyhow::Error> + Send + Sync + Unpin + 'static,
     ) {
-        let last_val = self.0.next_idx.fetch_add(1, Ordering::Relaxed);
-        let this_idx = (last_val + 1) % self.0.aggregators.len();
+        let feed_id = self.0.next_feed_id.fetch_add(1, Ordering::Relaxed);
+        let this_idx = aggregator_idx_for_feed(feed_id, self.0.aggregators.len());
+
+        (
+            feed_id,
+            self.0.aggregators[this_idx].subscribe_feed(feed_id),
+        )
+    }
+
+    /// Return the index of the aggregator that handles the feed with the given ID, or `None`
+    /// if no feed has been given this ID. The feed may have since disconnected.
+    pub fn aggregator_for_feed(&self, feed_id: u64) -> Option<usize> {
+        let next_feed_id = self.0.next_feed_id.load(Ordering::Relaxed);
+        (feed_id > 0 && feed_id < next_feed_id)
+            .then(|| aggregator_idx_for_feed(feed_id, self.0.aggregators.len()))
+    }
+
+    /// Return some details about the node with the given ID (as given in feed messages) on
+    /// the chain with the given genesis hash, or `None` if there's no such node. Every
+    /// aggregator knows about every node, so we just ask the first one.
+    pub async fn node_info(
+        &self,
+        genesis_hash: BlockHash,
+        node_id: usize,
+    ) -> anyhow::Result<Option<serde_json::Value>> {
+        self.0.aggregators[0].node_info(genesis_hash, node_id).await
+    }
+
+    /// Return some details about every connected feed. Feeds are spread across the
+    /// aggregators, so we ask each of them and gather the results up, ordered by feed ID.
+    pub async fn feeds(&self) -> anyhow::Result<Vec<serde_json::Value>> {
+        let mut feeds = Vec::new();
+        for aggregator in &self.0.aggregators {
+            feeds.extend(aggregator.feeds().await?);
+        }
+        feeds.sort_by_key(|feed| feed["id"].as_u64());
+        Ok(feeds)
+    }
+
+    /// Return a copy of the chains and nodes that we know about. Every aggregator holds
+    /// the state of every node, and so we only need to ask one of them.
+    pub async fn state(&self) -> anyhow::Result<StateSnapshot> {
+        self.0.aggregators[0].state().await
+    }
+
+    /// Close the least recently active feed. Feeds are spread across the aggregators, so we
+    /// ask each of them when their least recently active feed was last heard from, and then
+    /// tell the aggregator with the oldest one to close it.
+    pub async fn evict_oldest_feed(&self) -> anyhow::Result<()> {
+        let mut oldest: Option<(Instant, &Aggregator)> = None;
+        for aggregator in &self.0.aggregators {
+            if let Some(last_activity) = aggregator.oldest_feed_activity().await? {
+                let is_oldest = match oldest {
+                    Some((oldest, _)) => last_activity < oldest,
+                    None => true,
+                };
+                if is_oldest {
+                    oldest = Some((last_activity, aggregator));
+                }
+            }
+        }
+        if let Some((_, aggregator)) = oldest {
+            aggregator.evict_oldest_feed().await?;
+        }
+        Ok(())
+    }
+
+    /// Replace the names of the chains that nodes aren't allowed to connect from. Every
+    /// aggregator holds the state of every node, and so every aggregator is told.
+    pub async fn update_denylist(&self, denylist: HashSet<String>) -> anyhow::Result<()> {
+        for aggregator in &self.0.aggregators {
+            aggregator.update_denylist(denylist.clone()).await?;
+        }
+        Ok(())
+    }
+
+    /// Return the indexes of the aggregators that hold the state of a chain. Every aggregator
+    /// is sent every message from shards, and so every aggregator holds the state of every chain.
+    pub fn aggregators_for_chains(&self) -> std::ops::Range<usize> {
+        0..self.0.aggregators.len()
+    }
+}
+
+/// Feeds are spread across aggregators in turn, according to their ID.
+fn aggregator_idx_for_feed(feed_id: u64, num_aggregators: usize) -> usize {
+    (feed_id % num_aggregators as u64) as usize
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
 
-        self.0.aggregators[this_idx].subscribe_feed()
+    #[test]
+    fn feeds_are_spread_across_aggregators_by_id() {
+        let idxs: Vec<_> = (1..=6).map(|id| aggregator_idx_for_feed(id, 3)).collect();
+        assert_eq!(idxs, vec![1, 2, 0, 1, 2, 0]);
+        assert_eq!(aggregator_idx_for_feed(12345, 1), 0);
     }
 }
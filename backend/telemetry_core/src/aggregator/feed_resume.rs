@@ -0,0 +1,163 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Feeds which reconnect can ask to be sent only the updates about a chain that they missed,
+//! rather than the entire state of the chain again. To make this possible, each batch of
+//! messages sent to the feeds of a chain ends with a [`feed_message::Seq`] message, and the
+//! most recent batches for each chain are held onto. A feed that reconnects sends
+//! `resume:<genesis_hash>@<seq>`, giving the last sequence number that it saw, and is sent
+//! the batches which followed it if they're all still here.
+//!
+//! Each aggregator numbers its own batches, and feeds are split across aggregators before they
+//! ask to resume, so resuming is only enabled when there is a single aggregator.
+
+use crate::feed_message::{self, FeedMessageSerializer};
+use common::node_types::BlockHash;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{BuildHasher, Hasher};
+
+/// Holds onto the most recent batches of messages sent to the feeds of each chain.
+pub struct FeedResumeBuffer {
+    /// The sequence number to give to the next batch of messages.
+    next_seq: u64,
+    /// How many batches of messages to hold onto for each chain.
+    max_len: usize,
+    /// The most recent batches of messages for each chain, oldest first.
+    chains: HashMap<BlockHash, VecDeque<(u64, bytes::Bytes)>>,
+}
+
+impl FeedResumeBuffer {
+    /// Hold onto up to `max_len` batches of messages for each chain.
+    pub fn new(max_len: usize) -> Self {
+        FeedResumeBuffer {
+            next_seq: random_seq_start(),
+            max_len,
+            chains: HashMap::new(),
+        }
+    }
+
+    /// Number a batch of messages about a chain, finalize it and hold onto the result.
+    pub fn finalize(
+        &mut self,
+        genesis_hash: BlockHash,
+        mut serializer: FeedMessageSerializer,
+    ) -> Option<bytes::Bytes> {
+        if serializer.is_empty() || self.max_len == 0 {
+            return serializer.into_finalized();
+        }
+
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        serializer.push(feed_message::Seq(seq));
+        let bytes = serializer.into_finalized()?;
+
+        let batches = self.chains.entry(genesis_hash).or_default();
+        if batches.len() == self.max_len {
+            batches.pop_front();
+        }
+        batches.push_back((seq, bytes.clone()));
+        Some(bytes)
+    }
+
+    /// The sequence number of the most recent batch of messages about a chain.
+    pub fn latest_seq(&self, genesis_hash: &BlockHash) -> Option<u64> {
+        self.chains
+            .get(genesis_hash)
+            .and_then(|batches| batches.back())
+            .map(|(seq, _)| *seq)
+    }
+
+    /// Return the batches of messages about a chain which followed the one with the given
+    /// sequence number, or `None` if that batch is no longer (or never was) held onto.
+    pub fn messages_since(&self, genesis_hash: &BlockHash, seq: u64) -> Option<Vec<bytes::Bytes>> {
+        let batches = self.chains.get(genesis_hash)?;
+        let idx = batches.iter().position(|(s, _)| *s == seq)?;
+        Some(
+            batches
+                .iter()
+                .skip(idx + 1)
+                .map(|(_, bytes)| bytes.clone())
+                .collect(),
+        )
+    }
+
+    /// Forget the messages about a chain that has been removed.
+    pub fn remove_chain(&mut self, genesis_hash: &BlockHash) {
+        self.chains.remove(genesis_hash);
+    }
+}
+
+/// Sequence numbers start from a random point, so that those handed out before a restart
+/// are very unlikely to be mistaken for ours. They
+/// stay well below 2^53 so that browsers can represent them exactly.
+fn random_seq_start() -> u64 {
+    RandomState::new().build_hasher().finish() >> 12
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn batch(buffer: &mut FeedResumeBuffer, hash: BlockHash, n: u64) -> u64 {
+        let mut serializer = FeedMessageSerializer::new();
+        serializer.push(feed_message::Pong(&n.to_string()));
+        buffer.finalize(hash, serializer).expect("messages");
+        buffer.latest_seq(&hash).expect("a seq")
+    }
+
+    #[test]
+    fn batches_after_a_seq_are_returned() {
+        let hash = BlockHash::from_low_u64_be(1);
+        let mut buffer = FeedResumeBuffer::new(10);
+
+        let first = batch(&mut buffer, hash, 1);
+        let second = batch(&mut buffer, hash, 2);
+        let third = batch(&mut buffer, hash, 3);
+        assert!(first < second && second < third);
+
+        let missed = buffer.messages_since(&hash, first).expect("buffered");
+        assert_eq!(missed.len(), 2);
+        assert!(missed[1].ends_with(format!("[15,\"3\",33,{third}]").as_bytes()));
+
+        // Nothing was missed since the latest batch:
+        assert_eq!(buffer.messages_since(&hash, third), Some(vec![]));
+    }
+
+    #[test]
+    fn batches_which_have_rolled_over_cant_be_resumed_from() {
+        let hash = BlockHash::from_low_u64_be(1);
+        let mut buffer = FeedResumeBuffer::new(2);
+
+        let first = batch(&mut buffer, hash, 1);
+        let second = batch(&mut buffer, hash, 2);
+        batch(&mut buffer, hash, 3);
+
+        assert_eq!(buffer.messages_since(&hash, first), None);
+        assert_eq!(
+            buffer.messages_since(&hash, second).map(|m| m.len()),
+            Some(1)
+        );
+
+        // Sequence numbers for other chains (or from elsewhere) aren't resumed from:
+        assert_eq!(
+            buffer.messages_since(&BlockHash::from_low_u64_be(2), second),
+            None
+        );
+        buffer.remove_chain(&hash);
+        assert_eq!(buffer.messages_since(&hash, second), None);
+    }
+}
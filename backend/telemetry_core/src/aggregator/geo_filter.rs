@@ -0,0 +1,218 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Feeds can ask to only be sent the nodes in some geographic region, for instance to
+//! show a regional dashboard. The region is given along with the chain to subscribe to,
+//! like `subscribe:0x1234?bbox=35,-10,60,30&unlocated=include` for a bounding box (given
+//! as min latitude, min longitude, max latitude, max longitude), or `?country=DE,FR` for
+//! one or more countries.
+
+use common::node_types::NodeLocation;
+use std::str::FromStr;
+
+/// The region that a feed would like to see nodes in.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Region {
+    /// Nodes within a box of latitudes and longitudes. If `min_lon` is greater than
+    /// `max_lon`, the box wraps around the antimeridian.
+    BoundingBox {
+        min_lat: f32,
+        min_lon: f32,
+        max_lat: f32,
+        max_lon: f32,
+    },
+    /// Nodes in any of these countries, given as ISO 3166-1 codes.
+    Countries(Vec<Box<str>>),
+}
+
+impl Region {
+    fn contains(&self, location: &NodeLocation) -> bool {
+        match self {
+            Region::BoundingBox {
+                min_lat,
+                min_lon,
+                max_lat,
+                max_lon,
+            } => {
+                let lat = location.latitude;
+                let lon = location.longitude;
+                let lon_matches = if min_lon <= max_lon {
+                    (*min_lon..=*max_lon).contains(&lon)
+                } else {
+                    lon >= *min_lon || lon <= *max_lon
+                };
+                (*min_lat..=*max_lat).contains(&lat) && lon_matches
+            }
+            Region::Countries(countries) => match &location.country {
+                Some(country) => countries.iter().any(|c| c.eq_ignore_ascii_case(country)),
+                None => false,
+            },
+        }
+    }
+}
+
+/// Which nodes a feed would like to be sent.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GeoFilter {
+    pub region: Region,
+    /// Should nodes that haven't been located (yet) be sent too?
+    pub include_unlocated: bool,
+}
+
+impl GeoFilter {
+    /// Should a node at this location be sent to the feed?
+    pub fn matches(&self, location: Option<&NodeLocation>) -> bool {
+        match location {
+            Some(location) => self.region.contains(location),
+            None => self.include_unlocated,
+        }
+    }
+}
+
+impl FromStr for GeoFilter {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut region = None;
+        let mut include_unlocated = false;
+        for param in s.split('&') {
+            let (key, value) = match param.find('=') {
+                Some(idx) => (&param[..idx], &param[idx + 1..]),
+                None => return Err(anyhow::anyhow!("Expecting format `KEY=VALUE`")),
+            };
+            match key {
+                "bbox" => {
+                    let coords = value
+                        .split(',')
+                        .map(|n| n.trim().parse::<f32>())
+                        .collect::<Result<Vec<_>, _>>()?;
+                    let [min_lat, min_lon, max_lat, max_lon] = coords[..] else {
+                        return Err(anyhow::anyhow!(
+                            "Expecting bbox=MIN_LAT,MIN_LON,MAX_LAT,MAX_LON"
+                        ));
+                    };
+                    // "NaN" and "inf" parse as floats, but would never (or always) match:
+                    if !coords.iter().all(|n| n.is_finite()) {
+                        return Err(anyhow::anyhow!("The coordinates of a bbox must be numbers"));
+                    }
+                    if min_lat > max_lat {
+                        return Err(anyhow::anyhow!(
+                            "The min latitude of a bbox can't be greater than the max"
+                        ));
+                    }
+                    region = Some(Region::BoundingBox {
+                        min_lat,
+                        min_lon,
+                        max_lat,
+                        max_lon,
+                    });
+                }
+                "country" => {
+                    let countries: Vec<Box<str>> = value
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|c| !c.is_empty())
+                        .map(Into::into)
+                        .collect();
+                    if countries.is_empty() {
+                        return Err(anyhow::anyhow!("Expecting at least one country"));
+                    }
+                    region = Some(Region::Countries(countries));
+                }
+                "unlocated" => {
+                    include_unlocated = match value {
+                        "include" => true,
+                        "exclude" => false,
+                        _ => return Err(anyhow::anyhow!("Expecting unlocated=include|exclude")),
+                    };
+                }
+                _ => return Err(anyhow::anyhow!("Filter {} not recognised", key)),
+            }
+        }
+
+        match region {
+            Some(region) => Ok(GeoFilter {
+                region,
+                include_unlocated,
+            }),
+            None => Err(anyhow::anyhow!("Expecting a bbox or country to filter by")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn location(latitude: f32, longitude: f32, country: Option<&str>) -> NodeLocation {
+        NodeLocation {
+            latitude,
+            longitude,
+            city: "Somewhere".into(),
+            country: country.map(Into::into),
+        }
+    }
+
+    #[test]
+    fn bounding_boxes_are_parsed_and_matched() {
+        let filter: GeoFilter = "bbox=35,-10,60,30".parse().unwrap();
+        assert!(!filter.include_unlocated);
+
+        let berlin = location(52.5, 13.4, Some("DE"));
+        let new_york = location(40.7, -74.0, Some("US"));
+        assert!(filter.matches(Some(&berlin)));
+        assert!(!filter.matches(Some(&new_york)));
+        assert!(!filter.matches(None));
+    }
+
+    #[test]
+    fn bounding_boxes_can_wrap_around_the_antimeridian() {
+        let filter: GeoFilter = "bbox=-50,160,0,-170".parse().unwrap();
+        assert!(filter.matches(Some(&location(-40.0, 175.0, None))));
+        assert!(filter.matches(Some(&location(-15.0, -175.0, None))));
+        assert!(!filter.matches(Some(&location(-15.0, 0.0, None))));
+    }
+
+    #[test]
+    fn countries_are_parsed_and_matched() {
+        let filter: GeoFilter = "country=de,FR&unlocated=include".parse().unwrap();
+        assert!(filter.include_unlocated);
+        assert!(filter.matches(Some(&location(52.5, 13.4, Some("DE")))));
+        assert!(filter.matches(Some(&location(48.9, 2.4, Some("FR")))));
+        assert!(!filter.matches(Some(&location(40.7, -74.0, Some("US")))));
+        assert!(!filter.matches(Some(&location(0.0, 0.0, None))));
+        assert!(filter.matches(None));
+    }
+
+    #[test]
+    fn invalid_filters_are_rejected() {
+        for filter in [
+            "",
+            "unlocated=include",
+            "bbox=1,2,3",
+            "bbox=60,0,35,10",
+            "country=",
+            "bbox=1,2,3,4&unlocated=maybe",
+            "bbox=NaN,0,10,10",
+            "bbox=0,-inf,10,inf",
+            "radius=10",
+        ] {
+            assert!(
+                filter.parse::<GeoFilter>().is_err(),
+                "{filter} should be rejected"
+            );
+        }
+    }
+}
@@ -15,21 +15,29 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 use super::aggregator::ConnId;
-use crate::feed_message::{self, FeedMessageSerializer};
-use crate::state::{self, NodeId, State};
+use super::feed_resume::FeedResumeBuffer;
+use super::geo_filter::GeoFilter;
+use super::message_timings::MessageTimings;
+use crate::chain_metadata::ChainMetadata;
+use crate::feed_message::{self, FeedBytes, FeedMessage, FeedMessageSerializer};
+use crate::feed_recorder::FeedRecorder;
+use crate::memory_monitor::{MemoryMonitor, MemoryPressure};
+use crate::state::{self, ChainOptions, NodeId, Now, State, StateOptions};
+use crate::uptime::{Uptime, UptimeEvent};
 use crate::{find_location, AggregatorOpts};
 use bimap::BiMap;
 use common::{
     internal_messages::{self, MuteReason, ShardNodeId},
     node_message,
-    node_types::BlockHash,
-    time, MultiMapUnique,
+    node_types::{BlockHash, NetworkId, NodeDetails},
+    time, MultiMapUnique, NumStats,
 };
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::{
-    atomic::{AtomicU64, Ordering},
+    atomic::{AtomicBool, AtomicU64, Ordering},
     Arc,
 };
+use std::time::{Duration, Instant};
 use std::{net::IpAddr, str::FromStr};
 
 /// Incoming messages come via subscriptions, and end up looking like this.
@@ -38,9 +46,131 @@ pub enum ToAggregator {
     FromShardWebsocket(ConnId, FromShardWebsocket),
     FromFeedWebsocket(ConnId, FromFeedWebsocket),
     FromFindLocation(NodeId, find_location::Location),
+    /// How long the node with the given network ID has been up for, according to the uptime
+    /// database.
+    FromUptime(NodeId, NetworkId, Uptime),
     /// Hand back some metrics. The provided sender is expected not to block when
     /// a message is sent into it.
     GatherMetrics(flume::Sender<Metrics>),
+    /// Broadcast any best blocks that were held back because of
+    /// best block coalescing, if enough time has passed.
+    FlushCoalescedBestBlocks,
+    /// Broadcast any node updates that were batched up while in degraded feed mode.
+    FlushDegradedFeeds,
+    /// Broadcast any node updates that were held back because of node
+    /// update throttling, if enough time has passed.
+    FlushThrottledNodeUpdates,
+    /// Hand back some details about the node with the given ID (as given in feed messages)
+    /// on the chain with the given genesis hash, or `None` if there's no such node. The
+    /// provided sender is expected not to block when a message is sent into it.
+    GetNodeInfo(BlockHash, usize, flume::Sender<Option<serde_json::Value>>),
+    /// Hand back some details about each of the feeds connected to this aggregator. The
+    /// provided sender is expected not to block when a message is sent into it.
+    GetFeeds(flume::Sender<Vec<serde_json::Value>>),
+    /// Hand back a copy of the chains and nodes that we know about. The provided
+    /// sender is expected not to block when a message is sent into it.
+    GetState(flume::Sender<state::StateSnapshot>),
+    /// Hand back when the least recently active feed connected to this aggregator was last
+    /// heard from, or `None` if no feeds are connected. The provided sender is expected not
+    /// to block when a message is sent into it.
+    GetOldestFeedActivity(flume::Sender<Option<Instant>>),
+    /// Close the least recently active feed connected to this aggregator, to make room
+    /// for a new one.
+    EvictOldestFeed,
+    /// Remove any nodes whose removal was deferred, if enough time has passed.
+    RemoveExpiredNodes,
+    /// Broadcast the locations of any nodes that have been located since we last did so.
+    FlushLocatedNodes,
+    /// Remove any chains that have had no nodes for long enough.
+    RemoveExpiredChains,
+    /// Broadcast how long each node has been up for.
+    BroadcastNodeUptimes,
+    /// Replace the names of the chains that aren't allowed to connect, muting any nodes
+    /// that are already connected on chains that are now denied.
+    UpdateDenylist(HashSet<String>),
+}
+
+/// How important a message to the aggregator is. When the aggregator is overloaded, less
+/// important messages are dropped first to bring the queue of messages back down.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MessagePriority {
+    /// Routine node updates; periodic stats and IO, hardware benchmarks and authority sets.
+    /// These are dropped once the queue is longer than the max queue length.
+    Low,
+    /// Node updates about imported and finalized blocks. These are dropped only once the
+    /// queue is longer than twice the max queue length.
+    High,
+    /// Everything else, including nodes being added and removed. These are never dropped.
+    Essential,
+}
+
+impl MessagePriority {
+    /// Should a message with this priority be dropped, given the current queue length?
+    pub fn should_drop(self, queue_len: usize, max_queue_len: usize) -> bool {
+        match self {
+            MessagePriority::Low => queue_len > max_queue_len,
+            MessagePriority::High => queue_len > max_queue_len.saturating_mul(2),
+            MessagePriority::Essential => false,
+        }
+    }
+}
+
+impl ToAggregator {
+    /// How important is this message?
+    pub fn priority(&self) -> MessagePriority {
+        let payload = match self {
+            ToAggregator::FromShardWebsocket(_, FromShardWebsocket::Update { payload, .. }) => {
+                payload
+            }
+            _ => return MessagePriority::Essential,
+        };
+        match payload {
+            node_message::Payload::BlockImport(_)
+            | node_message::Payload::NotifyFinalized(_)
+            | node_message::Payload::SystemConnected(_) => MessagePriority::High,
+            node_message::Payload::SystemInterval(_)
+            | node_message::Payload::AfgAuthoritySet(_)
+            | node_message::Payload::HwBench(_) => MessagePriority::Low,
+        }
+    }
+
+    /// What kind of message is this? Node updates are broken down by the type of payload.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ToAggregator::FromShardWebsocket(_, msg) => match msg {
+                FromShardWebsocket::Initialize { .. } => "shard_initialize",
+                FromShardWebsocket::Identify { .. } => "shard_identify",
+                FromShardWebsocket::Add { .. } => "shard_add",
+                FromShardWebsocket::Update { payload, .. } => match payload {
+                    node_message::Payload::SystemConnected(_) => "shard_update_system_connected",
+                    node_message::Payload::SystemInterval(_) => "shard_update_system_interval",
+                    node_message::Payload::BlockImport(_) => "shard_update_block_import",
+                    node_message::Payload::NotifyFinalized(_) => "shard_update_notify_finalized",
+                    node_message::Payload::AfgAuthoritySet(_) => "shard_update_afg_authority_set",
+                    node_message::Payload::HwBench(_) => "shard_update_hwbench",
+                },
+                FromShardWebsocket::Remove { .. } => "shard_remove",
+                FromShardWebsocket::Disconnected => "shard_disconnected",
+            },
+            ToAggregator::FromFeedWebsocket(..) => "feed",
+            ToAggregator::FromFindLocation(..) => "find_location",
+            ToAggregator::FromUptime(..) => "uptime",
+            ToAggregator::GatherMetrics(_) => "gather_metrics",
+            ToAggregator::FlushCoalescedBestBlocks => "flush_coalesced_best_blocks",
+            ToAggregator::FlushDegradedFeeds => "flush_degraded_feeds",
+            ToAggregator::FlushThrottledNodeUpdates => "flush_throttled_node_updates",
+            ToAggregator::GetNodeInfo(..) => "get_node_info",
+            ToAggregator::GetFeeds(..) => "get_feeds",
+            ToAggregator::GetState(..) => "get_state",
+            ToAggregator::GetOldestFeedActivity(..) => "get_oldest_feed_activity",
+            ToAggregator::EvictOldestFeed => "evict_oldest_feed",
+            ToAggregator::RemoveExpiredNodes => "remove_expired_nodes",
+            ToAggregator::FlushLocatedNodes => "flush_located_nodes",
+            ToAggregator::RemoveExpiredChains => "remove_expired_chains",
+            ToAggregator::BroadcastNodeUptimes => "broadcast_node_uptimes",
+            ToAggregator::UpdateDenylist(_) => "update_denylist",
+        }
+    }
 }
 
 /// An incoming shard connection can send these messages to the aggregator.
@@ -50,7 +180,13 @@ pub enum FromShardWebsocket {
     /// so that we have a way to communicate back to it.
     Initialize {
         channel: flume::Sender<ToShardWebsocket>,
+        /// The version reported by the shard, or "unknown".
+        version: Box<str>,
+        /// The address that the shard connected from.
+        addr: std::net::SocketAddr,
     },
+    /// The ID that the shard gave itself, which newer shards send before any nodes.
+    Identify { shard_id: Box<str> },
     /// Tell the aggregator about a new node.
     Add {
         local_id: ShardNodeId,
@@ -77,6 +213,9 @@ pub enum ToShardWebsocket {
         local_id: ShardNodeId,
         reason: internal_messages::MuteReason,
     },
+    /// Let the shard know that we've handled its `Initialize` message
+    /// and are ready to receive node data from it.
+    Initialized,
 }
 
 /// An incoming feed connection can send these messages to the aggregator.
@@ -88,10 +227,31 @@ pub enum FromFeedWebsocket {
     /// progress.
     Initialize {
         channel: flume::Sender<ToFeedWebsocket>,
+        /// Set if the feed would like to be sent a fresh snapshot rather than a long
+        /// backlog of updates if it falls behind. The aggregator sets this to `true`
+        /// when it queues a [`ToFeedWebsocket::ResyncStart`], and the feed sets it back
+        /// to `false` when it reaches it.
+        resync_pending: Option<Arc<AtomicBool>>,
+        /// Set if the feed would like to be told about every chain in a single
+        /// [`feed_message::AddedChains`] message, rather than an
+        /// [`feed_message::AddedChain`] message per chain, when it connects.
+        chain_digest: bool,
     },
     /// The feed can subscribe to a chain to receive
-    /// messages relating to it.
-    Subscribe { chain: BlockHash },
+    /// messages relating to it, optionally only about
+    /// the nodes in some region.
+    Subscribe {
+        chain: BlockHash,
+        geo_filter: Option<GeoFilter>,
+    },
+    /// The feed would like the current state of a chain (including all of its nodes)
+    /// once, without subscribing to any further updates about it. This is ignored if the
+    /// feed is subscribed to another chain, since nothing in the messages about the nodes
+    /// says which chain they're on, so they'd be mixed up with the nodes of that chain.
+    Snapshot { chain: BlockHash },
+    /// The feed has reconnected, and would like to subscribe to a chain again, being sent
+    /// only the updates about it since the one with the given sequence number.
+    Resume { chain: BlockHash, seq: u64 },
     /// An explicit ping message.
     Ping { value: Box<str> },
     /// The feed is disconnected.
@@ -122,6 +282,41 @@ pub struct Metrics {
     pub connected_feeds: usize,
     /// How many shards are currently connected to this aggregator.
     pub connected_shards: usize,
+    /// How many chains are currently known to this aggregator.
+    pub chains: usize,
+    /// How many shards of each version are currently connected to this aggregator.
+    pub connected_shard_versions: HashMap<Box<str>, usize>,
+    /// How many node location lookups are currently being performed.
+    pub location_lookups_in_flight: usize,
+    /// How many node location lookups are queued, waiting to be performed.
+    pub location_lookups_queued: usize,
+    /// How many node location lookups have been dropped because too many were queued.
+    pub dropped_location_lookups: u64,
+    /// How many nodes haven't been geolocated because they have a private IP address.
+    pub skipped_private_ip_lookups: u64,
+    /// How many node connect and disconnect events haven't been recorded in the uptime
+    /// database because too many were queued.
+    pub dropped_uptime_events: u64,
+    /// Are node updates to feeds currently being batched up because the aggregator is overloaded?
+    pub degraded_feed_mode: bool,
+    /// How much memory (in bytes) the process was using when last checked.
+    pub memory_resident_bytes: u64,
+    /// How much load is being shed because we're running low on memory.
+    pub memory_pressure: MemoryPressure,
+    /// How many node updates have arrived for nodes that this aggregator doesn't know about.
+    pub updates_for_unknown_nodes: u64,
+    /// How many nodes have been added to this aggregator since it started.
+    pub node_adds_total: u64,
+    /// How many nodes have been removed from this aggregator since it started.
+    pub node_removes_total: u64,
+    /// How many nodes are connected to each first party or pinned chain, by chain label. Chains
+    /// that share a label are counted together.
+    pub per_chain_node_counts: Vec<(String, usize)>,
+    /// How long it's taken this aggregator to handle each kind of message.
+    pub message_timings: MessageTimings,
+    /// How long, on average, recent messages have spent queued up waiting to be handled by this
+    /// aggregator, in milliseconds.
+    pub aggregator_message_latency_ms: u64,
 }
 
 // The frontend sends text based commands; parse them into these messages:
@@ -136,9 +331,28 @@ impl FromStr for FromFeedWebsocket {
             "ping" => Ok(FromFeedWebsocket::Ping {
                 value: value.into(),
             }),
-            "subscribe" => Ok(FromFeedWebsocket::Subscribe {
+            "subscribe" => {
+                let (chain, geo_filter) = match value.find('?') {
+                    Some(idx) => (&value[..idx], Some(value[idx + 1..].parse()?)),
+                    None => (value, None),
+                };
+                Ok(FromFeedWebsocket::Subscribe {
+                    chain: chain.parse()?,
+                    geo_filter,
+                })
+            }
+            "snapshot" => Ok(FromFeedWebsocket::Snapshot {
                 chain: value.parse()?,
             }),
+            "resume" => {
+                let (chain, seq) = value
+                    .split_once('@')
+                    .ok_or_else(|| anyhow::anyhow!("Expecting format `resume:CHAIN@SEQ`"))?;
+                Ok(FromFeedWebsocket::Resume {
+                    chain: chain.parse()?,
+                    seq: seq.parse()?,
+                })
+            }
             _ => return Err(anyhow::anyhow!("Command {} not recognised", cmd)),
         }
     }
@@ -147,7 +361,20 @@ impl FromStr for FromFeedWebsocket {
 /// The aggregator can send these messages back to a feed connection.
 #[derive(Clone, Debug)]
 pub enum ToFeedWebsocket {
-    Bytes(bytes::Bytes),
+    Bytes(FeedBytes),
+    /// Updates about the chain that the feed is subscribed to. These can be skipped
+    /// if the feed is waiting for a [`ToFeedWebsocket::ResyncStart`], since the
+    /// snapshot that follows it supersedes them.
+    ChainBytes(FeedBytes),
+    /// A fresh snapshot of the chain that the feed is subscribed to follows this.
+    ResyncStart,
+    /// The state of a chain that the feed has subscribed to follows this. Sending it
+    /// has its own deadline, separate to that of other messages.
+    SubscribeStart,
+    /// Marks the end of the state of a chain that the feed has subscribed to.
+    SubscribeEnd,
+    /// Close the feed connection once any messages before this have been sent.
+    Close,
 }
 
 /// Instances of this are responsible for handling incoming and
@@ -161,14 +388,32 @@ pub struct InnerLoop {
 
     /// Keep track of how to send messages out to feeds.
     feed_channels: HashMap<ConnId, flume::Sender<ToFeedWebsocket>>,
+    /// When did we last hear from each feed?
+    feed_last_activity: HashMap<ConnId, Instant>,
     /// Keep track of how to send messages out to shards.
     shard_channels: HashMap<ConnId, flume::Sender<ToShardWebsocket>>,
+    /// The version that each connected shard reported.
+    shard_versions: HashMap<ConnId, Box<str>>,
+    /// A human readable name for each connected shard, which nodes are tagged with.
+    shard_names: HashMap<ConnId, Box<str>>,
 
     /// Which feeds are subscribed to a given chain?
     chain_to_feed_conn_ids: MultiMapUnique<BlockHash, ConnId>,
 
+    /// Feeds that only want to be sent the nodes in some region.
+    geo_filtered_feeds: HashMap<ConnId, GeoFilteredFeed>,
+
     /// Send messages here to make geographical location requests.
     tx_to_locator: flume::Sender<(NodeId, IpAddr)>,
+    /// Metrics about the location requests that we've made.
+    locator_metrics: Arc<find_location::LocatorMetrics>,
+
+    /// If set, node connect and disconnect events are sent here to be recorded in the
+    /// uptime database.
+    tx_to_uptime: Option<flume::Sender<UptimeEvent<NodeId>>>,
+
+    /// How many uptime events we've dropped because too many were queued.
+    dropped_uptime_events: u64,
 
     /// How big can the queue of messages coming in to the aggregator get before messages
     /// are prioritised and dropped to try and get back on track.
@@ -177,27 +422,289 @@ pub struct InnerLoop {
     /// Flag to expose the node's details (IP address, SysInfo, HwBench) of all connected
     /// nodes to the feed subscribers.
     expose_node_details: bool,
+
+    /// If set, we periodically flush any best blocks that were held back
+    /// while coalescing best block updates.
+    best_block_coalesce_interval: Option<Duration>,
+
+    /// If set, we periodically flush any node updates that were held back
+    /// while throttling them.
+    node_update_interval: Option<Duration>,
+
+    /// If set, we periodically flush any imported blocks that were held back
+    /// while coalescing them.
+    imported_block_interval: Option<Duration>,
+
+    /// If set, nodes that shards tell us have gone are marked as stale, and only removed
+    /// once this much time has passed without them being added again.
+    node_removal_grace: Option<Duration>,
+
+    /// Nodes whose removal has been deferred, and when to remove them.
+    pending_removals: HashMap<NodeId, Instant>,
+    /// Nodes whose removal has been deferred, by chain and network ID, so that we can quickly
+    /// find them again if they come back.
+    pending_removals_by_network_id: HashMap<(BlockHash, NetworkId), NodeId>,
+
+    /// If set, the locations of newly located nodes are batched up and broadcast
+    /// this often, rather than as soon as each node is located.
+    location_broadcast_interval: Option<Duration>,
+
+    /// If set, how long each node has been up for is broadcast this often.
+    node_uptime_broadcast_interval: Option<Duration>,
+
+    /// Nodes that have been located since we last broadcast node locations.
+    located_nodes: HashSet<NodeId>,
+
+    /// If set, we periodically remove chains that have had no nodes for this long.
+    empty_chain_ttl: Option<Duration>,
+
+    /// If the queue of messages to the aggregator grows beyond this length, we
+    /// enter degraded feed mode.
+    degraded_feed_queue_len: Option<usize>,
+
+    /// How often to send out node updates batched up in degraded feed mode.
+    degraded_feed_flush_interval: Duration,
+
+    /// Are we currently in degraded feed mode?
+    degraded_feed_mode: bool,
+
+    /// Node updates batched up for each chain while in degraded feed mode.
+    degraded_feed_buffers: HashMap<BlockHash, FeedMessageSerializer>,
+
+    /// How many updates to hold onto for a node that we don't know about yet, in
+    /// case the message adding it turns up shortly afterwards.
+    max_pending_updates_per_node: usize,
+
+    /// Updates for nodes that we don't know about yet, along with when the first one arrived.
+    pending_updates: HashMap<(ConnId, ShardNodeId), (Instant, Vec<node_message::Payload>)>,
+
+    /// How many updates have arrived for nodes that we don't know about.
+    updates_for_unknown_nodes: u64,
+
+    /// How many nodes we've added and removed, so that we can see how much they're churning.
+    node_adds_total: u64,
+    node_removes_total: u64,
+
+    /// If set, every message broadcast to feeds is recorded using this.
+    feed_recorder: Option<FeedRecorder>,
+
+    /// If a feed that has opted in to being resynchronised has more than this many
+    /// messages queued up, we send it a fresh snapshot of its chain.
+    feed_resync_queue_len: Option<usize>,
+
+    /// The feeds that have opted in to being resynchronised, and whether a snapshot is
+    /// currently on its way to each of them.
+    feed_resync_pending: HashMap<ConnId, Arc<AtomicBool>>,
+    /// If set, the most recent messages sent to the feeds of each chain, so that feeds
+    /// which reconnect can resume from where they left off.
+    feed_resume_buffer: Option<FeedResumeBuffer>,
+
+    /// If not empty, only nodes on these chains are geolocated.
+    geolocate_chains: HashSet<BlockHash>,
+
+    /// If true, nodes with private, loopback or otherwise reserved IP addresses aren't geolocated.
+    skip_private_ip_lookups: bool,
+
+    /// How many nodes we haven't geolocated because they have a private IP address.
+    skipped_private_ip_lookups: u64,
+
+    /// Chains with fewer than this many nodes aren't listed to feeds.
+    min_chain_node_count: usize,
+
+    /// Display metadata to send to feeds along with each chain.
+    chain_metadata: Arc<ChainMetadata>,
+
+    /// Tells us when to shed load because we're running low on memory.
+    memory_monitor: Arc<MemoryMonitor>,
+
+    /// Have we evicted the nodes on third party chains (and are we refusing any new ones)
+    /// because we're running low on memory?
+    evicting_third_party_chains: bool,
+
+    /// How long it's taken to handle each kind of message.
+    message_timings: MessageTimings,
+
+    /// How long (in ms) the most recently handled messages spent queued up before we got to them.
+    message_latency: NumStats<u64>,
 }
 
+/// A feed that only wants to be sent the nodes in some region.
+struct GeoFilteredFeed {
+    filter: GeoFilter,
+    /// The nodes on the feed's chain that it's been told about.
+    visible_nodes: HashSet<usize>,
+}
+
+/// The messages that geo filtered feeds with a given filter, which know about a given set
+/// of nodes, are sent about a chain (and the nodes that they know about afterwards).
+struct FilteredMessages {
+    filter: GeoFilter,
+    visible_nodes: HashSet<usize>,
+    visible_nodes_after: HashSet<usize>,
+    bytes: Option<FeedBytes>,
+}
+
+/// The maximum number of unknown nodes that we'll hold pending updates for at once.
+const MAX_NODES_WITH_PENDING_UPDATES: usize = 1000;
+
+/// How long to hold onto pending updates for an unknown node before we give up on it being added.
+const PENDING_UPDATES_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many of the most recently handled messages we average over to report message latency.
+const MESSAGE_LATENCY_WINDOW: usize = 1000;
+
 impl InnerLoop {
     /// Create a new inner loop handler with the various state it needs.
-    pub fn new(tx_to_locator: flume::Sender<(NodeId, IpAddr)>, opts: AggregatorOpts) -> Self {
+    pub fn new(
+        tx_to_locator: flume::Sender<(NodeId, IpAddr)>,
+        locator_metrics: Arc<find_location::LocatorMetrics>,
+        tx_to_uptime: Option<flume::Sender<UptimeEvent<NodeId>>>,
+        opts: AggregatorOpts,
+    ) -> Self {
+        let state_options = StateOptions {
+            max_third_party_nodes: opts.max_third_party_nodes,
+            empty_chain_ttl: opts.empty_chain_ttl,
+            max_chains: opts.max_chains,
+            max_total_nodes: opts.max_total_nodes,
+            chain: ChainOptions {
+                best_block_coalesce_interval: opts.best_block_coalesce_interval,
+                quality_score_weights: opts.quality_score_weights,
+                max_recent_blocks: opts.max_recent_blocks,
+                node_update_interval: opts.node_update_interval,
+                imported_block_interval: opts.imported_block_interval,
+                stale_timeout: opts.stale_timeout,
+                stats_interval: opts.stats_interval,
+            },
+        };
+        let mut node_state = State::new(opts.denylist, opts.allowlist, state_options);
+        for (genesis_hash, label) in &opts.pinned_chains {
+            node_state.pin_chain(*genesis_hash, label);
+        }
+        if let Some(snapshot) = &opts.snapshot {
+            node_state.load_snapshot(snapshot);
+        }
+
         InnerLoop {
-            node_state: State::new(opts.denylist, opts.max_third_party_nodes),
+            node_state,
             node_ids: BiMap::new(),
             feed_channels: HashMap::new(),
+            feed_last_activity: HashMap::new(),
             shard_channels: HashMap::new(),
+            shard_versions: HashMap::new(),
+            shard_names: HashMap::new(),
             chain_to_feed_conn_ids: MultiMapUnique::new(),
+            geo_filtered_feeds: HashMap::new(),
             tx_to_locator,
+            locator_metrics,
+            tx_to_uptime,
+            dropped_uptime_events: 0,
             max_queue_len: opts.max_queue_len,
             expose_node_details: opts.expose_node_details,
+            best_block_coalesce_interval: opts.best_block_coalesce_interval,
+            node_update_interval: opts.node_update_interval,
+            imported_block_interval: opts.imported_block_interval,
+            node_removal_grace: opts.node_removal_grace,
+            pending_removals: HashMap::new(),
+            pending_removals_by_network_id: HashMap::new(),
+            location_broadcast_interval: opts.location_broadcast_interval,
+            node_uptime_broadcast_interval: opts.node_uptime_broadcast_interval,
+            located_nodes: HashSet::new(),
+            empty_chain_ttl: opts.empty_chain_ttl,
+            degraded_feed_queue_len: opts.degraded_feed_queue_len,
+            degraded_feed_flush_interval: opts.degraded_feed_flush_interval,
+            degraded_feed_mode: false,
+            degraded_feed_buffers: HashMap::new(),
+            max_pending_updates_per_node: opts.max_pending_updates_per_node,
+            pending_updates: HashMap::new(),
+            updates_for_unknown_nodes: 0,
+            node_adds_total: 0,
+            node_removes_total: 0,
+            feed_recorder: opts.feed_recorder,
+            feed_resync_queue_len: opts.feed_resync_queue_len,
+            feed_resync_pending: HashMap::new(),
+            feed_resume_buffer: opts.feed_resume_buffer_len.map(FeedResumeBuffer::new),
+            geolocate_chains: opts.geolocate_chains.into_iter().collect(),
+            skip_private_ip_lookups: opts.skip_private_ip_lookups,
+            skipped_private_ip_lookups: 0,
+            min_chain_node_count: opts.min_chain_node_count,
+            chain_metadata: opts.chain_metadata,
+            memory_monitor: opts.memory_monitor,
+            evicting_third_party_chains: false,
+            message_timings: MessageTimings::default(),
+            message_latency: NumStats::new(MESSAGE_LATENCY_WINDOW),
         }
     }
 
     /// Start handling and responding to incoming messages.
     pub async fn handle(mut self, rx_from_external: flume::Receiver<ToAggregator>) {
         let max_queue_len = self.max_queue_len;
-        let (metered_tx, metered_rx) = flume::unbounded();
+        let memory_monitor = Arc::clone(&self.memory_monitor);
+        // Messages are sent along with the time (in unix ms) that they were queued up, so that
+        // we can see how long they wait before being handled.
+        let (metered_tx, metered_rx) = flume::unbounded::<(u64, ToAggregator)>();
+
+        // If best blocks are being coalesced, periodically ask the loop to send out any
+        // that have been held back, so that the latest best block is always broadcast.
+        if let Some(interval) = self.best_block_coalesce_interval {
+            spawn_ticker(interval, metered_tx.clone(), || {
+                ToAggregator::FlushCoalescedBestBlocks
+            });
+        }
+
+        // If node updates or imported blocks are being throttled, periodically ask the loop to
+        // send out any that have been held back, so that the latest node details are always
+        // broadcast.
+        let throttle_flush_interval = [self.node_update_interval, self.imported_block_interval]
+            .into_iter()
+            .flatten()
+            .min();
+        if let Some(interval) = throttle_flush_interval {
+            spawn_ticker(interval, metered_tx.clone(), || {
+                ToAggregator::FlushThrottledNodeUpdates
+            });
+        }
+
+        // If node removal is being deferred, periodically ask the loop to remove any nodes
+        // that haven't come back in time.
+        if let Some(grace) = self.node_removal_grace {
+            spawn_ticker(
+                grace.min(Duration::from_secs(1)),
+                metered_tx.clone(),
+                || ToAggregator::RemoveExpiredNodes,
+            );
+        }
+
+        // If empty chains are being kept around, periodically ask the loop to remove any
+        // that have been empty for too long.
+        if let Some(ttl) = self.empty_chain_ttl {
+            spawn_ticker(ttl.min(Duration::from_secs(1)), metered_tx.clone(), || {
+                ToAggregator::RemoveExpiredChains
+            });
+        }
+
+        // If node locations are being batched up, periodically ask the loop to send them out.
+        if let Some(interval) = self.location_broadcast_interval {
+            spawn_ticker(interval, metered_tx.clone(), || {
+                ToAggregator::FlushLocatedNodes
+            });
+        }
+
+        // If node uptimes are being broadcast, periodically ask the loop to send them out.
+        if let Some(interval) = self.node_uptime_broadcast_interval {
+            spawn_ticker(interval, metered_tx.clone(), || {
+                ToAggregator::BroadcastNodeUptimes
+            });
+        }
+
+        // If degraded feed mode is enabled, periodically ask the loop to send out any
+        // node updates that have been batched up while in that mode.
+        if self.degraded_feed_queue_len.is_some() {
+            spawn_ticker(
+                self.degraded_feed_flush_interval,
+                metered_tx.clone(),
+                || ToAggregator::FlushDegradedFeeds,
+            );
+        }
 
         // Keep count of the number of dropped/total messages for the sake of metric reporting
         let dropped_messages = Arc::new(AtomicU64::new(0));
@@ -209,7 +716,13 @@ impl InnerLoop {
         let dropped_messages2 = Arc::clone(&dropped_messages);
         let total_messages2 = Arc::clone(&total_messages);
         tokio::spawn(async move {
-            while let Ok(msg) = metered_rx.recv_async().await {
+            while let Ok((queued_at, msg)) = metered_rx.recv_async().await {
+                self.message_latency
+                    .push(time::now().saturating_sub(queued_at));
+                self.update_degraded_feed_mode(metered_rx.len());
+                self.update_third_party_chain_eviction();
+                let kind = msg.kind();
+                let started = Instant::now();
                 match msg {
                     ToAggregator::FromFeedWebsocket(feed_conn_id, msg) => {
                         self.handle_from_feed(feed_conn_id, msg)
@@ -220,13 +733,38 @@ impl InnerLoop {
                     ToAggregator::FromFindLocation(node_id, location) => {
                         self.handle_from_find_location(node_id, location)
                     }
+                    ToAggregator::FromUptime(node_id, network_id, uptime) => {
+                        self.handle_from_uptime(node_id, &network_id, uptime)
+                    }
                     ToAggregator::GatherMetrics(tx) => self.handle_gather_metrics(
                         tx,
                         metered_rx.len(),
                         dropped_messages2.load(Ordering::Relaxed),
                         total_messages2.load(Ordering::Relaxed),
                     ),
+                    ToAggregator::FlushCoalescedBestBlocks => {
+                        self.handle_flush_coalesced_best_blocks()
+                    }
+                    ToAggregator::FlushDegradedFeeds => self.flush_degraded_feeds(),
+                    ToAggregator::FlushThrottledNodeUpdates => {
+                        self.handle_flush_throttled_node_updates()
+                    }
+                    ToAggregator::GetNodeInfo(genesis_hash, node_id, tx) => {
+                        self.handle_get_node_info(genesis_hash, node_id, tx)
+                    }
+                    ToAggregator::GetFeeds(tx) => self.handle_get_feeds(tx),
+                    ToAggregator::GetState(tx) => self.handle_get_state(tx),
+                    ToAggregator::GetOldestFeedActivity(tx) => {
+                        self.handle_get_oldest_feed_activity(tx)
+                    }
+                    ToAggregator::EvictOldestFeed => self.handle_evict_oldest_feed(),
+                    ToAggregator::RemoveExpiredNodes => self.handle_remove_expired_nodes(),
+                    ToAggregator::FlushLocatedNodes => self.handle_flush_located_nodes(),
+                    ToAggregator::RemoveExpiredChains => self.handle_remove_expired_chains(),
+                    ToAggregator::BroadcastNodeUptimes => self.handle_broadcast_node_uptimes(),
+                    ToAggregator::UpdateDenylist(denylist) => self.handle_update_denylist(denylist),
                 }
+                self.message_timings.record(kind, started.elapsed());
             }
         });
 
@@ -235,26 +773,96 @@ impl InnerLoop {
 
             // ignore node updates if we have too many messages to handle, in an attempt
             // to reduce the queue length back to something reasonable, lest it get out of
-            // control and start consuming a load of memory.
-            if metered_tx.len() > max_queue_len {
-                if matches!(
-                    msg,
-                    ToAggregator::FromShardWebsocket(.., FromShardWebsocket::Update { .. })
-                ) {
-                    // Note: this wraps on overflow (which is probably the best
-                    // behaviour for graphing it anyway)
-                    dropped_messages.fetch_add(1, Ordering::Relaxed);
-                    continue;
-                }
+            // control and start consuming a load of memory. Less important updates are
+            // ignored first (see `MessagePriority`), and are always ignored if we're running
+            // low on memory.
+            let priority = msg.priority();
+            let low_on_memory = memory_monitor.pressure() >= MemoryPressure::DropLowPriority;
+            if (low_on_memory && priority == MessagePriority::Low)
+                || priority.should_drop(metered_tx.len(), max_queue_len)
+            {
+                // Note: this wraps on overflow (which is probably the best
+                // behaviour for graphing it anyway)
+                dropped_messages.fetch_add(1, Ordering::Relaxed);
+                continue;
             }
 
-            if let Err(e) = metered_tx.send(msg) {
+            if let Err(e) = metered_tx.send((time::now(), msg)) {
                 log::error!("Cannot send message into aggregator: {e}");
                 break;
             }
         }
     }
 
+    /// Hand back some details about a node, for debugging purposes.
+    fn handle_get_node_info(
+        &self,
+        genesis_hash: BlockHash,
+        node_id: usize,
+        tx: flume::Sender<Option<serde_json::Value>>,
+    ) {
+        let chain = match self.node_state.get_chain_by_genesis_hash(&genesis_hash) {
+            Some(chain) => chain,
+            None => {
+                let _ = tx.send(None);
+                return;
+            }
+        };
+
+        let info = chain
+            .nodes_slice()
+            .get(node_id)
+            .and_then(Option::as_ref)
+            .map(|node| {
+                let details = node.details();
+                serde_json::json!({
+                    "name": details.name,
+                    "implementation": details.implementation,
+                    "version": details.version,
+                    "network_id": details.network_id.as_str(),
+                    "shard": details.shard,
+                })
+            });
+
+        // Ignore error sending; assume the receiver stopped caring and dropped the channel:
+        let _ = tx.send(info);
+    }
+
+    /// Hand back the ID of each connected feed, the chain it's subscribed to (if any) and
+    /// how many messages are queued up to be sent to it, for debugging purposes.
+    fn handle_get_feeds(&self, tx: flume::Sender<Vec<serde_json::Value>>) {
+        let mut feeds: Vec<_> = self
+            .feed_channels
+            .iter()
+            .map(|(&feed_conn_id, chan)| {
+                let chain = self.chain_to_feed_conn_ids.get_key(&feed_conn_id);
+                (u64::from(feed_conn_id), chain, chan.len())
+            })
+            .collect();
+        feeds.sort_by_key(|&(id, ..)| id);
+
+        let info = feeds
+            .into_iter()
+            .map(|(id, chain, queued_messages)| {
+                serde_json::json!({
+                    "id": id,
+                    "chain": chain,
+                    "queued_messages": queued_messages,
+                })
+            })
+            .collect();
+
+        // Ignore error sending; assume the receiver stopped caring and dropped the channel:
+        let _ = tx.send(info);
+    }
+
+    /// Hand back a copy of the chains and nodes that we know about, which can be loaded
+    /// back in later with '--load-snapshot'.
+    fn handle_get_state(&self, tx: flume::Sender<state::StateSnapshot>) {
+        // Ignore error sending; assume the receiver stopped caring and dropped the channel:
+        let _ = tx.send(self.node_state.snapshot());
+    }
+
     /// Gather and return some metrics.
     fn handle_gather_metrics(
         &mut self,
@@ -269,7 +877,23 @@ impl InnerLoop {
         let chains_subscribed_to = self.chain_to_feed_conn_ids.num_keys();
         let connected_shards = self.shard_channels.len();
         let connected_feeds = self.feed_channels.len();
+        let chains = self.node_state.chain_count();
         let total_messages_to_feeds: usize = self.feed_channels.values().map(|c| c.len()).sum();
+        let mut connected_shard_versions = HashMap::new();
+        for version in self.shard_versions.values() {
+            *connected_shard_versions.entry(version.clone()).or_default() += 1;
+        }
+        // Only first party and pinned chains are counted, so that anybody connecting nodes to
+        // new chains can't give us an unbounded number of labels:
+        let mut per_chain_node_counts = BTreeMap::<String, usize>::new();
+        let labelled_chains = self.node_state.iter_chains().filter(|chain| {
+            chain.is_pinned() || state::is_first_party_network(&chain.genesis_hash())
+        });
+        for chain in labelled_chains {
+            *per_chain_node_counts
+                .entry(chain.label().to_owned())
+                .or_default() += chain.node_count();
+        }
 
         // Ignore error sending; assume the receiver stopped caring and dropped the channel:
         let _ = rx.send(Metrics {
@@ -283,42 +907,446 @@ impl InnerLoop {
             connected_nodes,
             connected_feeds,
             connected_shards,
+            chains,
+            connected_shard_versions,
+            location_lookups_in_flight: self.locator_metrics.in_flight(),
+            location_lookups_queued: self.locator_metrics.queued(),
+            dropped_location_lookups: self.locator_metrics.dropped(),
+            skipped_private_ip_lookups: self.skipped_private_ip_lookups,
+            dropped_uptime_events: self.dropped_uptime_events,
+            degraded_feed_mode: self.degraded_feed_mode,
+            memory_resident_bytes: self.memory_monitor.resident_bytes(),
+            memory_pressure: self.memory_monitor.pressure(),
+            updates_for_unknown_nodes: self.updates_for_unknown_nodes,
+            node_adds_total: self.node_adds_total,
+            node_removes_total: self.node_removes_total,
+            per_chain_node_counts: per_chain_node_counts.into_iter().collect(),
+            message_timings: self.message_timings.clone(),
+            aggregator_message_latency_ms: self.message_latency.average(),
         });
     }
 
+    /// Enter degraded feed mode if the queue of messages to handle has grown too long, and
+    /// leave it again once the queue has shrunk to half of that length.
+    fn update_degraded_feed_mode(&mut self, queue_len: usize) {
+        let threshold = match self.degraded_feed_queue_len {
+            Some(threshold) => threshold,
+            None => return,
+        };
+
+        if !self.degraded_feed_mode && queue_len > threshold {
+            log::warn!("Aggregator queue length is {queue_len}; entering degraded feed mode");
+            self.degraded_feed_mode = true;
+        } else if self.degraded_feed_mode && queue_len <= threshold / 2 {
+            log::info!("Aggregator queue length is {queue_len}; leaving degraded feed mode");
+            self.degraded_feed_mode = false;
+            self.flush_degraded_feeds();
+        }
+    }
+
+    /// Evict every node on a third party chain if we're running out of memory. Until memory
+    /// use has come back down, new nodes on third party chains are also refused.
+    fn update_third_party_chain_eviction(&mut self) {
+        let evict = self.memory_monitor.pressure() == MemoryPressure::EvictThirdPartyChains;
+        if evict == self.evicting_third_party_chains {
+            return;
+        }
+        self.evicting_third_party_chains = evict;
+        if !evict {
+            log::warn!("Memory use has come down; accepting nodes on third party chains again");
+            return;
+        }
+
+        let third_party_nodes: Vec<(NodeId, (ConnId, ShardNodeId))> = self
+            .node_ids
+            .iter()
+            .filter(|(node_id, _)| {
+                self.node_state
+                    .get_chain_by_node_id(**node_id)
+                    .map(|chain| !state::is_first_party_network(&chain.genesis_hash()))
+                    .unwrap_or(false)
+            })
+            .map(|(node_id, ids)| (*node_id, *ids))
+            .collect();
+        log::error!(
+            "MEMORY PRESSURE: Evicting {} nodes on third party chains",
+            third_party_nodes.len()
+        );
+
+        // Tell the shards to stop sending us anything for these nodes:
+        for (_, (shard_conn_id, local_id)) in &third_party_nodes {
+            if let Some(shard_conn) = self.shard_channels.get_mut(shard_conn_id) {
+                let _ = shard_conn.send(ToShardWebsocket::Mute {
+                    local_id: *local_id,
+                    reason: MuteReason::Overquota,
+                });
+            }
+        }
+        self.remove_nodes_and_broadcast_result(
+            third_party_nodes.into_iter().map(|(node_id, _)| node_id),
+        );
+    }
+
+    /// Replace the denylist, and mute and remove any nodes on chains that are now denied.
+    fn handle_update_denylist(&mut self, denylist: HashSet<String>) {
+        let denied_nodes = self.node_state.set_denylist(denylist);
+        if denied_nodes.is_empty() {
+            return;
+        }
+        log::info!(
+            "Removing {} nodes on chains that are now on the denylist",
+            denied_nodes.len()
+        );
+
+        // Tell the shards to stop sending us anything for these nodes:
+        for node_id in &denied_nodes {
+            let Some((shard_conn_id, local_id)) = self.node_ids.get_by_left(node_id) else {
+                continue;
+            };
+            if let Some(shard_conn) = self.shard_channels.get_mut(shard_conn_id) {
+                let _ = shard_conn.send(ToShardWebsocket::Mute {
+                    local_id: *local_id,
+                    reason: MuteReason::ChainNotAllowed,
+                });
+            }
+        }
+        self.remove_nodes_and_broadcast_result(denied_nodes);
+    }
+
+    /// Send out any node updates that were batched up in degraded feed mode.
+    fn flush_degraded_feeds(&mut self) {
+        for (genesis_hash, serializer) in std::mem::take(&mut self.degraded_feed_buffers) {
+            if let Some(bytes) = self.finalize_chain_messages(genesis_hash, serializer) {
+                self.broadcast_to_chain_feeds(&genesis_hash, bytes);
+            }
+        }
+    }
+
+    /// Broadcast any best blocks that were held back while coalescing.
+    fn handle_flush_coalesced_best_blocks(&mut self) {
+        for (genesis_hash, feed_serializer) in
+            self.node_state.flush_coalesced_best_blocks(Now::current())
+        {
+            self.finalize_and_broadcast_to_chain_feeds(&genesis_hash, feed_serializer);
+        }
+    }
+
+    fn handle_flush_throttled_node_updates(&mut self) {
+        let now = Instant::now();
+        for (genesis_hash, feed_serializer) in self.node_state.flush_throttled_node_updates(now) {
+            self.finalize_and_broadcast_to_chain_feeds(&genesis_hash, feed_serializer);
+        }
+    }
+
+    /// Remove nodes that shards have told us are gone. If removal is being deferred, the
+    /// nodes are marked as stale to feeds and only removed if they haven't been added
+    /// again once the grace period is over.
+    fn remove_nodes_after_grace(&mut self, node_ids: Vec<NodeId>) {
+        let grace = match self.node_removal_grace {
+            Some(grace) => grace,
+            None => return self.remove_nodes_and_broadcast_result(node_ids),
+        };
+
+        let remove_at = Instant::now() + grace;
+        let mut feed_messages_per_chain: HashMap<BlockHash, FeedMessageSerializer> = HashMap::new();
+        for node_id in node_ids {
+            // The shard has forgotten about the node, so we forget about its shard ID:
+            self.node_ids.remove_by_left(&node_id);
+            let genesis_hash = match self.node_state.get_chain_by_node_id(node_id) {
+                Some(chain) => chain.genesis_hash(),
+                None => continue,
+            };
+            self.pending_removals.insert(node_id, remove_at);
+            if let Some(key) = self.network_id_of(node_id) {
+                self.pending_removals_by_network_id.insert(key, node_id);
+            }
+            feed_messages_per_chain
+                .entry(genesis_hash)
+                .or_insert_with(FeedMessageSerializer::new)
+                .push(feed_message::StaleNode(node_id.get_chain_node_id().into()));
+        }
+        for (genesis_hash, feed_messages) in feed_messages_per_chain {
+            self.finalize_and_broadcast_to_chain_feeds(&genesis_hash, feed_messages);
+        }
+    }
+
+    /// Find a node awaiting removal that has the same network ID as the node given and is on
+    /// the same chain, if there is one.
+    fn find_pending_removal(&self, genesis_hash: BlockHash, node: &NodeDetails) -> Option<NodeId> {
+        if node.network_id.is_empty() {
+            return None;
+        }
+        self.pending_removals_by_network_id
+            .get(&(genesis_hash, node.network_id))
+            .copied()
+    }
+
+    /// The chain and network ID of a node, if it has a network ID.
+    fn network_id_of(&self, node_id: NodeId) -> Option<(BlockHash, NetworkId)> {
+        let chain = self.node_state.get_chain_by_node_id(node_id)?;
+        let chain_node_id: usize = node_id.get_chain_node_id().into();
+        let network_id = chain
+            .nodes_slice()
+            .get(chain_node_id)?
+            .as_ref()?
+            .details()
+            .network_id;
+        (!network_id.is_empty()).then(|| (chain.genesis_hash(), network_id))
+    }
+
+    /// Stop waiting to remove a node, if we were.
+    fn forget_pending_removal(&mut self, node_id: NodeId) {
+        if self.pending_removals.remove(&node_id).is_none() {
+            return;
+        }
+        if let Some(key) = self.network_id_of(node_id) {
+            if self.pending_removals_by_network_id.get(&key) == Some(&node_id) {
+                self.pending_removals_by_network_id.remove(&key);
+            }
+        }
+    }
+
+    /// A node awaiting removal has been added again, so we keep it, taking on the details that
+    /// it sent this time, and tell feeds that it's no longer stale (unless it's still stale
+    /// because it's behind on blocks). If its details have changed, feeds are sent the node
+    /// again in place of the one they know about.
+    fn recover_node(
+        &mut self,
+        node_id: NodeId,
+        details: NodeDetails,
+        shard_conn_id: ConnId,
+        local_id: ShardNodeId,
+    ) {
+        self.forget_pending_removal(node_id);
+        self.node_ids.insert(node_id, (shard_conn_id, local_id));
+        let details_changed = self.node_state.update_node_details(node_id, details);
+
+        if let Some(chain) = self.node_state.get_chain_by_node_id(node_id) {
+            let chain_node_id: usize = node_id.get_chain_node_id().into();
+            let genesis_hash = chain.genesis_hash();
+            let mut feed_messages_for_chain = FeedMessageSerializer::new();
+            if let Some(node) = chain.nodes_slice()[chain_node_id].as_ref() {
+                if details_changed {
+                    feed_messages_for_chain.push(feed_message::RemovedNode(chain_node_id));
+                    feed_messages_for_chain.push(feed_message::AddedNode(
+                        chain_node_id,
+                        node,
+                        self.expose_node_details,
+                    ));
+                    if node.stale() {
+                        feed_messages_for_chain.push(feed_message::StaleNode(chain_node_id));
+                    }
+                } else if !node.stale() {
+                    feed_messages_for_chain.push(feed_message::RecoveredNode(chain_node_id));
+                }
+            }
+            self.finalize_and_broadcast_to_chain_feeds(&genesis_hash, feed_messages_for_chain);
+        }
+
+        // Apply any updates that arrived for the node before it was added:
+        if let Some((_, payloads)) = self.pending_updates.remove(&(shard_conn_id, local_id)) {
+            for payload in payloads {
+                self.handle_node_update(node_id, payload);
+            }
+        }
+    }
+
+    /// Remove any nodes that haven't been added again within the grace period.
+    fn handle_remove_expired_nodes(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<NodeId> = self
+            .pending_removals
+            .iter()
+            .filter(|(_, &remove_at)| remove_at <= now)
+            .map(|(&node_id, _)| node_id)
+            .collect();
+        self.remove_nodes_and_broadcast_result(expired);
+    }
+
     /// Handle messages that come from the node geographical locator.
     fn handle_from_find_location(&mut self, node_id: NodeId, location: find_location::Location) {
         self.node_state
             .update_node_location(node_id, location.clone());
 
-        if let Some(loc) = location {
-            let mut feed_message_serializer = FeedMessageSerializer::new();
-            feed_message_serializer.push(feed_message::LocatedNode(
+        // If we're batching up locations, we'll send out the node's latest location next
+        // time we flush them. Failed lookups are sent straight away, so that feeds stop
+        // waiting for a location that won't come.
+        if self.location_broadcast_interval.is_some() && location.is_some() {
+            self.located_nodes.insert(node_id);
+            return;
+        }
+
+        let mut feed_message_serializer = FeedMessageSerializer::new();
+        match location {
+            Some(loc) => feed_message_serializer.push(feed_message::LocatedNode(
                 node_id.get_chain_node_id().into(),
                 loc.latitude,
                 loc.longitude,
                 &loc.city,
+            )),
+            None => feed_message_serializer.push(feed_message::LocationFailed(
+                node_id.get_chain_node_id().into(),
+            )),
+        }
+
+        let chain_genesis_hash = self
+            .node_state
+            .get_chain_by_node_id(node_id)
+            .map(|chain| chain.genesis_hash());
+
+        if let Some(chain_genesis_hash) = chain_genesis_hash {
+            self.finalize_and_broadcast_to_chain_feeds(
+                &chain_genesis_hash,
+                feed_message_serializer,
+            );
+        }
+    }
+
+    /// Handle how long a node has been up for, as reported by the uptime database. This is
+    /// ignored if the node has since been replaced by one with another network ID.
+    fn handle_from_uptime(&mut self, node_id: NodeId, network_id: &str, uptime: Uptime) {
+        if !self
+            .node_state
+            .update_node_uptime(node_id, network_id, uptime)
+        {
+            return;
+        }
+
+        let chain_genesis_hash = self
+            .node_state
+            .get_chain_by_node_id(node_id)
+            .map(|chain| chain.genesis_hash());
+
+        if let Some(chain_genesis_hash) = chain_genesis_hash {
+            let mut feed_message_serializer = FeedMessageSerializer::new();
+            feed_message_serializer.push(feed_message::NodeUptime(
+                node_id.get_chain_node_id().into(),
+                uptime.total_secs(time::now()),
             ));
+            self.finalize_and_broadcast_to_chain_feeds(
+                &chain_genesis_hash,
+                feed_message_serializer,
+            );
+        }
+    }
 
-            let chain_genesis_hash = self
-                .node_state
-                .get_chain_by_node_id(node_id)
-                .map(|chain| chain.genesis_hash());
+    /// Send an event to be recorded in the uptime database, if we're recording uptime. The
+    /// event is dropped if too many are already queued up, rather than waiting.
+    fn send_uptime_event(&mut self, event: UptimeEvent<NodeId>) {
+        let Some(tx_to_uptime) = &self.tx_to_uptime else {
+            return;
+        };
+        if let Err(flume::TrySendError::Full(_)) = tx_to_uptime.try_send(event) {
+            self.dropped_uptime_events += 1;
+        }
+    }
 
-            if let Some(chain_genesis_hash) = chain_genesis_hash {
-                self.finalize_and_broadcast_to_chain_feeds(
-                    &chain_genesis_hash,
-                    feed_message_serializer,
-                );
+    /// Remove any chains that have had no nodes for longer than the empty chain TTL, and
+    /// tell feeds that they've gone.
+    fn handle_remove_expired_chains(&mut self) {
+        let mut feed_messages_for_all = FeedMessageSerializer::new();
+        for genesis_hash in self.node_state.remove_expired_empty_chains(Instant::now()) {
+            if let Some(buffer) = &mut self.feed_resume_buffer {
+                buffer.remove_chain(&genesis_hash);
+            }
+            if self.is_chain_listed(&genesis_hash, 0) {
+                feed_messages_for_all.push(feed_message::RemovedChain(genesis_hash));
+            }
+        }
+        self.finalize_and_broadcast_to_all_feeds(feed_messages_for_all);
+    }
+
+    /// Tell feeds how long each node on the chains that they're subscribed to has been up for.
+    fn handle_broadcast_node_uptimes(&mut self) {
+        let now = time::now();
+        let mut feed_messages_per_chain = Vec::new();
+        for chain in self.node_state.iter_chains() {
+            let genesis_hash = chain.genesis_hash();
+            if self
+                .chain_to_feed_conn_ids
+                .get_values(&genesis_hash)
+                .is_none()
+            {
+                continue;
+            }
+            let mut feed_messages = FeedMessageSerializer::new();
+            for (node_id, node) in chain.nodes_slice().iter().enumerate() {
+                let node = match node {
+                    Some(node) => node,
+                    None => continue,
+                };
+                if let Some(uptime) = node.uptime() {
+                    feed_messages.push(feed_message::NodeUptime(node_id, uptime.total_secs(now)));
+                }
+                if let Some(uptime_secs) = node.process_uptime_secs(now) {
+                    feed_messages.push(feed_message::NodeProcessUptime(node_id, uptime_secs));
+                }
+            }
+            if !feed_messages.is_empty() {
+                feed_messages_per_chain.push((genesis_hash, feed_messages));
             }
         }
+        for (genesis_hash, feed_messages) in feed_messages_per_chain {
+            self.finalize_and_broadcast_to_chain_feeds(&genesis_hash, feed_messages);
+        }
+    }
+
+    /// Broadcast the current location of each node that's been located since we last did
+    /// so, in one message per chain.
+    fn handle_flush_located_nodes(&mut self) {
+        let mut feed_messages_per_chain: HashMap<BlockHash, FeedMessageSerializer> = HashMap::new();
+        for node_id in std::mem::take(&mut self.located_nodes) {
+            // Nodes removed since they were located are forgotten about, so this is
+            // always the node that was located:
+            let chain = match self.node_state.get_chain_by_node_id(node_id) {
+                Some(chain) => chain,
+                None => continue,
+            };
+            let chain_node_id: usize = node_id.get_chain_node_id().into();
+            let loc = match chain.nodes_slice()[chain_node_id]
+                .as_ref()
+                .and_then(|node| node.location())
+            {
+                Some(loc) => loc,
+                None => continue,
+            };
+            feed_messages_per_chain
+                .entry(chain.genesis_hash())
+                .or_insert_with(FeedMessageSerializer::new)
+                .push(feed_message::LocatedNode(
+                    chain_node_id,
+                    loc.latitude,
+                    loc.longitude,
+                    &loc.city,
+                ));
+        }
+        for (genesis_hash, feed_messages) in feed_messages_per_chain {
+            self.finalize_and_broadcast_to_chain_feeds(&genesis_hash, feed_messages);
+        }
     }
 
     /// Handle messages coming from shards.
     fn handle_from_shard(&mut self, shard_conn_id: ConnId, msg: FromShardWebsocket) {
         match msg {
-            FromShardWebsocket::Initialize { channel } => {
+            FromShardWebsocket::Initialize {
+                channel,
+                version,
+                addr,
+            } => {
+                let _ = channel.send(ToShardWebsocket::Initialized);
                 self.shard_channels.insert(shard_conn_id, channel);
+                // Until the shard identifies itself, go by its IP address. The port that it
+                // connects from changes each time, so leave it out to keep the name stable:
+                self.shard_names
+                    .insert(shard_conn_id, format!("{} ({version})", addr.ip()).into());
+                self.shard_versions.insert(shard_conn_id, version);
+            }
+            FromShardWebsocket::Identify { shard_id } => {
+                // The shard ID stays the same when the shard reconnects, so name it after that:
+                if let Some(version) = self.shard_versions.get(&shard_conn_id) {
+                    self.shard_names
+                        .insert(shard_conn_id, format!("{shard_id} ({version})").into());
+                }
             }
             FromShardWebsocket::Add {
                 local_id,
@@ -328,8 +1356,32 @@ impl InnerLoop {
             } => {
                 // Conditionally modify the node's details to include the IP address.
                 node.ip = self.expose_node_details.then_some(ip.to_string().into());
+                node.shard = self.shard_names.get(&shard_conn_id).cloned();
+
+                // Refuse nodes on third party chains while we're low on memory:
+                if self.evicting_third_party_chains && !state::is_first_party_network(&genesis_hash)
+                {
+                    self.pending_updates.remove(&(shard_conn_id, local_id));
+                    if let Some(shard_conn) = self.shard_channels.get_mut(&shard_conn_id) {
+                        let _ = shard_conn.send(ToShardWebsocket::Mute {
+                            local_id,
+                            reason: MuteReason::Overquota,
+                        });
+                    }
+                    return;
+                }
+
+                // If this node is coming back before we've finished removing it, pick up
+                // where it left off rather than adding it again:
+                if let Some(node_id) = self.find_pending_removal(genesis_hash, &node) {
+                    self.recover_node(node_id, node, shard_conn_id, local_id);
+                    return;
+                }
+
                 match self.node_state.add_node(genesis_hash, node) {
-                    state::AddNodeResult::ChainOnDenyList => {
+                    state::AddNodeResult::ChainOnDenyList
+                    | state::AddNodeResult::ChainNotAllowlisted => {
+                        self.pending_updates.remove(&(shard_conn_id, local_id));
                         if let Some(shard_conn) = self.shard_channels.get_mut(&shard_conn_id) {
                             let _ = shard_conn.send(ToShardWebsocket::Mute {
                                 local_id,
@@ -338,6 +1390,7 @@ impl InnerLoop {
                         }
                     }
                     state::AddNodeResult::ChainOverQuota => {
+                        self.pending_updates.remove(&(shard_conn_id, local_id));
                         if let Some(shard_conn) = self.shard_channels.get_mut(&shard_conn_id) {
                             let _ = shard_conn.send(ToShardWebsocket::Mute {
                                 local_id,
@@ -345,13 +1398,33 @@ impl InnerLoop {
                             });
                         }
                     }
+                    state::AddNodeResult::TooManyChains => {
+                        self.pending_updates.remove(&(shard_conn_id, local_id));
+                        if let Some(shard_conn) = self.shard_channels.get_mut(&shard_conn_id) {
+                            let _ = shard_conn.send(ToShardWebsocket::Mute {
+                                local_id,
+                                reason: MuteReason::TooManyChains,
+                            });
+                        }
+                    }
+                    state::AddNodeResult::GlobalQuota => {
+                        self.pending_updates.remove(&(shard_conn_id, local_id));
+                        if let Some(shard_conn) = self.shard_channels.get_mut(&shard_conn_id) {
+                            let _ = shard_conn.send(ToShardWebsocket::Mute {
+                                local_id,
+                                reason: MuteReason::GlobalQuota,
+                            });
+                        }
+                    }
                     state::AddNodeResult::NodeAddedToChain(details) => {
                         let node_id = details.id;
+                        self.node_adds_total += 1;
 
                         // Record ID <-> (shardId,localId) for future messages:
                         self.node_ids.insert(node_id, (shard_conn_id, local_id));
 
                         // Don't hold onto details too long because we want &mut self later:
+                        let network_id = details.node.details().network_id;
                         let new_chain_label = details.new_chain_label.to_owned();
                         let chain_node_count = details.chain_node_count;
                         let has_chain_label_changed = details.has_chain_label_changed;
@@ -367,24 +1440,56 @@ impl InnerLoop {
                             &genesis_hash,
                             feed_messages_for_chain,
                         );
-                        // Tell everybody about the new node count and potential rename:
-                        let mut feed_messages_for_all = FeedMessageSerializer::new();
-                        if has_chain_label_changed {
-                            feed_messages_for_all.push(feed_message::RemovedChain(genesis_hash));
+                        // Tell everybody about the new node count and potential rename, if
+                        // the chain is (now) big enough to be listed:
+                        if self.is_chain_listed(&genesis_hash, chain_node_count) {
+                            let mut feed_messages_for_all = FeedMessageSerializer::new();
+                            if has_chain_label_changed
+                                && self.is_chain_listed(&genesis_hash, chain_node_count - 1)
+                            {
+                                feed_messages_for_all
+                                    .push(feed_message::RemovedChain(genesis_hash));
+                            }
+                            feed_messages_for_all.push(feed_message::AddedChain(
+                                &new_chain_label,
+                                genesis_hash,
+                                chain_node_count,
+                                self.chain_metadata.get(&genesis_hash),
+                            ));
+                            self.finalize_and_broadcast_to_all_feeds(feed_messages_for_all);
                         }
-                        feed_messages_for_all.push(feed_message::AddedChain(
-                            &new_chain_label,
-                            genesis_hash,
-                            chain_node_count,
-                        ));
-                        self.finalize_and_broadcast_to_all_feeds(feed_messages_for_all);
 
-                        // Ask for the geographical location of the node.
-                        let _ = self.tx_to_locator.send((node_id, ip));
+                        // Ask for the geographical location of the node, if we care about it.
+                        if self.skip_private_ip_lookups && find_location::is_private_ip(ip) {
+                            self.skipped_private_ip_lookups += 1;
+                        } else if self.geolocate_chains.is_empty()
+                            || self.geolocate_chains.contains(&genesis_hash)
+                        {
+                            let _ = self.tx_to_locator.send((node_id, ip));
+                        }
+
+                        // Record that the node has connected, to find out how long it's been up for:
+                        if !network_id.is_empty() {
+                            self.send_uptime_event(UptimeEvent::Connected {
+                                id: node_id,
+                                network_id,
+                                at: time::now(),
+                            });
+                        }
+
+                        // Apply any updates that arrived for the node before it was added:
+                        if let Some((_, payloads)) =
+                            self.pending_updates.remove(&(shard_conn_id, local_id))
+                        {
+                            for payload in payloads {
+                                self.handle_node_update(node_id, payload);
+                            }
+                        }
                     }
                 }
             }
             FromShardWebsocket::Remove { local_id } => {
+                self.pending_updates.remove(&(shard_conn_id, local_id));
                 let node_id = match self.node_ids.remove_by_right(&(shard_conn_id, local_id)) {
                     Some((node_id, _)) => node_id,
                     None => {
@@ -397,37 +1502,30 @@ impl InnerLoop {
                         return;
                     }
                 };
-                self.remove_nodes_and_broadcast_result(Some(node_id));
+                self.remove_nodes_after_grace(vec![node_id]);
             }
             FromShardWebsocket::Update { local_id, payload } => {
                 let node_id = match self.node_ids.get_by_right(&(shard_conn_id, local_id)) {
                     Some(id) => *id,
                     None => {
-                        log::error!(
+                        // This is expected to happen now and then; for instance if an update arrives
+                        // for a node that's been muted, or before the node has been added.
+                        log::debug!(
                             "Update: Cannot find ID for node with shard/connectionId of {shard_conn_id:?}/{local_id:?}"
                         );
+                        self.updates_for_unknown_nodes += 1;
+                        self.buffer_pending_update(shard_conn_id, local_id, payload);
                         return;
                     }
                 };
-
-                let mut feed_message_serializer = FeedMessageSerializer::new();
-                self.node_state.update_node(
-                    node_id,
-                    payload,
-                    &mut feed_message_serializer,
-                    self.expose_node_details,
-                );
-
-                if let Some(chain) = self.node_state.get_chain_by_node_id(node_id) {
-                    let genesis_hash = chain.genesis_hash();
-                    self.finalize_and_broadcast_to_chain_feeds(
-                        &genesis_hash,
-                        feed_message_serializer,
-                    );
-                }
+                self.handle_node_update(node_id, payload);
             }
             FromShardWebsocket::Disconnected => {
                 self.shard_channels.remove(&shard_conn_id);
+                self.shard_versions.remove(&shard_conn_id);
+                self.shard_names.remove(&shard_conn_id);
+                self.pending_updates
+                    .retain(|&(this_shard_conn_id, _), _| this_shard_conn_id != shard_conn_id);
 
                 // Find all nodes associated with this shard connection ID:
                 let node_ids_to_remove: Vec<NodeId> = self
@@ -438,31 +1536,119 @@ impl InnerLoop {
                     .collect();
 
                 // ... and remove them:
-                self.remove_nodes_and_broadcast_result(node_ids_to_remove);
+                self.remove_nodes_after_grace(node_ids_to_remove);
+            }
+        }
+    }
+
+    /// Hold onto an update for a node that we don't know about yet, so that it can be applied
+    /// if the node is added shortly. Updates are only held for a short time, and there's a limit
+    /// to how many are held for each node and to how many nodes we hold them for.
+    fn buffer_pending_update(
+        &mut self,
+        shard_conn_id: ConnId,
+        local_id: ShardNodeId,
+        payload: node_message::Payload,
+    ) {
+        if self.max_pending_updates_per_node == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        let key = (shard_conn_id, local_id);
+        if !self.pending_updates.contains_key(&key) {
+            // Forget about nodes that look like they'll never be added before making room:
+            self.pending_updates.retain(|_, (first_seen, _)| {
+                now.duration_since(*first_seen) < PENDING_UPDATES_TIMEOUT
+            });
+            if self.pending_updates.len() >= MAX_NODES_WITH_PENDING_UPDATES {
+                return;
+            }
+        }
+
+        let (first_seen, payloads) = self
+            .pending_updates
+            .entry(key)
+            .or_insert_with(|| (now, Vec::new()));
+        if now.duration_since(*first_seen) < PENDING_UPDATES_TIMEOUT
+            && payloads.len() < self.max_pending_updates_per_node
+        {
+            payloads.push(payload);
+        }
+    }
+
+    /// Apply an update to a node that we know about, and tell feeds about the result.
+    fn handle_node_update(&mut self, node_id: NodeId, payload: node_message::Payload) {
+        let mut feed_message_serializer = FeedMessageSerializer::new();
+        self.node_state.update_node(
+            node_id,
+            payload,
+            &mut feed_message_serializer,
+            self.expose_node_details,
+            Now::current(),
+        );
+
+        if let Some(chain) = self.node_state.get_chain_by_node_id(node_id) {
+            let genesis_hash = chain.genesis_hash();
+            if self.degraded_feed_mode {
+                // Batch node updates up to be sent out periodically:
+                self.degraded_feed_buffers
+                    .entry(genesis_hash)
+                    .or_insert_with(FeedMessageSerializer::new)
+                    .append(feed_message_serializer);
+            } else {
+                self.finalize_and_broadcast_to_chain_feeds(&genesis_hash, feed_message_serializer);
             }
         }
     }
 
     /// Handle messages coming from feeds.
     fn handle_from_feed(&mut self, feed_conn_id: ConnId, msg: FromFeedWebsocket) {
+        if self.feed_channels.contains_key(&feed_conn_id) {
+            self.feed_last_activity.insert(feed_conn_id, Instant::now());
+        }
+
         match msg {
-            FromFeedWebsocket::Initialize { channel } => {
+            FromFeedWebsocket::Initialize {
+                channel,
+                resync_pending,
+                chain_digest,
+            } => {
                 self.feed_channels.insert(feed_conn_id, channel.clone());
+                self.feed_last_activity.insert(feed_conn_id, Instant::now());
+                if let Some(resync_pending) = resync_pending {
+                    if self.feed_resync_queue_len.is_some() {
+                        self.feed_resync_pending
+                            .insert(feed_conn_id, resync_pending);
+                    }
+                }
 
                 // Tell the new feed subscription some basic things to get it going:
                 let mut feed_serializer = FeedMessageSerializer::new();
                 feed_serializer.push(feed_message::Version(32));
-                for chain in self.node_state.iter_chains() {
-                    feed_serializer.push(feed_message::AddedChain(
-                        chain.label(),
-                        chain.genesis_hash(),
-                        chain.node_count(),
-                    ));
+                let added_chains = self
+                    .node_state
+                    .iter_chains()
+                    .filter(|chain| self.is_chain_listed(&chain.genesis_hash(), chain.node_count()))
+                    .map(|chain| {
+                        feed_message::AddedChain(
+                            chain.label(),
+                            chain.genesis_hash(),
+                            chain.node_count(),
+                            self.chain_metadata.get(&chain.genesis_hash()),
+                        )
+                    });
+                if chain_digest {
+                    feed_serializer.push(feed_message::AddedChains(added_chains.collect()));
+                } else {
+                    for added_chain in added_chains {
+                        feed_serializer.push(added_chain);
+                    }
                 }
 
                 // Send this to the channel that subscribed:
                 if let Some(bytes) = feed_serializer.into_finalized() {
-                    let _ = channel.send(ToFeedWebsocket::Bytes(bytes));
+                    let _ = channel.send(ToFeedWebsocket::Bytes(bytes.into()));
                 }
             }
             FromFeedWebsocket::Ping { value } => {
@@ -475,100 +1661,299 @@ impl InnerLoop {
                 let mut feed_serializer = FeedMessageSerializer::new();
                 feed_serializer.push(feed_message::Pong(&value));
                 if let Some(bytes) = feed_serializer.into_finalized() {
-                    let _ = feed_channel.send(ToFeedWebsocket::Bytes(bytes));
+                    let _ = feed_channel.send(ToFeedWebsocket::Bytes(bytes.into()));
                 }
             }
-            FromFeedWebsocket::Subscribe { chain } => {
-                let feed_channel = match self.feed_channels.get_mut(&feed_conn_id) {
-                    Some(chan) => chan,
-                    None => return,
-                };
+            FromFeedWebsocket::Subscribe { chain, geo_filter } => {
+                match geo_filter {
+                    Some(filter) => {
+                        let feed = GeoFilteredFeed {
+                            filter,
+                            visible_nodes: HashSet::new(),
+                        };
+                        self.geo_filtered_feeds.insert(feed_conn_id, feed);
+                    }
+                    None => {
+                        self.geo_filtered_feeds.remove(&feed_conn_id);
+                    }
+                }
+                self.subscribe_feed_to_chain(feed_conn_id, chain);
+            }
+            FromFeedWebsocket::Snapshot { chain } => {
+                match self.chain_to_feed_conn_ids.get_key(&feed_conn_id) {
+                    Some(subscribed_to) if *subscribed_to != chain => {
+                        log::debug!(
+                            "Feed {feed_conn_id:?} asked for a snapshot of {chain:?} while \
+                             subscribed to {subscribed_to:?}; ignoring"
+                        );
+                    }
+                    _ => self.send_chain_state(feed_conn_id, chain, false),
+                }
+            }
+            FromFeedWebsocket::Resume { chain, seq } => {
+                self.geo_filtered_feeds.remove(&feed_conn_id);
+                self.resume_feed(feed_conn_id, chain, seq);
+            }
+            FromFeedWebsocket::Disconnected => {
+                // The feed has disconnected; clean up references to it:
+                self.remove_feed(feed_conn_id);
+            }
+        }
+    }
 
-                // Unsubscribe from previous chain if subscribed to one:
-                let old_genesis_hash = self.chain_to_feed_conn_ids.remove_value(&feed_conn_id);
+    /// The feed that we heard from least recently, and when that was.
+    fn oldest_feed(&self) -> Option<(ConnId, Instant)> {
+        self.feed_last_activity
+            .iter()
+            .min_by_key(|(_, last_activity)| **last_activity)
+            .map(|(feed_conn_id, last_activity)| (*feed_conn_id, *last_activity))
+    }
 
-                // Get old chain if there was one:
-                let node_state = &self.node_state;
-                let old_chain =
-                    old_genesis_hash.and_then(|hash| node_state.get_chain_by_genesis_hash(&hash));
+    /// Hand back when the least recently active feed was last heard from.
+    fn handle_get_oldest_feed_activity(&self, tx: flume::Sender<Option<Instant>>) {
+        // Ignore error sending; assume the receiver stopped caring and dropped the channel:
+        let _ = tx.send(self.oldest_feed().map(|(_, last_activity)| last_activity));
+    }
 
-                // Get new chain, ignoring the rest if it doesn't exist.
-                let new_chain = match self.node_state.get_chain_by_genesis_hash(&chain) {
-                    Some(chain) => chain,
-                    None => return,
-                };
+    /// Close the least recently active feed, to make room for a new one.
+    fn handle_evict_oldest_feed(&mut self) {
+        if let Some((feed_conn_id, _)) = self.oldest_feed() {
+            log::debug!("Too many feeds connected; evicting least recently active feed");
+            if let Some(channel) = self.remove_feed(feed_conn_id) {
+                let _ = channel.send(ToFeedWebsocket::Close);
+            }
+        }
+    }
+
+    /// Subscribe a feed to a chain, sending it the current state of that chain.
+    fn subscribe_feed_to_chain(&mut self, feed_conn_id: ConnId, chain: BlockHash) {
+        self.send_chain_state(feed_conn_id, chain, true);
+    }
+
+    /// Send a feed the current state of a chain. If `subscribe` is true, the feed is also
+    /// subscribed to the chain (and unsubscribed from any other), so that it's sent updates
+    /// about it from now on. Otherwise, this is a one-off snapshot of the chain which leaves
+    /// any existing subscription alone.
+    fn send_chain_state(&mut self, feed_conn_id: ConnId, chain: BlockHash, subscribe: bool) {
+        // Send out any batched up updates for the chain before the feed subscribes to
+        // it; the current state of the chain that we send it will already include them.
+        if let Some(pending) = self.degraded_feed_buffers.remove(&chain) {
+            self.finalize_and_broadcast_to_chain_feeds(&chain, pending);
+        }
+
+        let feed_channel = match self.feed_channels.get_mut(&feed_conn_id) {
+            Some(chan) => chan,
+            None => return,
+        };
+
+        // Unsubscribe from previous chain if subscribed to one:
+        let old_genesis_hash = if subscribe {
+            self.chain_to_feed_conn_ids.remove_value(&feed_conn_id)
+        } else {
+            None
+        };
+
+        // Get old chain if there was one:
+        let node_state = &self.node_state;
+        let old_chain =
+            old_genesis_hash.and_then(|hash| node_state.get_chain_by_genesis_hash(&hash));
+
+        // Get new chain, ignoring the rest if it doesn't exist.
+        let new_chain = match self.node_state.get_chain_by_genesis_hash(&chain) {
+            Some(chain) => chain,
+            None => return,
+        };
+
+        // Send messages to the feed about this subscription:
+        let _ = feed_channel.send(ToFeedWebsocket::SubscribeStart);
+        let mut feed_serializer = FeedMessageSerializer::new();
+        if let Some(old_chain) = old_chain {
+            feed_serializer.push(feed_message::UnsubscribedFrom(old_chain.genesis_hash()));
+        }
+        if subscribe {
+            feed_serializer.push(feed_message::SubscribedTo(new_chain.genesis_hash()));
+        }
+        feed_serializer.push(feed_message::TimeSync(time::now()));
+        // Replay recent block history first, so that the current best and finalized
+        // blocks which follow (and any live updates after that) are always newer:
+        new_chain.write_recent_blocks(&mut feed_serializer);
+        feed_serializer.push(feed_message::BestBlock(
+            new_chain.best_block().height,
+            new_chain.timestamp(),
+            new_chain.average_block_time(),
+        ));
+        if let Some((median, p95)) = new_chain.block_time_percentiles() {
+            feed_serializer.push(feed_message::BlockTimePercentiles(median, p95));
+        }
+        feed_serializer.push(feed_message::BestFinalized(
+            new_chain.finalized_block().height,
+            new_chain.finalized_block().hash,
+        ));
+        feed_serializer.push(feed_message::MaxClaimedBlock(
+            new_chain.max_claimed_height(),
+        ));
+        feed_serializer.push(feed_message::ChainStatsUpdate(new_chain.stats()));
+        if !new_chain.peer_count_histogram().is_empty() {
+            feed_serializer.push(feed_message::PeerCountHistogram(
+                new_chain.genesis_hash(),
+                new_chain.peer_count_histogram(),
+            ));
+        }
+        // Let subscribers know where to resume from if they need to reconnect:
+        if let Some(seq) = self
+            .feed_resume_buffer
+            .as_ref()
+            .filter(|_| subscribe)
+            .and_then(|buffer| buffer.latest_seq(&chain))
+        {
+            feed_serializer.push(feed_message::Seq(seq));
+        }
+        if let Some(bytes) = feed_serializer.into_finalized() {
+            let _ = feed_channel.send(ToFeedWebsocket::Bytes(bytes.into()));
+        }
 
-                // Send messages to the feed about this subscription:
+        // If many (eg 10k) nodes are connected, serializing all of their info takes time.
+        // So, parallelise this with Rayon, but we still send out messages for each node in order
+        // (which is helpful for the UI as it tries to maintain a sorted list of nodes). The chunk
+        // size is the max number of node info we fit into 1 message; smaller messages allow the UI
+        // to react a little faster and not have to wait for a larger update to come in. A chunk size
+        // of 64 means each message is ~32k.
+        use rayon::prelude::*;
+        let new_chain_genesis_hash = new_chain.genesis_hash();
+        let removal_pending: HashSet<usize> = self
+            .pending_removals
+            .keys()
+            .filter(|&&node_id| {
+                self.node_state
+                    .get_chain_by_node_id(node_id)
+                    .map(|chain| chain.genesis_hash() == new_chain_genesis_hash)
+                    .unwrap_or(false)
+            })
+            .map(|node_id| node_id.get_chain_node_id().into())
+            .collect();
+        let geo_filter = self
+            .geo_filtered_feeds
+            .get(&feed_conn_id)
+            .filter(|_| subscribe)
+            .map(|feed| &feed.filter);
+        let all_feed_messages: Vec<_> = new_chain
+            .nodes_slice()
+            .par_iter()
+            .enumerate()
+            .chunks(64)
+            .filter_map(|nodes| {
                 let mut feed_serializer = FeedMessageSerializer::new();
-                if let Some(old_chain) = old_chain {
-                    feed_serializer.push(feed_message::UnsubscribedFrom(old_chain.genesis_hash()));
-                }
-                feed_serializer.push(feed_message::SubscribedTo(new_chain.genesis_hash()));
-                feed_serializer.push(feed_message::TimeSync(time::now()));
-                feed_serializer.push(feed_message::BestBlock(
-                    new_chain.best_block().height,
-                    new_chain.timestamp(),
-                    new_chain.average_block_time(),
-                ));
-                feed_serializer.push(feed_message::BestFinalized(
-                    new_chain.finalized_block().height,
-                    new_chain.finalized_block().hash,
-                ));
-                feed_serializer.push(feed_message::ChainStatsUpdate(new_chain.stats()));
-                if let Some(bytes) = feed_serializer.into_finalized() {
-                    let _ = feed_channel.send(ToFeedWebsocket::Bytes(bytes));
+                for (node_id, node) in nodes
+                    .iter()
+                    .filter_map(|&(idx, n)| n.as_ref().map(|n| (idx, n)))
+                    .filter(|(_, n)| geo_filter.map_or(true, |f| f.matches(n.location())))
+                {
+                    push_node_snapshot(
+                        &mut feed_serializer,
+                        node_id,
+                        node,
+                        self.expose_node_details,
+                        removal_pending.contains(&node_id),
+                    );
                 }
+                feed_serializer.into_finalized()
+            })
+            .collect();
+        if let Some(feed) = self
+            .geo_filtered_feeds
+            .get_mut(&feed_conn_id)
+            .filter(|_| subscribe)
+        {
+            feed.visible_nodes = new_chain
+                .nodes_slice()
+                .iter()
+                .enumerate()
+                .filter(|(_, n)| {
+                    n.as_ref()
+                        .map_or(false, |n| feed.filter.matches(n.location()))
+                })
+                .map(|(idx, _)| idx)
+                .collect();
+        }
+        for bytes in all_feed_messages {
+            let _ = feed_channel.send(ToFeedWebsocket::Bytes(bytes.into()));
+        }
+        let _ = feed_channel.send(ToFeedWebsocket::SubscribeEnd);
 
-                // If many (eg 10k) nodes are connected, serializing all of their info takes time.
-                // So, parallelise this with Rayon, but we still send out messages for each node in order
-                // (which is helpful for the UI as it tries to maintain a sorted list of nodes). The chunk
-                // size is the max number of node info we fit into 1 message; smaller messages allow the UI
-                // to react a little faster and not have to wait for a larger update to come in. A chunk size
-                // of 64 means each message is ~32k.
-                use rayon::prelude::*;
-                let all_feed_messages: Vec<_> = new_chain
-                    .nodes_slice()
-                    .par_iter()
-                    .enumerate()
-                    .chunks(64)
-                    .filter_map(|nodes| {
-                        let mut feed_serializer = FeedMessageSerializer::new();
-                        for (node_id, node) in nodes
-                            .iter()
-                            .filter_map(|&(idx, n)| n.as_ref().map(|n| (idx, n)))
-                        {
-                            feed_serializer.push(feed_message::AddedNode(
-                                node_id,
-                                node,
-                                self.expose_node_details,
-                            ));
-                            feed_serializer.push(feed_message::FinalizedBlock(
-                                node_id,
-                                node.finalized().height,
-                                node.finalized().hash,
-                            ));
-                            if node.stale() {
-                                feed_serializer.push(feed_message::StaleNode(node_id));
-                            }
-                        }
-                        feed_serializer.into_finalized()
-                    })
-                    .collect();
-                for bytes in all_feed_messages {
-                    let _ = feed_channel.send(ToFeedWebsocket::Bytes(bytes));
-                }
+        // Actually make a note of the new chain subscription:
+        if subscribe {
+            let new_genesis_hash = new_chain.genesis_hash();
+            self.chain_to_feed_conn_ids
+                .insert(new_genesis_hash, feed_conn_id);
+        }
+    }
 
-                // Actually make a note of the new chain subscription:
-                let new_genesis_hash = new_chain.genesis_hash();
-                self.chain_to_feed_conn_ids
-                    .insert(new_genesis_hash, feed_conn_id);
+    /// Subscribe a feed which has reconnected to a chain, sending it only the messages about
+    /// the chain which followed those with the given sequence number. If they aren't all held
+    /// onto any more, the feed is told so and sent the current state of the chain instead.
+    fn resume_feed(&mut self, feed_conn_id: ConnId, chain: BlockHash, seq: u64) {
+        let feed_channel = match self.feed_channels.get(&feed_conn_id) {
+            Some(chan) => chan.clone(),
+            None => return,
+        };
+
+        let missed = self
+            .feed_resume_buffer
+            .as_ref()
+            .and_then(|buffer| buffer.messages_since(&chain, seq));
+        let missed = match missed {
+            Some(missed) => missed,
+            None => {
+                let mut feed_serializer = FeedMessageSerializer::new();
+                feed_serializer.push(feed_message::ResumeFailed(chain));
+                if let Some(bytes) = feed_serializer.into_finalized() {
+                    let _ = feed_channel.send(ToFeedWebsocket::Bytes(bytes.into()));
+                }
+                self.subscribe_feed_to_chain(feed_conn_id, chain);
+                return;
             }
-            FromFeedWebsocket::Disconnected => {
-                // The feed has disconnected; clean up references to it:
-                self.chain_to_feed_conn_ids.remove_value(&feed_conn_id);
-                self.feed_channels.remove(&feed_conn_id);
+        };
+
+        // The feed already knows that it's subscribed to this chain, so we only
+        // tell it if it's no longer subscribed to some other one:
+        let old_genesis_hash = self.chain_to_feed_conn_ids.remove_value(&feed_conn_id);
+        if let Some(old_genesis_hash) = old_genesis_hash.filter(|&hash| hash != chain) {
+            let mut feed_serializer = FeedMessageSerializer::new();
+            feed_serializer.push(feed_message::UnsubscribedFrom(old_genesis_hash));
+            if let Some(bytes) = feed_serializer.into_finalized() {
+                let _ = feed_channel.send(ToFeedWebsocket::Bytes(bytes.into()));
             }
         }
+        for bytes in missed {
+            let _ = feed_channel.send(ToFeedWebsocket::ChainBytes(bytes.into()));
+        }
+        self.chain_to_feed_conn_ids.insert(chain, feed_conn_id);
+    }
+
+    /// Queue up a fresh snapshot of the chain that a slow feed is subscribed to. The feed
+    /// skips over any updates about the chain that are queued up ahead of the snapshot, since
+    /// the snapshot supersedes them.
+    fn resync_feed(&mut self, feed_conn_id: ConnId, chain: BlockHash) {
+        let (Some(resync_pending), Some(channel)) = (
+            self.feed_resync_pending.get(&feed_conn_id),
+            self.feed_channels.get(&feed_conn_id),
+        ) else {
+            return;
+        };
+
+        log::debug!("Feed {feed_conn_id:?} has fallen behind; sending it a fresh snapshot");
+        resync_pending.store(true, Ordering::Relaxed);
+        let _ = channel.send(ToFeedWebsocket::ResyncStart);
+        self.subscribe_feed_to_chain(feed_conn_id, chain);
+    }
+
+    /// Forget about a feed, returning the channel to talk to it if it existed.
+    fn remove_feed(&mut self, feed_conn_id: ConnId) -> Option<flume::Sender<ToFeedWebsocket>> {
+        self.chain_to_feed_conn_ids.remove_value(&feed_conn_id);
+        self.feed_last_activity.remove(&feed_conn_id);
+        self.feed_resync_pending.remove(&feed_conn_id);
+        self.geo_filtered_feeds.remove(&feed_conn_id);
+        self.feed_channels.remove(&feed_conn_id)
     }
 
     /// Remove all of the node IDs provided and broadcast messages to feeds as needed.
@@ -611,6 +1996,25 @@ impl InnerLoop {
     ) {
         // Remove our top level association (this may already have been done).
         self.node_ids.remove_by_left(&node_id);
+        self.forget_pending_removal(node_id);
+        self.located_nodes.remove(&node_id);
+
+        // Record that the node has disconnected, if we recorded it connecting:
+        let network_id = self
+            .node_state
+            .get_chain_by_node_id(node_id)
+            .and_then(|chain| {
+                let chain_node_id: usize = node_id.get_chain_node_id().into();
+                chain.nodes_slice()[chain_node_id]
+                    .as_ref()
+                    .map(|node| node.details().network_id)
+            });
+        if let Some(network_id) = network_id.filter(|id| !id.is_empty()) {
+            self.send_uptime_event(UptimeEvent::Disconnected {
+                network_id,
+                at: time::now(),
+            });
+        }
 
         let removed_details = match self.node_state.remove_node(node_id) {
             Some(remove_details) => remove_details,
@@ -619,64 +2023,579 @@ impl InnerLoop {
                 return;
             }
         };
+        self.node_removes_total += 1;
 
-        // The chain has been removed (no nodes left in it, or it was renamed):
-        if removed_details.chain_node_count == 0 || removed_details.has_chain_label_changed {
+        let genesis_hash = removed_details.chain_genesis_hash;
+        let was_listed = self.is_chain_listed(&genesis_hash, removed_details.chain_node_count + 1);
+        let is_listed = !removed_details.chain_removed
+            && self.is_chain_listed(&genesis_hash, removed_details.chain_node_count);
+
+        // The chain has been removed (no nodes left in it, too few nodes left in it to
+        // be listed, or it was renamed). Empty chains that are being kept around for a
+        // while stay listed with a node count of 0 if they're big enough to be listed:
+        if was_listed && (!is_listed || removed_details.has_chain_label_changed) {
             feed_for_all.push(feed_message::RemovedChain(
                 removed_details.chain_genesis_hash,
             ));
         }
 
-        // If the chain still exists, tell everybody about the new label or updated node count:
-        if removed_details.chain_node_count != 0 {
+        // If the chain is still listed, tell everybody about the new label or updated node count:
+        if is_listed {
             feed_for_all.push(feed_message::AddedChain(
                 &removed_details.new_chain_label,
                 removed_details.chain_genesis_hash,
                 removed_details.chain_node_count,
+                self.chain_metadata.get(&removed_details.chain_genesis_hash),
             ));
         }
 
+        if removed_details.chain_removed {
+            if let Some(buffer) = &mut self.feed_resume_buffer {
+                buffer.remove_chain(&removed_details.chain_genesis_hash);
+            }
+        }
+
         // Assuming the chain hasn't gone away, tell chain subscribers about the node removal
-        if removed_details.chain_node_count != 0 {
+        if !removed_details.chain_removed {
             feed_for_chain.push(feed_message::RemovedNode(
                 node_id.get_chain_node_id().into(),
             ));
         }
     }
 
+    /// Is the chain with this genesis hash listed to feeds if it has this many nodes? Pinned
+    /// chains are always listed, and other chains are listed if they're big enough.
+    fn is_chain_listed(&self, genesis_hash: &BlockHash, node_count: usize) -> bool {
+        node_count >= self.min_chain_node_count || self.node_state.is_chain_pinned(genesis_hash)
+    }
+
     /// Finalize a [`FeedMessageSerializer`] and broadcast the result to feeds for the chain.
     fn finalize_and_broadcast_to_chain_feeds(
         &mut self,
         genesis_hash: &BlockHash,
         serializer: FeedMessageSerializer,
     ) {
-        if let Some(bytes) = serializer.into_finalized() {
-            self.broadcast_to_chain_feeds(genesis_hash, ToFeedWebsocket::Bytes(bytes));
+        // Send any node updates batched up in degraded feed mode first, so that
+        // feeds always see messages about a chain in the order that they happened.
+        if let Some(pending) = self.degraded_feed_buffers.remove(genesis_hash) {
+            if let Some(bytes) = self.finalize_chain_messages(*genesis_hash, pending) {
+                self.broadcast_to_chain_feeds(genesis_hash, bytes);
+            }
+        }
+        if let Some(bytes) = self.finalize_chain_messages(*genesis_hash, serializer) {
+            self.broadcast_to_chain_feeds(genesis_hash, bytes);
+        }
+    }
+
+    /// Finalize some messages about a chain. If feeds can resume, the messages are numbered
+    /// and held onto, so that they can be sent to feeds which reconnect having missed them.
+    fn finalize_chain_messages(
+        &mut self,
+        genesis_hash: BlockHash,
+        serializer: FeedMessageSerializer,
+    ) -> Option<bytes::Bytes> {
+        match &mut self.feed_resume_buffer {
+            Some(buffer) => buffer.finalize(genesis_hash, serializer),
+            None => serializer.into_finalized(),
         }
     }
 
     /// Send a message to all chain feeds.
-    fn broadcast_to_chain_feeds(&mut self, genesis_hash: &BlockHash, message: ToFeedWebsocket) {
+    fn broadcast_to_chain_feeds(&mut self, genesis_hash: &BlockHash, bytes: bytes::Bytes) {
+        if let Some(recorder) = &self.feed_recorder {
+            recorder.record(Some(genesis_hash), &bytes);
+        }
+        let mut feeds_to_resync = Vec::new();
+        // Feeds with a geo filter are sent their own selection of the messages, which we
+        // decode the messages again to work out (at most once, and only if necessary). Feeds
+        // with the same filter that know about the same nodes are sent the same selection,
+        // so we only filter and serialize the messages once for each such group of feeds:
+        let chain = self.node_state.get_chain_by_genesis_hash(genesis_hash);
+        let nodes = chain.as_ref().map_or(&[][..], |chain| chain.nodes_slice());
+        let mut decoded_messages = None;
+        let mut filtered_messages = Vec::new();
+        let feed_bytes_for_all = FeedBytes::from(bytes.clone());
         if let Some(feeds) = self.chain_to_feed_conn_ids.get_values(genesis_hash) {
             for &feed_id in feeds {
-                if let Some(chan) = self.feed_channels.get_mut(&feed_id) {
-                    let _ = chan.send(message.clone());
+                if let Some(chan) = self.feed_channels.get(&feed_id) {
+                    let feed_bytes = match self.geo_filtered_feeds.get_mut(&feed_id) {
+                        Some(feed) => {
+                            let messages = decoded_messages.get_or_insert_with(|| {
+                                feed_message::decode_finalized(&bytes).unwrap_or_else(|e| {
+                                    log::error!("Couldn't decode messages for feeds: {e}");
+                                    Vec::new()
+                                })
+                            });
+                            filter_messages_for_similar_feeds(
+                                &mut filtered_messages,
+                                feed,
+                                messages,
+                                nodes,
+                                self.expose_node_details,
+                            )
+                        }
+                        None => Some(feed_bytes_for_all.clone()),
+                    };
+                    if let Some(feed_bytes) = feed_bytes {
+                        let _ = chan.send(ToFeedWebsocket::ChainBytes(feed_bytes));
+                    }
+
+                    // If the feed has fallen too far behind, send it a snapshot to catch up with:
+                    let needs_resync = match (
+                        self.feed_resync_queue_len,
+                        self.feed_resync_pending.get(&feed_id),
+                    ) {
+                        (Some(max_len), Some(resync_pending)) => {
+                            chan.len() > max_len && !resync_pending.load(Ordering::Relaxed)
+                        }
+                        _ => false,
+                    };
+                    if needs_resync {
+                        feeds_to_resync.push(feed_id);
+                    }
                 }
             }
         }
+        for feed_id in feeds_to_resync {
+            self.resync_feed(feed_id, *genesis_hash);
+        }
     }
 
     /// Finalize a [`FeedMessageSerializer`] and broadcast the result to all feeds
     fn finalize_and_broadcast_to_all_feeds(&mut self, serializer: FeedMessageSerializer) {
         if let Some(bytes) = serializer.into_finalized() {
-            self.broadcast_to_all_feeds(ToFeedWebsocket::Bytes(bytes));
+            self.broadcast_to_all_feeds(ToFeedWebsocket::Bytes(bytes.into()));
         }
     }
 
     /// Send a message to everybody.
     fn broadcast_to_all_feeds(&mut self, message: ToFeedWebsocket) {
+        if let (Some(recorder), ToFeedWebsocket::Bytes(bytes)) = (&self.feed_recorder, &message) {
+            recorder.record(None, bytes.bytes());
+        }
         for chan in self.feed_channels.values_mut() {
             let _ = chan.send(message.clone());
         }
     }
 }
+
+/// Send the message built by `msg` to the aggregator loop every `interval`, until the loop
+/// goes away.
+fn spawn_ticker(
+    interval: Duration,
+    tx: flume::Sender<(u64, ToAggregator)>,
+    msg: impl Fn() -> ToAggregator + Send + 'static,
+) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            if tx.send((time::now(), msg())).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+/// Push everything a feed needs to know about a node when it's first told about it.
+fn push_node_snapshot(
+    feed_serializer: &mut FeedMessageSerializer,
+    node_id: usize,
+    node: &state::Node,
+    expose_node_details: bool,
+    removal_pending: bool,
+) {
+    feed_serializer.push(feed_message::AddedNode(node_id, node, expose_node_details));
+    feed_serializer.push(feed_message::FinalizedBlock(
+        node_id,
+        node.finalized().height,
+        node.finalized().hash,
+    ));
+    if node.stale() || removal_pending {
+        feed_serializer.push(feed_message::StaleNode(node_id));
+    }
+    if let Some(score) = node.quality_score() {
+        feed_serializer.push(feed_message::NodeQualityScore(node_id, score));
+    }
+    let now = time::now();
+    if let Some(uptime) = node.uptime() {
+        feed_serializer.push(feed_message::NodeUptime(node_id, uptime.total_secs(now)));
+    }
+    if let Some(uptime_secs) = node.process_uptime_secs(now) {
+        feed_serializer.push(feed_message::NodeProcessUptime(node_id, uptime_secs));
+    }
+    let log_counts = node.log_counts();
+    if log_counts != state::NodeLogCounts::default() {
+        feed_serializer.push(feed_message::NodeLogCountsUpdate(
+            node_id,
+            log_counts.errors,
+            log_counts.warnings,
+        ));
+    }
+    if let Some(syncing) = node.major_syncing() {
+        feed_serializer.push(feed_message::NodeSyncState(node_id, syncing));
+    }
+}
+
+/// Work out which of the (decoded) messages being broadcast about a chain a geo filtered
+/// feed should be sent. Nodes are added to the feed when they're first found to be in its
+/// region (for instance, once they've been located), and removed if they move out of it.
+fn filter_messages_for_feed(
+    feed: &mut GeoFilteredFeed,
+    messages: &[(u8, serde_json::Value)],
+    nodes: &[Option<state::Node>],
+    expose_node_details: bool,
+) -> Option<bytes::Bytes> {
+    let mut feed_serializer = FeedMessageSerializer::new();
+    for (action, payload) in messages {
+        let node_id = match feed_message::decoded_node_id(*action, payload) {
+            Some(node_id) => node_id,
+            None => {
+                feed_serializer.push_raw(*action, payload);
+                continue;
+            }
+        };
+
+        if *action == feed_message::RemovedNode::ACTION {
+            if feed.visible_nodes.remove(&node_id) {
+                feed_serializer.push_raw(*action, payload);
+            }
+            continue;
+        }
+
+        let node = nodes.get(node_id).and_then(|node| node.as_ref());
+        let is_visible = feed.visible_nodes.contains(&node_id);
+        let in_region = feed.filter.matches(node.and_then(|node| node.location()));
+        match (is_visible, in_region, node) {
+            (true, true, _) => feed_serializer.push_raw(*action, payload),
+            (true, false, _) => {
+                feed.visible_nodes.remove(&node_id);
+                feed_serializer.push(feed_message::RemovedNode(node_id));
+            }
+            (false, true, Some(node)) => {
+                feed.visible_nodes.insert(node_id);
+                if *action == feed_message::AddedNode::ACTION {
+                    feed_serializer.push_raw(*action, payload);
+                } else {
+                    // The feed hasn't heard of this node yet, so tell it everything about
+                    // it (which supersedes the message itself):
+                    push_node_snapshot(
+                        &mut feed_serializer,
+                        node_id,
+                        node,
+                        expose_node_details,
+                        false,
+                    );
+                }
+            }
+            (false, _, _) => {}
+        }
+    }
+    feed_serializer.into_finalized()
+}
+
+/// Like [`filter_messages_for_feed`], but feeds with the same filter that know about the same
+/// nodes as one that's already had the messages filtered for it are sent the same selection,
+/// rather than us filtering and serializing the messages again.
+fn filter_messages_for_similar_feeds(
+    already_filtered: &mut Vec<FilteredMessages>,
+    feed: &mut GeoFilteredFeed,
+    messages: &[(u8, serde_json::Value)],
+    nodes: &[Option<state::Node>],
+    expose_node_details: bool,
+) -> Option<FeedBytes> {
+    let filtered = already_filtered
+        .iter()
+        .find(|f| f.filter == feed.filter && f.visible_nodes == feed.visible_nodes);
+    if let Some(filtered) = filtered {
+        feed.visible_nodes = filtered.visible_nodes_after.clone();
+        return filtered.bytes.clone();
+    }
+
+    let visible_nodes = feed.visible_nodes.clone();
+    let bytes =
+        filter_messages_for_feed(feed, messages, nodes, expose_node_details).map(FeedBytes::from);
+    already_filtered.push(FilteredMessages {
+        filter: feed.filter.clone(),
+        visible_nodes,
+        visible_nodes_after: feed.visible_nodes.clone(),
+        bytes: bytes.clone(),
+    });
+    bytes
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::node_types::Block;
+
+    fn update(payload: node_message::Payload) -> ToAggregator {
+        ToAggregator::FromShardWebsocket(
+            1.into(),
+            FromShardWebsocket::Update {
+                local_id: 1.into(),
+                payload,
+            },
+        )
+    }
+
+    #[test]
+    fn messages_are_prioritised() {
+        let block_import = update(node_message::Payload::BlockImport(Block {
+            hash: BlockHash::from_low_u64_be(1),
+            height: 1,
+        }));
+        let authority_set = update(node_message::Payload::AfgAuthoritySet(
+            node_message::AfgAuthoritySet {
+                authority_id: "foo".into(),
+            },
+        ));
+        let remove = ToAggregator::FromShardWebsocket(
+            1.into(),
+            FromShardWebsocket::Remove { local_id: 1.into() },
+        );
+
+        assert_eq!(block_import.priority(), MessagePriority::High);
+        assert_eq!(authority_set.priority(), MessagePriority::Low);
+        assert_eq!(remove.priority(), MessagePriority::Essential);
+        assert_eq!(
+            ToAggregator::FlushDegradedFeeds.priority(),
+            MessagePriority::Essential
+        );
+    }
+
+    #[test]
+    fn low_priority_messages_are_shed_first() {
+        let max_queue_len = 100;
+
+        // Nothing is dropped while the queue isn't overloaded:
+        for priority in [
+            MessagePriority::Low,
+            MessagePriority::High,
+            MessagePriority::Essential,
+        ] {
+            assert!(!priority.should_drop(100, max_queue_len));
+        }
+
+        // Only low priority messages are dropped once it is:
+        assert!(MessagePriority::Low.should_drop(101, max_queue_len));
+        assert!(!MessagePriority::High.should_drop(101, max_queue_len));
+        assert!(!MessagePriority::Essential.should_drop(101, max_queue_len));
+
+        // High priority messages are dropped too if it gets much worse:
+        assert!(MessagePriority::High.should_drop(201, max_queue_len));
+        assert!(!MessagePriority::Essential.should_drop(usize::MAX, max_queue_len));
+    }
+
+    #[test]
+    fn feeds_can_subscribe_with_a_geo_filter() {
+        let hash = format!("{:#x}", BlockHash::from_low_u64_be(1));
+        let parse = |s: String| match s.parse::<FromFeedWebsocket>().unwrap() {
+            FromFeedWebsocket::Subscribe { chain, geo_filter } => (chain, geo_filter),
+            msg => panic!("Expected a subscribe message, got {msg:?}"),
+        };
+
+        let (chain, geo_filter) = parse(format!("subscribe:{hash}"));
+        assert_eq!(chain, BlockHash::from_low_u64_be(1));
+        assert_eq!(geo_filter, None);
+
+        let (chain, geo_filter) = parse(format!("subscribe:{hash}?country=DE"));
+        assert_eq!(chain, BlockHash::from_low_u64_be(1));
+        assert_eq!(geo_filter, Some("country=DE".parse().unwrap()));
+
+        assert!(format!("subscribe:{hash}?radius=10")
+            .parse::<FromFeedWebsocket>()
+            .is_err());
+    }
+
+    #[test]
+    fn feeds_can_ask_for_a_snapshot() {
+        let hash = format!("{:#x}", BlockHash::from_low_u64_be(1));
+        match format!("snapshot:{hash}")
+            .parse::<FromFeedWebsocket>()
+            .unwrap()
+        {
+            FromFeedWebsocket::Snapshot { chain } => {
+                assert_eq!(chain, BlockHash::from_low_u64_be(1))
+            }
+            msg => panic!("Expected a snapshot message, got {msg:?}"),
+        }
+        assert!("snapshot:nonsense".parse::<FromFeedWebsocket>().is_err());
+    }
+
+    #[test]
+    fn feeds_can_ask_to_resume() {
+        let hash = format!("{:#x}", BlockHash::from_low_u64_be(1));
+        match format!("resume:{hash}@1234")
+            .parse::<FromFeedWebsocket>()
+            .unwrap()
+        {
+            FromFeedWebsocket::Resume { chain, seq } => {
+                assert_eq!(chain, BlockHash::from_low_u64_be(1));
+                assert_eq!(seq, 1234);
+            }
+            msg => panic!("Expected a resume message, got {msg:?}"),
+        }
+        assert!(format!("resume:{hash}")
+            .parse::<FromFeedWebsocket>()
+            .is_err());
+        assert!(format!("resume:{hash}@x")
+            .parse::<FromFeedWebsocket>()
+            .is_err());
+    }
+
+    #[test]
+    fn geo_filtered_feeds_are_told_about_nodes_in_their_region() {
+        // The actions of the messages that a feed is sent when this message is broadcast:
+        fn actions_sent<M: feed_message::FeedMessageWrite>(
+            feed: &mut GeoFilteredFeed,
+            nodes: &[Option<state::Node>],
+            msg: M,
+        ) -> Vec<u8> {
+            let mut ser = FeedMessageSerializer::new();
+            ser.push(msg);
+            let messages = feed_message::decode_finalized(&ser.into_finalized().unwrap()).unwrap();
+            match filter_messages_for_feed(feed, &messages, nodes, false) {
+                Some(bytes) => feed_message::decode_finalized(&bytes)
+                    .unwrap()
+                    .into_iter()
+                    .map(|(action, _)| action)
+                    .collect(),
+                None => Vec::new(),
+            }
+        }
+
+        let node = state::Node::new(NodeDetails {
+            chain: "Test".into(),
+            name: "Test".into(),
+            implementation: "Test".into(),
+            version: "0.1".into(),
+            target_arch: None,
+            target_env: None,
+            target_os: None,
+            validator: None,
+            network_id: Default::default(),
+            startup_time: None,
+            sysinfo: None,
+            ip: None,
+            shard: None,
+        });
+        let mut feed = GeoFilteredFeed {
+            filter: "country=DE".parse().unwrap(),
+            visible_nodes: HashSet::new(),
+        };
+        let mut nodes = vec![None, Some(node)];
+
+        // Nodes that haven't been located aren't sent:
+        assert!(actions_sent(&mut feed, &nodes, feed_message::StaleNode(1)).is_empty());
+
+        // Once located in the region, the feed is told all about the node:
+        nodes[1].as_mut().unwrap().update_location(Some(Arc::new(
+            common::node_types::NodeLocation {
+                latitude: 52.5,
+                longitude: 13.4,
+                city: "Berlin".into(),
+                country: Some("DE".into()),
+            },
+        )));
+        let added = actions_sent(
+            &mut feed,
+            &nodes,
+            feed_message::LocatedNode(1, 52.5, 13.4, "Berlin"),
+        );
+        assert_eq!(added, vec![3, 7]);
+        assert_eq!(feed.visible_nodes, HashSet::from([1]));
+
+        // After which it gets any updates about the node:
+        assert_eq!(
+            actions_sent(&mut feed, &nodes, feed_message::StaleNode(1)),
+            vec![20]
+        );
+
+        // And it's removed if it moves out of the region:
+        nodes[1].as_mut().unwrap().update_location(Some(Arc::new(
+            common::node_types::NodeLocation {
+                latitude: 40.7,
+                longitude: -74.0,
+                city: "New York".into(),
+                country: Some("US".into()),
+            },
+        )));
+        let removed = actions_sent(
+            &mut feed,
+            &nodes,
+            feed_message::LocatedNode(1, 40.7, -74.0, "New York"),
+        );
+        assert_eq!(removed, vec![4]);
+        assert!(feed.visible_nodes.is_empty());
+
+        // Messages that aren't about nodes are always sent:
+        assert_eq!(
+            actions_sent(&mut feed, &nodes, feed_message::TimeSync(1)),
+            vec![10]
+        );
+    }
+
+    #[test]
+    fn messages_are_filtered_once_for_similar_geo_filtered_feeds() {
+        let mut node = state::Node::new(NodeDetails {
+            chain: "Test".into(),
+            name: "Test".into(),
+            implementation: "Test".into(),
+            version: "0.1".into(),
+            target_arch: None,
+            target_env: None,
+            target_os: None,
+            validator: None,
+            network_id: Default::default(),
+            startup_time: None,
+            sysinfo: None,
+            ip: None,
+            shard: None,
+        });
+        node.update_location(Some(Arc::new(common::node_types::NodeLocation {
+            latitude: 52.5,
+            longitude: 13.4,
+            city: "Berlin".into(),
+            country: Some("DE".into()),
+        })));
+        let nodes = vec![Some(node)];
+        let new_feed = |filter: &str| GeoFilteredFeed {
+            filter: filter.parse().unwrap(),
+            visible_nodes: HashSet::new(),
+        };
+        let mut feeds = [
+            new_feed("country=DE"),
+            new_feed("country=US"),
+            new_feed("country=DE"),
+        ];
+
+        let mut ser = FeedMessageSerializer::new();
+        ser.push(feed_message::LocatedNode(0, 52.5, 13.4, "Berlin"));
+        let messages = feed_message::decode_finalized(&ser.into_finalized().unwrap()).unwrap();
+
+        let mut already_filtered = Vec::new();
+        let sent: Vec<_> = feeds
+            .iter_mut()
+            .map(|feed| {
+                filter_messages_for_similar_feeds(
+                    &mut already_filtered,
+                    feed,
+                    &messages,
+                    &nodes,
+                    false,
+                )
+            })
+            .collect();
+
+        // The messages are filtered once for each distinct filter:
+        assert_eq!(already_filtered.len(), 2);
+        // Feeds with the same filter are sent the same messages, and know about the same nodes:
+        assert!(sent[0].is_some());
+        assert_eq!(sent[0], sent[2]);
+        assert_eq!(sent[1], None);
+        assert_eq!(feeds[0].visible_nodes, HashSet::from([0]));
+        assert_eq!(feeds[2].visible_nodes, HashSet::from([0]));
+        assert!(feeds[1].visible_nodes.is_empty());
+    }
+}
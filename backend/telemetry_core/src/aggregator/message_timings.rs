@@ -0,0 +1,129 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Keep track of how long the aggregator loop spends handling each type of message, so that
+//! we can see which messages are expensive to handle. Times are counted into a handful of
+//! coarse buckets, so that recording them is cheap enough to do for every message.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// The upper bounds of the buckets that handling times are counted into, in microseconds. Any
+/// times above the last bound are counted in a final, unbounded, bucket.
+pub const BUCKET_BOUNDS_MICROS: [u64; 6] = [10, 100, 1_000, 10_000, 100_000, 1_000_000];
+
+/// How many times fell into each bucket, as well as the total time taken.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Histogram {
+    /// How many times fell into each bucket (not cumulative). The last entry
+    /// counts the times greater than every bound.
+    pub buckets: [u64; BUCKET_BOUNDS_MICROS.len() + 1],
+    /// The total of every time recorded, in microseconds.
+    pub sum_micros: u64,
+}
+
+impl Histogram {
+    fn record(&mut self, elapsed: Duration) {
+        let micros = elapsed.as_micros() as u64;
+        let idx = BUCKET_BOUNDS_MICROS
+            .iter()
+            .position(|&bound| micros <= bound)
+            .unwrap_or(BUCKET_BOUNDS_MICROS.len());
+        self.buckets[idx] += 1;
+        self.sum_micros += micros;
+    }
+
+    /// How many times have been recorded in total.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// Add the counts from another histogram to this one.
+    pub fn add(&mut self, other: &Histogram) {
+        for (a, b) in self.buckets.iter_mut().zip(other.buckets) {
+            *a += b;
+        }
+        self.sum_micros += other.sum_micros;
+    }
+}
+
+/// How long it's taken to handle each type of message.
+#[derive(Debug, Clone, Default)]
+pub struct MessageTimings {
+    by_kind: HashMap<&'static str, Histogram>,
+}
+
+impl MessageTimings {
+    /// Record how long it took to handle a message of the given kind.
+    pub fn record(&mut self, kind: &'static str, elapsed: Duration) {
+        self.by_kind.entry(kind).or_default().record(elapsed);
+    }
+
+    /// Add the timings from another set of timings to these ones.
+    pub fn add(&mut self, other: &MessageTimings) {
+        for (kind, histogram) in &other.by_kind {
+            self.by_kind.entry(kind).or_default().add(histogram);
+        }
+    }
+
+    /// Iterate over the timings for each kind of message, ordered by kind.
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, &Histogram)> {
+        let mut timings: Vec<_> = self.by_kind.iter().map(|(&k, h)| (k, h)).collect();
+        timings.sort_by_key(|&(kind, _)| kind);
+        timings.into_iter()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn times_are_counted_into_buckets() {
+        let mut timings = MessageTimings::default();
+        timings.record("b", Duration::from_micros(5));
+        timings.record("b", Duration::from_micros(10));
+        timings.record("b", Duration::from_micros(11));
+        timings.record("b", Duration::from_secs(2));
+        timings.record("a", Duration::from_millis(5));
+
+        let mut other = MessageTimings::default();
+        other.record("a", Duration::from_millis(5));
+        timings.add(&other);
+
+        let timings: Vec<_> = timings.iter().collect();
+        assert_eq!(
+            timings,
+            vec![
+                (
+                    "a",
+                    &Histogram {
+                        buckets: [0, 0, 0, 2, 0, 0, 0],
+                        sum_micros: 10_000
+                    }
+                ),
+                (
+                    "b",
+                    &Histogram {
+                        buckets: [2, 1, 0, 0, 0, 0, 1],
+                        sum_micros: 2_000_026
+                    }
+                ),
+            ]
+        );
+        assert_eq!(timings[1].1.count(), 4);
+    }
+}
@@ -16,10 +16,16 @@
 
 mod aggregator;
 mod aggregator_set;
+mod feed_resume;
+mod geo_filter;
 mod inner_loop;
+mod message_timings;
 
 // Expose the various message types that can be worked with externally:
-pub use aggregator::AggregatorOpts;
-pub use inner_loop::{FromFeedWebsocket, FromShardWebsocket, ToFeedWebsocket, ToShardWebsocket};
+pub use aggregator::{AggregatorOpts, MaxFeedsPolicy};
+pub use inner_loop::{
+    FromFeedWebsocket, FromShardWebsocket, Metrics, ToFeedWebsocket, ToShardWebsocket,
+};
 
 pub use aggregator_set::*;
+pub use message_timings::BUCKET_BOUNDS_MICROS;
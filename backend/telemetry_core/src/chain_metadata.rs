@@ -0,0 +1,88 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Optional display metadata for chains, so that frontends don't need to hardcode things like
+//! chain colours, logos and display names.
+//!
+//! If the core is started with `--chain-metadata <file>`, that file should contain a JSON object
+//! mapping genesis hashes to the metadata for each chain, for example:
+//!
+//! ```text
+//! {"0x91b171bb158e2d3848fa23a9f1c25182fb8e20313b2c1eb49219da7a70ce90c3":{"color":"#e6007a"}}
+//! ```
+//!
+//! We don't need to understand the metadata, and so the metadata for each chain can be any JSON
+//! value. It's appended to the `AddedChain` messages for that chain, and all of it is served
+//! from the `/chains` endpoint.
+
+use anyhow::Context;
+use common::node_types::BlockHash;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Display metadata for chains, keyed by genesis hash.
+#[derive(Debug, Default)]
+pub struct ChainMetadata {
+    by_genesis_hash: HashMap<BlockHash, serde_json::Value>,
+}
+
+impl ChainMetadata {
+    /// Load chain metadata from the JSON file given.
+    pub fn load(path: &Path) -> anyhow::Result<ChainMetadata> {
+        let bytes = std::fs::read(path)
+            .with_context(|| format!("Could not read chain metadata file {path:?}"))?;
+        ChainMetadata::from_json(&bytes)
+            .with_context(|| format!("Could not parse chain metadata file {path:?}"))
+    }
+
+    fn from_json(bytes: &[u8]) -> anyhow::Result<ChainMetadata> {
+        let by_genesis_hash = serde_json::from_slice(bytes)?;
+        Ok(ChainMetadata { by_genesis_hash })
+    }
+
+    /// The metadata for a chain, if there is any.
+    pub fn get(&self, genesis_hash: &BlockHash) -> Option<&serde_json::Value> {
+        self.by_genesis_hash.get(genesis_hash)
+    }
+
+    /// All of the chain metadata, as a JSON object keyed by genesis hash.
+    pub fn to_json(&self) -> Vec<u8> {
+        serde_json::to_vec(&self.by_genesis_hash).expect("chain metadata is valid JSON")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn metadata_is_looked_up_by_genesis_hash() {
+        let hash = BlockHash::from_low_u64_be(1);
+        let json = format!(r##"{{"{hash:#x}":{{"color":"#e6007a","logo":null}}}}"##);
+        let metadata = ChainMetadata::from_json(json.as_bytes()).unwrap();
+
+        assert_eq!(
+            metadata.get(&hash),
+            Some(&serde_json::json!({ "color": "#e6007a", "logo": null }))
+        );
+        assert_eq!(metadata.get(&BlockHash::from_low_u64_be(2)), None);
+    }
+
+    #[test]
+    fn metadata_must_be_keyed_by_genesis_hash() {
+        assert!(ChainMetadata::from_json(br##"{"polkadot":{"color":"#e6007a"}}"##).is_err());
+    }
+}
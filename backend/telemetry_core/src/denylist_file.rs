@@ -0,0 +1,111 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Keep the denylist up to date with a file on disk, so that chains can be blocked (or
+//! unblocked) without restarting the core.
+//!
+//! If the core is started with `--denylist-file <file>`, that file should contain the name of
+//! one chain per line. Blank lines, and lines starting with '#', are ignored. The file is
+//! re-read periodically, and any changes are handed to the aggregators. Chains given via
+//! `--denylist` are always denied, whatever the file says.
+
+use crate::aggregator::AggregatorSet;
+use anyhow::Context;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Load the names of the denied chains from the file given.
+pub fn load(path: &Path) -> anyhow::Result<HashSet<String>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Could not read denylist file {path:?}"))?;
+    Ok(parse(&contents))
+}
+
+fn parse(contents: &str) -> HashSet<String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(ToOwned::to_owned)
+        .collect()
+}
+
+/// Re-read the denylist file every `interval`, and whenever its contents change, tell the
+/// aggregators about the new denylist (which also includes each of the `fixed` chains).
+/// `current` is the set of chains from the file that the aggregators already know about.
+pub fn watch(
+    path: PathBuf,
+    interval: Duration,
+    fixed: Vec<String>,
+    mut current: HashSet<String>,
+    aggregator: AggregatorSet,
+) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        // The first tick completes immediately, and we've only just loaded the file:
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+
+            let path2 = path.clone();
+            let denylist = match tokio::task::spawn_blocking(move || load(&path2)).await {
+                Ok(Ok(denylist)) => denylist,
+                Ok(Err(e)) => {
+                    log::error!("Failed to reload denylist; still using the old one: {e:?}");
+                    continue;
+                }
+                Err(e) => {
+                    log::error!("Failed to reload denylist; still using the old one: {e}");
+                    continue;
+                }
+            };
+            if denylist == current {
+                continue;
+            }
+
+            log::info!(
+                "Denylist file changed; {} chains now denied",
+                denylist.len()
+            );
+            let all_denied = denylist
+                .iter()
+                .cloned()
+                .chain(fixed.iter().cloned())
+                .collect();
+            if let Err(e) = aggregator.update_denylist(all_denied).await {
+                // This means that the aggregators have stopped, so there's nothing left to update:
+                log::error!("Cannot send denylist to the aggregators (bailing): {e}");
+                return;
+            }
+            current = denylist;
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn blank_lines_and_comments_are_ignored() {
+        let denylist = parse("# Spammy chains\nChain One\n\n  Chain Two  \n#Chain Three\n");
+        let expected: HashSet<String> = ["Chain One".to_owned(), "Chain Two".to_owned()]
+            .into_iter()
+            .collect();
+        assert_eq!(denylist, expected);
+    }
+}
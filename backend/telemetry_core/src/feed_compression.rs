@@ -0,0 +1,151 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Optional compression of the messages we send to feeds.
+//!
+//! Feed messages are small and share a lot of structure (node updates especially), which
+//! generic compression can't take much advantage of. If the core is started with
+//! `--feed-zstd-dictionary`, feeds can instead ask for their messages to be compressed with
+//! zstd using a dictionary that we ship with the server, by connecting to `/feed?compression=zstd`.
+//!
+//! The dictionary is a zstd "raw content" dictionary: rather than being trained with
+//! `zstd --train`, it's simply a representative sample of feed output (see
+//! `feed_zstd_dictionary.txt`), which zstd uses as a prefix that compressed messages can
+//! refer back to. Clients can download it from `/feed/zstd_dictionary` and must load it as a
+//! raw content dictionary (for instance `ZSTD_dct_rawContent` in libzstd) to decompress
+//! messages. A trained dictionary can be dropped in place of the text file; zstd tells the
+//! two apart by the magic number that trained dictionaries begin with.
+//!
+//! Each websocket message is compressed as a single zstd frame. Clients that don't ask for
+//! compression, or ask when it's not enabled, are sent the usual uncompressed JSON. Since
+//! uncompressed messages always begin with `[`, and zstd frames always begin with the magic
+//! bytes `28 B5 2F FD`, clients can tell which they are being sent.
+
+use once_cell::sync::Lazy;
+use zstd::dict::EncoderDictionary;
+
+/// The dictionary we use to compress feed messages.
+pub const ZSTD_DICTIONARY: &[u8] = include_bytes!("feed_zstd_dictionary.txt");
+
+/// The zstd compression level we use. Feed messages are compressed once per feed
+/// connection, so we stick to a fairly cheap level.
+const ZSTD_LEVEL: i32 = 3;
+
+/// Preparing the dictionary is relatively expensive, so we do it once and share it.
+static ZSTD_PREPARED_DICTIONARY: Lazy<EncoderDictionary<'static>> =
+    Lazy::new(|| EncoderDictionary::copy(ZSTD_DICTIONARY, ZSTD_LEVEL));
+
+/// How should messages to a feed be compressed?
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeedCompression {
+    /// Send uncompressed JSON.
+    None,
+    /// Compress each message with zstd, using our dictionary.
+    ZstdDictionary,
+}
+
+impl FeedCompression {
+    /// Work out which compression a feed asked for via the query string it connected with,
+    /// falling back to no compression if it didn't ask for something we support.
+    pub fn from_query(query: Option<&str>, zstd_enabled: bool) -> FeedCompression {
+        let requested = query
+            .into_iter()
+            .flat_map(|q| q.split('&'))
+            .find_map(|param| param.strip_prefix("compression="));
+
+        match requested {
+            Some("zstd") if zstd_enabled => FeedCompression::ZstdDictionary,
+            _ => FeedCompression::None,
+        }
+    }
+}
+
+/// Compress messages to a single feed.
+pub struct FeedCompressor {
+    zstd: Option<zstd::bulk::Compressor<'static>>,
+}
+
+impl FeedCompressor {
+    /// Create a compressor which will compress messages as asked.
+    pub fn new(compression: FeedCompression) -> std::io::Result<Self> {
+        let zstd = match compression {
+            FeedCompression::None => None,
+            FeedCompression::ZstdDictionary => Some(
+                zstd::bulk::Compressor::with_prepared_dictionary(&ZSTD_PREPARED_DICTIONARY)?,
+            ),
+        };
+        Ok(FeedCompressor { zstd })
+    }
+
+    /// Compress the bytes of a single message, if necessary.
+    pub fn compress(&mut self, bytes: bytes::Bytes) -> std::io::Result<bytes::Bytes> {
+        match &mut self.zstd {
+            None => Ok(bytes),
+            Some(compressor) => compressor.compress(&bytes).map(Into::into),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn compression_chosen_from_query() {
+        assert_eq!(
+            FeedCompression::from_query(Some("compression=zstd"), true),
+            FeedCompression::ZstdDictionary
+        );
+        assert_eq!(
+            FeedCompression::from_query(Some("foo=bar&compression=zstd"), true),
+            FeedCompression::ZstdDictionary
+        );
+        assert_eq!(
+            FeedCompression::from_query(Some("compression=zstd"), false),
+            FeedCompression::None
+        );
+        assert_eq!(
+            FeedCompression::from_query(Some("compression=brotli"), true),
+            FeedCompression::None
+        );
+        assert_eq!(
+            FeedCompression::from_query(None, true),
+            FeedCompression::None
+        );
+    }
+
+    #[test]
+    fn zstd_compressed_messages_can_be_decompressed_with_dictionary() {
+        let msg = bytes::Bytes::from_static(
+            br#"[6,[12,[18000001,"0x0000000000000000000000000000000000000000000000000000000000000001",6000,1700000006000,250]]]"#,
+        );
+
+        let mut compressor = FeedCompressor::new(FeedCompression::ZstdDictionary).unwrap();
+        let compressed = compressor.compress(msg.clone()).unwrap();
+        assert!(compressed.len() < msg.len());
+
+        let mut decompressor = zstd::bulk::Decompressor::with_dictionary(ZSTD_DICTIONARY).unwrap();
+        let decompressed = decompressor.decompress(&compressed, msg.len()).unwrap();
+        assert_eq!(&decompressed[..], &msg[..]);
+    }
+
+    #[test]
+    fn uncompressed_messages_are_untouched() {
+        let msg = bytes::Bytes::from_static(b"[10,1700000000000]");
+        let mut compressor = FeedCompressor::new(FeedCompression::None).unwrap();
+        assert_eq!(compressor.compress(msg.clone()).unwrap(), msg);
+    }
+}
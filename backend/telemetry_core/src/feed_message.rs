@@ -19,11 +19,13 @@
 
 use serde::Serialize;
 
-use crate::state::Node;
+use crate::state::{Node, NodeLogCounts};
 use common::node_types::{
     BlockDetails, BlockHash, BlockNumber, NodeHardware, NodeIO, NodeStats, Timestamp,
 };
+use once_cell::sync::OnceCell;
 use serde_json::to_writer;
+use std::sync::Arc;
 
 type FeedNodeId = usize;
 
@@ -80,6 +82,39 @@ impl FeedMessageSerializer {
         let _ = to_writer(&mut self.buffer, value);
     }
 
+    /// Push a message that has already been decoded into its action and JSON payload.
+    pub fn push_raw(&mut self, action: u8, payload: &serde_json::Value) {
+        let glue = match self.buffer.len() {
+            0 => b'[',
+            _ => b',',
+        };
+
+        self.buffer.push(glue);
+        self.write(&action);
+        self.buffer.push(b',');
+        self.write(payload);
+    }
+
+    /// Append the messages that have been serialized into another serializer onto this one.
+    pub fn append(&mut self, other: FeedMessageSerializer) {
+        if other.buffer.is_empty() {
+            return;
+        }
+        if self.buffer.is_empty() {
+            self.buffer = other.buffer;
+            return;
+        }
+
+        // Skip the opening '[' of the other buffer, replacing it with a ','.
+        self.buffer.push(b',');
+        self.buffer.extend_from_slice(&other.buffer[1..]);
+    }
+
+    /// Have any messages been serialized yet?
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
     /// Return the bytes that we've serialized so far, consuming the serializer.
     pub fn into_finalized(mut self) -> Option<bytes::Bytes> {
         if self.buffer.is_empty() {
@@ -91,38 +126,178 @@ impl FeedMessageSerializer {
     }
 }
 
+/// Split some finalized feed messages back up into the action and payload of each message.
+pub fn decode_finalized(bytes: &[u8]) -> anyhow::Result<Vec<(u8, serde_json::Value)>> {
+    let values: Vec<serde_json::Value> = serde_json::from_slice(bytes)?;
+    if values.len() % 2 != 0 {
+        return Err(anyhow::anyhow!(
+            "Expecting an action and payload for every message"
+        ));
+    }
+    values
+        .chunks(2)
+        .map(|pair| match pair[0].as_u64() {
+            Some(action) if action <= u8::MAX as u64 => Ok((action as u8, pair[1].clone())),
+            _ => Err(anyhow::anyhow!("Invalid action {}", pair[0])),
+        })
+        .collect()
+}
+
+/// The actions of the messages that are about a single node. The payload of each of
+/// these is either the ID of the node or an array starting with it.
+const NODE_ACTIONS: [u8; 17] = [
+    AddedNode::ACTION,
+    RemovedNode::ACTION,
+    LocatedNode::ACTION,
+    ImportedBlock::ACTION,
+    FinalizedBlock::ACTION,
+    NodeStatsUpdate::ACTION,
+    Hardware::ACTION,
+    StaleNode::ACTION,
+    NodeIOUpdate::ACTION,
+    NodeQualityScore::ACTION,
+    NodeLogCountsUpdate::ACTION,
+    RecoveredNode::ACTION,
+    NodeFinalityLag::ACTION,
+    LocationFailed::ACTION,
+    NodeUptime::ACTION,
+    NodeSyncState::ACTION,
+    NodeProcessUptime::ACTION,
+];
+
+/// If a decoded message is about a single node, return the ID of that node.
+pub fn decoded_node_id(action: u8, payload: &serde_json::Value) -> Option<FeedNodeId> {
+    if !NODE_ACTIONS.contains(&action) {
+        return None;
+    }
+    let id = match payload {
+        serde_json::Value::Array(fields) => fields.first()?,
+        id => id,
+    };
+    id.as_u64().map(|id| id as FeedNodeId)
+}
+
+/// The shape of each line of the newline delimited JSON sent to '/feed/v2' connections.
+#[derive(Serialize)]
+struct NdJsonMessage<'a> {
+    action: &'static str,
+    payload: &'a serde_json::Value,
+}
+
+/// Convert some finalized feed messages into newline delimited JSON, with one object per
+/// message containing the name of the message's action and its payload.
+pub fn finalized_to_ndjson(bytes: &[u8]) -> anyhow::Result<bytes::Bytes> {
+    let mut out = Vec::with_capacity(bytes.len() * 2);
+    for (action, payload) in decode_finalized(bytes)? {
+        let action =
+            action_name(action).ok_or_else(|| anyhow::anyhow!("Unknown action {action}"))?;
+        to_writer(
+            &mut out,
+            &NdJsonMessage {
+                action,
+                payload: &payload,
+            },
+        )?;
+        out.push(b'\n');
+    }
+    Ok(out.into())
+}
+
+/// Some finalized feed messages on their way to one or more feeds. Clones share the newline
+/// delimited JSON version of the messages, so it's worked out at most once however many
+/// '/feed/v2' connections they're sent to.
+#[derive(Debug, Clone)]
+pub struct FeedBytes {
+    bytes: bytes::Bytes,
+    ndjson: Arc<OnceCell<bytes::Bytes>>,
+}
+
+impl FeedBytes {
+    /// The finalized messages.
+    pub fn bytes(&self) -> &bytes::Bytes {
+        &self.bytes
+    }
+
+    /// The messages as newline delimited JSON (see [`finalized_to_ndjson`]).
+    pub fn ndjson(&self) -> anyhow::Result<bytes::Bytes> {
+        self.ndjson
+            .get_or_try_init(|| finalized_to_ndjson(&self.bytes))
+            .cloned()
+    }
+}
+
+impl From<bytes::Bytes> for FeedBytes {
+    fn from(bytes: bytes::Bytes) -> Self {
+        FeedBytes {
+            bytes,
+            ndjson: Default::default(),
+        }
+    }
+}
+
+impl PartialEq for FeedBytes {
+    fn eq(&self, other: &Self) -> bool {
+        self.bytes == other.bytes
+    }
+}
+
+/// Declare the action of each message, along with the name that it's given in the newline
+/// delimited JSON sent to '/feed/v2' connections. The names are part of the feed protocol,
+/// so they shouldn't change if the types are renamed.
 macro_rules! actions {
-    ($($action:literal: $t:ty,)*) => {
+    ($($action:literal: $name:ident $(<$lt:lifetime>)? => $label:literal,)*) => {
         $(
-            impl FeedMessage for $t {
+            impl FeedMessage for $name $(<$lt>)? {
                 const ACTION: u8 = $action;
             }
         )*
+
+        /// The name of the message with the given action, if there is one.
+        pub fn action_name(action: u8) -> Option<&'static str> {
+            match action {
+                $($action => Some($label),)*
+                _ => None,
+            }
+        }
     }
 }
 
 actions! {
-     0: Version,
-     1: BestBlock,
-     2: BestFinalized,
-     3: AddedNode<'_>,
-     4: RemovedNode,
-     5: LocatedNode<'_>,
-     6: ImportedBlock<'_>,
-     7: FinalizedBlock,
-     8: NodeStatsUpdate<'_>,
-     9: Hardware<'_>,
-    10: TimeSync,
-    11: AddedChain<'_>,
-    12: RemovedChain,
-    13: SubscribedTo,
-    14: UnsubscribedFrom,
-    15: Pong<'_>,
+     0: Version => "Version",
+     1: BestBlock => "BestBlock",
+     2: BestFinalized => "BestFinalized",
+     3: AddedNode<'_> => "AddedNode",
+     4: RemovedNode => "RemovedNode",
+     5: LocatedNode<'_> => "LocatedNode",
+     6: ImportedBlock<'_> => "ImportedBlock",
+     7: FinalizedBlock => "FinalizedBlock",
+     8: NodeStatsUpdate<'_> => "NodeStatsUpdate",
+     9: Hardware<'_> => "Hardware",
+    10: TimeSync => "TimeSync",
+    11: AddedChain<'_> => "AddedChain",
+    12: RemovedChain => "RemovedChain",
+    13: SubscribedTo => "SubscribedTo",
+    14: UnsubscribedFrom => "UnsubscribedFrom",
+    15: Pong<'_> => "Pong",
     // Note; some now-unused messages were removed between IDs 15 and 20.
     // We maintain existing IDs for backward compatibility.
-    20: StaleNode,
-    21: NodeIOUpdate<'_>,
-    22: ChainStatsUpdate<'_>,
+    20: StaleNode => "StaleNode",
+    21: NodeIOUpdate<'_> => "NodeIOUpdate",
+    22: ChainStatsUpdate<'_> => "ChainStatsUpdate",
+    23: NodeQualityScore => "NodeQualityScore",
+    24: MaxClaimedBlock => "MaxClaimedBlock",
+    25: NodeLogCountsUpdate => "NodeLogCountsUpdate",
+    26: RecoveredNode => "RecoveredNode",
+    27: NodeFinalityLag => "NodeFinalityLag",
+    28: AddedChains<'_> => "AddedChains",
+    29: LocationFailed => "LocationFailed",
+    30: NodeUptime => "NodeUptime",
+    31: BlockTimePercentiles => "BlockTimePercentiles",
+    32: NodeSyncState => "NodeSyncState",
+    33: Seq => "Seq",
+    34: ResumeFailed => "ResumeFailed",
+    35: PeerCountHistogram<'_> => "PeerCountHistogram",
+    36: NodeProcessUptime => "NodeProcessUptime",
 }
 
 #[derive(Serialize)]
@@ -160,8 +335,17 @@ pub struct Hardware<'a>(pub FeedNodeId, pub &'a NodeHardware);
 #[derive(Serialize)]
 pub struct TimeSync(pub u64);
 
-#[derive(Serialize)]
-pub struct AddedChain<'a>(pub &'a str, pub BlockHash, pub usize);
+/// A chain's label, genesis hash, node count and any display metadata for it.
+pub struct AddedChain<'a>(
+    pub &'a str,
+    pub BlockHash,
+    pub usize,
+    pub Option<&'a serde_json::Value>,
+);
+
+/// Every chain that a feed is told about when it connects, in one message rather than an
+/// [`AddedChain`] message per chain. Each entry is the payload of the equivalent [`AddedChain`].
+pub struct AddedChains<'a>(pub Vec<AddedChain<'a>>);
 
 #[derive(Serialize)]
 pub struct RemovedChain(pub BlockHash);
@@ -178,12 +362,64 @@ pub struct Pong<'a>(pub &'a str);
 #[derive(Serialize)]
 pub struct StaleNode(pub FeedNodeId);
 
+#[derive(Serialize)]
+pub struct RecoveredNode(pub FeedNodeId);
+
+#[derive(Serialize)]
+pub struct NodeQualityScore(pub FeedNodeId, pub u8);
+
+#[derive(Serialize)]
+pub struct MaxClaimedBlock(pub BlockNumber);
+
+#[derive(Serialize)]
+pub struct NodeLogCountsUpdate(pub FeedNodeId, pub u64, pub u64);
+
+#[derive(Serialize)]
+pub struct NodeFinalityLag(pub FeedNodeId, pub BlockNumber);
+
+/// We tried to find the location of the node from its IP address, and couldn't.
+#[derive(Serialize)]
+pub struct LocationFailed(pub FeedNodeId);
+
+/// How many seconds the node has been up for in total, across reconnects.
+#[derive(Serialize)]
+pub struct NodeUptime(pub FeedNodeId, pub u64);
+
+/// The median and 95th percentile times (in milliseconds) between recent best blocks on a chain.
+#[derive(Serialize)]
+pub struct BlockTimePercentiles(pub u64, pub u64);
+
+/// Whether the node is doing a major sync (true) or is following the chain (false).
+#[derive(Serialize)]
+pub struct NodeSyncState(pub FeedNodeId, pub bool);
+
+/// The sequence number of the messages about a chain that this ends, which a feed can
+/// later resume from.
+#[derive(Serialize)]
+pub struct Seq(pub u64);
+
+/// The feed couldn't resume its subscription to this chain from where it left off, and
+/// so the current state of the chain follows instead.
+#[derive(Serialize)]
+pub struct ResumeFailed(pub BlockHash);
+
+/// How many nodes on a chain last reported a peer count within each bucket. Each bucket
+/// has an inclusive lower and exclusive upper bound (`None` if it's the last bucket).
+#[derive(Serialize)]
+pub struct PeerCountHistogram<'a>(pub BlockHash, pub &'a [((u64, Option<u64>), u64)]);
+
+/// How many seconds the node's process has been running for, going by the startup time
+/// that it reported.
+#[derive(Serialize)]
+pub struct NodeProcessUptime(pub FeedNodeId, pub u64);
+
 impl FeedMessageWrite for AddedNode<'_> {
     fn write_to_feed(&self, ser: &mut FeedMessageSerializer) {
         let AddedNode(nid, node, expose_node_details) = self;
 
         let details = node.details();
-        // Always include sysinfo, conditionally include ip and hwbench based on expose_node_details.
+        // Always include sysinfo, conditionally include ip, hwbench and shard based on
+        // expose_node_details.
         let node_hwbench = node.hwbench();
         let ip = if *expose_node_details {
             &details.ip
@@ -196,6 +432,11 @@ impl FeedMessageWrite for AddedNode<'_> {
         } else {
             &None
         };
+        let shard = if *expose_node_details {
+            &details.shard
+        } else {
+            &None
+        };
 
         let details = (
             &details.name,
@@ -209,6 +450,7 @@ impl FeedMessageWrite for AddedNode<'_> {
             &ip,
             &sys_info,
             &hwbench,
+            &shard,
         );
 
         ser.write(&(
@@ -220,10 +462,37 @@ impl FeedMessageWrite for AddedNode<'_> {
             node.block_details(),
             &node.location(),
             &node.startup_time(),
+            &node.finality_lag(),
         ));
     }
 }
 
+impl FeedMessageWrite for AddedChain<'_> {
+    fn write_to_feed(&self, ser: &mut FeedMessageSerializer) {
+        let AddedChain(label, genesis_hash, node_count, metadata) = self;
+
+        // Only chains with display metadata have it appended, so that this
+        // message is unchanged for every other chain.
+        match metadata {
+            Some(metadata) => ser.write(&(label, genesis_hash, node_count, metadata)),
+            None => ser.write(&(label, genesis_hash, node_count)),
+        }
+    }
+}
+
+impl FeedMessageWrite for AddedChains<'_> {
+    fn write_to_feed(&self, ser: &mut FeedMessageSerializer) {
+        ser.buffer.push(b'[');
+        for (idx, chain) in self.0.iter().enumerate() {
+            if idx > 0 {
+                ser.buffer.push(b',');
+            }
+            chain.write_to_feed(ser);
+        }
+        ser.buffer.push(b']');
+    }
+}
+
 #[derive(Serialize)]
 pub struct ChainStatsUpdate<'a>(pub &'a ChainStats);
 
@@ -239,9 +508,16 @@ pub struct ChainStats {
     pub version: Ranking<String>,
     pub target_os: Ranking<String>,
     pub target_arch: Ranking<String>,
+    /// The architecture and operating system pairs that nodes were built for (eg 'x86_64-linux').
+    pub target_platform: Ranking<String>,
     pub cpu: Ranking<String>,
     pub memory: Ranking<(u32, Option<u32>)>,
     pub core_count: Ranking<u32>,
+    /// The total number of CPU cores across the nodes which report them. Like `total_memory`,
+    /// this is capped at 2^53 - 1 so that feeds can represent it exactly.
+    pub total_core_count: u64,
+    /// The total amount of memory (in bytes) across the nodes which report it.
+    pub total_memory: u64,
     pub linux_kernel: Ranking<String>,
     pub linux_distro: Ranking<String>,
     pub is_virtual_machine: Ranking<bool>,
@@ -250,4 +526,310 @@ pub struct ChainStats {
     pub disk_sequential_write_score: Ranking<(u32, Option<u32>)>,
     pub disk_random_write_score: Ranking<(u32, Option<u32>)>,
     pub cpu_vendor: Ranking<String>,
+    pub database_size: Option<DatabaseSizeStats>,
+    pub log_counts: LogCountStats,
+    /// How many nodes are lagging a long way behind their best block in finalizing blocks.
+    pub nodes_with_excessive_finality_lag: u64,
+    /// When the chain last received a new best block, or `None` if it's yet to receive one.
+    pub last_block_at: Option<Timestamp>,
+}
+
+/// The errors and warnings logged by the nodes on a chain since they connected.
+#[derive(Serialize, PartialEq, Eq, Default, Debug)]
+pub struct LogCountStats {
+    pub errors: u64,
+    pub warnings: u64,
+    /// How many nodes have logged any errors.
+    pub nodes_with_errors: u64,
+    /// How many nodes have logged any warnings.
+    pub nodes_with_warnings: u64,
+}
+
+impl LogCountStats {
+    /// Total up the error and warning counts of each node.
+    pub fn from_counts(counts: impl IntoIterator<Item = NodeLogCounts>) -> LogCountStats {
+        let mut stats = LogCountStats::default();
+        for counts in counts {
+            stats.errors += counts.errors;
+            stats.warnings += counts.warnings;
+            stats.nodes_with_errors += (counts.errors > 0) as u64;
+            stats.nodes_with_warnings += (counts.warnings > 0) as u64;
+        }
+        stats
+    }
+}
+
+/// The spread of database sizes (in bytes) reported by the nodes on a chain.
+/// Only nodes that report their database size are counted.
+#[derive(Serialize, PartialEq, Eq, Default, Debug)]
+pub struct DatabaseSizeStats {
+    pub min: u64,
+    pub avg: u64,
+    pub max: u64,
+    /// How many nodes reported their database size.
+    pub count: u64,
+}
+
+impl DatabaseSizeStats {
+    /// Calculate the stats given the database sizes reported by each node,
+    /// returning `None` if no sizes are given.
+    pub fn from_sizes(sizes: impl IntoIterator<Item = u64>) -> Option<DatabaseSizeStats> {
+        let mut min = u64::MAX;
+        let mut max = 0;
+        let mut total: u128 = 0;
+        let mut count = 0;
+        for size in sizes {
+            min = min.min(size);
+            max = max.max(size);
+            total += size as u128;
+            count += 1;
+        }
+
+        if count == 0 {
+            return None;
+        }
+
+        Some(DatabaseSizeStats {
+            min,
+            avg: (total / count as u128) as u64,
+            max,
+            count,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn chain_metadata_is_only_appended_if_it_exists() {
+        let metadata = serde_json::json!({ "color": "#e6007a" });
+        let mut ser = FeedMessageSerializer::new();
+        ser.push(AddedChain("A", BlockHash::zero(), 1, None));
+        ser.push(AddedChain("B", BlockHash::zero(), 2, Some(&metadata)));
+
+        let hash = format!("{:#x}", BlockHash::zero());
+        let expected =
+            format!(r##"[11,["A","{hash}",1],11,["B","{hash}",2,{{"color":"#e6007a"}}]]"##);
+        assert_eq!(&ser.into_finalized().unwrap()[..], expected.as_bytes());
+    }
+
+    #[test]
+    fn added_chains_are_sent_as_one_array() {
+        let metadata = serde_json::json!({ "color": "#e6007a" });
+        let mut ser = FeedMessageSerializer::new();
+        ser.push(AddedChains(vec![
+            AddedChain("A", BlockHash::zero(), 1, None),
+            AddedChain("B", BlockHash::zero(), 2, Some(&metadata)),
+        ]));
+        ser.push(AddedChains(vec![]));
+
+        let hash = format!("{:#x}", BlockHash::zero());
+        let expected =
+            format!(r##"[28,[["A","{hash}",1],["B","{hash}",2,{{"color":"#e6007a"}}]],28,[]]"##);
+        assert_eq!(&ser.into_finalized().unwrap()[..], expected.as_bytes());
+    }
+
+    #[test]
+    fn node_shard_is_only_included_if_exposing_node_details() {
+        let node = Node::new(common::node_types::NodeDetails {
+            chain: "Test".into(),
+            name: "Test".into(),
+            implementation: "Test".into(),
+            version: "0.1".into(),
+            target_arch: None,
+            target_env: None,
+            target_os: None,
+            validator: None,
+            network_id: Default::default(),
+            startup_time: None,
+            sysinfo: None,
+            ip: None,
+            shard: Some("127.0.0.1:1234 (0.1.0)".into()),
+        });
+
+        let shard_of = |expose_node_details| {
+            let mut ser = FeedMessageSerializer::new();
+            ser.push(AddedNode(0, &node, expose_node_details));
+            let bytes = ser.into_finalized().unwrap();
+            let values: Vec<serde_json::Value> = serde_json::from_slice(&bytes).unwrap();
+            values[1][1][11].clone()
+        };
+
+        assert_eq!(shard_of(true), "127.0.0.1:1234 (0.1.0)");
+        assert_eq!(shard_of(false), serde_json::Value::Null);
+    }
+
+    #[test]
+    fn serializers_can_be_appended() {
+        let mut a = FeedMessageSerializer::new();
+        a.push(TimeSync(1));
+        let mut b = FeedMessageSerializer::new();
+        b.push(TimeSync(2));
+        b.push(StaleNode(3));
+        a.append(b);
+        a.append(FeedMessageSerializer::new());
+
+        let bytes = a.into_finalized().unwrap();
+        assert_eq!(&bytes[..], b"[10,1,10,2,20,3]");
+
+        let mut empty = FeedMessageSerializer::new();
+        let mut c = FeedMessageSerializer::new();
+        c.push(TimeSync(4));
+        empty.append(c);
+        assert_eq!(&empty.into_finalized().unwrap()[..], b"[10,4]");
+    }
+
+    #[test]
+    fn finalized_messages_can_be_decoded_and_pushed_again() {
+        let mut ser = FeedMessageSerializer::new();
+        ser.push(TimeSync(1));
+        ser.push(StaleNode(3));
+        ser.push(NodeQualityScore(4, 90));
+        let bytes = ser.into_finalized().unwrap();
+
+        let messages = decode_finalized(&bytes).unwrap();
+        let node_ids: Vec<_> = messages
+            .iter()
+            .map(|(action, payload)| decoded_node_id(*action, payload))
+            .collect();
+        assert_eq!(node_ids, vec![None, Some(3), Some(4)]);
+
+        let mut ser = FeedMessageSerializer::new();
+        for (action, payload) in &messages {
+            ser.push_raw(*action, payload);
+        }
+        assert_eq!(ser.into_finalized().unwrap(), bytes);
+
+        assert!(decode_finalized(b"[10]").is_err());
+    }
+
+    #[test]
+    fn every_message_about_a_node_has_its_id_decoded() {
+        // Messages about a node are about this one; nothing else uses this number:
+        const NODE: FeedNodeId = 4242;
+        let node = Node::new(common::node_types::NodeDetails {
+            chain: "Test".into(),
+            name: "Test".into(),
+            implementation: "Test".into(),
+            version: "0.1".into(),
+            target_arch: None,
+            target_env: None,
+            target_os: None,
+            validator: None,
+            network_id: Default::default(),
+            startup_time: None,
+            sysinfo: None,
+            ip: None,
+            shard: None,
+        });
+        let hash = BlockHash::zero();
+
+        let mut ser = FeedMessageSerializer::new();
+        ser.push(Version(1));
+        ser.push(BestBlock(1, 2, None));
+        ser.push(BestFinalized(1, hash));
+        ser.push(AddedNode(NODE, &node, false));
+        ser.push(RemovedNode(NODE));
+        ser.push(LocatedNode(NODE, 1.0, 2.0, "Berlin"));
+        ser.push(ImportedBlock(NODE, &BlockDetails::default()));
+        ser.push(FinalizedBlock(NODE, 1, hash));
+        ser.push(NodeStatsUpdate(NODE, &NodeStats::default()));
+        ser.push(Hardware(NODE, &NodeHardware::default()));
+        ser.push(TimeSync(1));
+        ser.push(AddedChain("A", hash, 1, None));
+        ser.push(RemovedChain(hash));
+        ser.push(SubscribedTo(hash));
+        ser.push(UnsubscribedFrom(hash));
+        ser.push(Pong("1"));
+        ser.push(StaleNode(NODE));
+        ser.push(NodeIOUpdate(NODE, &NodeIO::default()));
+        ser.push(ChainStatsUpdate(&ChainStats::default()));
+        ser.push(NodeQualityScore(NODE, 90));
+        ser.push(MaxClaimedBlock(1));
+        ser.push(NodeLogCountsUpdate(NODE, 1, 2));
+        ser.push(RecoveredNode(NODE));
+        ser.push(NodeFinalityLag(NODE, 1));
+        ser.push(AddedChains(vec![AddedChain("A", hash, 1, None)]));
+        ser.push(LocationFailed(NODE));
+        ser.push(NodeUptime(NODE, 1));
+        ser.push(BlockTimePercentiles(1, 2));
+        ser.push(NodeSyncState(NODE, true));
+        ser.push(Seq(1));
+        ser.push(ResumeFailed(hash));
+        ser.push(PeerCountHistogram(hash, &[((0, Some(1)), 1)]));
+        ser.push(NodeProcessUptime(NODE, 1));
+        let messages = decode_finalized(&ser.into_finalized().unwrap()).unwrap();
+
+        // Every kind of message is checked, so new ones must be added above:
+        let actions: Vec<u8> = messages.iter().map(|(action, _)| *action).collect();
+        let all_actions: Vec<u8> = (0..=u8::MAX)
+            .filter(|&action| action_name(action).is_some())
+            .collect();
+        assert_eq!(actions, all_actions);
+
+        for (action, payload) in &messages {
+            let first_value = match payload {
+                serde_json::Value::Array(fields) => fields.first(),
+                value => Some(value),
+            };
+            let is_about_node = first_value.and_then(|v| v.as_u64()) == Some(NODE as u64);
+            assert_eq!(
+                decoded_node_id(*action, payload),
+                is_about_node.then_some(NODE),
+                "{} messages",
+                action_name(*action).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn finalized_messages_can_be_converted_to_ndjson() {
+        let mut ser = FeedMessageSerializer::new();
+        ser.push(Version(32));
+        ser.push(NodeQualityScore(4, 90));
+        let bytes = finalized_to_ndjson(&ser.into_finalized().unwrap()).unwrap();
+
+        assert_eq!(
+            std::str::from_utf8(&bytes).unwrap(),
+            "{\"action\":\"Version\",\"payload\":32}\n\
+             {\"action\":\"NodeQualityScore\",\"payload\":[4,90]}\n"
+        );
+    }
+
+    #[test]
+    fn feed_bytes_are_converted_to_ndjson_once() {
+        let mut ser = FeedMessageSerializer::new();
+        ser.push(Version(32));
+        let bytes = FeedBytes::from(ser.into_finalized().unwrap());
+        let sent_elsewhere = bytes.clone();
+
+        let ndjson = bytes.ndjson().unwrap();
+        assert_eq!(sent_elsewhere.ndjson.get(), Some(&ndjson));
+        assert_eq!(sent_elsewhere.ndjson().unwrap(), ndjson);
+    }
+
+    #[test]
+    fn database_size_stats_only_count_reported_sizes() {
+        assert_eq!(DatabaseSizeStats::from_sizes(vec![]), None);
+        assert_eq!(
+            DatabaseSizeStats::from_sizes(vec![10, 40, 25]),
+            Some(DatabaseSizeStats {
+                min: 10,
+                avg: 25,
+                max: 40,
+                count: 3
+            })
+        );
+        assert_eq!(
+            DatabaseSizeStats::from_sizes(vec![u64::MAX, u64::MAX]),
+            Some(DatabaseSizeStats {
+                min: u64::MAX,
+                avg: u64::MAX,
+                max: u64::MAX,
+                count: 2
+            })
+        );
+    }
 }
@@ -0,0 +1,158 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Optional recording of the messages that we broadcast to feeds.
+//!
+//! If the core is started with `--record-feed <file>`, every message that's broadcast to feeds
+//! is written to that file, which is useful for debugging and for building test fixtures from
+//! real world data. Each line of the file is a JSON object of the form:
+//!
+//! ```text
+//! {"ts":1625000000000,"chain":"0x1234..","messages":[1,[100,1625000000000,null]]}
+//! ```
+//!
+//! where `ts` is the unix timestamp in milliseconds at which the message was broadcast, `chain`
+//! is the genesis hash of the chain that the message was broadcast to the subscribers of (or
+//! `null` if it was broadcast to every feed), and `messages` is exactly what the feeds were sent.
+//!
+//! Writes are buffered and happen on a separate thread, so that a slow disk doesn't hold up the
+//! aggregator. If the writer falls too far behind, messages are dropped from the recording rather
+//! than waiting for it to catch up.
+
+use anyhow::Context;
+use common::node_types::BlockHash;
+use common::time;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// How many messages can be waiting to be written before we start dropping them.
+const MAX_QUEUED_MESSAGES: usize = 10_000;
+
+/// A handle which can be used to record feed messages to a file.
+#[derive(Debug, Clone)]
+pub struct FeedRecorder {
+    tx: flume::Sender<RecordedMessage>,
+}
+
+#[derive(Debug)]
+struct RecordedMessage {
+    timestamp: u64,
+    chain: Option<BlockHash>,
+    bytes: bytes::Bytes,
+}
+
+impl FeedRecorder {
+    /// Create (or truncate) the file given and start a thread to write recorded messages to it.
+    pub fn create(path: &Path) -> anyhow::Result<FeedRecorder> {
+        let file = File::create(path)
+            .with_context(|| format!("Could not create feed recording file {path:?}"))?;
+        let (tx, rx) = flume::bounded(MAX_QUEUED_MESSAGES);
+
+        std::thread::Builder::new()
+            .name("feed_recorder".into())
+            .spawn(move || write_messages(rx, BufWriter::new(file)))?;
+
+        Ok(FeedRecorder { tx })
+    }
+
+    /// Record a message that's being broadcast to the feeds subscribed to the chain given,
+    /// or to every feed if no chain is given. This never blocks.
+    pub fn record(&self, chain: Option<&BlockHash>, bytes: &bytes::Bytes) {
+        let msg = RecordedMessage {
+            timestamp: time::now(),
+            chain: chain.copied(),
+            bytes: bytes.clone(),
+        };
+        if let Err(flume::TrySendError::Full(_)) = self.tx.try_send(msg) {
+            log::debug!("Feed recording is falling behind; dropping message");
+        }
+    }
+}
+
+/// Write messages to the file until every [`FeedRecorder`] has been dropped or
+/// something goes wrong, flushing whenever we've caught up.
+fn write_messages(rx: flume::Receiver<RecordedMessage>, mut writer: BufWriter<File>) {
+    while let Ok(msg) = rx.recv() {
+        let res = write_message(&mut writer, &msg).and_then(|_| {
+            if rx.is_empty() {
+                writer.flush()
+            } else {
+                Ok(())
+            }
+        });
+        if let Err(e) = res {
+            log::error!("Error writing to feed recording (no more messages will be recorded): {e}");
+            return;
+        }
+    }
+    let _ = writer.flush();
+}
+
+fn write_message(writer: &mut impl Write, msg: &RecordedMessage) -> std::io::Result<()> {
+    write!(writer, r#"{{"ts":{},"chain":"#, msg.timestamp)?;
+    match &msg.chain {
+        Some(hash) => serde_json::to_writer(&mut *writer, hash)?,
+        None => writer.write_all(b"null")?,
+    }
+    writer.write_all(br#","messages":"#)?;
+    writer.write_all(&msg.bytes)?;
+    writer.write_all(b"}\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn messages_are_written_one_per_line() {
+        let mut out = Vec::new();
+        write_message(
+            &mut out,
+            &RecordedMessage {
+                timestamp: 123,
+                chain: Some(BlockHash::from_low_u64_be(1)),
+                bytes: bytes::Bytes::from_static(b"[10,123]"),
+            },
+        )
+        .unwrap();
+        write_message(
+            &mut out,
+            &RecordedMessage {
+                timestamp: 456,
+                chain: None,
+                bytes: bytes::Bytes::from_static(b"[12,\"0x00\"]"),
+            },
+        )
+        .unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<serde_json::Value> = out
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["ts"], 123);
+        assert_eq!(
+            lines[0]["chain"],
+            serde_json::to_value(BlockHash::from_low_u64_be(1)).unwrap()
+        );
+        assert_eq!(lines[0]["messages"], serde_json::json!([10, 123]));
+        assert_eq!(lines[1]["ts"], 456);
+        assert!(lines[1]["chain"].is_null());
+    }
+}
@@ -0,0 +1,191 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! An optional warm-up period after startup, during which feeds aren't allowed to connect.
+//!
+//! When the core starts, every shard reconnects and sends us all of its nodes at once. Feeds
+//! that connect during this influx see a rapidly changing and incomplete list of chains, so if
+//! the core is started with `--feed-warmup-secs`, '/feed' connections are turned away until the
+//! warm-up is over. The warm-up ends after the given time, or earlier if
+//! `--feed-warmup-queue-len` is given and the influx of messages to the aggregators has settled.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How often we check whether the warm-up is over.
+const CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long the aggregator queues need to have stayed short for the influx to have settled.
+const SETTLE_TIME: Duration = Duration::from_secs(3);
+
+/// How long to warm up for.
+#[derive(Debug, Clone, Copy)]
+pub struct WarmupOpts {
+    /// The warm-up is over once this much time has passed.
+    pub duration: Duration,
+    /// If set, the warm-up is also over once the aggregator queues have grown longer than this
+    /// and then stayed at or below it for a few seconds.
+    pub settled_queue_len: Option<usize>,
+}
+
+/// Keeps track of where we are in the warm-up.
+struct Warmup {
+    opts: WarmupOpts,
+    started: Instant,
+    /// Have the aggregator queues been longer than `settled_queue_len` yet?
+    influx_seen: bool,
+    /// Since when have the aggregator queues been at or below `settled_queue_len`?
+    settled_since: Option<Instant>,
+}
+
+impl Warmup {
+    fn new(opts: WarmupOpts, now: Instant) -> Warmup {
+        Warmup {
+            opts,
+            started: now,
+            influx_seen: false,
+            settled_since: None,
+        }
+    }
+
+    /// Is the warm-up over, given the current length of the aggregator queues?
+    fn is_over(&mut self, now: Instant, queue_len: usize) -> bool {
+        if now.saturating_duration_since(self.started) >= self.opts.duration {
+            return true;
+        }
+
+        let settled_queue_len = match self.opts.settled_queue_len {
+            Some(len) => len,
+            None => return false,
+        };
+
+        if queue_len > settled_queue_len {
+            self.influx_seen = true;
+            self.settled_since = None;
+            return false;
+        }
+
+        // Until there's been an influx, the queues being short doesn't tell us anything:
+        if !self.influx_seen {
+            return false;
+        }
+
+        let settled_since = *self.settled_since.get_or_insert(now);
+        now.saturating_duration_since(settled_since) >= SETTLE_TIME
+    }
+}
+
+/// Whether feeds are allowed to connect yet.
+#[derive(Debug)]
+pub struct FeedWarmup {
+    ready: AtomicBool,
+}
+
+impl FeedWarmup {
+    /// Start warming up, using the function given to find out how long the aggregator queues
+    /// are. If no options are given, there's no warm-up and feeds can connect straight away.
+    pub fn spawn<F>(opts: Option<WarmupOpts>, queue_len: F) -> Arc<FeedWarmup>
+    where
+        F: Fn() -> usize + Send + 'static,
+    {
+        let opts = match opts {
+            Some(opts) => opts,
+            None => {
+                return Arc::new(FeedWarmup {
+                    ready: AtomicBool::new(true),
+                })
+            }
+        };
+
+        let feed_warmup = Arc::new(FeedWarmup {
+            ready: AtomicBool::new(false),
+        });
+
+        let feed_warmup2 = Arc::clone(&feed_warmup);
+        tokio::spawn(async move {
+            let mut warmup = Warmup::new(opts, Instant::now());
+            let mut interval = tokio::time::interval(CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                if warmup.is_over(Instant::now(), queue_len()) {
+                    log::info!(
+                        "Feed warm-up finished after {:?}; accepting feed connections",
+                        warmup.started.elapsed()
+                    );
+                    feed_warmup2.ready.store(true, Ordering::Relaxed);
+                    return;
+                }
+            }
+        });
+
+        feed_warmup
+    }
+
+    /// Are feeds allowed to connect yet?
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn secs(n: u64) -> Duration {
+        Duration::from_secs(n)
+    }
+
+    #[test]
+    fn warmup_is_over_after_duration() {
+        let start = Instant::now();
+        let mut warmup = Warmup::new(
+            WarmupOpts {
+                duration: secs(30),
+                settled_queue_len: None,
+            },
+            start,
+        );
+
+        assert!(!warmup.is_over(start, 0));
+        assert!(!warmup.is_over(start + secs(29), 0));
+        assert!(warmup.is_over(start + secs(30), 0));
+    }
+
+    #[test]
+    fn warmup_is_over_early_once_influx_settles() {
+        let start = Instant::now();
+        let mut warmup = Warmup::new(
+            WarmupOpts {
+                duration: secs(30),
+                settled_queue_len: Some(100),
+            },
+            start,
+        );
+
+        // Short queues before anything has arrived don't end the warm-up:
+        assert!(!warmup.is_over(start + secs(1), 0));
+        assert!(!warmup.is_over(start + secs(5), 0));
+        // The influx arrives, and then dies down, but pops back up briefly:
+        assert!(!warmup.is_over(start + secs(6), 5000));
+        assert!(!warmup.is_over(start + secs(7), 50));
+        assert!(!warmup.is_over(start + secs(8), 500));
+        assert!(!warmup.is_over(start + secs(9), 50));
+        assert!(!warmup.is_over(start + secs(11), 50));
+        // Having stayed short for long enough, the warm-up is over:
+        assert!(warmup.is_over(start + secs(12), 50));
+    }
+}
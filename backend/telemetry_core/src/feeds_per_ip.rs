@@ -0,0 +1,166 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Limit how many '/feed' connections can be open from any one IP address at once, so that a
+//! single misbehaving client can't hog every feed connection that we're willing to serve.
+
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::Arc;
+
+/// The number of feeds currently connected from each IP address.
+#[derive(Debug)]
+pub struct FeedsPerIp {
+    max: usize,
+    counts: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl FeedsPerIp {
+    /// Allow at most `max` feeds to be connected from each IP address.
+    pub fn new(max: usize) -> FeedsPerIp {
+        FeedsPerIp {
+            max,
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Make a note of a new feed connection from an IP address, returning a guard which should
+    /// be held onto for as long as the connection is open. Returns `None` if there are already
+    /// as many feeds connected from the address as we allow. IPv6 addresses are counted per /64
+    /// subnet (see [`address_group`]).
+    pub fn try_acquire(self: &Arc<Self>, ip: IpAddr) -> Option<FeedsPerIpGuard> {
+        let ip = address_group(ip);
+        let mut counts = self.counts.lock();
+        let count = counts.entry(ip).or_insert(0);
+        if *count >= self.max {
+            return None;
+        }
+        *count += 1;
+        Some(FeedsPerIpGuard {
+            feeds_per_ip: Arc::clone(self),
+            ip,
+        })
+    }
+}
+
+/// The addresses that we count feeds against. A single IPv6 client is typically handed a whole
+/// /64 subnet, and so could otherwise connect from as many addresses as it liked; we count
+/// every address in the same /64 together. IPv4 addresses (including those mapped into IPv6
+/// addresses) are counted individually.
+fn address_group(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & !(u64::MAX as u128))),
+        ip => ip,
+    }
+}
+
+/// Counts towards the feeds connected from an IP address until it's dropped.
+#[derive(Debug)]
+pub struct FeedsPerIpGuard {
+    feeds_per_ip: Arc<FeedsPerIp>,
+    ip: IpAddr,
+}
+
+impl Drop for FeedsPerIpGuard {
+    fn drop(&mut self) {
+        let mut counts = self.feeds_per_ip.counts.lock();
+        if let Some(count) = counts.get_mut(&self.ip) {
+            *count -= 1;
+            // Don't hold onto addresses that no longer have any feeds connected:
+            if *count == 0 {
+                counts.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// The response to send when too many feeds are connected from an IP address.
+pub fn too_many_feeds_response() -> hyper::Response<hyper::Body> {
+    hyper::Response::builder()
+        .status(429)
+        .body("Too many feeds connected from this address; try again later".into())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn count(feeds_per_ip: &FeedsPerIp, ip: IpAddr) -> usize {
+        feeds_per_ip
+            .counts
+            .lock()
+            .get(&address_group(ip))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    #[test]
+    fn feeds_are_limited_per_ip() {
+        let feeds_per_ip = Arc::new(FeedsPerIp::new(2));
+        let a: IpAddr = "1.2.3.4".parse().unwrap();
+        let b: IpAddr = "::1".parse().unwrap();
+
+        let a1 = feeds_per_ip.try_acquire(a).unwrap();
+        let _a2 = feeds_per_ip.try_acquire(a).unwrap();
+        assert!(feeds_per_ip.try_acquire(a).is_none());
+        assert_eq!(count(&feeds_per_ip, a), 2);
+
+        // Other addresses are unaffected:
+        let b1 = feeds_per_ip.try_acquire(b).unwrap();
+        assert_eq!(count(&feeds_per_ip, b), 1);
+
+        // Dropping a guard makes room for another feed:
+        drop(a1);
+        assert_eq!(count(&feeds_per_ip, a), 1);
+        assert!(feeds_per_ip.try_acquire(a).is_some());
+
+        // And addresses with no feeds are forgotten about:
+        drop(b1);
+        assert_eq!(count(&feeds_per_ip, b), 0);
+        assert!(!feeds_per_ip.counts.lock().contains_key(&address_group(b)));
+    }
+
+    #[test]
+    fn ipv6_feeds_are_limited_per_64_subnet() {
+        let feeds_per_ip = Arc::new(FeedsPerIp::new(2));
+        let a1: IpAddr = "2001:db8:1:2::1".parse().unwrap();
+        let a2: IpAddr = "2001:db8:1:2:ffff::2".parse().unwrap();
+        let a3: IpAddr = "2001:db8:1:2:abcd::3".parse().unwrap();
+        let b: IpAddr = "2001:db8:1:3::1".parse().unwrap();
+
+        // Addresses in the same /64 share a limit:
+        let _a1 = feeds_per_ip.try_acquire(a1).unwrap();
+        let _a2 = feeds_per_ip.try_acquire(a2).unwrap();
+        assert!(feeds_per_ip.try_acquire(a3).is_none());
+
+        // But addresses in other subnets don't:
+        assert!(feeds_per_ip.try_acquire(b).is_some());
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_are_counted_as_ipv4() {
+        let feeds_per_ip = Arc::new(FeedsPerIp::new(1));
+        let v4: IpAddr = "1.2.3.4".parse().unwrap();
+        let mapped: IpAddr = "::ffff:1.2.3.4".parse().unwrap();
+        let other_mapped: IpAddr = "::ffff:1.2.3.5".parse().unwrap();
+
+        let _v4 = feeds_per_ip.try_acquire(v4).unwrap();
+        assert!(feeds_per_ip.try_acquire(mapped).is_none());
+        assert!(feeds_per_ip.try_acquire(other_mapped).is_some());
+    }
+}
@@ -14,9 +14,15 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+use std::borrow::Cow;
+use std::collections::VecDeque;
 use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
+use anyhow::Context;
 use futures::{Sink, SinkExt};
 use maxminddb::{geoip2::City, Reader as GeoIpReader};
 use parking_lot::RwLock;
@@ -27,91 +33,362 @@ use common::node_types::NodeLocation;
 /// The returned location is optional; it may be None if not found.
 pub type Location = Option<Arc<NodeLocation>>;
 
+/// The most location requests that will be looked up together in one batch.
+const MAX_BATCH_LEN: usize = 256;
+
+/// Some metrics about the location requests being handled.
+#[derive(Debug, Default)]
+pub struct LocatorMetrics {
+    in_flight: AtomicUsize,
+    queued: AtomicUsize,
+    dropped: AtomicU64,
+}
+
+impl LocatorMetrics {
+    /// How many location lookups are currently being performed.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+    /// How many location requests are waiting to be looked up.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+    /// How many location requests have been dropped because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// The GeoIP database used to find the locations of IP addresses. This is the GeoLite2 City
+/// database built into the binary, unless a database file is given, in which case the file can
+/// be reloaded to pick up a newer version of it without restarting.
+#[derive(Debug, Clone)]
+pub struct GeoIpDatabase(Arc<GeoIpDatabaseInner>);
+
+#[derive(Debug)]
+struct GeoIpDatabaseInner {
+    path: Option<PathBuf>,
+    reader: RwLock<Arc<GeoIpReader<Cow<'static, [u8]>>>>,
+    /// Incremented every time the database is reloaded, so that
+    /// locators know to throw away any locations they've cached.
+    generation: AtomicU64,
+}
+
+impl GeoIpDatabase {
+    /// GeoLite database release data: 2024-03-29
+    /// Database and Contents Copyright (c) 2024 MaxMind, Inc.
+    /// To download the latest version visit: https://dev.maxmind.com/geoip/geolite2-free-geolocation-data.
+    ///
+    /// Use of this MaxMind product is governed by MaxMind's GeoLite2 End User License Agreement,
+    /// which can be viewed at https://www.maxmind.com/en/geolite2/eula.
+    /// This database incorporates GeoNames [https://www.geonames.org] geographical data,
+    /// which is made available under the Creative Commons Attribution 4.0 License.
+    /// To view a copy of this license, visit https://creativecommons.org/licenses/by/4.0/.
+    const CITY_DATA: &'static [u8] = include_bytes!("GeoLite2-City.mmdb");
+
+    /// Use the database built into the binary.
+    pub fn builtin() -> Self {
+        let reader = GeoIpReader::from_source(Cow::Borrowed(Self::CITY_DATA))
+            .expect("City data is always valid");
+        Self::new(None, reader)
+    }
+
+    /// Use the database in the file given.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let reader = Self::read(path)?;
+        log::info!(
+            "Loaded GeoIP database from {path:?} (built at unix time {})",
+            reader.metadata.build_epoch
+        );
+        Ok(Self::new(Some(path.to_owned()), reader))
+    }
+
+    fn new(path: Option<PathBuf>, reader: GeoIpReader<Cow<'static, [u8]>>) -> Self {
+        GeoIpDatabase(Arc::new(GeoIpDatabaseInner {
+            path,
+            reader: RwLock::new(Arc::new(reader)),
+            generation: AtomicU64::new(0),
+        }))
+    }
+
+    fn read(path: &Path) -> anyhow::Result<GeoIpReader<Cow<'static, [u8]>>> {
+        let bytes = std::fs::read(path)
+            .with_context(|| format!("Could not read GeoIP database {path:?}"))?;
+        GeoIpReader::from_source(Cow::Owned(bytes))
+            .map_err(|e| anyhow::anyhow!("Could not parse GeoIP database {path:?}: {e}"))
+    }
+
+    /// Load the database file again, and swap it in for the current one once it's loaded.
+    /// Any lookups happening in the meantime use the current database. This does nothing
+    /// if we're using the database built into the binary.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let path = match &self.0.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let reader = Self::read(path)?;
+        let build_epoch = reader.metadata.build_epoch;
+        *self.0.reader.write() = Arc::new(reader);
+        self.0.generation.fetch_add(1, Ordering::Relaxed);
+
+        log::info!("Reloaded GeoIP database from {path:?} (built at unix time {build_epoch})");
+        Ok(())
+    }
+
+    /// Reload the database file whenever we receive a SIGHUP.
+    #[cfg(unix)]
+    pub fn reload_on_sighup(&self) -> anyhow::Result<()> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut sighup = signal(SignalKind::hangup())?;
+        let database = self.clone();
+        tokio::spawn(async move {
+            while sighup.recv().await.is_some() {
+                // Reading and parsing the file blocks, so don't hold up other tasks while it happens:
+                let database = database.clone();
+                let res = tokio::task::spawn_blocking(move || database.reload()).await;
+                if let Ok(Err(e)) = res {
+                    log::error!("Failed to reload GeoIP database; still using the old one: {e:?}");
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Reloading on SIGHUP isn't supported on this platform.
+    #[cfg(not(unix))]
+    pub fn reload_on_sighup(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// The current database, and how many times it's been reloaded.
+    fn current(&self) -> (Arc<GeoIpReader<Cow<'static, [u8]>>>, u64) {
+        let reader = self.0.reader.read();
+        (
+            Arc::clone(&reader),
+            self.0.generation.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Is the IP address one that can't be geolocated, because it's private, loopback, link-local
+/// or otherwise reserved? Nodes behind proxies sometimes end up reporting addresses like these.
+pub fn is_private_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                // Shared address space (100.64.0.0/10), used for carrier-grade NAT:
+                || (a == 100 && (b & 0b1100_0000) == 0b0100_0000)
+                // Reserved for future use (240.0.0.0/4):
+                || a >= 240
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_private_ip(IpAddr::V4(ip));
+            }
+            let first_segment = ip.segments()[0];
+            ip.is_loopback()
+                || ip.is_unspecified()
+                // Unique local addresses (fc00::/7):
+                || (first_segment & 0xfe00) == 0xfc00
+                // Unicast link-local addresses (fe80::/10):
+                || (first_segment & 0xffc0) == 0xfe80
+                // Documentation addresses (2001:db8::/32):
+                || (first_segment == 0x2001 && ip.segments()[1] == 0x0db8)
+        }
+    }
+}
+
 /// This is responsible for taking an IP address and attempting
-/// to find a geographical location from this
-pub fn find_location<Id, R>(response_chan: R) -> flume::Sender<(Id, IpAddr)>
+/// to find a geographical location from this.
+///
+/// Requests are coalesced: once a request arrives, we wait up to `batch_window` for others to
+/// arrive too, and then look them all up together (up to `MAX_BATCH_LEN` at a time). This
+/// saves on spawning a separate lookup for every node when lots of them connect at once. Each
+/// location found is still sent back individually.
+///
+/// At most `max_in_flight` lookups are performed at once. Any other requests are queued, and
+/// if more than `max_queue_len` requests are waiting, the oldest are dropped (and so those
+/// nodes won't be given a location).
+pub fn find_location<Id, R>(
+    response_chan: R,
+    database: GeoIpDatabase,
+    max_in_flight: usize,
+    max_queue_len: usize,
+    batch_window: Duration,
+) -> (flume::Sender<(Id, IpAddr)>, Arc<LocatorMetrics>)
 where
     R: Sink<(Id, Option<Arc<NodeLocation>>)> + Unpin + Send + Clone + 'static,
     Id: Clone + Send + 'static,
 {
     let (tx, rx) = flume::unbounded();
+    let metrics = Arc::new(LocatorMetrics::default());
+    let max_in_flight = max_in_flight.max(1);
 
-    // cache entries
-    let mut cache: FxHashMap<IpAddr, Arc<NodeLocation>> = FxHashMap::default();
-
-    // Default entry for localhost
-    cache.insert(
-        Ipv4Addr::new(127, 0, 0, 1).into(),
-        Arc::new(NodeLocation {
-            latitude: 52.516_6667,
-            longitude: 13.4,
-            city: "Berlin".into(),
-        }),
-    );
-
-    // Create a locator with our cache. This is used to obtain locations.
-    let locator = Locator::new(cache);
+    // Create a locator. This is used to obtain locations.
+    let locator = Locator::new(database);
 
     // Spawn a loop to handle location requests
+    let loop_metrics = Arc::clone(&metrics);
     tokio::spawn(async move {
+        let metrics = loop_metrics;
+        // Each request is queued alongside the time it was received at.
+        let mut queue: VecDeque<(Id, IpAddr, tokio::time::Instant)> = VecDeque::new();
+        let mut in_flight = 0;
+        let (done_tx, done_rx) = flume::unbounded::<usize>();
+
         loop {
-            while let Ok((id, ip_address)) = rx.recv_async().await {
+            // If we could start some lookups, wait until the oldest request has waited long
+            // enough for others to be batched up with it:
+            let batch_deadline = match queue.front() {
+                Some((_, _, received_at)) if in_flight < max_in_flight => {
+                    Some(*received_at + batch_window)
+                }
+                _ => None,
+            };
+            let wait_for_batch = async move {
+                match batch_deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => futures::future::pending().await,
+                }
+            };
+
+            tokio::select! {
+                msg = rx.recv_async() => {
+                    let (id, ip_address) = match msg {
+                        Ok(req) => req,
+                        // Nobody can send any more requests, so end the loop.
+                        Err(_) => break,
+                    };
+                    queue.push_back((id, ip_address, tokio::time::Instant::now()));
+                    if queue.len() > max_queue_len {
+                        queue.pop_front();
+                        metrics.dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+                },
+                Ok(num_done) = done_rx.recv_async() => {
+                    in_flight -= num_done;
+                },
+                _ = wait_for_batch => {}
+            }
+
+            // Start as many lookups as we're allowed to, once the oldest request has waited
+            // for the batch window (or a full batch has already been queued up):
+            let now = tokio::time::Instant::now();
+            while in_flight < max_in_flight {
+                let batch_ready = match queue.front() {
+                    Some((_, _, received_at)) => {
+                        *received_at + batch_window <= now || queue.len() >= MAX_BATCH_LEN
+                    }
+                    None => false,
+                };
+                if !batch_ready {
+                    break;
+                }
+
+                let batch_len = queue
+                    .len()
+                    .min(MAX_BATCH_LEN)
+                    .min(max_in_flight - in_flight);
+                let batch: Vec<_> = queue
+                    .drain(..batch_len)
+                    .map(|(id, ip_address, _)| (id, ip_address))
+                    .collect();
+                in_flight += batch_len;
+
                 let mut response_chan = response_chan.clone();
                 let locator = locator.clone();
+                let done_tx = done_tx.clone();
 
                 tokio::spawn(async move {
-                    let location = tokio::task::spawn_blocking(move || locator.locate(ip_address))
-                        .await
-                        .expect("Locate never panics");
-                    let _ = response_chan.send((id, location)).await;
+                    let locations = tokio::task::spawn_blocking(move || {
+                        batch
+                            .into_iter()
+                            .map(|(id, ip_address)| (id, locator.locate(ip_address)))
+                            .collect::<Vec<_>>()
+                    })
+                    .await
+                    .expect("Locate never panics");
+                    for location in locations {
+                        let _ = response_chan.send(location).await;
+                    }
+                    let _ = done_tx.send(batch_len);
                 });
             }
+
+            metrics.in_flight.store(in_flight, Ordering::Relaxed);
+            metrics.queued.store(queue.len(), Ordering::Relaxed);
         }
     });
 
-    tx
+    (tx, metrics)
 }
 
 /// This struct can be used to make location requests, given
 /// an IPV4 or IPV6 address.
 #[derive(Debug, Clone)]
 struct Locator {
-    city: Arc<maxminddb::Reader<&'static [u8]>>,
-    cache: Arc<RwLock<FxHashMap<IpAddr, Arc<NodeLocation>>>>,
+    database: GeoIpDatabase,
+    /// Cached locations, and the generation of the database they were looked up in.
+    cache: Arc<RwLock<(u64, FxHashMap<IpAddr, Arc<NodeLocation>>)>>,
 }
 
 impl Locator {
-    /// GeoLite database release data: 2024-03-29
-    /// Database and Contents Copyright (c) 2024 MaxMind, Inc.
-    /// To download the latest version visit: https://dev.maxmind.com/geoip/geolite2-free-geolocation-data.
-    ///
-    /// Use of this MaxMind product is governed by MaxMind's GeoLite2 End User License Agreement,
-    /// which can be viewed at https://www.maxmind.com/en/geolite2/eula.
-    /// This database incorporates GeoNames [https://www.geonames.org] geographical data,
-    /// which is made available under the Creative Commons Attribution 4.0 License.
-    /// To view a copy of this license, visit https://creativecommons.org/licenses/by/4.0/.
-    const CITY_DATA: &'static [u8] = include_bytes!("GeoLite2-City.mmdb");
-
-    pub fn new(cache: FxHashMap<IpAddr, Arc<NodeLocation>>) -> Self {
+    pub fn new(database: GeoIpDatabase) -> Self {
+        let (_, generation) = database.current();
         Self {
-            city: GeoIpReader::from_source(Self::CITY_DATA)
-                .map(Arc::new)
-                .expect("City data is always valid"),
-            cache: Arc::new(RwLock::new(cache)),
+            database,
+            cache: Arc::new(RwLock::new((generation, Self::initial_cache()))),
         }
     }
 
+    /// The cache entries that we start with.
+    fn initial_cache() -> FxHashMap<IpAddr, Arc<NodeLocation>> {
+        let mut cache = FxHashMap::default();
+
+        // Default entry for localhost
+        cache.insert(
+            Ipv4Addr::new(127, 0, 0, 1).into(),
+            Arc::new(NodeLocation {
+                latitude: 52.516_6667,
+                longitude: 13.4,
+                city: "Berlin".into(),
+                country: Some("DE".into()),
+            }),
+        );
+
+        cache
+    }
+
     pub fn locate(&self, ip: IpAddr) -> Option<Arc<NodeLocation>> {
-        // Return location quickly if it's cached:
+        let (city_db, generation) = self.database.current();
+
+        // Return location quickly if it's cached (and the database hasn't been reloaded since):
         let cached_loc = {
             let cache_reader = self.cache.read();
-            cache_reader.get(&ip).cloned()
+            if cache_reader.0 == generation {
+                cache_reader.1.get(&ip).cloned()
+            } else {
+                None
+            }
         };
         if cached_loc.is_some() {
             return cached_loc;
         }
 
-        let City { city, location, .. } = self.city.lookup(ip.into()).ok()?;
+        let City {
+            city,
+            country,
+            location,
+            ..
+        } = city_db.lookup(ip.into()).ok()?;
         let city = city
             .as_ref()?
             .names
@@ -121,13 +398,22 @@ impl Locator {
             .into_boxed_str();
         let latitude = location.as_ref()?.latitude? as f32;
         let longitude = location?.longitude? as f32;
+        let country = country.and_then(|country| country.iso_code).map(Into::into);
 
         let location = Arc::new(NodeLocation {
             city,
+            country,
             latitude,
             longitude,
         });
-        self.cache.write().insert(ip, Arc::clone(&location));
+        let mut cache_writer = self.cache.write();
+        if cache_writer.0 < generation {
+            *cache_writer = (generation, Self::initial_cache());
+        }
+        // Don't cache locations from a database that's since been replaced:
+        if cache_writer.0 == generation {
+            cache_writer.1.insert(ip, Arc::clone(&location));
+        }
 
         Some(location)
     }
@@ -137,15 +423,108 @@ impl Locator {
 mod tests {
     use super::*;
 
+    #[test]
+    fn private_ips_are_detected() {
+        let private = [
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "127.0.0.1",
+            "169.254.1.1",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fd12:3456:789a::1",
+            "fe80::1",
+            "::ffff:192.168.1.1",
+        ];
+        for ip in private {
+            assert!(is_private_ip(ip.parse().unwrap()), "{ip} should be private");
+        }
+
+        let public = [
+            "12.5.56.25",
+            "100.128.0.1",
+            "8.8.8.8",
+            "2a00:1450:4009::1",
+            "::ffff:8.8.8.8",
+        ];
+        for ip in public {
+            assert!(!is_private_ip(ip.parse().unwrap()), "{ip} should be public");
+        }
+    }
+
+    #[tokio::test]
+    async fn batched_requests_are_all_located() {
+        let (res_tx, res_rx) = flume::unbounded();
+        let (tx, _metrics) = find_location(
+            res_tx.into_sink(),
+            GeoIpDatabase::builtin(),
+            8,
+            1000,
+            Duration::from_millis(50),
+        );
+
+        let ip: IpAddr = "12.5.56.25".parse().unwrap();
+        for id in 0..100 {
+            tx.send((id, ip)).unwrap();
+        }
+
+        let mut located = Vec::new();
+        for _ in 0..100 {
+            let (id, location) = tokio::time::timeout(Duration::from_secs(10), res_rx.recv_async())
+                .await
+                .expect("all nodes should be located")
+                .unwrap();
+            assert_eq!(
+                &*location.expect("location should be found").city,
+                "Gardena"
+            );
+            located.push(id);
+        }
+        located.sort_unstable();
+        assert_eq!(located, (0..100).collect::<Vec<_>>());
+    }
+
     #[test]
     fn locator_construction() {
-        Locator::new(Default::default());
+        Locator::new(GeoIpDatabase::builtin());
     }
 
     #[test]
     fn locate_random_ip() {
         let ip = "12.5.56.25".parse().unwrap();
-        let node_location = Locator::new(Default::default()).locate(ip).unwrap();
+        let node_location = Locator::new(GeoIpDatabase::builtin()).locate(ip).unwrap();
         assert_eq!(&*node_location.city, "Gardena");
+        assert_eq!(node_location.country.as_deref(), Some("US"));
+    }
+
+    #[test]
+    fn database_can_be_reloaded_from_file() {
+        let path =
+            std::env::temp_dir().join(format!("telemetry_geoip_test_{}.mmdb", std::process::id()));
+        std::fs::write(&path, GeoIpDatabase::CITY_DATA).unwrap();
+
+        let database = GeoIpDatabase::open(&path).unwrap();
+        let locator = Locator::new(database.clone());
+        let ip = "12.5.56.25".parse().unwrap();
+        assert_eq!(&*locator.locate(ip).unwrap().city, "Gardena");
+
+        // A broken file is rejected, and the current database is kept:
+        std::fs::write(&path, b"not a database").unwrap();
+        assert!(database.reload().is_err());
+        assert_eq!(&*locator.locate(ip).unwrap().city, "Gardena");
+
+        std::fs::write(&path, GeoIpDatabase::CITY_DATA).unwrap();
+        database.reload().unwrap();
+        assert_eq!(&*locator.locate(ip).unwrap().city, "Gardena");
+
+        // The cache is cleared out (apart from the entries we start with) on reload:
+        assert_eq!(locator.cache.read().1.len(), 2);
+        assert_eq!(locator.cache.read().0, 1);
+
+        std::fs::remove_file(&path).unwrap();
     }
 }
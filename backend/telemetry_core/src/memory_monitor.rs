@@ -0,0 +1,310 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! A last resort safety valve to avoid being killed for running out of memory.
+//!
+//! If the core is started with `--memory-limit-mb`, we periodically check how much memory we're
+//! using (according to jemalloc), and once this gets close to the limit, we start shedding load:
+//! first by dropping low priority messages to the aggregators, and then by evicting the nodes on
+//! third party chains and refusing any more of them, until memory use drops back down again.
+
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often we check how much memory we're using.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Once we're shedding load, we only shed less of it when memory use has dropped this many
+/// percent of the limit below the point at which we started, so that we don't flap between
+/// shedding and not shedding load while memory use hovers around that point.
+const HYSTERESIS_PERCENT: u8 = 5;
+
+/// How much load we're shedding because of memory pressure.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MemoryPressure {
+    /// Memory use is fine; nothing is being shed.
+    #[default]
+    Normal,
+    /// Low priority messages to the aggregators are being dropped.
+    DropLowPriority,
+    /// Low priority messages are being dropped, and third party chains are being evicted.
+    EvictThirdPartyChains,
+}
+
+impl MemoryPressure {
+    fn from_u8(n: u8) -> MemoryPressure {
+        match n {
+            0 => MemoryPressure::Normal,
+            1 => MemoryPressure::DropLowPriority,
+            _ => MemoryPressure::EvictThirdPartyChains,
+        }
+    }
+
+    fn as_u8(self) -> u8 {
+        match self {
+            MemoryPressure::Normal => 0,
+            MemoryPressure::DropLowPriority => 1,
+            MemoryPressure::EvictThirdPartyChains => 2,
+        }
+    }
+}
+
+/// At what point we start shedding load.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryLimits {
+    /// The amount of memory, in bytes, that we want to stay below.
+    pub limit: u64,
+    /// Start dropping low priority messages at this percentage of the limit.
+    pub drop_low_priority_percent: u8,
+    /// Start evicting third party chains at this percentage of the limit.
+    pub evict_third_party_percent: u8,
+}
+
+impl MemoryLimits {
+    /// Check that the percentages given are sensible before using them.
+    pub fn new(
+        limit: u64,
+        drop_low_priority_percent: u8,
+        evict_third_party_percent: u8,
+    ) -> anyhow::Result<MemoryLimits> {
+        for percent in [drop_low_priority_percent, evict_third_party_percent] {
+            if percent == 0 || percent > 100 {
+                anyhow::bail!("memory limit percentages must be between 1 and 100, not {percent}");
+            }
+        }
+        if drop_low_priority_percent > evict_third_party_percent {
+            anyhow::bail!(
+                "low priority messages must be dropped ({drop_low_priority_percent}%) before third party chains are evicted ({evict_third_party_percent}%)"
+            );
+        }
+        Ok(MemoryLimits {
+            limit,
+            drop_low_priority_percent,
+            evict_third_party_percent,
+        })
+    }
+
+    /// How much load should we shed, given how much memory we're using and how much load
+    /// we're shedding already?
+    fn pressure(&self, current: MemoryPressure, resident_bytes: u64) -> MemoryPressure {
+        let pressure = self.pressure_at(resident_bytes);
+        if pressure >= current {
+            return pressure;
+        }
+
+        // Memory use has dropped, but only shed less load once it's dropped well below the
+        // point at which we started shedding it:
+        let hysteresis = self.percent_of_limit(HYSTERESIS_PERCENT);
+        self.pressure_at(resident_bytes.saturating_add(hysteresis))
+            .min(current)
+    }
+
+    fn pressure_at(&self, resident_bytes: u64) -> MemoryPressure {
+        if resident_bytes >= self.percent_of_limit(self.evict_third_party_percent) {
+            MemoryPressure::EvictThirdPartyChains
+        } else if resident_bytes >= self.percent_of_limit(self.drop_low_priority_percent) {
+            MemoryPressure::DropLowPriority
+        } else {
+            MemoryPressure::Normal
+        }
+    }
+
+    fn percent_of_limit(&self, percent: u8) -> u64 {
+        (self.limit as u128 * percent as u128 / 100) as u64
+    }
+}
+
+/// Keeps track of how much memory we're using, and how much load to shed because of it.
+#[derive(Debug, Default)]
+pub struct MemoryMonitor {
+    resident_bytes: AtomicU64,
+    pressure: AtomicU8,
+}
+
+impl MemoryMonitor {
+    /// Start monitoring our memory use. If limits are given, we start shedding load as we
+    /// get close to them. Otherwise, we only keep track of memory use for our metrics.
+    pub fn spawn(limits: Option<MemoryLimits>) -> Arc<MemoryMonitor> {
+        let monitor = Arc::new(MemoryMonitor::default());
+
+        let monitor2 = Arc::clone(&monitor);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                let resident_bytes = match resident_bytes() {
+                    Some(resident_bytes) => resident_bytes,
+                    None => continue,
+                };
+                monitor2
+                    .resident_bytes
+                    .store(resident_bytes, Ordering::Relaxed);
+
+                if let Some(limits) = &limits {
+                    monitor2.update_pressure(
+                        limits.pressure(monitor2.pressure(), resident_bytes),
+                        resident_bytes,
+                        limits,
+                    );
+                }
+            }
+        });
+
+        monitor
+    }
+
+    /// How much memory (in bytes) we were using when we last checked.
+    pub fn resident_bytes(&self) -> u64 {
+        self.resident_bytes.load(Ordering::Relaxed)
+    }
+
+    /// How much load we should be shedding.
+    pub fn pressure(&self) -> MemoryPressure {
+        MemoryPressure::from_u8(self.pressure.load(Ordering::Relaxed))
+    }
+
+    fn update_pressure(
+        &self,
+        pressure: MemoryPressure,
+        resident_bytes: u64,
+        limits: &MemoryLimits,
+    ) {
+        let old_pressure =
+            MemoryPressure::from_u8(self.pressure.swap(pressure.as_u8(), Ordering::Relaxed));
+        if old_pressure == pressure {
+            return;
+        }
+
+        let limit = limits.limit;
+        match pressure {
+            MemoryPressure::Normal => log::warn!(
+                "Using {resident_bytes} bytes of memory (limit: {limit}); no longer shedding load"
+            ),
+            MemoryPressure::DropLowPriority => log::error!(
+                "MEMORY PRESSURE: Using {resident_bytes} bytes of memory (limit: {limit}); dropping low priority messages"
+            ),
+            MemoryPressure::EvictThirdPartyChains => log::error!(
+                "MEMORY PRESSURE: Using {resident_bytes} bytes of memory (limit: {limit}); dropping low priority messages and evicting third party chains"
+            ),
+        }
+    }
+}
+
+/// How many bytes of memory jemalloc has mapped in physically resident pages.
+#[cfg(not(target_env = "msvc"))]
+fn resident_bytes() -> Option<u64> {
+    // The stats are cached, and only refreshed when the epoch is advanced:
+    if let Err(e) = jemalloc_ctl::epoch::advance() {
+        log::error!("Failed to refresh memory stats: {e}");
+        return None;
+    }
+    match jemalloc_ctl::stats::resident::read() {
+        Ok(bytes) => Some(bytes as u64),
+        Err(e) => {
+            log::error!("Failed to read memory stats: {e}");
+            None
+        }
+    }
+}
+
+/// We don't use jemalloc on this platform, so we don't know how much memory we're using.
+#[cfg(target_env = "msvc")]
+fn resident_bytes() -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn limits() -> MemoryLimits {
+        MemoryLimits::new(1000, 80, 90).unwrap()
+    }
+
+    #[test]
+    fn pressure_increases_with_memory_use() {
+        let limits = limits();
+        let pressure = |resident_bytes| limits.pressure(MemoryPressure::Normal, resident_bytes);
+
+        assert_eq!(pressure(0), MemoryPressure::Normal);
+        assert_eq!(pressure(799), MemoryPressure::Normal);
+        assert_eq!(pressure(800), MemoryPressure::DropLowPriority);
+        assert_eq!(pressure(899), MemoryPressure::DropLowPriority);
+        assert_eq!(pressure(900), MemoryPressure::EvictThirdPartyChains);
+        assert_eq!(pressure(2000), MemoryPressure::EvictThirdPartyChains);
+    }
+
+    #[test]
+    fn pressure_only_drops_once_memory_use_is_well_below_where_it_rose() {
+        let limits = limits();
+        let evicting = MemoryPressure::EvictThirdPartyChains;
+        let dropping = MemoryPressure::DropLowPriority;
+
+        // Hovering just below where we started shedding load doesn't stop us shedding it:
+        assert_eq!(limits.pressure(evicting, 899), evicting);
+        assert_eq!(limits.pressure(evicting, 850), evicting);
+        assert_eq!(limits.pressure(dropping, 799), dropping);
+        assert_eq!(limits.pressure(dropping, 750), dropping);
+
+        // But dropping well below it does:
+        assert_eq!(limits.pressure(evicting, 849), dropping);
+        assert_eq!(limits.pressure(evicting, 749), MemoryPressure::Normal);
+        assert_eq!(limits.pressure(dropping, 749), MemoryPressure::Normal);
+
+        // And memory use rising again sheds more load straight away:
+        assert_eq!(limits.pressure(dropping, 900), evicting);
+    }
+
+    #[test]
+    fn limits_are_worked_out_without_losing_precision() {
+        let limits = MemoryLimits::new(199, 50, 100).unwrap();
+        let pressure = |resident_bytes| limits.pressure(MemoryPressure::Normal, resident_bytes);
+
+        // Dividing before multiplying would put these at 50 and 100 bytes:
+        assert_eq!(pressure(98), MemoryPressure::Normal);
+        assert_eq!(pressure(99), MemoryPressure::DropLowPriority);
+        assert_eq!(pressure(198), MemoryPressure::DropLowPriority);
+        assert_eq!(pressure(199), MemoryPressure::EvictThirdPartyChains);
+
+        // And huge limits don't overflow:
+        let limits = MemoryLimits::new(u64::MAX, 80, 90).unwrap();
+        let pressure = |resident_bytes| limits.pressure(MemoryPressure::Normal, resident_bytes);
+        assert_eq!(pressure(u64::MAX / 2), MemoryPressure::Normal);
+        assert_eq!(pressure(u64::MAX), MemoryPressure::EvictThirdPartyChains);
+    }
+
+    #[test]
+    fn percentages_out_of_range_are_refused() {
+        assert!(MemoryLimits::new(1000, 0, 90).is_err());
+        assert!(MemoryLimits::new(1000, 80, 101).is_err());
+        assert!(MemoryLimits::new(1000, 200, 90).is_err());
+        assert!(MemoryLimits::new(1000, 95, 90).is_err());
+        assert!(MemoryLimits::new(1000, 90, 90).is_ok());
+        assert!(MemoryLimits::new(1000, 100, 100).is_ok());
+    }
+
+    #[test]
+    fn pressure_round_trips() {
+        for pressure in [
+            MemoryPressure::Normal,
+            MemoryPressure::DropLowPriority,
+            MemoryPressure::EvictThirdPartyChains,
+        ] {
+            assert_eq!(MemoryPressure::from_u8(pressure.as_u8()), pressure);
+        }
+    }
+}
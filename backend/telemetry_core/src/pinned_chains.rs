@@ -0,0 +1,63 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Chains which are always listed to feeds, even before any nodes have connected to them and
+//! after the last node has gone, so that dashboards can always show the networks they know about.
+//!
+//! If the core is started with `--pinned-chains <file>`, that file should contain a JSON object
+//! mapping the genesis hash of each pinned chain to the label to show for it, for example:
+//!
+//! ```text
+//! {"0x91b171bb158e2d3848fa23a9f1c25182fb8e20313b2c1eb49219da7a70ce90c3":"Polkadot"}
+//! ```
+
+use anyhow::Context;
+use common::node_types::BlockHash;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Load the genesis hash and label of each pinned chain from the JSON file given.
+pub fn load(path: &Path) -> anyhow::Result<Vec<(BlockHash, String)>> {
+    let bytes = std::fs::read(path)
+        .with_context(|| format!("Could not read pinned chains file {path:?}"))?;
+    parse(&bytes).with_context(|| format!("Could not parse pinned chains file {path:?}"))
+}
+
+fn parse(bytes: &[u8]) -> anyhow::Result<Vec<(BlockHash, String)>> {
+    let pinned: BTreeMap<BlockHash, String> = serde_json::from_slice(bytes)?;
+    Ok(pinned.into_iter().collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pinned_chains_are_parsed() {
+        let hash = BlockHash::from_low_u64_be(1);
+        let json = format!(r#"{{"{hash:#x}":"Polkadot"}}"#);
+
+        assert_eq!(
+            parse(json.as_bytes()).unwrap(),
+            vec![(hash, "Polkadot".to_owned())]
+        );
+    }
+
+    #[test]
+    fn pinned_chains_must_be_keyed_by_genesis_hash() {
+        assert!(parse(br#"{"polkadot":"Polkadot"}"#).is_err());
+    }
+}
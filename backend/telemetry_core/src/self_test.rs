@@ -0,0 +1,95 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! A self-test, to check that a freshly started core is working properly. We connect to our
+//! own '/shard_submit' endpoint as though we were a shard, tell the core about a synthetic
+//! node, and then make sure that it shows up on our own '/feed/v2' endpoint.
+
+use bincode::Options;
+use common::internal_messages::{FromShardAggregator, ShardNodeId};
+use common::node_types::{BlockHash, NodeDetails};
+use common::ws_client;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use tokio::time::{Duration, Instant};
+
+/// The name of the synthetic node that we look for on the feed.
+const NODE_NAME: &str = "Telemetry Core Self-Test";
+
+/// Run the self-test against a core listening on the address given, failing if the
+/// synthetic node doesn't show up on the feed before the timeout.
+pub async fn run(socket_addr: SocketAddr, timeout: Duration) -> anyhow::Result<()> {
+    let deadline = Instant::now() + timeout;
+    let addr = local_addr(socket_addr);
+    let genesis_hash = BlockHash::repeat_byte(0x5e);
+
+    let shard_uri: http::Uri = format!("ws://{addr}/shard_submit?version=self-test").parse()?;
+    let (shard_tx, _shard_rx) = common::self_test::connect(&shard_uri, deadline).await?;
+    let add_node = FromShardAggregator::AddNode {
+        ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+        node: node_details(),
+        local_id: ShardNodeId::new(0),
+        genesis_hash,
+    };
+    let bytes = bincode::options()
+        .serialize(&add_node)
+        .expect("internal messages must be serializable");
+    shard_tx.unbounded_send(ws_client::SentMessage::Binary(bytes))?;
+
+    let feed_uri: http::Uri = format!("ws://{addr}/feed/v2").parse()?;
+    common::self_test::wait_for_node_on_feed(&feed_uri, genesis_hash, NODE_NAME, deadline).await
+}
+
+/// If we're listening on every interface, connect to ourselves via localhost.
+fn local_addr(socket_addr: SocketAddr) -> SocketAddr {
+    match socket_addr.ip() {
+        ip if ip.is_unspecified() => {
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), socket_addr.port())
+        }
+        _ => socket_addr,
+    }
+}
+
+fn node_details() -> NodeDetails {
+    NodeDetails {
+        chain: "Self-Test".into(),
+        name: NODE_NAME.into(),
+        implementation: "telemetry_core".into(),
+        version: env!("CARGO_PKG_VERSION").into(),
+        validator: None,
+        network_id: Default::default(),
+        startup_time: None,
+        target_os: None,
+        target_arch: None,
+        target_env: None,
+        sysinfo: None,
+        ip: None,
+        shard: None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn connects_to_localhost_if_listening_everywhere() {
+        let addr: SocketAddr = "0.0.0.0:8000".parse().unwrap();
+        assert_eq!(local_addr(addr), "127.0.0.1:8000".parse().unwrap());
+
+        let addr: SocketAddr = "10.0.0.1:8000".parse().unwrap();
+        assert_eq!(local_addr(addr), addr);
+    }
+}
@@ -16,19 +16,23 @@
 
 use common::node_message::Payload;
 use common::node_types::BlockHash;
-use common::node_types::{Block, Timestamp};
+use common::node_types::{Block, BlockNumber, NodeDetails, Timestamp};
 use common::{id_type, time, DenseMap, MostSeen, NumStats};
 use once_cell::sync::Lazy;
-use std::collections::HashSet;
+use std::collections::{HashSet, VecDeque};
 use std::str::FromStr;
 use std::time::{Duration, Instant};
 
-use crate::feed_message::{self, ChainStats, FeedMessageSerializer};
+use crate::feed_message::{
+    self, ChainStats, DatabaseSizeStats, FeedMessageSerializer, LogCountStats,
+};
 use crate::find_location;
+use crate::uptime::Uptime;
 
-use super::chain_stats::ChainStatsCollator;
+use super::chain_stats::{ChainStatsCollator, PeerCountHistogram};
 use super::counter::CounterValue;
-use super::node::Node;
+use super::node::{Node, PendingNodeUpdates};
+use super::quality_score::QualityScoreWeights;
 
 id_type! {
     /// A Node ID that is unique to the chain it's in.
@@ -37,23 +41,73 @@ id_type! {
 
 pub type Label = Box<str>;
 
-const STALE_TIMEOUT: u64 = 2 * 60 * 1000; // 2 minutes
-const STATS_UPDATE_INTERVAL: Duration = Duration::from_secs(5);
+/// Nodes whose finality lag is greater than this many blocks are counted in the chain stats.
+const EXCESSIVE_FINALITY_LAG: BlockNumber = 20;
+
+/// Options that every chain is created with.
+#[derive(Debug, Clone, Copy)]
+pub struct ChainOptions {
+    /// If set, best block feed messages are sent at most once per this interval.
+    pub best_block_coalesce_interval: Option<Duration>,
+    /// If set, each node is given a quality score, calculated using these weights.
+    pub quality_score_weights: Option<QualityScoreWeights>,
+    /// How many recent best and finalized block events are kept hold of, to replay
+    /// to feeds that subscribe to the chain.
+    pub max_recent_blocks: usize,
+    /// If set, feed messages about changes to a node's hardware, stats and IO are
+    /// sent at most once per this interval for each node.
+    pub node_update_interval: Option<Duration>,
+    /// If set, imported block feed messages are sent at most once per this interval
+    /// for each node.
+    pub imported_block_interval: Option<Duration>,
+    /// Nodes that haven't had a new best block in this long are marked as stale.
+    pub stale_timeout: Duration,
+    /// The chain stats are regenerated at most once per this interval.
+    pub stats_interval: Duration,
+}
+
+/// The time at which something happens to a chain: a monotonic instant to measure the
+/// intervals that we hold feed messages back for against, and the unix timestamp (in ms)
+/// that block times and staleness are worked out from.
+#[derive(Debug, Clone, Copy)]
+pub struct Now {
+    pub instant: Instant,
+    pub timestamp: Timestamp,
+}
+
+impl Now {
+    /// The current time.
+    pub fn current() -> Now {
+        Now {
+            instant: Instant::now(),
+            timestamp: time::now(),
+        }
+    }
+}
 
 pub struct Chain {
     /// Labels that nodes use for this chain. We keep track of
     /// the most commonly used label as nodes are added/removed.
     labels: MostSeen<Label>,
+    /// If set, the chain has been pinned, and always uses this label rather than
+    /// the one that nodes use most.
+    pinned_label: Option<Label>,
     /// Set of nodes that are in this chain
     nodes: DenseMap<ChainNodeId, Node>,
     /// Best block
     best: Block,
     /// Finalized block
     finalized: Block,
+    /// The highest block that any node has claimed to have imported, whether
+    /// or not it's been adopted as the best block. This is only brought back
+    /// down when the best block is recalculated after the chain goes stale.
+    max_claimed_height: BlockNumber,
     /// Block times history, stored so we can calculate averages
     block_times: NumStats<u64>,
     /// Calculated average block time
     average_block_time: Option<u64>,
+    /// Calculated median and 95th percentile block times
+    block_time_percentiles: Option<(u64, u64)>,
     /// When the best block first arrived
     timestamp: Option<Timestamp>,
     /// Genesis hash of this chain
@@ -66,6 +120,44 @@ pub struct Chain {
     stats: ChainStats,
     /// Timestamp of when the stats were last regenerated.
     stats_last_regenerated: Instant,
+    /// How many nodes have a peer count within each bucket.
+    peer_counts: PeerCountHistogram,
+    /// Has a node been added or removed, or reported a new peer count, since we last
+    /// regenerated the stats?
+    peer_counts_changed: bool,
+    /// The peer count histogram that we last sent to feeds.
+    peer_count_histogram: Vec<((u64, Option<u64>), u64)>,
+    /// If set, `BestBlock` feed messages are sent at most once per this interval.
+    best_block_coalesce_interval: Option<Duration>,
+    /// When we last sent out a `BestBlock` feed message.
+    best_block_last_broadcast: Option<Instant>,
+    /// Has a new best block been held back while coalescing?
+    best_block_broadcast_pending: bool,
+    /// If set, each node is given a quality score, calculated using these weights.
+    quality_score_weights: Option<QualityScoreWeights>,
+    /// The most recent best and finalized block events, oldest first, which are
+    /// replayed to feeds when they subscribe to this chain.
+    recent_blocks: VecDeque<RecentBlock>,
+    /// How many events we keep in `recent_blocks`.
+    max_recent_blocks: usize,
+    /// If set, feed messages about changes to a node's hardware, stats and IO are
+    /// sent at most once per this interval for each node.
+    node_update_interval: Option<Duration>,
+    /// If set, `ImportedBlock` feed messages are sent at most once per this interval
+    /// for each node.
+    imported_block_interval: Option<Duration>,
+    /// Nodes that haven't had a new best block in this long are marked as stale.
+    stale_timeout: Duration,
+    /// The chain stats are regenerated at most once per this interval.
+    stats_interval: Duration,
+    /// Nodes with changes that have been held back, to be sent once the interval passes.
+    throttled_nodes: HashSet<ChainNodeId>,
+}
+
+/// A best or finalized block event that we keep hold of for a while.
+enum RecentBlock {
+    Best(BlockNumber, Timestamp, Option<u64>),
+    Finalized(BlockNumber, BlockHash),
 }
 
 pub enum AddNodeResult {
@@ -80,6 +172,52 @@ pub struct RemoveNodeResult {
     pub chain_renamed: bool,
 }
 
+/// Push feed messages for any changes to a node's hardware, stats and IO that feeds haven't been
+/// told about, unless they're being held back because they were told about some less than
+/// `min_interval` ago. Returns true if any messages were pushed.
+fn push_pending_node_updates(
+    nid: ChainNodeId,
+    node: &mut Node,
+    now: Instant,
+    min_interval: Option<Duration>,
+    feed: &mut FeedMessageSerializer,
+) -> bool {
+    let updates = match node.take_pending_updates(now, min_interval) {
+        Some(updates) => updates,
+        None => return false,
+    };
+
+    if updates.hardware {
+        feed.push(feed_message::Hardware(nid.into(), node.hardware()));
+    }
+    if updates.stats {
+        feed.push(feed_message::NodeStatsUpdate(nid.into(), node.stats()));
+    }
+    if updates.io {
+        feed.push(feed_message::NodeIOUpdate(nid.into(), node.io()));
+    }
+    true
+}
+
+/// Push an `ImportedBlock` feed message for the latest block that a node imported, if feeds
+/// haven't been told about it, unless it's being held back because they were told about one
+/// less than `min_interval` ago. Returns true if a message was pushed.
+fn push_pending_imported_block(
+    nid: ChainNodeId,
+    node: &mut Node,
+    now: Instant,
+    min_interval: Option<Duration>,
+    feed: &mut FeedMessageSerializer,
+) -> bool {
+    match node.take_pending_imported_block(now, min_interval) {
+        Some(details) => {
+            feed.push(feed_message::ImportedBlock(nid.into(), details));
+            true
+        }
+        None => false,
+    }
+}
+
 /// Genesis hashes of chains we consider "first party". These chains allow any
 /// number of nodes to connect.
 static FIRST_PARTY_NETWORKS: Lazy<HashSet<BlockHash>> = Lazy::new(|| {
@@ -104,20 +242,46 @@ pub fn is_first_party_network(genesis_hash: &BlockHash) -> bool {
 
 impl Chain {
     /// Create a new chain with an initial label.
-    pub fn new(genesis_hash: BlockHash, max_nodes: usize) -> Self {
+    pub fn new(genesis_hash: BlockHash, max_nodes: usize, opts: ChainOptions) -> Self {
+        let ChainOptions {
+            best_block_coalesce_interval,
+            quality_score_weights,
+            max_recent_blocks,
+            node_update_interval,
+            imported_block_interval,
+            stale_timeout,
+            stats_interval,
+        } = opts;
         Chain {
             labels: MostSeen::default(),
+            pinned_label: None,
             nodes: DenseMap::new(),
             best: Block::zero(),
             finalized: Block::zero(),
+            max_claimed_height: 0,
             block_times: NumStats::new(50),
             average_block_time: None,
+            block_time_percentiles: None,
             timestamp: None,
             genesis_hash,
             max_nodes,
             stats_collator: Default::default(),
             stats: Default::default(),
             stats_last_regenerated: Instant::now(),
+            peer_counts: PeerCountHistogram::default(),
+            peer_counts_changed: false,
+            peer_count_histogram: Vec::new(),
+            best_block_coalesce_interval,
+            best_block_last_broadcast: None,
+            best_block_broadcast_pending: false,
+            quality_score_weights,
+            recent_blocks: VecDeque::with_capacity(max_recent_blocks),
+            max_recent_blocks,
+            node_update_interval,
+            imported_block_interval,
+            stale_timeout,
+            stats_interval,
+            throttled_nodes: HashSet::new(),
         }
     }
 
@@ -138,16 +302,19 @@ impl Chain {
 
         let node_chain_label = &details.chain;
         let label_result = self.labels.insert(node_chain_label);
+        self.peer_counts.add(node.stats().peers);
+        self.peer_counts_changed = true;
         let node_id = self.nodes.add(node);
 
         AddNodeResult::Added {
             id: node_id,
-            chain_renamed: label_result.has_changed(),
+            chain_renamed: label_result.has_changed() && !self.is_pinned(),
         }
     }
 
     /// Remove a node from this chain.
     pub fn remove_node(&mut self, node_id: ChainNodeId) -> RemoveNodeResult {
+        self.throttled_nodes.remove(&node_id);
         let node = match self.nodes.remove(node_id) {
             Some(node) => node,
             None => {
@@ -163,9 +330,11 @@ impl Chain {
 
         let node_chain_label = &node.details().chain;
         let label_result = self.labels.remove(node_chain_label);
+        self.peer_counts.remove(node.stats().peers);
+        self.peer_counts_changed = true;
 
         RemoveNodeResult {
-            chain_renamed: label_result.has_changed(),
+            chain_renamed: label_result.has_changed() && !self.is_pinned(),
         }
     }
 
@@ -176,23 +345,77 @@ impl Chain {
         payload: Payload,
         feed: &mut FeedMessageSerializer,
         expose_node_details: bool,
+        now: Now,
     ) {
         if let Some(block) = payload.best_block() {
-            self.handle_block(block, nid, feed);
+            self.handle_block(block, nid, feed, now);
         }
 
+        self.update_node_inner(nid, payload, feed, expose_node_details, now);
+
+        // Having updated the node, see whether its quality score has changed much:
+        let average_block_time = self.average_block_time;
+        if let Some(node) = self.nodes.get_mut(nid) {
+            if let Some(weights) = &self.quality_score_weights {
+                let score =
+                    weights.score(node, now.timestamp, average_block_time, self.stale_timeout);
+                if let Some(score) = node.update_quality_score(score) {
+                    feed.push(feed_message::NodeQualityScore(nid.into(), score));
+                }
+            }
+            if let Some(lag) = node.update_finality_lag() {
+                feed.push(feed_message::NodeFinalityLag(nid.into(), lag));
+            }
+        }
+    }
+
+    /// Update the details of a node given some payload, pushing feed messages for anything that changes.
+    fn update_node_inner(
+        &mut self,
+        nid: ChainNodeId,
+        payload: Payload,
+        feed: &mut FeedMessageSerializer,
+        expose_node_details: bool,
+        now: Now,
+    ) {
         if let Some(node) = self.nodes.get_mut(nid) {
             match payload {
                 Payload::SystemInterval(ref interval) => {
-                    // Send a feed message if any of the relevant node details change:
-                    if node.update_hardware(interval) {
-                        feed.push(feed_message::Hardware(nid.into(), node.hardware()));
+                    // Send a feed message if any of the relevant node details change, unless
+                    // we sent one for this node too recently, in which case the latest details
+                    // are sent once `node_update_interval` has passed:
+                    let old_peers = node.stats().peers;
+                    let updates = PendingNodeUpdates {
+                        hardware: node.update_hardware(interval),
+                        stats: node.update_stats(interval).is_some(),
+                        io: node.update_io(interval).is_some(),
+                    };
+                    if node.stats().peers != old_peers {
+                        self.peer_counts.remove(old_peers);
+                        self.peer_counts.add(node.stats().peers);
+                        self.peer_counts_changed = true;
+                    }
+                    node.add_pending_updates(updates);
+                    push_pending_node_updates(
+                        nid,
+                        node,
+                        now.instant,
+                        self.node_update_interval,
+                        feed,
+                    );
+                    if node.has_pending_updates() {
+                        self.throttled_nodes.insert(nid);
                     }
-                    if let Some(stats) = node.update_stats(interval) {
-                        feed.push(feed_message::NodeStatsUpdate(nid.into(), stats));
+                    node.update_database_size(interval);
+                    if let Some(counts) = node.update_log_counts(interval) {
+                        feed.push(feed_message::NodeLogCountsUpdate(
+                            nid.into(),
+                            counts.errors,
+                            counts.warnings,
+                        ));
                     }
-                    if let Some(io) = node.update_io(interval) {
-                        feed.push(feed_message::NodeIOUpdate(nid.into(), io));
+                    if let Some(syncing) = node.update_major_syncing(interval) {
+                        feed.push(feed_message::NodeSyncState(nid.into(), syncing));
                     }
                 }
                 Payload::AfgAuthoritySet(authority) => {
@@ -235,7 +458,8 @@ impl Chain {
             }
 
             if let Some(block) = payload.finalized_block() {
-                if let Some(finalized) = node.update_finalized(block) {
+                // Copy the block out so that we're done with the node before updating the chain:
+                if let Some(finalized) = node.update_finalized(block).copied() {
                     feed.push(feed_message::FinalizedBlock(
                         nid.into(),
                         finalized.height,
@@ -243,24 +467,35 @@ impl Chain {
                     ));
 
                     if finalized.height > self.finalized.height {
-                        self.finalized = *finalized;
+                        self.finalized = finalized;
                         feed.push(feed_message::BestFinalized(
                             finalized.height,
                             finalized.hash,
                         ));
+                        self.push_recent_block(RecentBlock::Finalized(
+                            finalized.height,
+                            finalized.hash,
+                        ));
                     }
                 }
             }
         }
     }
 
-    fn handle_block(&mut self, block: &Block, nid: ChainNodeId, feed: &mut FeedMessageSerializer) {
+    fn handle_block(
+        &mut self,
+        block: &Block,
+        nid: ChainNodeId,
+        feed: &mut FeedMessageSerializer,
+        now: Now,
+    ) {
         let mut propagation_time = None;
-        let now = time::now();
+        let instant = now.instant;
+        let now = now.timestamp;
         let nodes_len = self.nodes.len();
 
         self.update_stale_nodes(now, feed);
-        self.regenerate_stats_if_necessary(feed);
+        self.regenerate_stats_if_necessary(feed, instant);
 
         let node = match self.nodes.get_mut(nid) {
             Some(node) => node,
@@ -268,6 +503,11 @@ impl Chain {
         };
 
         if node.update_block(*block) {
+            if block.height > self.max_claimed_height {
+                self.max_claimed_height = block.height;
+                feed.push(feed_message::MaxClaimedBlock(self.max_claimed_height));
+            }
+
             if block.height > self.best.height {
                 self.best = *block;
                 log::debug!(
@@ -280,13 +520,28 @@ impl Chain {
                 if let Some(timestamp) = self.timestamp {
                     self.block_times.push(now.saturating_sub(timestamp));
                     self.average_block_time = Some(self.block_times.average());
+                    let [median, p95] = self.block_times.percentiles([50.0, 95.0]);
+                    self.block_time_percentiles = Some((median, p95));
+                    feed.push(feed_message::BlockTimePercentiles(median, p95));
                 }
                 self.timestamp = Some(now);
-                feed.push(feed_message::BestBlock(
+                // Unlike `timestamp`, this isn't wound back when stale nodes are ignored in
+                // finding the best block. It's kept up to date in the stats that we hand out,
+                // but isn't a change worth sending the stats to feeds again for by itself:
+                self.stats.last_block_at = Some(now);
+                self.push_recent_block(RecentBlock::Best(
                     self.best.height,
                     now,
                     self.average_block_time,
                 ));
+                self.best_block_broadcast_pending = true;
+                self.flush_coalesced_best_block(
+                    feed,
+                    Now {
+                        instant,
+                        timestamp: now,
+                    },
+                );
                 propagation_time = Some(0);
             } else if block.height == self.best.height {
                 if let Some(timestamp) = self.timestamp {
@@ -294,8 +549,17 @@ impl Chain {
                 }
             }
 
-            if let Some(details) = node.update_details(now, propagation_time) {
-                feed.push(feed_message::ImportedBlock(nid.into(), details));
+            // Flushing the best block needs all of `self`, so look the node up again:
+            let node = self.nodes.get_mut(nid).expect("node exists; checked above");
+            // Tell feeds about the imported block, unless we told them about one for this node
+            // too recently, in which case the latest is sent once `imported_block_interval`
+            // has passed:
+            if node.update_details(now, propagation_time).is_some() {
+                node.add_pending_imported_block();
+                push_pending_imported_block(nid, node, instant, self.imported_block_interval, feed);
+                if node.has_pending_updates() {
+                    self.throttled_nodes.insert(nid);
+                }
             }
         }
     }
@@ -303,7 +567,7 @@ impl Chain {
     /// Check if the chain is stale (has not received a new best block in a while).
     /// If so, find a new best block, ignoring any stale nodes and marking them as such.
     fn update_stale_nodes(&mut self, now: u64, feed: &mut FeedMessageSerializer) {
-        let threshold = now - STALE_TIMEOUT;
+        let threshold = now.saturating_sub(self.stale_timeout.as_millis() as u64);
         let timestamp = match self.timestamp {
             Some(ts) => ts,
             None => return,
@@ -317,8 +581,12 @@ impl Chain {
         let mut best = Block::zero();
         let mut finalized = Block::zero();
         let mut timestamp = None;
+        let mut max_claimed_height = 0;
 
         for (nid, node) in self.nodes.iter_mut() {
+            // Stale nodes still count towards the highest claimed block:
+            max_claimed_height = max_claimed_height.max(node.best().height);
+
             if !node.update_stale(threshold) {
                 if node.best().height > best.height {
                     best = *node.best();
@@ -338,6 +606,15 @@ impl Chain {
             self.finalized = finalized;
             self.block_times.reset();
             self.timestamp = timestamp;
+            self.best_block_broadcast_pending = false;
+
+            // Forget any recent blocks beyond the ones we've wound back to, so that they
+            // aren't replayed to feeds as though they were still the latest:
+            let (best_height, finalized_height) = (best.height, finalized.height);
+            self.recent_blocks.retain(|event| match *event {
+                RecentBlock::Best(height, ..) => height <= best_height,
+                RecentBlock::Finalized(height, _) => height <= finalized_height,
+            });
 
             feed.push(feed_message::BestBlock(
                 self.best.height,
@@ -348,22 +625,145 @@ impl Chain {
                 finalized.height,
                 finalized.hash,
             ));
+
+            if max_claimed_height != self.max_claimed_height {
+                self.max_claimed_height = max_claimed_height;
+                feed.push(feed_message::MaxClaimedBlock(max_claimed_height));
+            }
+        }
+    }
+
+    /// Push feed messages for any nodes whose changes were held back and are now due to be
+    /// sent. Returns true if any messages were pushed.
+    pub fn flush_throttled_node_updates(
+        &mut self,
+        feed: &mut FeedMessageSerializer,
+        now: Instant,
+    ) -> bool {
+        let node_update_interval = self.node_update_interval;
+        let imported_block_interval = self.imported_block_interval;
+        let nodes = &mut self.nodes;
+        let mut pushed = false;
+
+        self.throttled_nodes
+            .retain(|&nid| match nodes.get_mut(nid) {
+                Some(node) => {
+                    pushed |= push_pending_node_updates(nid, node, now, node_update_interval, feed);
+                    pushed |=
+                        push_pending_imported_block(nid, node, now, imported_block_interval, feed);
+                    node.has_pending_updates()
+                }
+                None => false,
+            });
+        pushed
+    }
+
+    /// If a new best block has not yet been broadcast, push a `BestBlock` feed message
+    /// for it, unless we're coalescing best blocks and sent one out too recently.
+    /// Returns true if a message was pushed.
+    pub fn flush_coalesced_best_block(
+        &mut self,
+        feed: &mut FeedMessageSerializer,
+        now: Now,
+    ) -> bool {
+        if !self.best_block_broadcast_pending {
+            return false;
+        }
+
+        if let (Some(interval), Some(last)) = (
+            self.best_block_coalesce_interval,
+            self.best_block_last_broadcast,
+        ) {
+            if now.instant - last < interval {
+                return false;
+            }
+        }
+
+        self.best_block_broadcast_pending = false;
+        self.best_block_last_broadcast = Some(now.instant);
+        feed.push(feed_message::BestBlock(
+            self.best.height,
+            self.timestamp.unwrap_or(now.timestamp),
+            self.average_block_time,
+        ));
+        true
+    }
+
+    /// Remember a best or finalized block event, forgetting the oldest one if we're at capacity.
+    fn push_recent_block(&mut self, event: RecentBlock) {
+        if self.max_recent_blocks == 0 {
+            return;
+        }
+        if self.recent_blocks.len() >= self.max_recent_blocks {
+            self.recent_blocks.pop_front();
+        }
+        self.recent_blocks.push_back(event);
+    }
+
+    /// Push `BestBlock` and `BestFinalized` feed messages for the recent block events
+    /// we've kept hold of, oldest first.
+    pub fn write_recent_blocks(&self, feed: &mut FeedMessageSerializer) {
+        for event in &self.recent_blocks {
+            match *event {
+                RecentBlock::Best(height, timestamp, average_block_time) => {
+                    feed.push(feed_message::BestBlock(
+                        height,
+                        timestamp,
+                        average_block_time,
+                    ));
+                }
+                RecentBlock::Finalized(height, hash) => {
+                    feed.push(feed_message::BestFinalized(height, hash));
+                }
+            }
         }
     }
 
-    fn regenerate_stats_if_necessary(&mut self, feed: &mut FeedMessageSerializer) {
-        let now = Instant::now();
+    fn regenerate_stats_if_necessary(&mut self, feed: &mut FeedMessageSerializer, now: Instant) {
         let elapsed = now - self.stats_last_regenerated;
-        if elapsed < STATS_UPDATE_INTERVAL {
+        if elapsed < self.stats_interval {
             return;
         }
 
         self.stats_last_regenerated = now;
-        let new_stats = self.stats_collator.generate();
+        let mut new_stats = self.stats_collator.generate();
+        new_stats.database_size = DatabaseSizeStats::from_sizes(
+            self.nodes
+                .iter()
+                .filter_map(|(_, node)| node.database_size()),
+        );
+        new_stats.log_counts =
+            LogCountStats::from_counts(self.nodes.iter().map(|(_, node)| node.log_counts()));
+        new_stats.nodes_with_excessive_finality_lag = self
+            .nodes
+            .iter()
+            .filter(|(_, node)| !node.stale())
+            .filter(|(_, node)| matches!(node.finality_lag(), Some(lag) if lag > EXCESSIVE_FINALITY_LAG))
+            .count() as u64;
+        new_stats.last_block_at = self.stats.last_block_at;
         if new_stats != self.stats {
             self.stats = new_stats;
             feed.push(feed_message::ChainStatsUpdate(&self.stats));
         }
+
+        if !std::mem::take(&mut self.peer_counts_changed) {
+            return;
+        }
+        let peer_count_histogram = self.peer_counts.buckets();
+        if peer_count_histogram != self.peer_count_histogram {
+            self.peer_count_histogram = peer_count_histogram;
+            feed.push(feed_message::PeerCountHistogram(
+                self.genesis_hash,
+                &self.peer_count_histogram,
+            ));
+        }
+    }
+
+    pub fn update_node_details(&mut self, node_id: ChainNodeId, details: NodeDetails) -> bool {
+        match self.nodes.get_mut(node_id) {
+            Some(node) => node.set_details(details),
+            None => false,
+        }
     }
 
     pub fn update_node_location(
@@ -379,6 +779,21 @@ impl Chain {
         }
     }
 
+    pub fn update_node_uptime(
+        &mut self,
+        node_id: ChainNodeId,
+        network_id: &str,
+        uptime: Uptime,
+    ) -> bool {
+        match self.nodes.get_mut(node_id) {
+            Some(node) if node.details().network_id.as_str() == network_id => {
+                node.update_uptime(uptime);
+                true
+            }
+            _ => false,
+        }
+    }
+
     pub fn get_node(&self, id: ChainNodeId) -> Option<&Node> {
         self.nodes.get(id)
     }
@@ -386,7 +801,17 @@ impl Chain {
         self.nodes.as_slice()
     }
     pub fn label(&self) -> &str {
-        &self.labels.best()
+        match &self.pinned_label {
+            Some(label) => label,
+            None => self.labels.best(),
+        }
+    }
+    /// Pin the chain, so that it always uses the label given.
+    pub fn pin(&mut self, label: Label) {
+        self.pinned_label = Some(label);
+    }
+    pub fn is_pinned(&self) -> bool {
+        self.pinned_label.is_some()
     }
     pub fn node_count(&self) -> usize {
         self.nodes.len()
@@ -400,13 +825,22 @@ impl Chain {
     pub fn average_block_time(&self) -> Option<u64> {
         self.average_block_time
     }
+    pub fn block_time_percentiles(&self) -> Option<(u64, u64)> {
+        self.block_time_percentiles
+    }
     pub fn finalized_block(&self) -> &Block {
         &self.finalized
     }
+    pub fn max_claimed_height(&self) -> BlockNumber {
+        self.max_claimed_height
+    }
     pub fn genesis_hash(&self) -> BlockHash {
         self.genesis_hash
     }
     pub fn stats(&self) -> &ChainStats {
         &self.stats
     }
+    pub fn peer_count_histogram(&self) -> &[((u64, Option<u64>), u64)] {
+        &self.peer_count_histogram
+    }
 }
@@ -99,6 +99,81 @@ fn bucket_memory(memory: u64) -> (u32, Option<u32>) {
     }
 }
 
+/// The lower bounds of the buckets that node peer counts are sorted into.
+const PEER_COUNT_BUCKETS: [u64; 7] = [0, 1, 5, 10, 25, 50, 100];
+
+/// How many nodes have a peer count within each peer count bucket. This is kept up to
+/// date as nodes come and go and report new peer counts, rather than being worked out
+/// from every node each time that it's needed.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PeerCountHistogram {
+    counts: [u64; PEER_COUNT_BUCKETS.len()],
+}
+
+impl PeerCountHistogram {
+    fn bucket(peers: u64) -> usize {
+        PEER_COUNT_BUCKETS
+            .iter()
+            .rposition(|&min| peers >= min)
+            .unwrap_or(0)
+    }
+
+    /// Count a node with the given number of peers.
+    pub fn add(&mut self, peers: u64) {
+        self.counts[Self::bucket(peers)] += 1;
+    }
+
+    /// Stop counting a node with the given number of peers.
+    pub fn remove(&mut self, peers: u64) {
+        let count = &mut self.counts[Self::bucket(peers)];
+        *count = count.saturating_sub(1);
+    }
+
+    /// Each bucket, given as its inclusive lower and exclusive upper bound, along with the
+    /// number of nodes in it. Every bucket is returned, in order, even if it's empty.
+    pub fn buckets(&self) -> Vec<((u64, Option<u64>), u64)> {
+        PEER_COUNT_BUCKETS
+            .iter()
+            .enumerate()
+            .map(|(idx, &min)| {
+                (
+                    (min, PEER_COUNT_BUCKETS.get(idx + 1).copied()),
+                    self.counts[idx],
+                )
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn test_peer_count_histogram() {
+    let mut histogram = PeerCountHistogram::default();
+    assert!(histogram.buckets().iter().all(|&(_, count)| count == 0));
+
+    for peers in [0, 1, 4, 5, 9, 30, 99, 100, 5000] {
+        histogram.add(peers);
+    }
+    assert_eq!(
+        histogram.buckets(),
+        vec![
+            ((0, Some(1)), 1),
+            ((1, Some(5)), 2),
+            ((5, Some(10)), 2),
+            ((10, Some(25)), 0),
+            ((25, Some(50)), 1),
+            ((50, Some(100)), 1),
+            ((100, None), 2),
+        ]
+    );
+
+    histogram.remove(4);
+    histogram.remove(5000);
+    histogram.add(12);
+    assert_eq!(histogram.buckets()[1], ((1, Some(5)), 1));
+    assert_eq!(histogram.buckets()[3], ((10, Some(25)), 1));
+    assert_eq!(histogram.buckets()[6], ((100, None), 1));
+}
+
 fn kernel_version_number(version: &Box<str>) -> &str {
     let index = version
         .find("-")
@@ -133,14 +208,35 @@ fn cpu_vendor(cpu: &Box<str>) -> &str {
     }
 }
 
+/// The architecture and operating system that a node was built for, in the same order that
+/// they appear in old style versions (eg 'aarch64-macos'), if we know both.
+fn target_platform(details: &common::node_types::NodeDetails) -> Option<String> {
+    let arch = details.target_arch.as_deref()?;
+    let os = details.target_os.as_deref()?;
+    Some(format!("{arch}-{os}"))
+}
+
+/// The largest integer that the frontend can represent exactly (`Number.MAX_SAFE_INTEGER`).
+const MAX_JS_SAFE_INTEGER: u64 = (1 << 53) - 1;
+
+/// Totals are sent to feeds as JSON numbers, which the frontend can't represent exactly
+/// beyond [`MAX_JS_SAFE_INTEGER`], so bigger totals are capped to it.
+fn cap_to_js_safe_integer(total: u128) -> u64 {
+    total.min(MAX_JS_SAFE_INTEGER as u128) as u64
+}
+
 #[derive(Default)]
 pub struct ChainStatsCollator {
     version: Counter<String>,
     target_os: Counter<String>,
     target_arch: Counter<String>,
+    target_platform: Counter<String>,
     cpu: Counter<String>,
     memory: Counter<(u32, Option<u32>)>,
     core_count: Counter<u32>,
+    // These are wide enough that they can't overflow, however much nodes claim to have:
+    total_core_count: u128,
+    total_memory: u128,
     linux_kernel: Counter<String>,
     linux_distro: Counter<String>,
     is_virtual_machine: Counter<bool>,
@@ -166,6 +262,9 @@ impl ChainStatsCollator {
         self.target_arch
             .modify(details.target_arch.as_ref().map(|value| &**value), op);
 
+        self.target_platform
+            .modify(target_platform(details).as_ref(), op);
+
         let sysinfo = details.sysinfo.as_ref();
         self.cpu.modify(
             sysinfo
@@ -180,6 +279,19 @@ impl ChainStatsCollator {
         self.core_count
             .modify(sysinfo.and_then(|sysinfo| sysinfo.core_count.as_ref()), op);
 
+        let core_count = sysinfo.and_then(|sysinfo| sysinfo.core_count).unwrap_or(0) as u128;
+        let memory = sysinfo.and_then(|sysinfo| sysinfo.memory).unwrap_or(0) as u128;
+        match op {
+            CounterValue::Increment => {
+                self.total_core_count += core_count;
+                self.total_memory += memory;
+            }
+            CounterValue::Decrement => {
+                self.total_core_count = self.total_core_count.saturating_sub(core_count);
+                self.total_memory = self.total_memory.saturating_sub(memory);
+            }
+        }
+
         self.linux_kernel.modify(
             sysinfo
                 .and_then(|sysinfo| sysinfo.linux_kernel.as_ref())
@@ -248,9 +360,12 @@ impl ChainStatsCollator {
             version: self.version.generate_ranking_top(10),
             target_os: self.target_os.generate_ranking_top(10),
             target_arch: self.target_arch.generate_ranking_top(10),
+            target_platform: self.target_platform.generate_ranking_top(10),
             cpu: self.cpu.generate_ranking_top(10),
             memory: self.memory.generate_ranking_ordered(),
             core_count: self.core_count.generate_ranking_top(10),
+            total_core_count: cap_to_js_safe_integer(self.total_core_count),
+            total_memory: cap_to_js_safe_integer(self.total_memory),
             linux_kernel: self.linux_kernel.generate_ranking_top(10),
             linux_distro: self.linux_distro.generate_ranking_top(10),
             is_virtual_machine: self.is_virtual_machine.generate_ranking_ordered(),
@@ -261,6 +376,159 @@ impl ChainStatsCollator {
                 .generate_ranking_ordered(),
             disk_random_write_score: self.disk_random_write_score.generate_ranking_ordered(),
             cpu_vendor: self.cpu_vendor.generate_ranking_top(10),
+            // This is calculated from the current state of each node rather than being collated:
+            database_size: None,
+            log_counts: Default::default(),
+            nodes_with_excessive_finality_lag: 0,
+            last_block_at: None,
         }
     }
 }
+
+#[test]
+fn test_target_platform_counts() {
+    use crate::feed_message::Ranking;
+    use common::node_types::{NetworkId, NodeDetails};
+
+    fn details(target: Option<(&str, &str)>) -> NodeDetails {
+        NodeDetails {
+            chain: "Chain".into(),
+            name: "Node".into(),
+            implementation: "Substrate Node".into(),
+            version: "0.9.17-75dd6c7d0".into(),
+            validator: None,
+            network_id: NetworkId::new(),
+            startup_time: None,
+            target_os: target.map(|(_, os)| os.into()),
+            target_arch: target.map(|(arch, _)| arch.into()),
+            target_env: None,
+            sysinfo: None,
+            ip: None,
+            shard: None,
+        }
+    }
+
+    let nodes = [
+        details(Some(("x86_64", "linux"))),
+        details(Some(("aarch64", "linux"))),
+        details(Some(("x86_64", "linux"))),
+        details(Some(("aarch64", "macos"))),
+        details(None),
+        details(Some(("x86_64", "linux"))),
+        details(Some(("aarch64", "linux"))),
+        details(Some(("x86_64", "linux"))),
+    ];
+    let mut collator = ChainStatsCollator::default();
+    for node in &nodes {
+        collator.add_or_remove_node(node, None, CounterValue::Increment);
+    }
+
+    let list = |ranking: Ranking<String>| -> Vec<(String, u64)> {
+        assert_eq!(ranking.other, 0);
+        assert_eq!(ranking.unknown, 1);
+        ranking.list
+    };
+    let stats = collator.generate();
+    assert_eq!(
+        list(stats.target_platform),
+        vec![
+            ("x86_64-linux".to_owned(), 4),
+            ("aarch64-linux".to_owned(), 2),
+            ("aarch64-macos".to_owned(), 1),
+        ]
+    );
+    assert_eq!(
+        list(stats.target_arch),
+        vec![("x86_64".to_owned(), 4), ("aarch64".to_owned(), 3)]
+    );
+    assert_eq!(
+        list(stats.target_os),
+        vec![("linux".to_owned(), 6), ("macos".to_owned(), 1)]
+    );
+
+    // Removing a node takes it out of the tallies again:
+    collator.add_or_remove_node(&nodes[3], None, CounterValue::Decrement);
+    assert_eq!(
+        list(collator.generate().target_platform),
+        vec![
+            ("x86_64-linux".to_owned(), 4),
+            ("aarch64-linux".to_owned(), 2),
+        ]
+    );
+}
+
+#[test]
+fn test_sysinfo_totals() {
+    use common::node_types::{NetworkId, NodeDetails, NodeSysInfo};
+
+    fn details(core_count: Option<u32>, memory: Option<u64>) -> NodeDetails {
+        NodeDetails {
+            chain: "Chain".into(),
+            name: "Node".into(),
+            implementation: "Substrate Node".into(),
+            version: "0.9.17-75dd6c7d0".into(),
+            validator: None,
+            network_id: NetworkId::new(),
+            startup_time: None,
+            target_os: None,
+            target_arch: None,
+            target_env: None,
+            sysinfo: Some(NodeSysInfo {
+                cpu: None,
+                memory,
+                core_count,
+                linux_kernel: None,
+                linux_distro: None,
+                is_virtual_machine: None,
+            }),
+            ip: None,
+            shard: None,
+        }
+    }
+
+    const GB: u64 = 1024 * 1024 * 1024;
+    let nodes = [
+        details(Some(8), Some(16 * GB)),
+        details(Some(4), None),
+        details(None, Some(32 * GB)),
+        details(Some(8), Some(16 * GB)),
+    ];
+    let mut collator = ChainStatsCollator::default();
+    for node in &nodes {
+        collator.add_or_remove_node(node, None, CounterValue::Increment);
+    }
+    // Nodes without any sysinfo don't count towards the totals:
+    let mut no_sysinfo = details(None, None);
+    no_sysinfo.sysinfo = None;
+    collator.add_or_remove_node(&no_sysinfo, None, CounterValue::Increment);
+
+    let stats = collator.generate();
+    assert_eq!(stats.total_core_count, 20);
+    assert_eq!(stats.total_memory, 64 * GB);
+    assert_eq!(stats.core_count.list, vec![(8, 2), (4, 1)]);
+    assert_eq!(stats.core_count.unknown, 2);
+
+    // Removing a node takes its share away again:
+    collator.add_or_remove_node(&nodes[0], None, CounterValue::Decrement);
+    let stats = collator.generate();
+    assert_eq!(stats.total_core_count, 12);
+    assert_eq!(stats.total_memory, 48 * GB);
+
+    // However much nodes claim to have, the totals don't overflow, and are capped to what the
+    // frontend can represent exactly:
+    let huge = details(Some(u32::MAX), Some(u64::MAX));
+    for _ in 0..3 {
+        collator.add_or_remove_node(&huge, None, CounterValue::Increment);
+    }
+    let stats = collator.generate();
+    assert_eq!(stats.total_core_count, 12 + 3 * u32::MAX as u64);
+    assert_eq!(stats.total_memory, MAX_JS_SAFE_INTEGER);
+
+    // Taking them away again leaves exactly what was there before:
+    for _ in 0..3 {
+        collator.add_or_remove_node(&huge, None, CounterValue::Decrement);
+    }
+    let stats = collator.generate();
+    assert_eq!(stats.total_core_count, 12);
+    assert_eq!(stats.total_memory, 48 * GB);
+}
@@ -18,8 +18,13 @@ mod chain;
 mod chain_stats;
 mod counter;
 mod node;
+mod quality_score;
+mod snapshot;
 
 mod state;
 
-pub use node::Node;
+pub use chain::{is_first_party_network, ChainOptions, Now};
+pub use node::{Node, NodeLogCounts};
+pub use quality_score::QualityScoreWeights;
+pub use snapshot::StateSnapshot;
 pub use state::*;
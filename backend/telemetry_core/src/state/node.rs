@@ -15,17 +15,23 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 use crate::find_location;
+use crate::uptime::Uptime;
 use common::node_message::SystemInterval;
 use common::node_types::{
-    Block, BlockDetails, NodeDetails, NodeHardware, NodeHwBench, NodeIO, NodeLocation, NodeStats,
-    Timestamp,
+    Block, BlockDetails, BlockNumber, NodeDetails, NodeHardware, NodeHwBench, NodeIO, NodeLocation,
+    NodeStats, Timestamp,
 };
 use common::time;
+use std::time::{Duration, Instant};
 
 /// Minimum time between block below broadcasting updates to the browser gets throttled, in ms.
 const THROTTLE_THRESHOLD: u64 = 100;
 /// Minimum time of intervals for block updates sent to the browser when throttled, in ms.
 const THROTTLE_INTERVAL: u64 = 1000;
+/// How much does a node's quality score need to change by before we report the change.
+const QUALITY_SCORE_MIN_CHANGE: u8 = 5;
+/// How much does a node's finality lag need to change by before we report the change.
+const FINALITY_LAG_MIN_CHANGE: BlockNumber = 5;
 
 pub struct Node {
     /// Static details
@@ -44,12 +50,55 @@ pub struct Node {
     hardware: NodeHardware,
     /// Physical location details
     location: find_location::Location,
+    /// How long the node has been up for, if we're recording uptime and know yet
+    uptime: Option<Uptime>,
     /// Flag marking if the node is stale (not syncing or producing blocks)
     stale: bool,
     /// Unix timestamp for when node started up (falls back to connection time)
     startup_time: Option<Timestamp>,
     /// Hardware benchmark results for the node
     hwbench: Option<NodeHwBench>,
+    /// The last quality score that we reported for this node
+    quality_score: Option<u8>,
+    /// The last finality lag (best minus finalized block height) that we reported for this node
+    finality_lag: Option<BlockNumber>,
+    /// The last database size (in bytes) reported by the node, if it reports one
+    database_size: Option<u64>,
+    /// Errors and warnings logged by the node since it connected
+    log_counts: NodeLogCounts,
+    /// The raw error and warning counters last reported by the node
+    reported_log_counts: Option<NodeLogCounts>,
+    /// Whether the node last reported that it's doing a major sync, if it reports this
+    major_syncing: Option<bool>,
+    /// Changes to the node's hardware, stats and IO that feeds haven't been told about yet
+    pending_updates: PendingNodeUpdates,
+    /// When feeds were last told about changes to the node's hardware, stats or IO
+    pending_updates_last_sent: Option<Instant>,
+    /// Has the node imported a block that feeds haven't been told about yet?
+    imported_block_pending: bool,
+    /// When feeds were last told about a block that the node imported
+    imported_block_last_sent: Option<Instant>,
+}
+
+/// Which of a node's hardware, stats and IO have changed without feeds being told yet.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PendingNodeUpdates {
+    pub hardware: bool,
+    pub stats: bool,
+    pub io: bool,
+}
+
+impl PendingNodeUpdates {
+    pub fn is_empty(&self) -> bool {
+        !(self.hardware || self.stats || self.io)
+    }
+}
+
+/// Counts of the errors and warnings that a node has logged.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct NodeLogCounts {
+    pub errors: u64,
+    pub warnings: u64,
 }
 
 impl Node {
@@ -68,9 +117,20 @@ impl Node {
             throttle: 0,
             hardware: NodeHardware::default(),
             location: None,
+            uptime: None,
             stale: false,
             startup_time,
             hwbench: None,
+            quality_score: None,
+            finality_lag: None,
+            database_size: None,
+            log_counts: NodeLogCounts::default(),
+            reported_log_counts: None,
+            major_syncing: None,
+            pending_updates: PendingNodeUpdates::default(),
+            pending_updates_last_sent: None,
+            imported_block_pending: false,
+            imported_block_last_sent: None,
         }
     }
 
@@ -78,6 +138,21 @@ impl Node {
         &self.details
     }
 
+    /// Replace the details of a node that has connected again. It keeps the chain label that
+    /// it was counted under. Returns `true` if the details have changed.
+    pub fn set_details(&mut self, mut details: NodeDetails) -> bool {
+        details.chain = self.details.chain.clone();
+        let startup_time = details
+            .startup_time
+            .take()
+            .and_then(|time| time.parse().ok());
+
+        let changed = details != self.details || startup_time != self.startup_time;
+        self.details = details;
+        self.startup_time = startup_time;
+        changed
+    }
+
     pub fn stats(&self) -> &NodeStats {
         &self.stats
     }
@@ -106,6 +181,21 @@ impl Node {
         self.location.as_deref()
     }
 
+    pub fn uptime(&self) -> Option<Uptime> {
+        self.uptime
+    }
+
+    /// How many seconds the node's process has been running for, as of now, going by the
+    /// startup time that it reported.
+    pub fn process_uptime_secs(&self, now: Timestamp) -> Option<u64> {
+        // A startup time in the future is as unusable as one that didn't parse:
+        Some(now.checked_sub(self.startup_time?)? / 1000)
+    }
+
+    pub fn update_uptime(&mut self, uptime: Uptime) {
+        self.uptime = Some(uptime);
+    }
+
     pub fn update_location(&mut self, location: find_location::Location) {
         self.location = location;
     }
@@ -190,6 +280,63 @@ impl Node {
         }
     }
 
+    pub fn database_size(&self) -> Option<u64> {
+        self.database_size
+    }
+
+    pub fn update_database_size(&mut self, interval: &SystemInterval) {
+        if let Some(size) = interval.database_size {
+            self.database_size = Some(size);
+        }
+    }
+
+    pub fn major_syncing(&self) -> Option<bool> {
+        self.major_syncing
+    }
+
+    /// Update whether the node is doing a major sync. Returns the new value if it has
+    /// changed. Nodes that don't report this leave it untouched.
+    pub fn update_major_syncing(&mut self, interval: &SystemInterval) -> Option<bool> {
+        let syncing = interval.major_syncing?;
+        if self.major_syncing == Some(syncing) {
+            return None;
+        }
+        self.major_syncing = Some(syncing);
+        Some(syncing)
+    }
+
+    pub fn log_counts(&self) -> NodeLogCounts {
+        self.log_counts
+    }
+
+    /// Update the error and warning counts given the counters reported by the node. The
+    /// counters should only ever go up, so if one goes down we assume that the node has
+    /// restarted and its counter has started again from 0. Returns the new counts if the
+    /// node has logged any more errors or warnings.
+    pub fn update_log_counts(&mut self, interval: &SystemInterval) -> Option<NodeLogCounts> {
+        if interval.error_count.is_none() && interval.warning_count.is_none() {
+            return None;
+        }
+
+        let last = self.reported_log_counts.unwrap_or_default();
+        let reported = NodeLogCounts {
+            errors: interval.error_count.unwrap_or(last.errors),
+            warnings: interval.warning_count.unwrap_or(last.warnings),
+        };
+        self.reported_log_counts = Some(reported);
+
+        let delta = |new: u64, old: u64| if new >= old { new - old } else { new };
+        let new_errors = delta(reported.errors, last.errors);
+        let new_warnings = delta(reported.warnings, last.warnings);
+        if new_errors == 0 && new_warnings == 0 {
+            return None;
+        }
+
+        self.log_counts.errors += new_errors;
+        self.log_counts.warnings += new_warnings;
+        Some(self.log_counts)
+    }
+
     pub fn update_io(&mut self, interval: &SystemInterval) -> Option<&NodeIO> {
         let mut changed = false;
 
@@ -213,6 +360,64 @@ impl Node {
         }
     }
 
+    /// Note that some of the node's hardware, stats or IO have changed, and feeds need telling.
+    pub fn add_pending_updates(&mut self, updates: PendingNodeUpdates) {
+        self.pending_updates.hardware |= updates.hardware;
+        self.pending_updates.stats |= updates.stats;
+        self.pending_updates.io |= updates.io;
+    }
+
+    /// Note that the node has imported a block, and feeds need telling.
+    pub fn add_pending_imported_block(&mut self) {
+        self.imported_block_pending = true;
+    }
+
+    /// Are there any changes (including imported blocks) that feeds haven't been told about?
+    pub fn has_pending_updates(&self) -> bool {
+        !self.pending_updates.is_empty() || self.imported_block_pending
+    }
+
+    /// Take the changes that feeds need telling about, unless they were last told about some
+    /// less than `min_interval` ago, in which case the changes are held back for now.
+    pub fn take_pending_updates(
+        &mut self,
+        now: Instant,
+        min_interval: Option<Duration>,
+    ) -> Option<PendingNodeUpdates> {
+        if self.pending_updates.is_empty() {
+            return None;
+        }
+        if let (Some(interval), Some(last)) = (min_interval, self.pending_updates_last_sent) {
+            if now - last < interval {
+                return None;
+            }
+        }
+
+        self.pending_updates_last_sent = Some(now);
+        Some(std::mem::take(&mut self.pending_updates))
+    }
+
+    /// Take the details of the latest block that the node imported if feeds need telling about
+    /// it, unless they were last told about one less than `min_interval` ago.
+    pub fn take_pending_imported_block(
+        &mut self,
+        now: Instant,
+        min_interval: Option<Duration>,
+    ) -> Option<&BlockDetails> {
+        if !self.imported_block_pending {
+            return None;
+        }
+        if let (Some(interval), Some(last)) = (min_interval, self.imported_block_last_sent) {
+            if now - last < interval {
+                return None;
+            }
+        }
+
+        self.imported_block_pending = false;
+        self.imported_block_last_sent = Some(now);
+        Some(&self.best)
+    }
+
     pub fn update_stale(&mut self, threshold: u64) -> bool {
         if self.best.block_timestamp < threshold {
             self.stale = true;
@@ -237,4 +442,107 @@ impl Node {
     pub fn startup_time(&self) -> Option<Timestamp> {
         self.startup_time
     }
+
+    pub fn quality_score(&self) -> Option<u8> {
+        self.quality_score
+    }
+
+    /// Update the quality score for this node, returning it if it has
+    /// changed enough since it was last updated to be worth reporting.
+    pub fn update_quality_score(&mut self, score: Option<u8>) -> Option<u8> {
+        let changed = match (self.quality_score, score) {
+            (Some(old), Some(new)) => old.abs_diff(new) >= QUALITY_SCORE_MIN_CHANGE,
+            (None, None) => false,
+            _ => true,
+        };
+
+        if changed {
+            self.quality_score = score;
+            score
+        } else {
+            None
+        }
+    }
+
+    pub fn finality_lag(&self) -> Option<BlockNumber> {
+        self.finality_lag
+    }
+
+    /// Update the finality lag for this node from its best and finalized blocks, returning
+    /// it if it has changed enough since it was last updated to be worth reporting. We don't
+    /// know the lag until the node has told us about a finalized block beyond genesis.
+    pub fn update_finality_lag(&mut self) -> Option<BlockNumber> {
+        if self.finalized.height == 0 {
+            return None;
+        }
+
+        let lag = self.best.block.height.saturating_sub(self.finalized.height);
+        let changed = match self.finality_lag {
+            Some(old) => old.abs_diff(lag) >= FINALITY_LAG_MIN_CHANGE,
+            None => true,
+        };
+
+        if changed {
+            self.finality_lag = Some(lag);
+            Some(lag)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::node_types::NetworkId;
+
+    fn node(startup_time: Option<&str>) -> Node {
+        Node::new(NodeDetails {
+            chain: "Chain".into(),
+            name: "Node".into(),
+            implementation: "Substrate Node".into(),
+            version: "0.9.17-75dd6c7d0".into(),
+            validator: None,
+            network_id: NetworkId::new(),
+            startup_time: startup_time.map(Into::into),
+            target_os: None,
+            target_arch: None,
+            target_env: None,
+            sysinfo: None,
+            ip: None,
+            shard: None,
+        })
+    }
+
+    #[test]
+    fn process_uptime_is_worked_out_from_the_startup_time() {
+        let now = 1_625_565_542_717;
+        assert_eq!(
+            node(Some("1625565542717")).process_uptime_secs(now + 90_500),
+            Some(90)
+        );
+        // Without a usable startup time, we don't know how long the node has been up:
+        assert_eq!(node(None).process_uptime_secs(now), None);
+        assert_eq!(node(Some("yesterday")).process_uptime_secs(now), None);
+        assert_eq!(node(Some("-1")).process_uptime_secs(now), None);
+        assert_eq!(
+            node(Some("1625565542717")).process_uptime_secs(now - 1),
+            None
+        );
+    }
+
+    #[test]
+    fn process_uptime_is_independent_of_the_uptime_database() {
+        let now = 1_625_565_542_717;
+        let mut node = node(Some("1625565542717"));
+        node.update_uptime(Uptime {
+            previous_secs: 1000,
+            connected_at: now,
+        });
+        assert_eq!(node.process_uptime_secs(now + 10_000), Some(10));
+        assert_eq!(
+            node.uptime().map(|u| u.total_secs(now + 10_000)),
+            Some(1010)
+        );
+    }
 }
@@ -0,0 +1,254 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! A per-node "quality score", combining a handful of the metrics we already track into a
+//! single number between 0 (bad) and 100 (good) that the UI can use as an at-a-glance indicator
+//! of node health.
+//!
+//! Each component below is normalised to a value between 0.0 and 1.0:
+//!
+//! - **peers**: `min(peers, 20) / 20`. Nodes with 20 or more peers score full marks.
+//! - **propagation**: `1 - min(propagation_time / expected_block_time, 1)`, where the expected
+//!   block time is the chain's average block time (or 6 seconds if that's not known yet). Nodes
+//!   that were first to see the best block (or that we have no propagation time for) score 1.
+//! - **staleness**: 0 if the node is stale, else `1 - min(time_since_last_block / stale_timeout, 1)`,
+//!   where the stale timeout is the one that the chain marks nodes as stale after.
+//! - **finality**: `1 - min((best_height - finalized_height) / 100, 1)`. Nodes whose finalized
+//!   block is 100 or more blocks behind their best block score 0.
+//!
+//! The score is then the weighted average of these components, scaled to 0-100 and rounded:
+//!
+//! ```text
+//! score = round(100 * (w_peers * peers + w_propagation * propagation
+//!     + w_staleness * staleness + w_finality * finality) / (w_peers + w_propagation + w_staleness + w_finality))
+//! ```
+//!
+//! If all of the weights are 0, no score is calculated.
+
+use super::node::Node;
+use std::time::Duration;
+
+/// Nodes with at least this many peers score full marks for peers.
+const PEERS_TARGET: f64 = 20.0;
+/// If we don't know the average block time for a chain yet, assume this, in ms.
+const DEFAULT_EXPECTED_BLOCK_TIME: f64 = 6000.0;
+/// Nodes whose finalized block lags their best block by this much score 0 for finality.
+const FINALITY_LAG_LIMIT: f64 = 100.0;
+
+/// How much each component contributes to a node's quality score.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QualityScoreWeights {
+    pub peers: f64,
+    pub propagation: f64,
+    pub staleness: f64,
+    pub finality: f64,
+}
+
+impl Default for QualityScoreWeights {
+    fn default() -> Self {
+        QualityScoreWeights {
+            peers: 1.0,
+            propagation: 1.0,
+            staleness: 1.0,
+            finality: 1.0,
+        }
+    }
+}
+
+impl QualityScoreWeights {
+    /// Calculate a quality score for the node given, from 0 to 100. `now` is the current
+    /// time in ms, `average_block_time` is the average block time of the node's chain, and
+    /// `stale_timeout` is how long the chain waits for a new block before marking a node as
+    /// stale. Returns `None` if all of the weights are 0.
+    pub fn score(
+        &self,
+        node: &Node,
+        now: u64,
+        average_block_time: Option<u64>,
+        stale_timeout: Duration,
+    ) -> Option<u8> {
+        let total_weight = self.peers + self.propagation + self.staleness + self.finality;
+        if total_weight <= 0.0 {
+            return None;
+        }
+
+        let peers = (node.stats().peers as f64 / PEERS_TARGET).min(1.0);
+
+        let expected_block_time = average_block_time
+            .filter(|&t| t > 0)
+            .map(|t| t as f64)
+            .unwrap_or(DEFAULT_EXPECTED_BLOCK_TIME);
+        let propagation = match node.block_details().propagation_time {
+            Some(t) => 1.0 - (t as f64 / expected_block_time).min(1.0),
+            None => 1.0,
+        };
+
+        let staleness = if node.stale() {
+            0.0
+        } else {
+            let since_last_block = now.saturating_sub(node.best_timestamp());
+            let stale_timeout = stale_timeout.as_millis() as f64;
+            1.0 - (since_last_block as f64 / stale_timeout).min(1.0)
+        };
+
+        let finality_lag = node.best().height.saturating_sub(node.finalized().height);
+        let finality = 1.0 - (finality_lag as f64 / FINALITY_LAG_LIMIT).min(1.0);
+
+        let weighted = self.peers * peers
+            + self.propagation * propagation
+            + self.staleness * staleness
+            + self.finality * finality;
+
+        Some((100.0 * weighted / total_weight).round().clamp(0.0, 100.0) as u8)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::node_message::SystemInterval;
+    use common::node_types::{Block, NetworkId, NodeDetails};
+
+    const STALE_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+
+    fn node() -> Node {
+        Node::new(NodeDetails {
+            chain: "Test".into(),
+            name: "Test".into(),
+            implementation: "Test".into(),
+            version: "0.1".into(),
+            target_arch: None,
+            target_env: None,
+            target_os: None,
+            validator: None,
+            network_id: NetworkId::new(),
+            startup_time: None,
+            sysinfo: None,
+            ip: None,
+            shard: None,
+        })
+    }
+
+    fn set_peers(node: &mut Node, peers: u64) {
+        node.update_stats(&SystemInterval {
+            peers: Some(peers),
+            txcount: None,
+            bandwidth_upload: None,
+            bandwidth_download: None,
+            finalized_height: None,
+            finalized_hash: None,
+            block: None,
+            used_state_cache_size: None,
+            database_size: None,
+            error_count: None,
+            warning_count: None,
+            major_syncing: None,
+        });
+    }
+
+    fn block(height: u64) -> Block {
+        Block {
+            height,
+            ..Block::zero()
+        }
+    }
+
+    #[test]
+    fn healthy_node_scores_100() {
+        let mut node = node();
+        set_peers(&mut node, 50);
+        let now = node.best_timestamp();
+
+        let score = QualityScoreWeights::default().score(&node, now, Some(6000), STALE_TIMEOUT);
+        assert_eq!(score, Some(100));
+    }
+
+    #[test]
+    fn components_are_weighted() {
+        // No peers, but everything else is perfect:
+        let node = node();
+        let now = node.best_timestamp();
+
+        let score = QualityScoreWeights::default().score(&node, now, None, STALE_TIMEOUT);
+        assert_eq!(score, Some(75));
+
+        let only_peers = QualityScoreWeights {
+            peers: 1.0,
+            propagation: 0.0,
+            staleness: 0.0,
+            finality: 0.0,
+        };
+        assert_eq!(only_peers.score(&node, now, None, STALE_TIMEOUT), Some(0));
+
+        let mostly_peers = QualityScoreWeights {
+            peers: 3.0,
+            ..Default::default()
+        };
+        assert_eq!(
+            mostly_peers.score(&node, now, None, STALE_TIMEOUT),
+            Some(50)
+        );
+    }
+
+    #[test]
+    fn finality_lag_and_staleness_reduce_score() {
+        let mut node = node();
+        set_peers(&mut node, 20);
+        node.update_block(block(150));
+        node.update_finalized(block(100));
+        let now = node.best_timestamp() + 60 * 1000;
+
+        // peers=1, propagation=1, staleness=0.5, finality=0.5
+        let score = QualityScoreWeights::default().score(&node, now, None, STALE_TIMEOUT);
+        assert_eq!(score, Some(75));
+
+        node.update_stale(now + 1);
+        // peers=1, propagation=1, staleness=0, finality=0.5
+        let score = QualityScoreWeights::default().score(&node, now, None, STALE_TIMEOUT);
+        assert_eq!(score, Some(63));
+    }
+
+    #[test]
+    fn staleness_is_relative_to_the_stale_timeout() {
+        let node = node();
+        let now = node.best_timestamp() + 60 * 1000;
+        let only_staleness = QualityScoreWeights {
+            peers: 0.0,
+            propagation: 0.0,
+            staleness: 1.0,
+            finality: 0.0,
+        };
+
+        let score = only_staleness.score(&node, now, None, STALE_TIMEOUT);
+        assert_eq!(score, Some(50));
+        let score = only_staleness.score(&node, now, None, Duration::from_secs(4 * 60));
+        assert_eq!(score, Some(75));
+        let score = only_staleness.score(&node, now, None, Duration::from_secs(60));
+        assert_eq!(score, Some(0));
+    }
+
+    #[test]
+    fn no_score_if_weights_are_zero() {
+        let node = node();
+        let weights = QualityScoreWeights {
+            peers: 0.0,
+            propagation: 0.0,
+            staleness: 0.0,
+            finality: 0.0,
+        };
+        assert_eq!(weights.score(&node, 0, None, STALE_TIMEOUT), None);
+    }
+}
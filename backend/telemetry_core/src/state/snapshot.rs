@@ -0,0 +1,158 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! A copy of the chains and nodes that we know about, which can be dumped to JSON (see
+//! '/admin/state') and loaded back in later (see '--load-snapshot') in order to serve feeds
+//! without any shards connected.
+
+use anyhow::Context;
+use common::node_message::{self, Payload, SystemInterval};
+use common::node_types::{Block, BlockHash, NodeDetails, NodeHwBench, NodeLocation, NodeStats};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use super::node::Node;
+
+/// The version of the snapshot format that we write, and the only one that we can load. This
+/// should be bumped whenever the format changes in a way that older versions can't load.
+pub const STATE_SNAPSHOT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StateSnapshot {
+    pub version: u32,
+    pub chains: Vec<ChainSnapshot>,
+}
+
+impl StateSnapshot {
+    pub fn new(chains: Vec<ChainSnapshot>) -> StateSnapshot {
+        StateSnapshot {
+            version: STATE_SNAPSHOT_VERSION,
+            chains,
+        }
+    }
+
+    /// Load a snapshot from the JSON file given.
+    pub fn load(path: &Path) -> anyhow::Result<StateSnapshot> {
+        let bytes = std::fs::read(path)
+            .with_context(|| format!("Could not read state snapshot {path:?}"))?;
+        StateSnapshot::from_json(&bytes)
+            .with_context(|| format!("Could not load state snapshot {path:?}"))
+    }
+
+    /// Parse a snapshot, checking that it's a version that we know how to load.
+    pub fn from_json(bytes: &[u8]) -> anyhow::Result<StateSnapshot> {
+        #[derive(Deserialize)]
+        struct Version {
+            version: Option<u32>,
+        }
+
+        // Look at the version first, so that we can complain about it rather than about
+        // whatever else has changed:
+        let version = serde_json::from_slice::<Version>(bytes)
+            .context("Could not parse state snapshot")?
+            .version;
+        if version != Some(STATE_SNAPSHOT_VERSION) {
+            anyhow::bail!(
+                "State snapshot is version {}, but only version {STATE_SNAPSHOT_VERSION} can be loaded",
+                version.map_or("unknown".to_owned(), |v| v.to_string()),
+            );
+        }
+        serde_json::from_slice(bytes).context("Could not parse state snapshot")
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChainSnapshot {
+    pub genesis_hash: BlockHash,
+    pub nodes: Vec<NodeSnapshot>,
+}
+
+/// The details that we keep of each node. Things that are built up over time (for instance
+/// the node's IO history) aren't kept.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NodeSnapshot {
+    pub details: NodeDetails,
+    pub stats: NodeStats,
+    pub best: Block,
+    pub finalized: Block,
+    pub location: Option<NodeLocation>,
+    pub hwbench: Option<NodeHwBench>,
+    pub database_size: Option<u64>,
+    pub major_syncing: Option<bool>,
+}
+
+impl NodeSnapshot {
+    pub fn new(node: &Node) -> NodeSnapshot {
+        let mut details = node.details().clone();
+        // This is taken out of the details when the node is created, so put it back:
+        details.startup_time = node.startup_time().map(|time| time.to_string().into());
+
+        NodeSnapshot {
+            details,
+            stats: *node.stats(),
+            best: *node.best(),
+            finalized: *node.finalized(),
+            location: node.location().cloned(),
+            hwbench: node.hwbench().cloned(),
+            database_size: node.database_size(),
+            major_syncing: node.major_syncing(),
+        }
+    }
+
+    /// The payloads which, if a node had just connected with the details in this
+    /// snapshot, would bring it up to date with the rest of the snapshot.
+    pub fn payloads(&self) -> Vec<Payload> {
+        let mut payloads = vec![Payload::SystemInterval(SystemInterval {
+            peers: Some(self.stats.peers),
+            txcount: Some(self.stats.txcount),
+            bandwidth_upload: None,
+            bandwidth_download: None,
+            finalized_height: Some(self.finalized.height),
+            finalized_hash: Some(self.finalized.hash),
+            block: Some(self.best),
+            used_state_cache_size: None,
+            database_size: self.database_size,
+            error_count: None,
+            warning_count: None,
+            major_syncing: self.major_syncing,
+        })];
+        if let Some(hwbench) = &self.hwbench {
+            payloads.push(Payload::HwBench(node_message::NodeHwBench {
+                cpu_hashrate_score: hwbench.cpu_hashrate_score,
+                memory_memcpy_score: hwbench.memory_memcpy_score,
+                disk_sequential_write_score: hwbench.disk_sequential_write_score,
+                disk_random_write_score: hwbench.disk_random_write_score,
+            }));
+        }
+        payloads
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn snapshots_of_other_versions_are_refused() {
+        let err = StateSnapshot::from_json(br#"{ "version": 2, "chains": [] }"#).unwrap_err();
+        assert!(err.to_string().contains("version 2"));
+        let err = StateSnapshot::from_json(br#"{ "chains": [] }"#).unwrap_err();
+        assert!(err.to_string().contains("version unknown"));
+
+        let snapshot = StateSnapshot::from_json(br#"{ "version": 1, "chains": [] }"#).unwrap();
+        assert!(snapshot.chains.is_empty());
+    }
+}
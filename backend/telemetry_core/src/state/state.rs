@@ -15,15 +15,19 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 use super::node::Node;
+use super::snapshot::{ChainSnapshot, NodeSnapshot, StateSnapshot};
 use crate::feed_message::{ChainStats, FeedMessageSerializer};
 use crate::find_location;
+use crate::uptime::Uptime;
 use common::node_message::Payload;
-use common::node_types::{Block, BlockHash, NodeDetails, Timestamp};
+use common::node_types::{Block, BlockHash, BlockNumber, NodeDetails, Timestamp};
 use common::{id_type, DenseMap};
 use std::collections::{HashMap, HashSet};
 use std::iter::IntoIterator;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use super::chain::{self, Chain, ChainNodeId};
+use super::chain::{self, Chain, ChainNodeId, ChainOptions, Now};
 
 id_type! {
     /// A globally unique Chain ID.
@@ -51,17 +55,67 @@ pub struct State {
     /// Chain labels that we do not want to allow connecting.
     denylist: HashSet<String>,
 
+    /// If not empty, the only chain labels that we allow connecting.
+    allowlist: HashSet<String>,
+
     /// How many nodes from third party chains are allowed to connect
     /// before we prevent connections from them.
     max_third_party_nodes: usize,
+
+    /// If set, the maximum number of chains that we'll keep track of. Nodes on
+    /// new chains beyond this are refused (first party chains are always allowed).
+    max_chains: Option<usize>,
+
+    /// If set, the maximum number of nodes that we'll keep track of across all
+    /// chains. Nodes beyond this are refused.
+    max_total_nodes: Option<usize>,
+
+    /// How many nodes we're keeping track of across all chains.
+    node_count: usize,
+
+    /// Options that each new chain is created with.
+    chain_options: ChainOptions,
+
+    /// If set, chains that have no nodes left are kept for this long
+    /// before being removed.
+    empty_chain_ttl: Option<Duration>,
+
+    /// Chains that have no nodes left, and when to remove them.
+    empty_chains: HashMap<ChainId, Instant>,
+}
+
+/// Options that the state is created with.
+#[derive(Debug, Clone, Copy)]
+pub struct StateOptions {
+    /// How many nodes from third party chains are allowed to connect
+    /// before we prevent connections from them.
+    pub max_third_party_nodes: usize,
+    /// If set, chains that have no nodes left are kept for this long
+    /// before being removed.
+    pub empty_chain_ttl: Option<Duration>,
+    /// If set, the maximum number of chains with nodes that we'll keep track of. Chains
+    /// that are only being kept around because of `empty_chain_ttl` don't count.
+    pub max_chains: Option<usize>,
+    /// If set, the maximum number of nodes that we'll keep track of across all chains.
+    pub max_total_nodes: Option<usize>,
+    /// Options that each new chain is created with.
+    pub chain: ChainOptions,
 }
 
 /// Adding a node to a chain leads to this result.
 pub enum AddNodeResult<'a> {
     /// The chain is on the "deny list", so we can't add the node
     ChainOnDenyList,
+    /// There is an "allow list" and the chain isn't on it, so we can't add the node
+    ChainNotAllowlisted,
     /// The chain is over quota (too many nodes connected), so can't add the node
     ChainOverQuota,
+    /// The node is on a new chain, but we're already tracking the maximum number
+    /// of chains, so can't add the node
+    TooManyChains,
+    /// We're already tracking the maximum number of nodes across all chains,
+    /// so can't add the node
+    GlobalQuota,
     /// The node was added to the chain
     NodeAddedToChain(NodeAddedToChain<'a>),
 }
@@ -93,8 +147,12 @@ pub struct NodeAddedToChain<'a> {
 
 /// if removing a node is successful, we get this information back.
 pub struct RemovedNode {
-    /// How many nodes remain on the chain (0 if the chain was removed)
+    /// How many nodes remain on the chain. If this is 0, the chain has either been removed,
+    /// or is being kept until the empty chain TTL has passed (see `chain_removed`).
     pub chain_node_count: usize,
+    /// Was the chain removed because it has no nodes left? Chains with no nodes left
+    /// are kept for a while if an empty chain TTL is set.
+    pub chain_removed: bool,
     /// Has the chain label been updated?
     pub has_chain_label_changed: bool,
     /// The old label of the chain.
@@ -106,13 +164,46 @@ pub struct RemovedNode {
 }
 
 impl State {
-    pub fn new<T: IntoIterator<Item = String>>(denylist: T, max_third_party_nodes: usize) -> State {
+    pub fn new<T: IntoIterator<Item = String>, A: IntoIterator<Item = String>>(
+        denylist: T,
+        allowlist: A,
+        opts: StateOptions,
+    ) -> State {
         State {
             chains: DenseMap::new(),
             chains_by_genesis_hash: HashMap::new(),
             denylist: denylist.into_iter().collect(),
-            max_third_party_nodes,
+            allowlist: allowlist.into_iter().collect(),
+            max_third_party_nodes: opts.max_third_party_nodes,
+            max_chains: opts.max_chains,
+            max_total_nodes: opts.max_total_nodes,
+            node_count: 0,
+            chain_options: opts.chain,
+            empty_chain_ttl: opts.empty_chain_ttl,
+            empty_chains: HashMap::new(),
+        }
+    }
+
+    pub fn chain_count(&self) -> usize {
+        self.chains.len()
+    }
+
+    /// Replace the list of chains that nodes aren't allowed to connect from. This returns the IDs
+    /// of any nodes that are already connected on chains that are now on the list; it's up to the
+    /// caller to remove them.
+    pub fn set_denylist(&mut self, denylist: HashSet<String>) -> Vec<NodeId> {
+        self.denylist = denylist;
+
+        let mut denied_nodes = Vec::new();
+        for (chain_id, chain) in self.chains.iter() {
+            for (idx, node) in chain.nodes_slice().iter().enumerate() {
+                let Some(node) = node else { continue };
+                if self.denylist.contains(&*node.details().chain) {
+                    denied_nodes.push(NodeId(chain_id, idx.into()));
+                }
+            }
         }
+        denied_nodes
     }
 
     pub fn iter_chains(&self) -> impl Iterator<Item = StateChain<'_>> {
@@ -140,6 +231,15 @@ impl State {
         if self.denylist.contains(&*node_details.chain) {
             return AddNodeResult::ChainOnDenyList;
         }
+        if !self.allowlist.is_empty() && !self.allowlist.contains(&*node_details.chain) {
+            return AddNodeResult::ChainNotAllowlisted;
+        }
+        if self
+            .max_total_nodes
+            .is_some_and(|max| self.node_count >= max)
+        {
+            return AddNodeResult::GlobalQuota;
+        }
 
         // Get the chain ID, creating a new empty chain if one doesn't exist.
         // If we create a chain here, we are expecting that it will allow at
@@ -148,13 +248,12 @@ impl State {
         let chain_id = match self.chains_by_genesis_hash.get(&genesis_hash) {
             Some(id) => *id,
             None => {
-                let max_nodes = match chain::is_first_party_network(&genesis_hash) {
-                    true => usize::MAX,
-                    false => self.max_third_party_nodes,
-                };
-                let chain_id = self.chains.add(Chain::new(genesis_hash, max_nodes));
-                self.chains_by_genesis_hash.insert(genesis_hash, chain_id);
-                chain_id
+                let is_first_party = chain::is_first_party_network(&genesis_hash);
+                let chain_count = self.chains.len() - self.empty_chains.len();
+                if !is_first_party && self.max_chains.is_some_and(|max| chain_count >= max) {
+                    return AddNodeResult::TooManyChains;
+                }
+                self.add_chain(genesis_hash)
             }
         };
 
@@ -169,6 +268,8 @@ impl State {
         match chain.add_node(node) {
             chain::AddNodeResult::Overquota => AddNodeResult::ChainOverQuota,
             chain::AddNodeResult::Added { id, chain_renamed } => {
+                self.empty_chains.remove(&chain_id);
+                self.node_count += 1;
                 let chain = &*chain;
 
                 AddNodeResult::NodeAddedToChain(NodeAddedToChain {
@@ -183,10 +284,43 @@ impl State {
         }
     }
 
+    /// Pin a chain, so that it's kept even when it has no nodes, and always uses the label given.
+    /// The chain is added if we don't know about it yet.
+    pub fn pin_chain(&mut self, genesis_hash: BlockHash, label: &str) {
+        let chain_id = match self.chains_by_genesis_hash.get(&genesis_hash) {
+            Some(id) => *id,
+            None => self.add_chain(genesis_hash),
+        };
+        self.empty_chains.remove(&chain_id);
+        if let Some(chain) = self.chains.get_mut(chain_id) {
+            chain.pin(label.into());
+        }
+    }
+
+    /// Is the chain with this genesis hash pinned?
+    pub fn is_chain_pinned(&self, genesis_hash: &BlockHash) -> bool {
+        self.get_chain_by_genesis_hash(genesis_hash)
+            .is_some_and(|chain| chain.is_pinned())
+    }
+
+    /// Add a new, empty chain.
+    fn add_chain(&mut self, genesis_hash: BlockHash) -> ChainId {
+        let max_nodes = match chain::is_first_party_network(&genesis_hash) {
+            true => usize::MAX,
+            false => self.max_third_party_nodes,
+        };
+        let chain_id = self
+            .chains
+            .add(Chain::new(genesis_hash, max_nodes, self.chain_options));
+        self.chains_by_genesis_hash.insert(genesis_hash, chain_id);
+        chain_id
+    }
+
     /// Remove a node
     pub fn remove_node(&mut self, NodeId(chain_id, chain_node_id): NodeId) -> Option<RemovedNode> {
         let chain = self.chains.get_mut(chain_id)?;
         let old_chain_label = chain.label().into();
+        let old_chain_node_count = chain.node_count();
 
         // Actually remove the node
         let remove_result = chain.remove_node(chain_node_id);
@@ -194,24 +328,57 @@ impl State {
         // Get updated chain details.
         let new_chain_label: Box<str> = chain.label().into();
         let chain_node_count = chain.node_count();
+        self.node_count -= old_chain_node_count - chain_node_count;
         let chain_genesis_hash = chain.genesis_hash();
 
-        // Is the chain empty? Remove if so and clean up indexes to it
-        if chain_node_count == 0 {
-            let genesis_hash = chain.genesis_hash();
-            self.chains_by_genesis_hash.remove(&genesis_hash);
-            self.chains.remove(chain_id);
+        // Is the chain empty? Remove if so and clean up indexes to it, unless
+        // we're keeping empty chains around for a while, or it's pinned.
+        let mut chain_removed = false;
+        if chain_node_count == 0 && !chain.is_pinned() {
+            match self.empty_chain_ttl {
+                Some(ttl) => {
+                    self.empty_chains.insert(chain_id, Instant::now() + ttl);
+                }
+                None => {
+                    self.chains_by_genesis_hash.remove(&chain_genesis_hash);
+                    self.chains.remove(chain_id);
+                    chain_removed = true;
+                }
+            }
         }
 
         Some(RemovedNode {
             old_chain_label,
             new_chain_label,
             chain_node_count,
+            chain_removed,
             chain_genesis_hash,
             has_chain_label_changed: remove_result.chain_renamed,
         })
     }
 
+    /// Remove any chains that have had no nodes for longer than the empty chain TTL,
+    /// returning the genesis hashes of the chains that were removed.
+    pub fn remove_expired_empty_chains(&mut self, now: Instant) -> Vec<BlockHash> {
+        let expired: Vec<ChainId> = self
+            .empty_chains
+            .iter()
+            .filter(|(_, &remove_at)| remove_at <= now)
+            .map(|(&chain_id, _)| chain_id)
+            .collect();
+
+        let mut removed = Vec::with_capacity(expired.len());
+        for chain_id in expired {
+            self.empty_chains.remove(&chain_id);
+            if let Some(chain) = self.chains.remove(chain_id) {
+                let genesis_hash = chain.genesis_hash();
+                self.chains_by_genesis_hash.remove(&genesis_hash);
+                removed.push(genesis_hash);
+            }
+        }
+        removed
+    }
+
     /// Attempt to update the best block seen, given a node and block.
     pub fn update_node(
         &mut self,
@@ -219,6 +386,7 @@ impl State {
         payload: Payload,
         feed: &mut FeedMessageSerializer,
         expose_node_details: bool,
+        now: Now,
     ) {
         let chain = match self.chains.get_mut(chain_id) {
             Some(chain) => chain,
@@ -228,7 +396,53 @@ impl State {
             }
         };
 
-        chain.update_node(chain_node_id, payload, feed, expose_node_details)
+        chain.update_node(chain_node_id, payload, feed, expose_node_details, now)
+    }
+
+    /// Hand back feed messages for any chains which have a best block that was held
+    /// back by coalescing and is now due to be broadcast.
+    pub fn flush_coalesced_best_blocks(
+        &mut self,
+        now: Now,
+    ) -> Vec<(BlockHash, FeedMessageSerializer)> {
+        let mut flushed = Vec::new();
+        for (_, chain) in self.chains.iter_mut() {
+            let mut feed = FeedMessageSerializer::new();
+            if chain.flush_coalesced_best_block(&mut feed, now) {
+                flushed.push((chain.genesis_hash(), feed));
+            }
+        }
+        flushed
+    }
+
+    /// Hand back feed messages for any chains which have node updates that were held
+    /// back by throttling and are now due to be broadcast.
+    pub fn flush_throttled_node_updates(
+        &mut self,
+        now: Instant,
+    ) -> Vec<(BlockHash, FeedMessageSerializer)> {
+        let mut flushed = Vec::new();
+        for (_, chain) in self.chains.iter_mut() {
+            let mut feed = FeedMessageSerializer::new();
+            if chain.flush_throttled_node_updates(&mut feed, now) {
+                flushed.push((chain.genesis_hash(), feed));
+            }
+        }
+        flushed
+    }
+
+    /// Replace the details of a node that has connected again. Return `true` if the node was
+    /// found and its details have changed.
+    pub fn update_node_details(
+        &mut self,
+        NodeId(chain_id, chain_node_id): NodeId,
+        details: NodeDetails,
+    ) -> bool {
+        if let Some(chain) = self.chains.get_mut(chain_id) {
+            chain.update_node_details(chain_node_id, details)
+        } else {
+            false
+        }
     }
 
     /// Update the location for a node. Return `false` if the node was not found.
@@ -243,6 +457,69 @@ impl State {
             false
         }
     }
+
+    /// Take a copy of the chains and nodes that we know about.
+    pub fn snapshot(&self) -> StateSnapshot {
+        let chains = self
+            .iter_chains()
+            .map(|chain| ChainSnapshot {
+                genesis_hash: chain.genesis_hash(),
+                nodes: chain
+                    .nodes_slice()
+                    .iter()
+                    .flatten()
+                    .map(NodeSnapshot::new)
+                    .collect(),
+            })
+            .collect();
+        StateSnapshot::new(chains)
+    }
+
+    /// Add the chains and nodes in a snapshot, as if each node had connected and
+    /// reported the details in it. Nodes on chains which aren't allowed are skipped.
+    ///
+    /// A snapshot is served as-is (shards can't connect to add or remove nodes), so its nodes
+    /// are kept for as long as we're running. Since there's nothing for `max_total_nodes` and
+    /// `max_chains` to protect us from, they aren't applied, so the whole snapshot is loaded.
+    pub fn load_snapshot(&mut self, snapshot: &StateSnapshot) {
+        let max_total_nodes = self.max_total_nodes.take();
+        let max_chains = self.max_chains.take();
+        let now = Now::current();
+        for chain in &snapshot.chains {
+            for node in &chain.nodes {
+                let node_id = match self.add_node(chain.genesis_hash, node.details.clone()) {
+                    AddNodeResult::NodeAddedToChain(details) => details.id,
+                    _ => continue,
+                };
+                // Nobody is subscribed to hear about the updates yet:
+                let mut feed = FeedMessageSerializer::new();
+                for payload in node.payloads() {
+                    self.update_node(node_id, payload, &mut feed, false, now);
+                }
+                if let Some(location) = &node.location {
+                    self.update_node_location(node_id, Some(Arc::new(location.clone())));
+                }
+            }
+        }
+        self.max_total_nodes = max_total_nodes;
+        self.max_chains = max_chains;
+    }
+
+    /// Update how long the node with the given network ID has been up for. Return `false` if
+    /// the node was not found, or if the ID now belongs to a node with another network ID (the
+    /// uptime is looked up asynchronously, so the node may have been replaced in the meantime).
+    pub fn update_node_uptime(
+        &mut self,
+        NodeId(chain_id, chain_node_id): NodeId,
+        network_id: &str,
+        uptime: Uptime,
+    ) -> bool {
+        if let Some(chain) = self.chains.get_mut(chain_id) {
+            chain.update_node_uptime(chain_node_id, network_id, uptime)
+        } else {
+            false
+        }
+    }
 }
 
 /// When we ask for a chain, we get this struct back. This ensures that we have
@@ -254,6 +531,9 @@ pub struct StateChain<'a> {
 }
 
 impl<'a> StateChain<'a> {
+    pub fn is_pinned(&self) -> bool {
+        self.chain.is_pinned()
+    }
     pub fn label(&self) -> &'a str {
         self.chain.label()
     }
@@ -272,22 +552,58 @@ impl<'a> StateChain<'a> {
     pub fn average_block_time(&self) -> Option<u64> {
         self.chain.average_block_time()
     }
+    pub fn block_time_percentiles(&self) -> Option<(u64, u64)> {
+        self.chain.block_time_percentiles()
+    }
     pub fn finalized_block(&self) -> &'a Block {
         self.chain.finalized_block()
     }
+    pub fn max_claimed_height(&self) -> BlockNumber {
+        self.chain.max_claimed_height()
+    }
     pub fn nodes_slice(&self) -> &[Option<Node>] {
         self.chain.nodes_slice()
     }
     pub fn stats(&self) -> &ChainStats {
         self.chain.stats()
     }
+    pub fn peer_count_histogram(&self) -> &'a [((u64, Option<u64>), u64)] {
+        self.chain.peer_count_histogram()
+    }
+    pub fn write_recent_blocks(&self, feed: &mut FeedMessageSerializer) {
+        self.chain.write_recent_blocks(feed)
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::feed_message::{self, FeedMessage};
+    use crate::state::QualityScoreWeights;
+    use common::node_message::{Finalized, SystemInterval};
     use common::node_types::NetworkId;
 
+    const DEFAULT_STALE_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+    const DEFAULT_STATS_INTERVAL: Duration = Duration::from_secs(5);
+
+    fn options() -> StateOptions {
+        StateOptions {
+            max_third_party_nodes: 1000,
+            empty_chain_ttl: None,
+            max_chains: None,
+            max_total_nodes: None,
+            chain: ChainOptions {
+                best_block_coalesce_interval: None,
+                quality_score_weights: Some(QualityScoreWeights::default()),
+                max_recent_blocks: 0,
+                node_update_interval: None,
+                imported_block_interval: None,
+                stale_timeout: DEFAULT_STALE_TIMEOUT,
+                stats_interval: DEFAULT_STATS_INTERVAL,
+            },
+        }
+    }
+
     fn node(name: &str, chain: &str) -> NodeDetails {
         NodeDetails {
             chain: chain.into(),
@@ -302,12 +618,57 @@ mod test {
             startup_time: None,
             sysinfo: None,
             ip: None,
+            shard: None,
         }
     }
 
+    /// Split the messages in a feed back up into the action and payload of each one.
+    fn decode(feed: FeedMessageSerializer) -> Vec<(u8, serde_json::Value)> {
+        feed.into_finalized()
+            .map(|bytes| feed_message::decode_finalized(&bytes).unwrap())
+            .unwrap_or_default()
+    }
+
+    /// Return the payload of every message of the given type in a feed.
+    fn payloads<M: FeedMessage>(feed: FeedMessageSerializer) -> Vec<serde_json::Value> {
+        decode(feed)
+            .into_iter()
+            .filter(|(action, _)| *action == M::ACTION)
+            .map(|(_, payload)| payload)
+            .collect()
+    }
+
+    /// The time that it will be once `millis` have passed since `now`.
+    fn after(now: Now, millis: u64) -> Now {
+        Now {
+            instant: now.instant + Duration::from_millis(millis),
+            timestamp: now.timestamp + millis,
+        }
+    }
+
+    #[test]
+    fn nodes_that_connect_again_take_on_their_new_details() {
+        let mut state = State::new(None, None, options());
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let node_id = state
+            .add_node(genesis_hash, node("A", "Chain One"))
+            .unwrap_id();
+
+        // Nothing has changed:
+        assert!(!state.update_node_details(node_id, node("A", "Chain One")));
+
+        // The name has changed, but the node is still counted under the same chain label:
+        assert!(state.update_node_details(node_id, node("B", "Chain Two")));
+        let chain = state.get_chain_by_node_id(node_id).unwrap();
+        let details = chain.nodes_slice()[0].as_ref().unwrap().details();
+        assert_eq!(&*details.name, "B");
+        assert_eq!(&*details.chain, "Chain One");
+        assert_eq!(chain.label(), "Chain One");
+    }
+
     #[test]
     fn adding_a_node_returns_expected_response() {
-        let mut state = State::new(None, 1000);
+        let mut state = State::new(None, None, options());
 
         let chain1_genesis = BlockHash::from_low_u64_be(1);
 
@@ -315,7 +676,10 @@ mod test {
 
         let add_node_result = match add_result {
             AddNodeResult::ChainOnDenyList => panic!("Chain not on deny list"),
+            AddNodeResult::ChainNotAllowlisted => panic!("Chain not excluded by the allow list"),
             AddNodeResult::ChainOverQuota => panic!("Chain not Overquota"),
+            AddNodeResult::TooManyChains => panic!("Not too many chains"),
+            AddNodeResult::GlobalQuota => panic!("Not over the global quota"),
             AddNodeResult::NodeAddedToChain(details) => details,
         };
 
@@ -329,7 +693,10 @@ mod test {
 
         let add_node_result = match add_result {
             AddNodeResult::ChainOnDenyList => panic!("Chain not on deny list"),
+            AddNodeResult::ChainNotAllowlisted => panic!("Chain not excluded by the allow list"),
             AddNodeResult::ChainOverQuota => panic!("Chain not Overquota"),
+            AddNodeResult::TooManyChains => panic!("Not too many chains"),
+            AddNodeResult::GlobalQuota => panic!("Not over the global quota"),
             AddNodeResult::NodeAddedToChain(details) => details,
         };
 
@@ -340,9 +707,126 @@ mod test {
         assert_eq!(add_node_result.has_chain_label_changed, false);
     }
 
+    #[test]
+    fn denylist_can_be_replaced() {
+        let mut state = State::new(vec!["Chain One".to_owned()], None, options());
+
+        let add_result = state.add_node(BlockHash::from_low_u64_be(1), node("A", "Chain One"));
+        assert!(matches!(add_result, AddNodeResult::ChainOnDenyList));
+        let add_result = state.add_node(BlockHash::from_low_u64_be(2), node("A", "Chain Two"));
+        let node_id = match add_result {
+            AddNodeResult::NodeAddedToChain(details) => details.id,
+            _ => panic!("Node should have been added"),
+        };
+
+        // Denying the chain of a connected node hands back its ID, and no new nodes are added:
+        let denied_nodes = state.set_denylist(["Chain Two".to_owned()].into_iter().collect());
+        assert_eq!(denied_nodes, vec![node_id]);
+        let add_result = state.add_node(BlockHash::from_low_u64_be(2), node("B", "Chain Two"));
+        assert!(matches!(add_result, AddNodeResult::ChainOnDenyList));
+
+        // Chains that are no longer denied can be added again:
+        let add_result = state.add_node(BlockHash::from_low_u64_be(1), node("A", "Chain One"));
+        assert!(matches!(add_result, AddNodeResult::NodeAddedToChain(_)));
+    }
+
+    #[test]
+    fn only_allowlisted_chains_can_be_added() {
+        let mut state = State::new(
+            vec!["Chain Two".to_owned()],
+            vec!["Chain One".to_owned(), "Chain Two".to_owned()],
+            options(),
+        );
+
+        let add_result = state.add_node(BlockHash::from_low_u64_be(1), node("A", "Chain One"));
+        assert!(matches!(add_result, AddNodeResult::NodeAddedToChain(_)));
+
+        // The denylist takes precedence over the allowlist:
+        let add_result = state.add_node(BlockHash::from_low_u64_be(2), node("A", "Chain Two"));
+        assert!(matches!(add_result, AddNodeResult::ChainOnDenyList));
+
+        let add_result = state.add_node(BlockHash::from_low_u64_be(3), node("A", "Chain Three"));
+        assert!(matches!(add_result, AddNodeResult::ChainNotAllowlisted));
+
+        // Matching is case sensitive:
+        let add_result = state.add_node(BlockHash::from_low_u64_be(4), node("A", "chain one"));
+        assert!(matches!(add_result, AddNodeResult::ChainNotAllowlisted));
+    }
+
+    #[test]
+    fn nodes_are_refused_beyond_max_total_nodes() {
+        let max_total_nodes = 5;
+        let mut state = State::new(
+            None,
+            None,
+            StateOptions {
+                max_total_nodes: Some(max_total_nodes),
+                ..options()
+            },
+        );
+
+        // Spread the nodes across a few chains:
+        let mut node_ids = Vec::new();
+        for n in 0..max_total_nodes as u64 {
+            let genesis_hash = BlockHash::from_low_u64_be(n % 3 + 1);
+            let add_result = state.add_node(genesis_hash, node("A", "Chain"));
+            node_ids.push(add_result.unwrap_id());
+        }
+
+        // One node too many, whether it's on a chain we know about or a new one:
+        let add_result = state.add_node(BlockHash::from_low_u64_be(1), node("B", "Chain"));
+        assert!(matches!(add_result, AddNodeResult::GlobalQuota));
+        let new_genesis = BlockHash::from_low_u64_be(4);
+        let add_result = state.add_node(new_genesis, node("B", "Chain"));
+        assert!(matches!(add_result, AddNodeResult::GlobalQuota));
+        assert!(state.get_chain_by_genesis_hash(&new_genesis).is_none());
+
+        // Removing a node makes room for another:
+        state.remove_node(node_ids[0]).expect("node exists");
+        let add_result = state.add_node(new_genesis, node("B", "Chain"));
+        assert!(matches!(add_result, AddNodeResult::NodeAddedToChain(_)));
+        let add_result = state.add_node(new_genesis, node("C", "Chain"));
+        assert!(matches!(add_result, AddNodeResult::GlobalQuota));
+    }
+
+    #[test]
+    fn nodes_on_new_chains_are_refused_beyond_max_chains() {
+        let max_chains = 3;
+        let mut state = State::new(
+            None,
+            None,
+            StateOptions {
+                max_chains: Some(max_chains),
+                ..options()
+            },
+        );
+
+        for n in 1..=max_chains as u64 {
+            let add_result = state.add_node(BlockHash::from_low_u64_be(n), node("A", "Chain"));
+            assert!(matches!(add_result, AddNodeResult::NodeAddedToChain(_)));
+        }
+
+        // One chain too many:
+        let too_many_genesis = BlockHash::from_low_u64_be(max_chains as u64 + 1);
+        let add_result = state.add_node(too_many_genesis, node("A", "Chain"));
+        assert!(matches!(add_result, AddNodeResult::TooManyChains));
+        assert!(state.get_chain_by_genesis_hash(&too_many_genesis).is_none());
+
+        // Existing chains still accept nodes:
+        let add_result = state.add_node(BlockHash::from_low_u64_be(1), node("B", "Chain"));
+        assert!(matches!(add_result, AddNodeResult::NodeAddedToChain(_)));
+        assert_eq!(
+            state
+                .get_chain_by_genesis_hash(&BlockHash::from_low_u64_be(1))
+                .unwrap()
+                .node_count(),
+            2
+        );
+    }
+
     #[test]
     fn adding_and_removing_nodes_updates_chain_label_mapping() {
-        let mut state = State::new(None, 1000);
+        let mut state = State::new(None, None, options());
 
         let chain1_genesis = BlockHash::from_low_u64_be(1);
         let node_id0 = state
@@ -400,9 +884,101 @@ mod test {
         assert!(state.get_chain_by_genesis_hash(&chain1_genesis).is_some());
     }
 
+    #[test]
+    fn uptime_is_only_updated_for_the_node_that_it_was_looked_up_for() {
+        let mut state = State::new(None, None, options());
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let uptime = Uptime {
+            previous_secs: 10,
+            connected_at: 0,
+        };
+        let with_network_id = |network_id: &str| NodeDetails {
+            network_id: NetworkId::from(network_id).unwrap(),
+            ..node("A", "Chain One")
+        };
+
+        // The uptime of the first node is looked up, but it's replaced before it comes back:
+        let node_id = state
+            .add_node(genesis_hash, with_network_id("first"))
+            .unwrap_id();
+        state.remove_node(node_id).expect("Removal OK");
+        let new_node_id = state
+            .add_node(genesis_hash, with_network_id("second"))
+            .unwrap_id();
+        assert_eq!(node_id, new_node_id);
+
+        assert!(!state.update_node_uptime(node_id, "first", uptime));
+        assert!(state.update_node_uptime(node_id, "second", uptime));
+        let chain = state.get_chain_by_genesis_hash(&genesis_hash).unwrap();
+        let node = chain.nodes_slice()[0].as_ref().unwrap();
+        assert_eq!(node.uptime(), Some(uptime));
+    }
+
+    #[test]
+    fn empty_chains_dont_count_towards_max_chains() {
+        let mut state = State::new(
+            None,
+            None,
+            StateOptions {
+                max_chains: Some(1),
+                empty_chain_ttl: Some(Duration::from_secs(60)),
+                ..options()
+            },
+        );
+
+        let node_id = state
+            .add_node(BlockHash::from_low_u64_be(1), node("A", "Chain One"))
+            .unwrap_id();
+        let add_result = state.add_node(BlockHash::from_low_u64_be(2), node("A", "Chain Two"));
+        assert!(matches!(add_result, AddNodeResult::TooManyChains));
+
+        // Once the first chain is empty, it's kept around but doesn't stop another being added:
+        state.remove_node(node_id).expect("Removal OK");
+        assert!(state
+            .get_chain_by_genesis_hash(&BlockHash::from_low_u64_be(1))
+            .is_some());
+        let add_result = state.add_node(BlockHash::from_low_u64_be(2), node("A", "Chain Two"));
+        assert!(matches!(add_result, AddNodeResult::NodeAddedToChain(_)));
+    }
+
+    #[test]
+    fn empty_chains_are_kept_until_ttl_expires() {
+        let mut state = State::new(
+            None,
+            None,
+            StateOptions {
+                empty_chain_ttl: Some(Duration::from_secs(60)),
+                ..options()
+            },
+        );
+
+        let genesis = BlockHash::from_low_u64_be(1);
+        let node_id = state.add_node(genesis, node("A", "Chain One")).unwrap_id();
+        let removed = state.remove_node(node_id).expect("Removal OK");
+        assert_eq!(removed.chain_node_count, 0);
+        assert!(!removed.chain_removed);
+
+        // The empty chain sticks around until the TTL has passed:
+        let chain = state
+            .get_chain_by_genesis_hash(&genesis)
+            .expect("Empty chain should be kept");
+        assert_eq!(chain.node_count(), 0);
+        assert!(state.remove_expired_empty_chains(Instant::now()).is_empty());
+
+        // A node coming back means the chain won't be removed:
+        let node_id = state.add_node(genesis, node("A", "Chain One")).unwrap_id();
+        let later = Instant::now() + Duration::from_secs(120);
+        assert!(state.remove_expired_empty_chains(later).is_empty());
+
+        // But once it's empty again and the TTL has passed, it's removed:
+        state.remove_node(node_id).expect("Removal OK");
+        assert_eq!(state.remove_expired_empty_chains(later), vec![genesis]);
+        assert!(state.get_chain_by_genesis_hash(&genesis).is_none());
+    }
+
     #[test]
     fn chain_removed_when_last_node_is() {
-        let mut state = State::new(None, 1000);
+        let mut state = State::new(None, None, options());
 
         let chain1_genesis = BlockHash::from_low_u64_be(1);
         let node_id = state
@@ -417,4 +993,1054 @@ mod test {
         assert!(state.get_chain_by_genesis_hash(&chain1_genesis).is_none());
         assert_eq!(state.iter_chains().count(), 0);
     }
+
+    #[test]
+    fn pinned_chains_are_kept_when_empty_and_keep_their_label() {
+        let mut state = State::new(None, None, options());
+
+        // Pinned chains exist before any nodes connect to them:
+        let genesis = BlockHash::from_low_u64_be(1);
+        state.pin_chain(genesis, "Pinned Chain");
+        let chain = state.get_chain_by_genesis_hash(&genesis).unwrap();
+        assert_eq!(chain.label(), "Pinned Chain");
+        assert_eq!(chain.node_count(), 0);
+        assert!(state.is_chain_pinned(&genesis));
+
+        // Nodes don't rename them:
+        let added = state.add_node(genesis, node("A", "Chain One"));
+        let node_id = match added {
+            AddNodeResult::NodeAddedToChain(details) => {
+                assert_eq!(details.new_chain_label, "Pinned Chain");
+                assert!(!details.has_chain_label_changed);
+                details.id
+            }
+            _ => panic!("node should be added"),
+        };
+
+        // And they're kept once the last node goes:
+        let removed = state.remove_node(node_id).expect("Removal OK");
+        assert!(!removed.chain_removed);
+        assert_eq!(removed.new_chain_label.as_ref(), "Pinned Chain");
+        assert!(state.get_chain_by_genesis_hash(&genesis).is_some());
+        assert!(!state.is_chain_pinned(&BlockHash::from_low_u64_be(2)));
+    }
+
+    /// Return the heights of any `BestBlock` messages in the feed.
+    fn best_block_heights(feed: FeedMessageSerializer) -> Vec<u64> {
+        payloads::<feed_message::BestBlock>(feed)
+            .into_iter()
+            .map(|payload| payload[0].as_u64().unwrap())
+            .collect()
+    }
+
+    fn block_import(height: u64) -> Payload {
+        Payload::BlockImport(Block {
+            hash: BlockHash::from_low_u64_be(height),
+            height,
+        })
+    }
+
+    #[test]
+    fn best_blocks_are_coalesced_and_latest_is_flushed() {
+        let mut state = State::new(
+            None,
+            None,
+            StateOptions {
+                chain: ChainOptions {
+                    best_block_coalesce_interval: Some(Duration::from_secs(60)),
+                    ..options().chain
+                },
+                ..options()
+            },
+        );
+
+        let chain1_genesis = BlockHash::from_low_u64_be(1);
+        let node_id = state
+            .add_node(chain1_genesis, node("A", "Chain One"))
+            .unwrap_id();
+
+        // The first best block goes out immediately:
+        let now = Now::current();
+        let mut feed = FeedMessageSerializer::new();
+        state.update_node(node_id, block_import(1), &mut feed, false, now);
+        assert_eq!(best_block_heights(feed), vec![1]);
+
+        // Subsequent ones within the interval are held back:
+        let mut feed = FeedMessageSerializer::new();
+        state.update_node(
+            node_id,
+            block_import(2),
+            &mut feed,
+            false,
+            after(now, 1_000),
+        );
+        state.update_node(
+            node_id,
+            block_import(3),
+            &mut feed,
+            false,
+            after(now, 2_000),
+        );
+        assert!(best_block_heights(feed).is_empty());
+
+        // Nothing to flush until the interval has passed:
+        assert!(state
+            .flush_coalesced_best_blocks(after(now, 59_000))
+            .is_empty());
+    }
+
+    #[test]
+    fn best_blocks_are_not_coalesced_by_default() {
+        let mut state = State::new(None, None, options());
+
+        let chain1_genesis = BlockHash::from_low_u64_be(1);
+        let node_id = state
+            .add_node(chain1_genesis, node("A", "Chain One"))
+            .unwrap_id();
+
+        let now = Now::current();
+        let mut feed = FeedMessageSerializer::new();
+        state.update_node(node_id, block_import(1), &mut feed, false, now);
+        state.update_node(node_id, block_import(2), &mut feed, false, now);
+        assert_eq!(best_block_heights(feed), vec![1, 2]);
+        assert!(state.flush_coalesced_best_blocks(now).is_empty());
+    }
+
+    #[test]
+    fn coalesced_best_block_is_flushed_after_interval() {
+        let mut state = State::new(
+            None,
+            None,
+            StateOptions {
+                chain: ChainOptions {
+                    best_block_coalesce_interval: Some(Duration::from_secs(60)),
+                    ..options().chain
+                },
+                ..options()
+            },
+        );
+
+        let chain1_genesis = BlockHash::from_low_u64_be(1);
+        let node_id = state
+            .add_node(chain1_genesis, node("A", "Chain One"))
+            .unwrap_id();
+
+        let now = Now::current();
+        let mut feed = FeedMessageSerializer::new();
+        state.update_node(node_id, block_import(1), &mut feed, false, now);
+        state.update_node(
+            node_id,
+            block_import(2),
+            &mut feed,
+            false,
+            after(now, 1_000),
+        );
+        state.update_node(
+            node_id,
+            block_import(3),
+            &mut feed,
+            false,
+            after(now, 2_000),
+        );
+        assert_eq!(best_block_heights(feed), vec![1]);
+
+        // The latest best block is sent out once the interval has elapsed:
+        let later = after(now, 60_000);
+        let flushed = state.flush_coalesced_best_blocks(later);
+        assert_eq!(flushed.len(), 1);
+        let (genesis_hash, feed) = flushed.into_iter().next().unwrap();
+        assert_eq!(genesis_hash, chain1_genesis);
+        assert_eq!(best_block_heights(feed), vec![3]);
+
+        // And only once:
+        assert!(state.flush_coalesced_best_blocks(later).is_empty());
+    }
+
+    /// Return the heights of any `BestFinalized` messages in the feed.
+    fn best_finalized_heights(feed: FeedMessageSerializer) -> Vec<u64> {
+        payloads::<feed_message::BestFinalized>(feed)
+            .into_iter()
+            .map(|payload| payload[0].as_u64().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn coalescing_best_blocks_leaves_imports_and_finality_alone() {
+        let mut state = State::new(
+            None,
+            None,
+            StateOptions {
+                chain: ChainOptions {
+                    best_block_coalesce_interval: Some(Duration::from_secs(60)),
+                    ..options().chain
+                },
+                ..options()
+            },
+        );
+
+        let chain1_genesis = BlockHash::from_low_u64_be(1);
+        let node_id = state
+            .add_node(chain1_genesis, node("A", "Chain One"))
+            .unwrap_id();
+
+        // Every block that the node imports is still sent out (they're imported a second apart,
+        // so that nodes aren't throttled for importing them too quickly):
+        let now = Now::current();
+        let mut feed = FeedMessageSerializer::new();
+        for height in 1..=3 {
+            let at = after(now, height * 1_000);
+            state.update_node(node_id, block_import(height), &mut feed, false, at);
+        }
+        assert_eq!(imported_block_heights(feed), vec![1, 2, 3]);
+
+        // As is every new finalized block:
+        let mut feed = FeedMessageSerializer::new();
+        state.update_node(
+            node_id,
+            notify_finalized(2),
+            &mut feed,
+            false,
+            after(now, 4_000),
+        );
+        state.update_node(
+            node_id,
+            notify_finalized(4),
+            &mut feed,
+            false,
+            after(now, 5_000),
+        );
+        assert_eq!(best_finalized_heights(feed), vec![2, 4]);
+    }
+
+    fn peers(peers: u64) -> Payload {
+        Payload::SystemInterval(SystemInterval {
+            peers: Some(peers),
+            txcount: None,
+            bandwidth_upload: None,
+            bandwidth_download: None,
+            finalized_height: None,
+            finalized_hash: None,
+            block: None,
+            used_state_cache_size: None,
+            database_size: None,
+            error_count: None,
+            warning_count: None,
+            major_syncing: None,
+        })
+    }
+
+    /// Return the peer counts in any `NodeStatsUpdate` messages in the feed.
+    fn peer_count_updates(feed: FeedMessageSerializer) -> Vec<u64> {
+        payloads::<feed_message::NodeStatsUpdate<'_>>(feed)
+            .into_iter()
+            .map(|payload| payload[1][0].as_u64().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn node_updates_are_throttled_and_latest_is_flushed() {
+        let mut state = State::new(
+            None,
+            None,
+            StateOptions {
+                chain: ChainOptions {
+                    node_update_interval: Some(Duration::from_secs(60)),
+                    ..options().chain
+                },
+                ..options()
+            },
+        );
+
+        let chain1_genesis = BlockHash::from_low_u64_be(1);
+        let node_id = state
+            .add_node(chain1_genesis, node("A", "Chain One"))
+            .unwrap_id();
+
+        // The first update goes out immediately, and later ones are held back:
+        let now = Now::current();
+        let mut feed = FeedMessageSerializer::new();
+        state.update_node(node_id, peers(1), &mut feed, false, now);
+        state.update_node(node_id, peers(2), &mut feed, false, after(now, 1_000));
+        state.update_node(node_id, peers(3), &mut feed, false, after(now, 2_000));
+        assert_eq!(peer_count_updates(feed), vec![1]);
+
+        // Block imports are never held back:
+        let mut feed = FeedMessageSerializer::new();
+        state.update_node(
+            node_id,
+            block_import(1),
+            &mut feed,
+            false,
+            after(now, 3_000),
+        );
+        assert_eq!(best_block_heights(feed), vec![1]);
+
+        // The latest stats are sent out once the interval has elapsed:
+        let later = after(now, 60_000).instant;
+        let flushed = state.flush_throttled_node_updates(later);
+        assert_eq!(flushed.len(), 1);
+        let (genesis_hash, feed) = flushed.into_iter().next().unwrap();
+        assert_eq!(genesis_hash, chain1_genesis);
+        assert_eq!(peer_count_updates(feed), vec![3]);
+
+        // And only once:
+        assert!(state.flush_throttled_node_updates(later).is_empty());
+    }
+
+    fn imported_block_heights(feed: FeedMessageSerializer) -> Vec<u64> {
+        payloads::<feed_message::ImportedBlock<'_>>(feed)
+            .into_iter()
+            .map(|payload| payload[1][0].as_u64().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn imported_blocks_are_coalesced_and_latest_is_flushed() {
+        let mut state = State::new(
+            None,
+            None,
+            StateOptions {
+                chain: ChainOptions {
+                    imported_block_interval: Some(Duration::from_secs(60)),
+                    ..options().chain
+                },
+                ..options()
+            },
+        );
+
+        let chain1_genesis = BlockHash::from_low_u64_be(1);
+        let node_id = state
+            .add_node(chain1_genesis, node("A", "Chain One"))
+            .unwrap_id();
+
+        // The first imported block goes out immediately, and the next is held back. Blocks are
+        // imported a few seconds apart, so that nodes aren't throttled for importing them too
+        // quickly:
+        let now = Now::current();
+        let mut feed = FeedMessageSerializer::new();
+        state.update_node(node_id, block_import(1), &mut feed, false, now);
+        state.update_node(
+            node_id,
+            block_import(2),
+            &mut feed,
+            false,
+            after(now, 5_000),
+        );
+        assert_eq!(imported_block_heights(feed), vec![1]);
+
+        // The latest imported block is sent out once the interval has elapsed:
+        let later = after(now, 60_000).instant;
+        let flushed = state.flush_throttled_node_updates(later);
+        assert_eq!(flushed.len(), 1);
+        let (_, feed) = flushed.into_iter().next().unwrap();
+        assert_eq!(imported_block_heights(feed), vec![2]);
+
+        // And only once:
+        assert!(state.flush_throttled_node_updates(later).is_empty());
+    }
+
+    fn block_events(feed: FeedMessageSerializer) -> Vec<(u64, u64)> {
+        decode(feed)
+            .into_iter()
+            .map(|(action, payload)| (action as u64, payload[0].as_u64().unwrap()))
+            .collect()
+    }
+
+    fn notify_finalized(height: u64) -> Payload {
+        Payload::NotifyFinalized(Finalized {
+            hash: BlockHash::from_low_u64_be(height),
+            height: height.to_string().into(),
+        })
+    }
+
+    #[test]
+    fn recent_blocks_are_replayed_oldest_first() {
+        let mut state = State::new(
+            None,
+            None,
+            StateOptions {
+                chain: ChainOptions {
+                    max_recent_blocks: 3,
+                    ..options().chain
+                },
+                ..options()
+            },
+        );
+
+        let chain1_genesis = BlockHash::from_low_u64_be(1);
+        let node_id = state
+            .add_node(chain1_genesis, node("A", "Chain One"))
+            .unwrap_id();
+
+        let mut feed = FeedMessageSerializer::new();
+        state.update_node(node_id, block_import(1), &mut feed, false, Now::current());
+        state.update_node(node_id, block_import(2), &mut feed, false, Now::current());
+        state.update_node(
+            node_id,
+            notify_finalized(1),
+            &mut feed,
+            false,
+            Now::current(),
+        );
+        state.update_node(node_id, block_import(3), &mut feed, false, Now::current());
+
+        // Only the 3 most recent events are kept, in the order they happened:
+        let mut feed = FeedMessageSerializer::new();
+        state
+            .get_chain_by_genesis_hash(&chain1_genesis)
+            .unwrap()
+            .write_recent_blocks(&mut feed);
+        assert_eq!(block_events(feed), vec![(1, 2), (2, 1), (1, 3)]);
+    }
+
+    #[test]
+    fn recent_blocks_not_kept_by_default() {
+        let mut state = State::new(None, None, options());
+
+        let chain1_genesis = BlockHash::from_low_u64_be(1);
+        let node_id = state
+            .add_node(chain1_genesis, node("A", "Chain One"))
+            .unwrap_id();
+
+        let mut feed = FeedMessageSerializer::new();
+        state.update_node(node_id, block_import(1), &mut feed, false, Now::current());
+
+        let mut feed = FeedMessageSerializer::new();
+        state
+            .get_chain_by_genesis_hash(&chain1_genesis)
+            .unwrap()
+            .write_recent_blocks(&mut feed);
+        assert!(block_events(feed).is_empty());
+    }
+
+    #[test]
+    fn recent_blocks_beyond_a_wound_back_best_block_are_forgotten() {
+        let mut state = State::new(
+            None,
+            None,
+            StateOptions {
+                chain: ChainOptions {
+                    max_recent_blocks: 10,
+                    stale_timeout: Duration::from_secs(60),
+                    ..options().chain
+                },
+                ..options()
+            },
+        );
+
+        let chain1_genesis = BlockHash::from_low_u64_be(1);
+        let node_a = state
+            .add_node(chain1_genesis, node("A", "Chain One"))
+            .unwrap_id();
+
+        let now = Now::current();
+        let mut feed = FeedMessageSerializer::new();
+        state.update_node(node_a, block_import(1), &mut feed, false, now);
+        state.update_node(node_a, block_import(2), &mut feed, false, now);
+        state.update_node(node_a, notify_finalized(1), &mut feed, false, now);
+        state.update_node(node_a, block_import(3), &mut feed, false, now);
+
+        // Node A goes stale, and so the best block is wound back to that of node B, which
+        // hasn't imported anything yet. Node B then imports block 1:
+        let node_b = state
+            .add_node(chain1_genesis, node("B", "Chain One"))
+            .unwrap_id();
+        state.update_node(
+            node_b,
+            block_import(1),
+            &mut feed,
+            false,
+            after(now, 120_000),
+        );
+
+        // Only what happened after the best block was wound back is replayed:
+        let mut feed = FeedMessageSerializer::new();
+        state
+            .get_chain_by_genesis_hash(&chain1_genesis)
+            .unwrap()
+            .write_recent_blocks(&mut feed);
+        assert_eq!(block_events(feed), vec![(1, 1)]);
+    }
+
+    /// Return the heights of any `MaxClaimedBlock` messages in the feed.
+    fn max_claimed_heights(feed: FeedMessageSerializer) -> Vec<u64> {
+        payloads::<feed_message::MaxClaimedBlock>(feed)
+            .into_iter()
+            .map(|payload| payload.as_u64().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn max_claimed_height_is_sent_when_it_increases() {
+        let mut state = State::new(None, None, options());
+
+        let chain1_genesis = BlockHash::from_low_u64_be(1);
+        let node_a = state
+            .add_node(chain1_genesis, node("A", "Chain One"))
+            .unwrap_id();
+        let node_b = state
+            .add_node(chain1_genesis, node("B", "Chain One"))
+            .unwrap_id();
+
+        let mut feed = FeedMessageSerializer::new();
+        state.update_node(node_a, block_import(5), &mut feed, false, Now::current());
+        state.update_node(node_b, block_import(3), &mut feed, false, Now::current());
+        state.update_node(node_b, block_import(5), &mut feed, false, Now::current());
+        state.update_node(node_b, block_import(7), &mut feed, false, Now::current());
+        assert_eq!(max_claimed_heights(feed), vec![5, 7]);
+
+        let chain = state.get_chain_by_genesis_hash(&chain1_genesis).unwrap();
+        assert_eq!(chain.max_claimed_height(), 7);
+    }
+
+    fn log_counts(errors: u64, warnings: u64) -> Payload {
+        Payload::SystemInterval(SystemInterval {
+            peers: None,
+            txcount: None,
+            bandwidth_upload: None,
+            bandwidth_download: None,
+            finalized_height: None,
+            finalized_hash: None,
+            block: None,
+            used_state_cache_size: None,
+            database_size: None,
+            error_count: Some(errors),
+            warning_count: Some(warnings),
+            major_syncing: None,
+        })
+    }
+
+    /// Return the error and warning counts in any `NodeLogCountsUpdate` messages in the feed.
+    fn log_count_updates(feed: FeedMessageSerializer) -> Vec<(u64, u64)> {
+        payloads::<feed_message::NodeLogCountsUpdate>(feed)
+            .into_iter()
+            .map(|payload| (payload[1].as_u64().unwrap(), payload[2].as_u64().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn log_counts_are_sent_when_they_increase_and_survive_restarts() {
+        let mut state = State::new(None, None, options());
+
+        let chain1_genesis = BlockHash::from_low_u64_be(1);
+        let node_id = state
+            .add_node(chain1_genesis, node("A", "Chain One"))
+            .unwrap_id();
+
+        let mut feed = FeedMessageSerializer::new();
+        state.update_node(node_id, log_counts(2, 5), &mut feed, false, Now::current());
+        // No change, so nothing to send:
+        state.update_node(node_id, log_counts(2, 5), &mut feed, false, Now::current());
+        state.update_node(node_id, log_counts(3, 5), &mut feed, false, Now::current());
+        // The counters went down, so the node has restarted and we count on from 0:
+        state.update_node(node_id, log_counts(0, 1), &mut feed, false, Now::current());
+        assert_eq!(log_count_updates(feed), vec![(2, 5), (3, 5), (3, 6)]);
+    }
+
+    fn major_syncing(syncing: Option<bool>) -> Payload {
+        Payload::SystemInterval(SystemInterval {
+            peers: None,
+            txcount: None,
+            bandwidth_upload: None,
+            bandwidth_download: None,
+            finalized_height: None,
+            finalized_hash: None,
+            block: None,
+            used_state_cache_size: None,
+            database_size: None,
+            error_count: None,
+            warning_count: None,
+            major_syncing: syncing,
+        })
+    }
+
+    /// Return the flags in any `NodeSyncState` messages in the feed.
+    fn sync_state_updates(feed: FeedMessageSerializer) -> Vec<bool> {
+        payloads::<feed_message::NodeSyncState>(feed)
+            .into_iter()
+            .map(|payload| payload[1].as_bool().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn sync_state_is_sent_when_it_changes() {
+        let mut state = State::new(None, None, options());
+
+        let chain1_genesis = BlockHash::from_low_u64_be(1);
+        let node_id = state
+            .add_node(chain1_genesis, node("A", "Chain One"))
+            .unwrap_id();
+
+        let mut feed = FeedMessageSerializer::new();
+        // Nodes that don't report whether they're syncing don't send anything:
+        state.update_node(
+            node_id,
+            major_syncing(None),
+            &mut feed,
+            false,
+            Now::current(),
+        );
+        state.update_node(
+            node_id,
+            major_syncing(Some(true)),
+            &mut feed,
+            false,
+            Now::current(),
+        );
+        // No change, so nothing to send:
+        state.update_node(
+            node_id,
+            major_syncing(Some(true)),
+            &mut feed,
+            false,
+            Now::current(),
+        );
+        // Not reporting it doesn't change what we last knew:
+        state.update_node(
+            node_id,
+            major_syncing(None),
+            &mut feed,
+            false,
+            Now::current(),
+        );
+        state.update_node(
+            node_id,
+            major_syncing(Some(false)),
+            &mut feed,
+            false,
+            Now::current(),
+        );
+        assert_eq!(sync_state_updates(feed), vec![true, false]);
+    }
+
+    /// Return the lags in any `NodeFinalityLag` messages in the feed.
+    fn finality_lags(feed: FeedMessageSerializer) -> Vec<u64> {
+        payloads::<feed_message::NodeFinalityLag>(feed)
+            .into_iter()
+            .map(|payload| payload[1].as_u64().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn finality_lag_is_sent_when_it_changes_enough() {
+        let mut state = State::new(None, None, options());
+
+        let chain1_genesis = BlockHash::from_low_u64_be(1);
+        let node_id = state
+            .add_node(chain1_genesis, node("A", "Chain One"))
+            .unwrap_id();
+
+        let mut feed = FeedMessageSerializer::new();
+        // Nothing is finalized yet, so we don't know the lag:
+        state.update_node(node_id, block_import(10), &mut feed, false, Now::current());
+        state.update_node(
+            node_id,
+            notify_finalized(8),
+            &mut feed,
+            false,
+            Now::current(),
+        );
+        // Small changes aren't worth sending:
+        state.update_node(node_id, block_import(12), &mut feed, false, Now::current());
+        state.update_node(node_id, block_import(14), &mut feed, false, Now::current());
+        state.update_node(node_id, block_import(15), &mut feed, false, Now::current());
+        state.update_node(
+            node_id,
+            notify_finalized(15),
+            &mut feed,
+            false,
+            Now::current(),
+        );
+        assert_eq!(finality_lags(feed), vec![2, 7, 0]);
+    }
+
+    /// Return the IDs of any nodes in `StaleNode` messages in the feed.
+    fn stale_nodes(feed: FeedMessageSerializer) -> Vec<u64> {
+        payloads::<feed_message::StaleNode>(feed)
+            .into_iter()
+            .map(|payload| payload.as_u64().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn nodes_are_marked_stale_after_the_configured_timeout() {
+        let mut state = State::new(
+            None,
+            None,
+            StateOptions {
+                chain: ChainOptions {
+                    stale_timeout: Duration::from_secs(60),
+                    ..options().chain
+                },
+                ..options()
+            },
+        );
+
+        let chain1_genesis = BlockHash::from_low_u64_be(1);
+        let node_a = state
+            .add_node(chain1_genesis, node("A", "Chain One"))
+            .unwrap_id();
+        let node_b = state
+            .add_node(chain1_genesis, node("B", "Chain One"))
+            .unwrap_id();
+
+        // Node A imports block 1, and node B catches up with it a little later:
+        let now = Now::current();
+        let mut feed = FeedMessageSerializer::new();
+        state.update_node(node_a, block_import(1), &mut feed, false, now);
+        state.update_node(
+            node_b,
+            block_import(1),
+            &mut feed,
+            false,
+            after(now, 30_000),
+        );
+        assert_eq!(stale_nodes(feed), Vec::<u64>::new());
+
+        // Once the timeout has passed (but before the default timeout would have), the next block
+        // notices that node A went stale, while node B heard about a block recently enough:
+        let mut feed = FeedMessageSerializer::new();
+        state.update_node(
+            node_b,
+            block_import(2),
+            &mut feed,
+            false,
+            after(now, 70_000),
+        );
+        assert_eq!(stale_nodes(feed), vec![0]);
+
+        // And node A stays stale until it imports a new block:
+        let chain = state.get_chain_by_genesis_hash(&chain1_genesis).unwrap();
+        let is_stale = |id: usize| chain.nodes_slice()[id].as_ref().unwrap().stale();
+        assert!(is_stale(0));
+        assert!(!is_stale(1));
+    }
+
+    /// Return the number of `ChainStatsUpdate` messages in the feed.
+    fn chain_stats_updates(feed: FeedMessageSerializer) -> usize {
+        payloads::<feed_message::ChainStatsUpdate>(feed).len()
+    }
+
+    fn state_with_stats_interval(stats_interval: Duration) -> State {
+        State::new(
+            None,
+            None,
+            StateOptions {
+                chain: ChainOptions {
+                    stats_interval,
+                    ..options().chain
+                },
+                ..options()
+            },
+        )
+    }
+
+    #[test]
+    fn chain_stats_are_regenerated_at_the_configured_interval() {
+        let mut state = state_with_stats_interval(Duration::from_secs(60));
+
+        let chain1_genesis = BlockHash::from_low_u64_be(1);
+        let node_id = state
+            .add_node(chain1_genesis, node("A", "Chain One"))
+            .unwrap_id();
+
+        // The interval hasn't passed since the chain was created:
+        let now = Now::current();
+        let mut feed = FeedMessageSerializer::new();
+        state.update_node(node_id, block_import(1), &mut feed, false, now);
+        assert_eq!(chain_stats_updates(feed), 0);
+
+        // Once it has, the next block regenerates the stats:
+        let mut feed = FeedMessageSerializer::new();
+        state.update_node(
+            node_id,
+            block_import(2),
+            &mut feed,
+            false,
+            after(now, 60_000),
+        );
+        assert_eq!(chain_stats_updates(feed), 1);
+    }
+
+    #[test]
+    fn chain_stats_are_not_regenerated_before_the_configured_interval() {
+        let mut state = state_with_stats_interval(Duration::from_secs(60 * 60));
+
+        let chain1_genesis = BlockHash::from_low_u64_be(1);
+        let node_id = state
+            .add_node(chain1_genesis, node("A", "Chain One"))
+            .unwrap_id();
+
+        // Time passes and blocks arrive, but the stats aren't regenerated until the interval is up:
+        let now = Now::current();
+        let mut feed = FeedMessageSerializer::new();
+        for height in 1..=10 {
+            let at = after(now, height * 60_000);
+            state.update_node(node_id, block_import(height), &mut feed, false, at);
+        }
+        assert_eq!(chain_stats_updates(feed), 0);
+    }
+
+    /// Return the payload of each `PeerCountHistogram` message in the feed.
+    fn peer_count_histograms(
+        feed: FeedMessageSerializer,
+    ) -> Vec<(BlockHash, Vec<((u64, Option<u64>), u64)>)> {
+        payloads::<feed_message::PeerCountHistogram<'_>>(feed)
+            .into_iter()
+            .map(|payload| serde_json::from_value(payload).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn peer_count_histogram_reflects_the_peers_each_node_reported() {
+        let mut state = state_with_stats_interval(Duration::ZERO);
+
+        let chain1_genesis = BlockHash::from_low_u64_be(1);
+        let node_ids: Vec<_> = [0, 3, 3, 7, 30, 200]
+            .into_iter()
+            .enumerate()
+            .map(|(idx, node_peers)| {
+                let node_id = state
+                    .add_node(chain1_genesis, node(&format!("Node {idx}"), "Chain One"))
+                    .unwrap_id();
+                state.update_node(
+                    node_id,
+                    peers(node_peers),
+                    &mut FeedMessageSerializer::new(),
+                    false,
+                    Now::current(),
+                );
+                node_id
+            })
+            .collect();
+
+        let mut feed = FeedMessageSerializer::new();
+        state.update_node(
+            node_ids[0],
+            block_import(1),
+            &mut feed,
+            false,
+            Now::current(),
+        );
+        let expected = vec![
+            ((0, Some(1)), 1),
+            ((1, Some(5)), 2),
+            ((5, Some(10)), 1),
+            ((10, Some(25)), 0),
+            ((25, Some(50)), 1),
+            ((50, Some(100)), 0),
+            ((100, None), 1),
+        ];
+        assert_eq!(
+            peer_count_histograms(feed),
+            vec![(chain1_genesis, expected.clone())]
+        );
+        assert_eq!(
+            state
+                .get_chain_by_genesis_hash(&chain1_genesis)
+                .unwrap()
+                .peer_count_histogram(),
+            &expected[..]
+        );
+
+        // Nothing is sent if the distribution hasn't changed:
+        let mut feed = FeedMessageSerializer::new();
+        state.update_node(
+            node_ids[0],
+            block_import(2),
+            &mut feed,
+            false,
+            Now::current(),
+        );
+        assert!(peer_count_histograms(feed).is_empty());
+
+        // A node moving bucket is reflected in the next histogram:
+        state.update_node(
+            node_ids[1],
+            peers(60),
+            &mut FeedMessageSerializer::new(),
+            false,
+            Now::current(),
+        );
+        let mut feed = FeedMessageSerializer::new();
+        state.update_node(
+            node_ids[0],
+            block_import(3),
+            &mut feed,
+            false,
+            Now::current(),
+        );
+        let histograms = peer_count_histograms(feed);
+        assert_eq!(histograms.len(), 1);
+        assert_eq!(histograms[0].1[1], ((1, Some(5)), 1));
+        assert_eq!(histograms[0].1[5], ((50, Some(100)), 1));
+
+        // As is a node going away:
+        state.remove_node(node_ids[5]).expect("node exists");
+        let mut feed = FeedMessageSerializer::new();
+        state.update_node(
+            node_ids[0],
+            block_import(4),
+            &mut feed,
+            false,
+            Now::current(),
+        );
+        let histograms = peer_count_histograms(feed);
+        assert_eq!(histograms.len(), 1);
+        assert_eq!(histograms[0].1[6], ((100, None), 0));
+    }
+
+    #[test]
+    fn snapshot_written_by_version_1_can_be_loaded() {
+        // If this stops loading, the snapshot format has changed and STATE_SNAPSHOT_VERSION
+        // needs bumping (with a new fixture to go along with it):
+        let snapshot = StateSnapshot::from_json(include_bytes!("test_snapshot_v1.json"))
+            .expect("fixture should load");
+        let mut state = state_with_stats_interval(DEFAULT_STATS_INTERVAL);
+        state.load_snapshot(&snapshot);
+        assert_eq!(state.chain_count(), 2);
+
+        let chain = state
+            .get_chain_by_genesis_hash(&BlockHash::from_low_u64_be(1))
+            .unwrap();
+        assert_eq!(chain.label(), "Chain One");
+        assert_eq!(chain.node_count(), 2);
+        assert_eq!(chain.best_block().height, 10);
+        assert_eq!(chain.finalized_block().height, 8);
+
+        let chain = state
+            .get_chain_by_genesis_hash(&BlockHash::from_low_u64_be(2))
+            .unwrap();
+        let peer_counts: Vec<_> = chain
+            .nodes_slice()
+            .iter()
+            .flatten()
+            .map(|node| node.stats().peers)
+            .collect();
+        assert_eq!(peer_counts, vec![12]);
+    }
+
+    #[test]
+    fn snapshots_are_loaded_in_full_regardless_of_limits() {
+        let mut state = State::new(None, None, options());
+        for n in 1..=3 {
+            state
+                .add_node(BlockHash::from_low_u64_be(n), node("A", "Chain"))
+                .unwrap_id();
+        }
+
+        let mut restored = State::new(
+            None,
+            None,
+            StateOptions {
+                max_total_nodes: Some(1),
+                max_chains: Some(1),
+                ..options()
+            },
+        );
+        restored.load_snapshot(&state.snapshot());
+        assert_eq!(restored.chain_count(), 3);
+        let node_count: usize = restored.iter_chains().map(|chain| chain.node_count()).sum();
+        assert_eq!(node_count, 3);
+
+        // The limits still apply to nodes added afterwards:
+        let add_result = restored.add_node(BlockHash::from_low_u64_be(4), node("B", "Chain"));
+        assert!(matches!(add_result, AddNodeResult::GlobalQuota));
+    }
+
+    #[test]
+    fn state_can_be_restored_from_a_snapshot() {
+        let mut state = state_with_stats_interval(DEFAULT_STATS_INTERVAL);
+
+        let chain1_genesis = BlockHash::from_low_u64_be(1);
+        let chain2_genesis = BlockHash::from_low_u64_be(2);
+        let node_a = state
+            .add_node(chain1_genesis, node("A", "Chain One"))
+            .unwrap_id();
+        state
+            .add_node(chain1_genesis, node("B", "Chain One"))
+            .unwrap_id();
+        let node_c = state
+            .add_node(chain2_genesis, node("C", "Chain Two"))
+            .unwrap_id();
+
+        let mut feed = FeedMessageSerializer::new();
+        state.update_node(node_a, block_import(10), &mut feed, false, Now::current());
+        state.update_node(
+            node_a,
+            notify_finalized(8),
+            &mut feed,
+            false,
+            Now::current(),
+        );
+        state.update_node(node_c, peers(12), &mut feed, false, Now::current());
+
+        // The snapshot survives a trip through JSON, to be loaded into a fresh state:
+        let json = serde_json::to_string(&state.snapshot()).unwrap();
+        let mut restored = state_with_stats_interval(DEFAULT_STATS_INTERVAL);
+        restored.load_snapshot(&serde_json::from_str(&json).unwrap());
+        assert_eq!(restored.chain_count(), 2);
+
+        let chain = restored.get_chain_by_genesis_hash(&chain1_genesis).unwrap();
+        assert_eq!(chain.label(), "Chain One");
+        assert_eq!(chain.best_block().height, 10);
+        assert_eq!(chain.finalized_block().height, 8);
+        let names: Vec<_> = chain
+            .nodes_slice()
+            .iter()
+            .flatten()
+            .map(|node| &*node.details().name)
+            .collect();
+        assert_eq!(names, vec!["A", "B"]);
+
+        let chain = restored.get_chain_by_genesis_hash(&chain2_genesis).unwrap();
+        let peer_counts: Vec<_> = chain
+            .nodes_slice()
+            .iter()
+            .flatten()
+            .map(|node| node.stats().peers)
+            .collect();
+        assert_eq!(peer_counts, vec![12]);
+    }
+
+    #[test]
+    fn chain_stats_record_when_the_last_block_arrived() {
+        // Stats are regenerated every time that a node is updated:
+        let mut state = state_with_stats_interval(Duration::ZERO);
+        let import_block = |state: &mut State, node_id, height, now| {
+            let mut feed = FeedMessageSerializer::new();
+            state.update_node(node_id, block_import(height), &mut feed, false, now);
+            decode(feed)
+                .into_iter()
+                .map(|(action, _)| action)
+                .collect::<Vec<_>>()
+        };
+        let last_block_at = |state: &State| {
+            state
+                .get_chain_by_genesis_hash(&BlockHash::from_low_u64_be(1))
+                .unwrap()
+                .stats()
+                .last_block_at
+        };
+
+        let node_id = state
+            .add_node(BlockHash::from_low_u64_be(1), node("A", "Chain One"))
+            .unwrap_id();
+        assert_eq!(last_block_at(&state), None);
+
+        // As soon as a block arrives, the stats say so:
+        let now = Now::current();
+        import_block(&mut state, node_id, 1, now);
+        assert_eq!(last_block_at(&state), Some(now.timestamp));
+
+        // A new best block moves it on, but isn't enough to send the stats to feeds again:
+        let actions = import_block(&mut state, node_id, 2, after(now, 6_000));
+        assert!(!actions.contains(&feed_message::ChainStatsUpdate::ACTION));
+        assert_eq!(last_block_at(&state), Some(now.timestamp + 6_000));
+
+        // And it stays put while no new blocks arrive:
+        import_block(&mut state, node_id, 2, after(now, 12_000));
+        import_block(&mut state, node_id, 1, after(now, 18_000));
+        assert_eq!(last_block_at(&state), Some(now.timestamp + 6_000));
+    }
 }
@@ -0,0 +1,267 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Keep track of how long nodes have been connected for in total, across reconnects and
+//! restarts of the core, by recording when they connect and disconnect (going by their network
+//! ID) in a postgres database. If the core stops without nodes disconnecting, the sessions they
+//! were in the middle of aren't counted.
+//!
+//! The database is connected to over TLS if the server supports it (or if the connection string
+//! has 'sslmode=require'), unless the connection string has 'sslmode=disable'.
+
+use anyhow::Context;
+use common::node_types::{NetworkId, Timestamp};
+use common::ws_client::CaCertificates;
+use futures::{Sink, SinkExt};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_postgres::tls::{ChannelBinding, MakeTlsConnect, TlsConnect};
+use tokio_postgres::Client;
+use tokio_rustls::rustls::ServerName;
+
+/// Create the table that we record uptime in, if it doesn't already exist.
+const CREATE_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS node_uptime (
+        network_id TEXT PRIMARY KEY,
+        uptime_secs BIGINT NOT NULL DEFAULT 0,
+        connected_at BIGINT
+    )
+";
+
+/// Record that a node has connected, handing back how long it's been up for previously.
+const RECORD_CONNECTED: &str = "
+    INSERT INTO node_uptime (network_id, connected_at) VALUES ($1, $2)
+    ON CONFLICT (network_id) DO UPDATE SET connected_at = $2
+    RETURNING uptime_secs
+";
+
+/// Record that a node has disconnected, adding the time since it connected to its uptime.
+const RECORD_DISCONNECTED: &str = "
+    UPDATE node_uptime
+    SET uptime_secs = uptime_secs + GREATEST($2 - connected_at, 0), connected_at = NULL
+    WHERE network_id = $1 AND connected_at IS NOT NULL
+";
+
+/// How long a node has been up for, according to the uptime database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Uptime {
+    /// How many seconds the node was up for before it last connected.
+    pub previous_secs: u64,
+    /// When the node last connected (in milliseconds since the unix epoch).
+    pub connected_at: Timestamp,
+}
+
+impl Uptime {
+    /// How many seconds the node has been up for in total, as of now.
+    pub fn total_secs(&self, now: Timestamp) -> u64 {
+        self.previous_secs + now.saturating_sub(self.connected_at) / 1000
+    }
+}
+
+/// Something that's happened to a node, to be recorded in the uptime database.
+#[derive(Debug, Clone)]
+pub enum UptimeEvent<Id> {
+    /// A node has connected. How long it's been up for is handed back once it's been recorded.
+    Connected {
+        id: Id,
+        network_id: NetworkId,
+        at: Timestamp,
+    },
+    /// A node has disconnected.
+    Disconnected {
+        network_id: NetworkId,
+        at: Timestamp,
+    },
+}
+
+/// Connect to the uptime database and spawn a task to record the events sent to the returned
+/// channel in it. The uptime of each node that connects is sent to `response_chan`, along with
+/// the network ID that it was looked up for. If connecting over TLS, the server's certificate
+/// must be signed by one of the usual web PKI certificate authorities or `ca_certificates`.
+///
+/// At most `queue_len` events are queued up; events sent beyond this are rejected rather than
+/// waiting, so that whoever is sending them is never held up by the database.
+pub async fn track_uptime<Id, R>(
+    response_chan: R,
+    connection_string: &str,
+    ca_certificates: &CaCertificates,
+    queue_len: usize,
+) -> anyhow::Result<flume::Sender<UptimeEvent<Id>>>
+where
+    R: Sink<(Id, NetworkId, Uptime)> + Unpin + Send + 'static,
+    Id: Send + 'static,
+{
+    let tls = MakeRustlsConnect(ca_certificates.tls_connector()?);
+    let (client, connection) = tokio_postgres::connect(connection_string, tls)
+        .await
+        .context("Could not connect to the uptime database")?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            log::error!("Lost connection to the uptime database: {e}");
+        }
+    });
+    client
+        .batch_execute(CREATE_TABLE)
+        .await
+        .context("Could not create the uptime table")?;
+
+    let (tx, rx) = flume::bounded(queue_len);
+    tokio::spawn(async move {
+        let mut response_chan = response_chan;
+        while let Ok(event) = rx.recv_async().await {
+            match event {
+                UptimeEvent::Connected { id, network_id, at } => {
+                    match record_connected(&client, &network_id, at).await {
+                        Ok(uptime) => {
+                            let _ = response_chan.send((id, network_id, uptime)).await;
+                        }
+                        Err(e) => log::warn!("Could not record that {network_id} connected: {e}"),
+                    }
+                }
+                UptimeEvent::Disconnected { network_id, at } => {
+                    if let Err(e) = record_disconnected(&client, &network_id, at).await {
+                        log::warn!("Could not record that {network_id} disconnected: {e}");
+                    }
+                }
+            }
+        }
+    });
+
+    Ok(tx)
+}
+
+async fn record_connected(
+    client: &Client,
+    network_id: &str,
+    at: Timestamp,
+) -> Result<Uptime, tokio_postgres::Error> {
+    let row = client
+        .query_one(RECORD_CONNECTED, &[&network_id, &to_secs(at)])
+        .await?;
+    let previous_secs: i64 = row.get(0);
+    Ok(Uptime {
+        previous_secs: previous_secs.max(0) as u64,
+        connected_at: at,
+    })
+}
+
+async fn record_disconnected(
+    client: &Client,
+    network_id: &str,
+    at: Timestamp,
+) -> Result<(), tokio_postgres::Error> {
+    client
+        .execute(RECORD_DISCONNECTED, &[&network_id, &to_secs(at)])
+        .await?;
+    Ok(())
+}
+
+/// Timestamps are stored in the database as seconds since the unix epoch.
+fn to_secs(timestamp: Timestamp) -> i64 {
+    (timestamp / 1000) as i64
+}
+
+/// Connect to the uptime database over TLS, using rustls.
+struct MakeRustlsConnect(tokio_rustls::TlsConnector);
+
+impl<S> MakeTlsConnect<S> for MakeRustlsConnect
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    type Stream = RustlsStream<S>;
+    type TlsConnect = RustlsConnect;
+    type Error = io::Error;
+
+    fn make_tls_connect(&mut self, domain: &str) -> io::Result<RustlsConnect> {
+        let domain = ServerName::try_from(domain)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid dns name"))?;
+        Ok(RustlsConnect(self.0.clone(), domain))
+    }
+}
+
+/// Connect to the given server over TLS.
+struct RustlsConnect(tokio_rustls::TlsConnector, ServerName);
+
+impl<S> TlsConnect<S> for RustlsConnect
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    type Stream = RustlsStream<S>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<RustlsStream<S>>> + Send>>;
+
+    fn connect(self, stream: S) -> Self::Future {
+        let RustlsConnect(connector, domain) = self;
+        Box::pin(async move { Ok(RustlsStream(connector.connect(domain, stream).await?)) })
+    }
+}
+
+/// A TLS connection to the uptime database.
+struct RustlsStream<S>(tokio_rustls::client::TlsStream<S>);
+
+impl<S: AsyncRead + AsyncWrite + Unpin> tokio_postgres::tls::TlsStream for RustlsStream<S> {
+    fn channel_binding(&self) -> ChannelBinding {
+        ChannelBinding::none()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for RustlsStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for RustlsStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn uptime_includes_time_since_connecting() {
+        let uptime = Uptime {
+            previous_secs: 100,
+            connected_at: 10_000,
+        };
+        assert_eq!(uptime.total_secs(10_000), 100);
+        assert_eq!(uptime.total_secs(15_999), 105);
+        // Clocks going backwards don't lead to a shorter uptime than we started with:
+        assert_eq!(uptime.total_secs(5_000), 100);
+    }
+}
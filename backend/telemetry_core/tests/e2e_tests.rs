@@ -36,13 +36,18 @@ cargo test e2e -- --ignored
 ```
 */
 
-use common::node_types::BlockHash;
-use common::ws_client::SentMessage;
+use common::node_message::{NodeMessage, Payload, SystemConnected};
+use common::node_types::{self, BlockHash};
+use common::ws_client::{ConnectError, SentMessage};
 use serde_json::json;
 use std::{str::FromStr, time::Duration};
 use test_utils::{
     assert_contains_matches,
     feed_message_de::{FeedMessage, NodeDetails},
+    server::{
+        channels::{FeedReceiver, ShardSender},
+        CoreProcess, Server,
+    },
     workspace::{start_server, start_server_debug, CoreOpts, ServerOpts, ShardOpts},
 };
 
@@ -56,6 +61,79 @@ fn ghash(id: u64) -> BlockHash {
     BlockHash::from_low_u64_be(id)
 }
 
+/// Shard options that allow a single node connection to add a lot of nodes at once.
+fn lots_of_nodes_shard_opts() -> ShardOpts {
+    ShardOpts {
+        max_nodes_per_connection: Some(100_000),
+        // Prevent the shard being being banned when it sends a load of data at once:
+        max_node_data_per_second: Some(100_000_000),
+        ..Default::default()
+    }
+}
+
+/// Add `num_nodes` Polkadot nodes via the node connection given, so that there's plenty of
+/// data to send to feeds. The shard should be started with [`lots_of_nodes_shard_opts`].
+fn add_polkadot_nodes(node_tx: &mut ShardSender, num_nodes: usize) {
+    for n in 1..=num_nodes {
+        node_tx
+            .send_json_text(json!({
+                "id":n,
+                "ts":"2021-07-12T10:37:47.714666+01:00",
+                "payload": {
+                    "authority":true,
+                    "chain":"Polkadot",
+                    "config":"",
+                    "genesis_hash": polkadot_genesis_hash(), // First party node connections aren't limited.
+                    "implementation":"Substrate Node",
+                    "msg":"system.connected",
+                    "name": format!("Alice {}", n),
+                    "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                    "startup_time":"1625565542717",
+                    "version":"2.0.0-07a1af348-aarch64-macos"
+                }
+            }))
+            .unwrap();
+    }
+}
+
+/// A message adding a node to the core, as a shard would send it. Tests can pretend to be a
+/// shard, and send these to the core themselves, to see exactly what the core sends back.
+fn shard_add_node(
+    local_id: usize,
+    name: &str,
+    chain: &str,
+    genesis_hash: BlockHash,
+) -> SentMessage {
+    use bincode::Options;
+    use common::internal_messages::{FromShardAggregator, ShardNodeId};
+
+    let msg = FromShardAggregator::AddNode {
+        ip: "127.0.0.1".parse().unwrap(),
+        node: node_types::NodeDetails {
+            chain: chain.into(),
+            name: name.into(),
+            implementation: "Substrate Node".into(),
+            version: "2.0.0-07a1af348".into(),
+            validator: None,
+            network_id: node_types::NetworkId::from(
+                "12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+            )
+            .unwrap(),
+            startup_time: None,
+            // Feeds expect these to be given, as they are by real nodes:
+            target_os: Some("macos".into()),
+            target_arch: Some("aarch64".into()),
+            target_env: Some("".into()),
+            sysinfo: None,
+            ip: None,
+            shard: None,
+        },
+        local_id: ShardNodeId::new(local_id),
+        genesis_hash,
+    };
+    SentMessage::Binary(bincode::options().serialize(&msg).unwrap())
+}
+
 /// The simplest test we can run; the main benefit of this test (since we check similar)
 /// below) is just to give a feel for _how_ we can test basic feed related things.
 #[tokio::test]
@@ -260,6 +338,81 @@ async fn e2e_feed_add_and_remove_node() {
     server.shutdown().await;
 }
 
+/// Nodes can send bincode encoded messages to "/submit_bin" instead of JSON ones.
+#[tokio::test]
+async fn e2e_node_can_submit_bincode_messages() {
+    let mut server = start_server_debug().await;
+    let shard_id = server.add_shard().await.unwrap();
+
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node_bin()
+        .await
+        .expect("can connect to shard");
+
+    node_tx
+        .send_bincode(&NodeMessage::V2 {
+            id: 1,
+            payload: Payload::SystemConnected(SystemConnected {
+                genesis_hash: ghash(1),
+                node: node_types::NodeDetails {
+                    chain: "Local Testnet".into(),
+                    name: "Alice".into(),
+                    implementation: "Substrate Node".into(),
+                    version: "2.0.0-07a1af348-aarch64-macos".into(),
+                    validator: None,
+                    network_id: node_types::NetworkId::from(
+                        "12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                    )
+                    .unwrap(),
+                    startup_time: None,
+                    target_os: None,
+                    target_arch: None,
+                    target_env: None,
+                    sysinfo: None,
+                    ip: None,
+                    shard: None,
+                },
+            }),
+        })
+        .unwrap();
+
+    // Text messages on the same connection are still understood as JSON:
+    node_tx
+        .send_json_text(json!(
+            {
+                "id":2,
+                "ts":"2021-07-12T10:37:47.714666+01:00",
+                "payload": {
+                    "authority":true,
+                    "chain":"Local Testnet",
+                    "config":"",
+                    "genesis_hash": ghash(1),
+                    "implementation":"Substrate Node",
+                    "msg":"system.connected",
+                    "name":"Bob",
+                    "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEq",
+                    "startup_time":"1625565542717",
+                    "version":"2.0.0-07a1af348-aarch64-macos"
+                },
+            }
+        ))
+        .unwrap();
+
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let (_feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert!(feed_messages.contains(&FeedMessage::AddedChain {
+        name: "Local Testnet".to_owned(),
+        genesis_hash: ghash(1),
+        node_count: 2,
+    }));
+
+    server.shutdown().await;
+}
+
 /// If a node is added, a connecting feed should be told about the new chain.
 /// However, sending a duplicate "system.connected" message from the same node
 /// should not count as a new node but rather the second message should be ignored.
@@ -502,6 +655,112 @@ async fn e2e_feed_add_and_remove_shard() {
     server.shutdown().await;
 }
 
+/// If a shard is cut off from the core, feeds are told that its nodes have gone and stop
+/// hearing from them. Once the shard can reach the core again, it reconnects, and its nodes
+/// are heard from again once they've reconnected to it.
+#[tokio::test]
+async fn e2e_feeds_hear_from_nodes_again_once_a_shard_partition_heals() {
+    use FeedMessage::*;
+
+    let mut server = start_server_debug().await;
+    let shard_id = server.add_shard().await.unwrap();
+
+    let system_connected = json!({
+        "id":1,
+        "ts":"2021-07-12T10:37:47.714666+01:00",
+        "payload": {
+            "authority":true,
+            "chain":"Local Testnet",
+            "config":"",
+            "genesis_hash": ghash(1),
+            "implementation":"Substrate Node",
+            "msg":"system.connected",
+            "name":"Alice",
+            "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+            "startup_time":"1625565542717",
+            "version":"2.0.0-07a1af348-aarch64-macos"
+        },
+    });
+    let system_interval = json!(
+        {"id":1, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":1},"ts":"2021-07-12T10:37:48.330433+01:00" }
+    );
+    let subscribe = |feed_tx: &test_utils::server::channels::FeedSender| {
+        feed_tx
+            .send_command(
+                "subscribe",
+                "0x0000000000000000000000000000000000000000000000000000000000000001",
+            )
+            .unwrap();
+    };
+
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+    node_tx.send_json_text(system_connected.clone()).unwrap();
+
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+    subscribe(&feed_tx);
+    feed_rx.recv_feed_messages().await.unwrap();
+
+    // Cut the shard off from the core; its node goes away:
+    assert!(server.partition_shard(shard_id));
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert!(feed_messages.contains(&RemovedChain {
+        genesis_hash: ghash(1),
+    }));
+
+    // And updates from the node no longer reach the feed:
+    let _ = node_tx.send_json_text(system_interval.clone());
+    let feed_messages = feed_rx
+        .recv_feed_messages_timeout(Duration::from_secs(2))
+        .await
+        .unwrap();
+    assert!(!feed_messages
+        .iter()
+        .any(|msg| matches!(msg, NodeStatsUpdate { .. } | AddedChain { .. })));
+
+    // Once healed, the shard reconnects to the core and asks its nodes to reconnect too. Keep
+    // trying until the node's reconnection makes it through to the feed:
+    assert!(server.heal_shard(shard_id));
+    let mut node = None;
+    for _ in 0..20 {
+        let (mut node_tx, node_rx) = server
+            .get_shard(shard_id)
+            .unwrap()
+            .connect_node()
+            .await
+            .unwrap();
+        node_tx.send_json_text(system_connected.clone()).unwrap();
+        let feed_messages = feed_rx
+            .recv_feed_messages_timeout(Duration::from_secs(1))
+            .await
+            .unwrap();
+        if feed_messages
+            .iter()
+            .any(|msg| matches!(msg, AddedChain { genesis_hash, .. } if *genesis_hash == ghash(1)))
+        {
+            node = Some((node_tx, node_rx));
+            break;
+        }
+    }
+    let (mut node_tx, _node_rx) =
+        node.expect("node should be heard from once the shard reconnects");
+
+    // Updates from the node reach the feed again:
+    subscribe(&feed_tx);
+    feed_rx.recv_feed_messages().await.unwrap();
+    node_tx.send_json_text(system_interval).unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(&feed_messages, NodeStatsUpdate { .. });
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
 /// feeds can subscribe to one chain at a time. They should get the relevant
 /// messages for that chain and no other.
 #[tokio::test]
@@ -617,78 +876,14 @@ async fn e2e_feed_can_subscribe_and_unsubscribe_from_chain() {
     server.shutdown().await;
 }
 
-/// If a node sends more than some rolling average amount of data, it'll be booted.
-#[tokio::test]
-async fn e2e_node_banned_if_it_sends_too_much_data() {
-    async fn try_send_data(max_bytes: usize, send_msgs: usize, bytes_per_msg: usize) -> bool {
-        let mut server = start_server(
-            ServerOpts::default(),
-            CoreOpts::default(),
-            ShardOpts {
-                // Remember, this is (currently) averaged over the last 10 seconds,
-                // so we need to send 10x this amount of data for an imemdiate ban:
-                max_node_data_per_second: Some(max_bytes),
-                ..Default::default()
-            },
-        )
-        .await;
-
-        // Give us a shard to talk to:
-        let shard_id = server.add_shard().await.unwrap();
-        let (node_tx, _node_rx) = server
-            .get_shard(shard_id)
-            .unwrap()
-            .connect_node()
-            .await
-            .unwrap();
-
-        // Send the data requested to the shard:
-        for _ in 0..send_msgs {
-            node_tx
-                .unbounded_send(SentMessage::Binary(vec![1; bytes_per_msg]))
-                .unwrap();
-        }
-
-        // Wait a little for the shard to react and cut off the connection (or not):
-        tokio::time::sleep(Duration::from_millis(250)).await;
-
-        // Has the connection been closed?
-        node_tx.is_closed()
-    }
-
-    assert_eq!(
-        try_send_data(1000, 10, 1000).await,
-        false,
-        "shouldn't be closed; we didn't exceed 10x threshold"
-    );
-    assert_eq!(
-        try_send_data(999, 10, 1000).await,
-        true,
-        "should be closed; we sent just over 10x the block threshold"
-    );
-}
-
-/// Feeds will be disconnected if they can't receive messages quickly enough.
+/// Feeds can ask for the current state of a chain once, without being sent any
+/// further updates about it.
 #[tokio::test]
-async fn e2e_slow_feeds_are_disconnected() {
-    let mut server = start_server(
-        ServerOpts::default(),
-        // Timeout faster so the test can be quicker:
-        CoreOpts {
-            feed_timeout: Some(1),
-            ..Default::default()
-        },
-        // Allow us to send more messages in more easily:
-        ShardOpts {
-            max_nodes_per_connection: Some(100_000),
-            // Prevent the shard being being banned when it sends a load of data at once:
-            max_node_data_per_second: Some(100_000_000),
-            ..Default::default()
-        },
-    )
-    .await;
+async fn e2e_feed_can_ask_for_a_chain_snapshot() {
+    use FeedMessage::*;
 
-    // Give us a shard to talk to:
+    // Start server, add shard, connect node:
+    let mut server = start_server_debug().await;
     let shard_id = server.add_shard().await.unwrap();
     let (mut node_tx, _node_rx) = server
         .get_shard(shard_id)
@@ -697,92 +892,2684 @@ async fn e2e_slow_feeds_are_disconnected() {
         .await
         .unwrap();
 
-    // Add a load of nodes from this shard so there's plenty of data to give to a feed.
-    // We want to exhaust any buffers between core and feed (eg BufWriters). If the number
-    // is too low, data will happily be sent into a buffer and the connection won't need to
-    // be closed.
-    for n in 1..100_000 {
-        node_tx
-            .send_json_text(json!({
-                "id":n,
+    node_tx
+        .send_json_text(json!(
+            {
+                "id":1,
                 "ts":"2021-07-12T10:37:47.714666+01:00",
                 "payload": {
                     "authority":true,
-                    "chain":"Polkadot",
+                    "chain":"Local Testnet",
                     "config":"",
-                    "genesis_hash": polkadot_genesis_hash(), // First party node connections aren't limited.
+                    "genesis_hash": ghash(1),
                     "implementation":"Substrate Node",
                     "msg":"system.connected",
-                    "name": format!("Alice {}", n),
+                    "name":"Alice",
                     "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
                     "startup_time":"1625565542717",
                     "version":"2.0.0-07a1af348-aarch64-macos"
-                }
-            }))
-            .unwrap();
-    }
+                },
+            }
+        ))
+        .unwrap();
 
-    // Connect a raw feed so that we can control how fast we consume data from the websocket
-    let (mut raw_feed_tx, mut raw_feed_rx) = server.get_core().connect_feed_raw().await.unwrap();
+    // Connect a feed
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
 
-    // Subscribe the feed:
-    raw_feed_tx
-        .send_text("subscribe:0x0000000000000000000000000000000000000000000000000000000000000001")
-        .await
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(feed_messages, AddedChain { name, genesis_hash, node_count: 1 } if name == "Local Testnet" && genesis_hash == ghash(1));
+
+    // Ask for a snapshot of the chain:
+    feed_tx
+        .send_command(
+            "snapshot",
+            "0x0000000000000000000000000000000000000000000000000000000000000001",
+        )
         .unwrap();
 
-    // Wait a little.. the feed hasn't been receiving messages so it should
-    // be booted after ~a second.
-    tokio::time::sleep(Duration::from_secs(5)).await;
+    // We're told about the chain and its nodes, but not subscribed to it:
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        &feed_messages,
+        TimeSync {..},
+        BestBlock {..},
+        BestFinalized {..},
+        AddedNode { node: NodeDetails { name, .. }, ..} if name == "Alice",
+        FinalizedBlock {..},
+    );
+    assert!(!feed_messages
+        .iter()
+        .any(|msg| matches!(msg, SubscribedTo { .. })));
 
-    // Drain anything out and expect to hit a "closed" error, rather than get stuck
-    // waiting to receive more data (or see some other error).
-    loop {
-        let mut v = Vec::new();
-        let data =
-            tokio::time::timeout(Duration::from_secs(2), raw_feed_rx.receive_data(&mut v)).await;
+    // So we don't receive updates relating to nodes on that chain (wait a sec to ensure no messages are sent):
+    node_tx.send_json_text(json!(
+        {"id":1, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":1},"ts":"2021-07-12T10:37:48.330433+01:00" }
+    )).unwrap();
 
-        match data {
-            Ok(Ok(_)) => {
-                continue; // Drain data
-            }
-            Ok(Err(soketto::connection::Error::Closed)) => {
-                break; // End loop; success!
+    tokio::time::timeout(Duration::from_secs(1), feed_rx.recv_feed_messages())
+        .await
+        .expect_err("Timeout should elapse since no messages sent");
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Feeds that are subscribed to one chain can't ask for a snapshot of another, since the nodes
+/// of the two chains would be mixed up.
+#[tokio::test]
+async fn e2e_feed_cant_ask_for_a_snapshot_of_another_chain() {
+    // Start server, add shard, connect a node to each of two chains:
+    let mut server = start_server_debug().await;
+    let shard_id = server.add_shard().await.unwrap();
+    let mut node_txs = Vec::new();
+    for (idx, name) in [(1, "Alice"), (2, "Bob")] {
+        let (mut node_tx, node_rx) = server
+            .get_shard(shard_id)
+            .unwrap()
+            .connect_node()
+            .await
+            .unwrap();
+        node_tx
+            .send_json_text(json!(
+                {
+                    "id":1,
+                    "ts":"2021-07-12T10:37:47.714666+01:00",
+                    "payload": {
+                        "authority":true,
+                        "chain":format!("Chain {idx}"),
+                        "config":"",
+                        "genesis_hash": ghash(idx),
+                        "implementation":"Substrate Node",
+                        "msg":"system.connected",
+                        "name":name,
+                        "network_id":format!("12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDE{idx}"),
+                        "startup_time":"1625565542717",
+                        "version":"2.0.0-07a1af348-aarch64-macos"
+                    },
+                }
+            ))
+            .unwrap();
+        node_txs.push((node_tx, node_rx));
+    }
+
+    // Connect a feed and subscribe it to the first chain:
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+    feed_tx
+        .send_command(
+            "subscribe",
+            "0x0000000000000000000000000000000000000000000000000000000000000001",
+        )
+        .unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+
+    // A snapshot of the second chain isn't sent:
+    feed_tx
+        .send_command(
+            "snapshot",
+            "0x0000000000000000000000000000000000000000000000000000000000000002",
+        )
+        .unwrap();
+    tokio::time::timeout(Duration::from_secs(1), feed_rx.recv_feed_messages())
+        .await
+        .expect_err("Timeout should elapse since no messages sent");
+
+    // But a snapshot of the chain that it's subscribed to is:
+    feed_tx
+        .send_command(
+            "snapshot",
+            "0x0000000000000000000000000000000000000000000000000000000000000001",
+        )
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        &feed_messages,
+        FeedMessage::AddedNode { node: NodeDetails { name, .. }, ..} if name == "Alice",
+    );
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Connected feeds, along with the chains they're subscribed to, can be listed via
+/// '/admin/feeds' by anybody with the admin token.
+#[tokio::test]
+async fn e2e_admin_can_list_feeds_and_their_subscriptions() {
+    // Start server, add shard, connect node:
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            admin_token: Some("letmein".to_string()),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+
+    node_tx
+        .send_json_text(json!(
+            {
+                "id":1,
+                "ts":"2021-07-12T10:37:47.714666+01:00",
+                "payload": {
+                    "authority":true,
+                    "chain":"Local Testnet",
+                    "config":"",
+                    "genesis_hash": ghash(1),
+                    "implementation":"Substrate Node",
+                    "msg":"system.connected",
+                    "name":"Alice",
+                    "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                    "startup_time":"1625565542717",
+                    "version":"2.0.0-07a1af348-aarch64-macos"
+                },
             }
-            Ok(Err(_e)) => {
-                // Occasionally we might hit an error here before the channel is marked as closed. The error probably
-                // means that the socket has been killed, but we haven't managed to set the state to closed in time
-                // and so we still hit this. We may be able to tighten this up and avoid this permanently, at which point
-                // this can become a test failure.
-                break;
+        ))
+        .unwrap();
+
+    // Connect a feed and subscribe it to the chain:
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+    feed_tx
+        .send_command(
+            "subscribe",
+            "0x0000000000000000000000000000000000000000000000000000000000000001",
+        )
+        .unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+
+    let uri: hyper::Uri = format!("http://{}/admin/feeds", server.get_core().host())
+        .parse()
+        .unwrap();
+    let client = hyper::Client::new();
+
+    // Without the token, we aren't allowed to see the feeds:
+    let res = client.get(uri.clone()).await.unwrap();
+    assert_eq!(res.status(), 401);
+
+    // With it, we see our feed and the chain that it's subscribed to:
+    let req = hyper::Request::get(uri)
+        .header("Authorization", "Bearer letmein")
+        .body(hyper::Body::empty())
+        .unwrap();
+    let res = client.request(req).await.unwrap();
+    assert_eq!(res.status(), 200);
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    let feeds: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+    let feeds = feeds.as_array().expect("an array of feeds");
+    assert_eq!(feeds.len(), 1);
+    assert_eq!(feeds[0]["chain"], json!(ghash(1)));
+    assert!(feeds[0]["id"].is_u64());
+    assert!(feeds[0]["queued_messages"].is_u64());
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// The state dumped from '/admin/state' can be loaded into a new core with '--load-snapshot',
+/// which then serves it to feeds without any shards.
+#[tokio::test]
+async fn e2e_feeds_can_be_served_from_a_state_snapshot() {
+    // Start server, add shard, connect node:
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            admin_token: Some("letmein".to_string()),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+
+    node_tx
+        .send_json_text(json!(
+            {
+                "id":1,
+                "ts":"2021-07-12T10:37:47.714666+01:00",
+                "payload": {
+                    "authority":true,
+                    "chain":"Local Testnet",
+                    "config":"",
+                    "genesis_hash": ghash(1),
+                    "implementation":"Substrate Node",
+                    "msg":"system.connected",
+                    "name":"Alice",
+                    "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                    "startup_time":"1625565542717",
+                    "version":"2.0.0-07a1af348-aarch64-macos"
+                },
             }
-            Err(_) => {
-                panic!("recv should be closed but seems to be happy waiting for more data");
+        ))
+        .unwrap();
+
+    // Wait for the node to be added before dumping the state:
+    let (_feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(feed_messages, FeedMessage::AddedChain { node_count: 1, .. });
+
+    let uri: hyper::Uri = format!("http://{}/admin/state", server.get_core().host())
+        .parse()
+        .unwrap();
+    let req = hyper::Request::get(uri)
+        .header("Authorization", "Bearer letmein")
+        .body(hyper::Body::empty())
+        .unwrap();
+    let res = hyper::Client::new().request(req).await.unwrap();
+    assert_eq!(res.status(), 200);
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    server.shutdown().await;
+
+    let snapshot_path =
+        std::env::temp_dir().join(format!("telemetry_snapshot_test_{}", std::process::id()));
+    std::fs::write(&snapshot_path, &body).unwrap();
+
+    // Start a new server from the snapshot; feeds see the chain and node from before:
+    let server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            load_snapshot: Some(snapshot_path.to_str().unwrap().to_owned()),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    feed_tx
+        .send_command("subscribe", &format!("{:#x}", ghash(1)))
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        feed_messages,
+        FeedMessage::AddedChain { name, node_count: 1, .. } if name == "Local Testnet",
+        FeedMessage::SubscribedTo { genesis_hash } if genesis_hash == ghash(1),
+        FeedMessage::AddedNode { node_id: 0, node, .. } if node.name == "Alice"
+    );
+
+    // Shards can't connect to change what's in the snapshot:
+    let uri: hyper::Uri = format!("http://{}/shard_submit", server.get_core().host())
+        .parse()
+        .unwrap();
+    let res = hyper::Client::new().get(uri).await.unwrap();
+    assert_eq!(res.status(), 503);
+
+    // Tidy up:
+    server.shutdown().await;
+    let _ = std::fs::remove_file(&snapshot_path);
+}
+
+/// The sequence number of the last batch of messages about a chain in some feed messages.
+fn last_seq(feed_messages: &[FeedMessage]) -> u64 {
+    feed_messages
+        .iter()
+        .rev()
+        .find_map(|msg| match msg {
+            FeedMessage::Seq { seq } => Some(*seq),
+            _ => None,
+        })
+        .expect("a sequence number")
+}
+
+/// Start a server which holds onto `feed_resume_buffer_len` batches of updates for each chain,
+/// and have a feed miss an update about a node before reconnecting and trying to resume from
+/// where it was. Returns the messages sent to the new feed in response.
+async fn resume_after_a_missed_update(
+    feed_resume_buffer_len: usize,
+) -> (Server, ShardSender, FeedReceiver, Vec<FeedMessage>) {
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            feed_resume_buffer_len: Some(feed_resume_buffer_len),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+    node_tx
+        .send_json_text(json!(
+            {
+                "id":1,
+                "ts":"2021-07-12T10:37:47.714666+01:00",
+                "payload": {
+                    "authority":true,
+                    "chain":"Local Testnet",
+                    "config":"",
+                    "genesis_hash": ghash(1),
+                    "implementation":"Substrate Node",
+                    "msg":"system.connected",
+                    "name":"Alice",
+                    "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                    "startup_time":"1625565542717",
+                    "version":"2.0.0-07a1af348-aarch64-macos"
+                },
             }
-        }
-    }
+        ))
+        .unwrap();
+
+    // Connect a feed and subscribe to the chain, noting where it's up to:
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+    feed_tx
+        .send_command(
+            "subscribe",
+            "0x0000000000000000000000000000000000000000000000000000000000000001",
+        )
+        .unwrap();
+    let seq = last_seq(&feed_rx.recv_feed_messages().await.unwrap());
+
+    // The node is updated while the feed is still connected:
+    node_tx.send_json_text(json!(
+        {"id":1, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":1},"ts":"2021-07-12T10:37:48.330433+01:00" }
+    )).unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_ne!(last_seq(&feed_messages), seq);
+
+    // The feed disconnects and a new one tries to resume from where it was:
+    drop((feed_tx, feed_rx));
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+    feed_tx
+        .send_command(
+            "resume",
+            &format!("0x0000000000000000000000000000000000000000000000000000000000000001@{seq}"),
+        )
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+
+    (server, node_tx, feed_rx, feed_messages)
+}
+
+/// Feeds which reconnect can resume their subscription to a chain, being sent only the
+/// updates that they missed rather than the full state of the chain.
+#[tokio::test]
+async fn e2e_feed_can_resume_from_where_it_left_off() {
+    use FeedMessage::*;
+
+    let (server, mut node_tx, mut feed_rx, feed_messages) = resume_after_a_missed_update(100).await;
+
+    // It's sent only the update that it missed, and isn't resubscribed from scratch:
+    assert_contains_matches!(&feed_messages, NodeStatsUpdate { node_id: 0, .. });
+    assert!(!feed_messages.iter().any(|msg| matches!(
+        msg,
+        ResumeFailed { .. } | SubscribedTo { .. } | AddedNode { .. }
+    )));
+
+    // And carries on receiving updates about the chain:
+    node_tx.send_json_text(json!(
+        {"id":1, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":2},"ts":"2021-07-12T10:37:49.330433+01:00" }
+    )).unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(&feed_messages, NodeStatsUpdate { node_id: 0, .. });
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// If the updates that a feed missed are no longer held onto, it's told that it can't
+/// resume, and is sent the full state of the chain instead.
+#[tokio::test]
+async fn e2e_feed_cant_resume_once_updates_have_rolled_over() {
+    use FeedMessage::*;
+
+    let (server, _node_tx, _feed_rx, feed_messages) = resume_after_a_missed_update(1).await;
+
+    // Only the latest update was held onto, so the feed is subscribed from scratch:
+    assert_contains_matches!(
+        &feed_messages,
+        ResumeFailed { genesis_hash } if *genesis_hash == ghash(1),
+        SubscribedTo { genesis_hash } if *genesis_hash == ghash(1),
+        AddedNode { node: NodeDetails { name, .. }, ..} if name == "Alice",
+    );
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// If a node sends more than some rolling average amount of data, it'll be booted.
+#[tokio::test]
+async fn e2e_node_banned_if_it_sends_too_much_data() {
+    async fn try_send_data(max_bytes: usize, send_msgs: usize, bytes_per_msg: usize) -> bool {
+        let mut server = start_server(
+            ServerOpts::default(),
+            CoreOpts::default(),
+            ShardOpts {
+                // Remember, this is (currently) averaged over the last 10 seconds,
+                // so we need to send 10x this amount of data for an imemdiate ban:
+                max_node_data_per_second: Some(max_bytes),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        // Give us a shard to talk to:
+        let shard_id = server.add_shard().await.unwrap();
+        let (node_tx, _node_rx) = server
+            .get_shard(shard_id)
+            .unwrap()
+            .connect_node()
+            .await
+            .unwrap();
+
+        // Send the data requested to the shard:
+        for _ in 0..send_msgs {
+            node_tx
+                .unbounded_send(SentMessage::Binary(vec![1; bytes_per_msg]))
+                .unwrap();
+        }
+
+        // Wait a little for the shard to react and cut off the connection (or not):
+        tokio::time::sleep(Duration::from_millis(250)).await;
+
+        // Has the connection been closed?
+        node_tx.is_closed()
+    }
+
+    assert_eq!(
+        try_send_data(1000, 10, 1000).await,
+        false,
+        "shouldn't be closed; we didn't exceed 10x threshold"
+    );
+    assert_eq!(
+        try_send_data(999, 10, 1000).await,
+        true,
+        "should be closed; we sent just over 10x the block threshold"
+    );
+}
+
+/// Nodes banned for sending too much data are told how much they were sending when they
+/// try to reconnect.
+#[tokio::test]
+async fn e2e_node_ban_reports_the_traffic_that_caused_it() {
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts::default(),
+        ShardOpts {
+            max_node_data_per_second: Some(999),
+            ..Default::default()
+        },
+    )
+    .await;
+
+    let shard_id = server.add_shard().await.unwrap();
+    let shard = server.get_shard(shard_id).unwrap();
+    let (node_tx, _node_rx) = shard.connect_node().await.unwrap();
+
+    // Send just over 10x the limit, which is enough to be banned straight away:
+    for _ in 0..10 {
+        node_tx
+            .unbounded_send(SentMessage::Binary(vec![1; 1000]))
+            .unwrap();
+    }
+    tokio::time::sleep(Duration::from_millis(250)).await;
+    assert!(node_tx.is_closed(), "node should have been banned");
+
+    // Trying to connect again, we're told why we can't:
+    let uri: hyper::Uri = format!("http://{}/submit", shard.host()).parse().unwrap();
+    let res = hyper::Client::new().get(uri).await.unwrap();
+    assert_eq!(res.status(), 403);
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    assert_eq!(
+        std::str::from_utf8(&body).unwrap(),
+        "Too much traffic (1000 bytes per second)"
+    );
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Connections which send messages larger than '--max-node-message-size' are closed without the
+/// message being handled, but nodes can connect again and send messages which are small enough.
+#[tokio::test]
+async fn e2e_oversized_node_messages_close_the_connection() {
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts::default(),
+        ShardOpts {
+            max_node_message_size: Some(2048),
+            ..Default::default()
+        },
+    )
+    .await;
+
+    let shard_id = server.add_shard().await.unwrap();
+    let node_init_msg = |genesis_hash, name: String| {
+        json!({
+            "id":1,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload": {
+                "authority":true,
+                "chain":"Local Testnet",
+                "config":"",
+                "genesis_hash": genesis_hash,
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name":name,
+                "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                "startup_time":"1625565542717",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            },
+        })
+    };
+
+    // A perfectly valid message, but too large:
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .expect("can connect to shard");
+    node_tx
+        .send_json_text(node_init_msg(ghash(1), "A".repeat(4096)))
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert!(node_tx.is_closed(), "connection should be closed");
+
+    // A normal sized message on a new connection:
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .expect("can connect to shard");
+    node_tx
+        .send_json_text(node_init_msg(ghash(2), "Alice".to_owned()))
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    // Only the normal sized message was handled:
+    let (_feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert!(feed_messages.contains(&FeedMessage::AddedChain {
+        name: "Local Testnet".to_owned(),
+        genesis_hash: ghash(2),
+        node_count: 1,
+    }));
+    assert!(!feed_messages.iter().any(|msg| matches!(
+        msg,
+        FeedMessage::AddedChain { genesis_hash, .. } if *genesis_hash == ghash(1)
+    )));
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Once the core is tracking '--max-total-nodes' nodes, connections whose nodes are all muted
+/// because of this are closed after '--global-quota-backoff-seconds', so that they try again.
+#[tokio::test]
+async fn e2e_nodes_over_the_global_quota_are_asked_to_try_again() {
+    use FeedMessage::*;
+
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            max_total_nodes: Some(1),
+            ..Default::default()
+        },
+        ShardOpts {
+            global_quota_backoff_seconds: Some(1),
+            ..Default::default()
+        },
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+    let shard = server.get_shard(shard_id).unwrap();
+
+    // The first node takes the only space there is:
+    let (mut first_node_tx, _first_node_rx) = shard.connect_node().await.unwrap();
+    add_polkadot_nodes(&mut first_node_tx, 1);
+    let (_feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    loop {
+        let feed_messages = feed_rx
+            .recv_feed_messages_timeout(Duration::from_secs(10))
+            .await
+            .expect("first node should be added");
+        if feed_messages
+            .iter()
+            .any(|msg| matches!(msg, AddedChain { node_count: 1, .. }))
+        {
+            break;
+        }
+    }
+
+    // The next is muted, and once the backoff has passed, its connection is closed:
+    let (mut second_node_tx, _second_node_rx) = shard.connect_node().await.unwrap();
+    add_polkadot_nodes(&mut second_node_tx, 1);
+    tokio::time::sleep(Duration::from_millis(3000)).await;
+    assert!(
+        second_node_tx.is_closed(),
+        "muted node should be asked to try again"
+    );
+    assert!(
+        !first_node_tx.is_closed(),
+        "node that was added should be left alone"
+    );
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Node connections which go completely silent are closed after '--idle-socket-timeout',
+/// even though the node they told us about isn't yet stale.
+#[tokio::test]
+async fn e2e_idle_node_sockets_are_closed() {
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts::default(),
+        ShardOpts {
+            idle_socket_timeout: Some(2),
+            ..Default::default()
+        },
+    )
+    .await;
+
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .expect("can connect to shard");
+
+    node_tx
+        .send_json_text(json!({
+            "id":1,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload": {
+                "authority":true,
+                "chain":"Local Testnet",
+                "config":"",
+                "genesis_hash": ghash(1),
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name":"Alice",
+                "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                "startup_time":"1625565542717",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            },
+        }))
+        .unwrap();
+
+    // Well within the timeout, the connection is still open:
+    tokio::time::sleep(Duration::from_millis(1000)).await;
+    assert!(!node_tx.is_closed(), "connection should still be open");
+
+    // Once we've been silent for longer than the timeout, it's closed:
+    tokio::time::sleep(Duration::from_millis(2000)).await;
+    assert!(node_tx.is_closed(), "idle connection should be closed");
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Feeds will be disconnected if they can't receive messages quickly enough.
+#[tokio::test]
+async fn e2e_slow_feeds_are_disconnected() {
+    let mut server = start_server(
+        ServerOpts::default(),
+        // Timeout faster so the test can be quicker:
+        CoreOpts {
+            feed_timeout: Some(1),
+            ..Default::default()
+        },
+        lots_of_nodes_shard_opts(),
+    )
+    .await;
+
+    // Give us a shard to talk to:
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+
+    // Add a load of nodes from this shard so there's plenty of data to give to a feed.
+    // We want to exhaust any buffers between core and feed (eg BufWriters). If the number
+    // is too low, data will happily be sent into a buffer and the connection won't need to
+    // be closed.
+    add_polkadot_nodes(&mut node_tx, 100_000);
+
+    // Connect a raw feed so that we can control how fast we consume data from the websocket
+    let (mut raw_feed_tx, mut raw_feed_rx) = server.get_core().connect_feed_raw().await.unwrap();
+
+    // Subscribe the feed:
+    raw_feed_tx
+        .send_text("subscribe:0x0000000000000000000000000000000000000000000000000000000000000001")
+        .await
+        .unwrap();
+
+    // Wait a little.. the feed hasn't been receiving messages so it should
+    // be booted after ~a second.
+    tokio::time::sleep(Duration::from_secs(5)).await;
+
+    // Drain anything out and expect to hit a "closed" error, rather than get stuck
+    // waiting to receive more data (or see some other error).
+    loop {
+        let mut v = Vec::new();
+        let data =
+            tokio::time::timeout(Duration::from_secs(2), raw_feed_rx.receive_data(&mut v)).await;
+
+        match data {
+            Ok(Ok(_)) => {
+                continue; // Drain data
+            }
+            Ok(Err(soketto::connection::Error::Closed)) => {
+                break; // End loop; success!
+            }
+            Ok(Err(_e)) => {
+                // Occasionally we might hit an error here before the channel is marked as closed. The error probably
+                // means that the socket has been killed, but we haven't managed to set the state to closed in time
+                // and so we still hit this. We may be able to tighten this up and avoid this permanently, at which point
+                // this can become a test failure.
+                break;
+            }
+            Err(_) => {
+                panic!("recv should be closed but seems to be happy waiting for more data");
+            }
+        }
+    }
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Feeds subscribing to a large chain are given '--feed-subscribe-timeout' rather than
+/// '--feed-timeout' to receive the state of every node on it, so they aren't disconnected
+/// for being slow to receive it.
+#[tokio::test]
+async fn e2e_feeds_have_longer_to_receive_large_chains_they_subscribe_to() {
+    use FeedMessage::*;
+
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            feed_timeout: Some(1),
+            feed_subscribe_timeout: Some(60),
+            ..Default::default()
+        },
+        lots_of_nodes_shard_opts(),
+    )
+    .await;
+
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+
+    // Add enough nodes that the state of the chain won't fit in any buffers between the
+    // core and the feed, so that sending it waits on the feed receiving it:
+    let num_nodes = 100_000;
+    add_polkadot_nodes(&mut node_tx, num_nodes);
+
+    // Wait until every node has been added, so that nothing else is sent to feeds:
+    let (_feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    loop {
+        let feed_messages = feed_rx
+            .recv_feed_messages_timeout(Duration::from_secs(30))
+            .await
+            .expect("all nodes should be added");
+        if feed_messages.iter().any(|msg| {
+            matches!(msg, AddedChain { genesis_hash, node_count, .. }
+                if *genesis_hash == polkadot_genesis_hash() && *node_count == num_nodes)
+        }) {
+            break;
+        }
+    }
+
+    // Subscribe a raw feed to the chain, and take longer than '--feed-timeout' to start
+    // receiving it:
+    let (mut raw_feed_tx, mut raw_feed_rx) = server.get_core().connect_feed_raw().await.unwrap();
+    raw_feed_tx
+        .send_text(format!("subscribe:{:#x}", polkadot_genesis_hash()))
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_secs(3)).await;
+
+    // We can still receive all of it, and the feed isn't closed:
+    loop {
+        let mut v = Vec::new();
+        let data =
+            tokio::time::timeout(Duration::from_secs(2), raw_feed_rx.receive_data(&mut v)).await;
+        match data {
+            Ok(Ok(_)) => continue,
+            Ok(Err(e)) => panic!("feed should not be closed while receiving the chain: {e}"),
+            Err(_) => break,
+        }
+    }
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// If something connects to the `/submit` endpoint, there is a limit to the number
+/// of different messages IDs it can send telemetry about, to prevent a malicious actor from
+/// spamming a load of message IDs and exhausting our memory.
+#[tokio::test]
+async fn e2e_max_nodes_per_connection_is_enforced() {
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts::default(),
+        // Limit max nodes per connection to 2; any other msgs should be ignored.
+        ShardOpts {
+            max_nodes_per_connection: Some(2),
+            ..Default::default()
+        },
+    )
+    .await;
+
+    // Connect to a shard
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+
+    // Connect a feed.
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+
+    // We'll send these messages from the node:
+    let json_msg = |n| {
+        json!({
+            "id":n,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload": {
+                "authority":true,
+                "chain":"Test Chain",
+                "config":"",
+                "genesis_hash": ghash(1),
+                "implementation":"Polkadot",
+                "msg":"system.connected",
+                "name": format!("Alice {}", n),
+                "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                "startup_time":"1625565542717",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            }
+        })
+    };
+
+    // First message ID should lead to feed messages:
+    node_tx.send_json_text(json_msg(1)).unwrap();
+    assert_ne!(
+        feed_rx
+            .recv_feed_messages_timeout(Duration::from_secs(1))
+            .await
+            .unwrap()
+            .len(),
+        0
+    );
+
+    // Second message ID should lead to feed messages as well:
+    node_tx.send_json_text(json_msg(2)).unwrap();
+    assert_ne!(
+        feed_rx
+            .recv_feed_messages_timeout(Duration::from_secs(1))
+            .await
+            .unwrap()
+            .len(),
+        0
+    );
+
+    // Third message ID should be ignored:
+    node_tx.send_json_text(json_msg(3)).unwrap();
+    assert_eq!(
+        feed_rx
+            .recv_feed_messages_timeout(Duration::from_secs(1))
+            .await
+            .unwrap()
+            .len(),
+        0
+    );
+
+    // Forth message ID should be ignored as well:
+    node_tx.send_json_text(json_msg(4)).unwrap();
+    assert_eq!(
+        feed_rx
+            .recv_feed_messages_timeout(Duration::from_secs(1))
+            .await
+            .unwrap()
+            .len(),
+        0
+    );
+
+    // (now that the chain "Test Chain" is known about, subscribe to it for update messages.
+    // This wasn't needed to receive messages re the above since everybody hears about node
+    // count changes)
+    feed_tx
+        .send_command(
+            "subscribe",
+            "0x0000000000000000000000000000000000000000000000000000000000000001",
+        )
+        .unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+
+    // Update about non-ignored IDs should still lead to feed output:
+
+    node_tx.send_json_text(json!(
+        {"id":1, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":1},"ts":"2021-07-12T10:38:48.330433+01:00" }
+    )).unwrap();
+    assert_ne!(
+        feed_rx
+            .recv_feed_messages_timeout(Duration::from_secs(1))
+            .await
+            .unwrap()
+            .len(),
+        0
+    );
+
+    node_tx.send_json_text(json!(
+        {"id":2, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":1},"ts":"2021-07-12T10:38:48.330433+01:00" }
+    )).unwrap();
+    assert_ne!(
+        feed_rx
+            .recv_feed_messages_timeout(Duration::from_secs(1))
+            .await
+            .unwrap()
+            .len(),
+        0
+    );
+
+    // Updates about ignored IDs are still ignored:
+
+    node_tx.send_json_text(json!(
+        {"id":3, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":1},"ts":"2021-07-12T10:38:48.330433+01:00" }
+    )).unwrap();
+    assert_eq!(
+        feed_rx
+            .recv_feed_messages_timeout(Duration::from_secs(1))
+            .await
+            .unwrap()
+            .len(),
+        0
+    );
+
+    node_tx.send_json_text(json!(
+        {"id":4, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":1},"ts":"2021-07-12T10:38:48.330433+01:00" }
+    )).unwrap();
+    assert_eq!(
+        feed_rx
+            .recv_feed_messages_timeout(Duration::from_secs(1))
+            .await
+            .unwrap()
+            .len(),
+        0
+    );
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Wait for a raw feed connection to be closed, returning false if it's
+/// still open and waiting for more data after a couple of seconds.
+async fn raw_feed_is_closed(raw_feed_rx: &mut common::ws_client::RawReceiver) -> bool {
+    loop {
+        let mut v = Vec::new();
+        let data =
+            tokio::time::timeout(Duration::from_secs(2), raw_feed_rx.receive_data(&mut v)).await;
+
+        match data {
+            Ok(Ok(_)) => continue,
+            Ok(Err(_)) => return true,
+            Err(_) => return false,
+        }
+    }
+}
+
+/// If more than `--max-feeds` feeds connect, the "reject" policy turns the new feed
+/// connections away, leaving the existing ones alone. The limit applies across every
+/// aggregator rather than to each of them.
+#[tokio::test]
+async fn e2e_max_feeds_reject_turns_new_feeds_away() {
+    let server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            num_aggregators: Some(2),
+            max_feeds: Some(3),
+            max_feeds_policy: Some("reject".to_owned()),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+
+    let mut old_feeds = Vec::new();
+    for _ in 0..3 {
+        old_feeds.push(server.get_core().connect_feed_raw().await.unwrap());
+    }
+    for _ in 0..3 {
+        assert!(server.get_core().connect_feed_raw().await.is_err());
+    }
+    for (_, old_feed_rx) in &mut old_feeds {
+        assert!(!raw_feed_is_closed(old_feed_rx).await);
+    }
+
+    // Once a feed disconnects, there's room for another:
+    drop(old_feeds.pop());
+    let mut connected = false;
+    for _ in 0..20 {
+        if server.get_core().connect_feed_raw().await.is_ok() {
+            connected = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(connected, "a new feed should be able to connect");
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// When the core is asked to shut down, connected feeds are sent a close frame rather
+/// than having their connections dropped.
+#[tokio::test]
+async fn e2e_feeds_are_closed_cleanly_on_shutdown() {
+    let server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            shutdown_grace_seconds: Some(5),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+
+    let (_raw_feed_tx, mut raw_feed_rx) = server.get_core().connect_feed_raw().await.unwrap();
+    server.get_core().terminate().await.unwrap();
+
+    // Drain anything out and expect to hit a "closed" error, rather than the
+    // connection just dropping (or getting stuck waiting for more data).
+    loop {
+        let mut v = Vec::new();
+        let data =
+            tokio::time::timeout(Duration::from_secs(5), raw_feed_rx.receive_data(&mut v)).await;
+
+        match data {
+            Ok(Ok(_)) => continue,
+            Ok(Err(soketto::connection::Error::Closed)) => break,
+            Ok(Err(e)) => panic!("recv should be closed cleanly but instead we saw {:?}", e),
+            Err(_) => panic!("recv should be closed but seems to be happy waiting for more data"),
+        }
+    }
+
+    server.shutdown().await;
+}
+
+/// When a shard is asked to shut down, it closes its connections and exits within the
+/// grace period it's been given.
+#[tokio::test]
+async fn e2e_shards_exit_within_the_shutdown_grace_period() {
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts::default(),
+        ShardOpts {
+            shutdown_grace_seconds: Some(2),
+            ..Default::default()
+        },
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+    add_polkadot_nodes(&mut node_tx, 1);
+
+    let shard = server.get_shard_mut(shard_id).unwrap();
+    shard.terminate().await.unwrap();
+    assert!(
+        shard.exited_within(Duration::from_secs(3)).await.unwrap(),
+        "shard should exit within its shutdown grace period"
+    );
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Feeds that connect to '/feed?chain_digest=true' are told about every chain in a single
+/// 'AddedChains' message rather than an 'AddedChain' message per chain.
+#[tokio::test]
+async fn e2e_feeds_can_ask_for_a_chain_digest() {
+    let mut server = start_server_debug().await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .expect("can connect to shard");
+
+    for (id, chain_name, genesis_hash) in [(1, "Chain 1", ghash(1)), (2, "Chain 2", ghash(2))] {
+        node_tx
+            .send_json_text(json!({
+                "id":id,
+                "ts":"2021-07-12T10:37:47.714666+01:00",
+                "payload": {
+                    "authority":true,
+                    "chain": chain_name,
+                    "config":"",
+                    "genesis_hash": genesis_hash,
+                    "implementation":"Substrate Node",
+                    "msg":"system.connected",
+                    "name": format!("Node {id}"),
+                    "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                    "startup_time":"1625565542717",
+                    "version":"2.0.0-07a1af348-aarch64-macos"
+                },
+            }))
+            .unwrap();
+    }
+
+    // Wait a little for these messages to propagate to the core:
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let uri: http::Uri = format!("http://{}/feed?chain_digest=true", server.get_core().host())
+        .parse()
+        .unwrap();
+    let (_feed_tx, feed_rx) = common::ws_client::connect(&uri)
+        .await
+        .unwrap()
+        .into_channels();
+    let mut feed_rx: FeedReceiver = feed_rx.into();
+
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    let mut chains = feed_messages
+        .iter()
+        .find_map(|msg| match msg {
+            FeedMessage::AddedChains { chains } => Some(chains.clone()),
+            _ => None,
+        })
+        .expect("should be told about chains in one message");
+    chains.sort();
+    assert_eq!(
+        chains,
+        vec![
+            ("Chain 1".to_owned(), ghash(1), 1),
+            ("Chain 2".to_owned(), ghash(2), 1)
+        ]
+    );
+    assert!(!feed_messages
+        .iter()
+        .any(|msg| matches!(msg, FeedMessage::AddedChain { .. })));
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Nodes whose location can't be found from their IP address (here, because they connect
+/// from a private address) lead to a 'LocationFailed' message rather than a 'LocatedNode' one.
+#[tokio::test]
+async fn e2e_feeds_are_told_when_a_node_cannot_be_located() {
+    use FeedMessage::*;
+    let mut server = start_server_debug().await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .expect("can connect to shard");
+
+    let system_connected = |id: u64, name: &str| {
+        json!({
+            "id":id,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload": {
+                "authority":true,
+                "chain":"Local Testnet",
+                "config":"",
+                "genesis_hash": ghash(1),
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name": name,
+                "network_id": format!("12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDE{id}"),
+                "startup_time":"1625565542717",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            },
+        })
+    };
+
+    // Add a node so that there's a chain to subscribe to:
+    node_tx
+        .send_json_text(system_connected(1, "Alice"))
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    feed_tx
+        .send_command("subscribe", &format!("{:#x}", ghash(1)))
+        .unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+
+    // The feed hears about the next node, and that it couldn't be located:
+    let (mut private_node_tx, _private_node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node_with_headers(&[("x-forwarded-for", "10.0.0.1")])
+        .await
+        .expect("can connect to shard");
+    private_node_tx
+        .send_json_text(system_connected(2, "Bob"))
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(&feed_messages, AddedNode { node_id: 1, .. });
+    assert_contains_matches!(&feed_messages, LocationFailed { node_id: 1 });
+    assert!(!feed_messages
+        .iter()
+        .any(|msg| matches!(msg, LocatedNode { .. })));
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// With `--location-broadcast-interval-ms`, the locations of nodes found within one interval
+/// are batched up, and every one of them is sent out when the batch is flushed.
+#[tokio::test]
+async fn e2e_every_batched_up_location_is_sent() {
+    use FeedMessage::*;
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            location_broadcast_interval_ms: Some(2000),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+
+    let system_connected = |id: u64, name: &str| {
+        json!({
+            "id":id,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload": {
+                "authority":true,
+                "chain":"Local Testnet",
+                "config":"",
+                "genesis_hash": ghash(1),
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name": name,
+                "network_id": format!("12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDE{id}"),
+                "startup_time":"1625565542717",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            },
+        })
+    };
+
+    // Add a node so that there's a chain to subscribe to:
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .expect("can connect to shard");
+    node_tx
+        .send_json_text(system_connected(1, "Alice"))
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    feed_tx
+        .send_command("subscribe", &format!("{:#x}", ghash(1)))
+        .unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+
+    // Several more nodes are located well within one interval:
+    let mut nodes = Vec::new();
+    for (id, name, ip) in [
+        (2, "Bob", "12.5.56.25"),
+        (3, "Charlie", "127.0.0.1"),
+        (4, "Dave", "12.5.56.25"),
+    ] {
+        let (mut node_tx, node_rx) = server
+            .get_shard(shard_id)
+            .unwrap()
+            .connect_node_with_headers(&[("x-forwarded-for", ip)])
+            .await
+            .expect("can connect to shard");
+        node_tx.send_json_text(system_connected(id, name)).unwrap();
+        nodes.push((node_tx, node_rx));
+    }
+
+    // The feed is told where every one of them is once the batch is flushed:
+    let mut located = Vec::new();
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+    while located.len() < 3 && tokio::time::Instant::now() < deadline {
+        let feed_messages = feed_rx
+            .recv_feed_messages_timeout(Duration::from_secs(5))
+            .await
+            .unwrap();
+        // Alice may only be located now if we were slow, so ignore her:
+        located.extend(feed_messages.into_iter().filter_map(|msg| match msg {
+            LocatedNode { node_id, city, .. } if node_id != 0 => Some((node_id, city)),
+            _ => None,
+        }));
+    }
+    located.sort();
+    let node_ids: Vec<_> = located.iter().map(|(node_id, _)| *node_id).collect();
+    let mut cities: Vec<_> = located.iter().map(|(_, city)| city.as_str()).collect();
+    cities.sort_unstable();
+    assert_eq!(node_ids, vec![1, 2, 3]);
+    assert_eq!(cities, vec!["Berlin", "Gardena", "Gardena"]);
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// '/feed/v2' sends the same messages as '/feed', but as newline delimited JSON objects
+/// which each name the message's action.
+#[tokio::test]
+async fn e2e_feed_v2_sends_ndjson() {
+    let server = start_server_debug().await;
+
+    let uri: http::Uri = format!("http://{}/feed/v2", server.get_core().host())
+        .parse()
+        .unwrap();
+    let (_feed_tx, mut feed_rx) = common::ws_client::connect(&uri).await.unwrap().into_raw();
+
+    let mut data = Vec::new();
+    tokio::time::timeout(Duration::from_secs(10), feed_rx.receive_data(&mut data))
+        .await
+        .expect("timed out waiting for feed messages")
+        .unwrap();
+
+    let first_line = std::str::from_utf8(&data).unwrap().lines().next().unwrap();
+    let version: serde_json::Value = serde_json::from_str(first_line).unwrap();
+    assert_eq!(version, json!({ "action": "Version", "payload": 32 }));
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Rather than picking an endpoint, feeds can offer a websocket subprotocol to pick the
+/// format of the messages that they're sent, and are told which one we'll speak.
+#[tokio::test]
+async fn e2e_feed_format_can_be_negotiated_with_a_subprotocol() {
+    let server = start_server_debug().await;
+
+    async fn receive_first_message(feed_rx: &mut common::ws_client::RawReceiver) -> String {
+        let mut data = Vec::new();
+        tokio::time::timeout(Duration::from_secs(10), feed_rx.receive_data(&mut data))
+            .await
+            .expect("timed out waiting for feed messages")
+            .unwrap();
+        String::from_utf8(data).unwrap()
+    }
+
+    // Ask for newline delimited JSON from '/feed':
+    let uri: http::Uri = format!("http://{}/feed", server.get_core().host())
+        .parse()
+        .unwrap();
+    let conn = common::ws_client::connect_with_protocols(&uri, &["foo", "telemetry-feed-ndjson"])
+        .await
+        .unwrap();
+    assert_eq!(conn.protocol(), Some("telemetry-feed-ndjson"));
+    let (_feed_tx, mut feed_rx) = conn.into_raw();
+    let data = receive_first_message(&mut feed_rx).await;
+    let version: serde_json::Value = serde_json::from_str(data.lines().next().unwrap()).unwrap();
+    assert_eq!(version, json!({ "action": "Version", "payload": 32 }));
+
+    // Ask for JSON arrays from '/feed/v2':
+    let uri: http::Uri = format!("http://{}/feed/v2", server.get_core().host())
+        .parse()
+        .unwrap();
+    let conn = common::ws_client::connect_with_protocols(&uri, &["telemetry-feed-v32"])
+        .await
+        .unwrap();
+    assert_eq!(conn.protocol(), Some("telemetry-feed-v32"));
+    let (_feed_tx, mut feed_rx) = conn.into_raw();
+    let data = receive_first_message(&mut feed_rx).await;
+    let messages: Vec<serde_json::Value> = serde_json::from_str(&data).unwrap();
+    assert_eq!(messages[..2], [json!(0), json!(32)]);
+
+    // Nothing is agreed if we don't offer anything that the server speaks:
+    let conn = common::ws_client::connect_with_protocols(&uri, &["foo"])
+        .await
+        .unwrap();
+    assert_eq!(conn.protocol(), None);
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// With '--feed-permessage-deflate', feeds that offer the permessage-deflate extension have
+/// their messages compressed, and these decompress back into the usual feed messages.
+#[tokio::test]
+async fn e2e_feeds_can_use_permessage_deflate() {
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            feed_permessage_deflate: true,
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .expect("can connect to shard");
+    node_tx
+        .send_json_text(json!({
+            "id":1,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload": {
+                "authority":true,
+                "chain":"Local Testnet",
+                "config":"",
+                "genesis_hash": ghash(1),
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name":"Alice",
+                "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                "startup_time":"1625565542717",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            },
+        }))
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    // The extension is accepted when it's offered:
+    let response = hyper::Client::new()
+        .request(
+            hyper::Request::get(format!("http://{}/feed", server.get_core().host()))
+                .header("Connection", "upgrade")
+                .header("Upgrade", "websocket")
+                .header("Sec-WebSocket-Version", "13")
+                .header("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==")
+                .header(
+                    "Sec-WebSocket-Extensions",
+                    "permessage-deflate; client_max_window_bits",
+                )
+                .body(hyper::Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), hyper::StatusCode::SWITCHING_PROTOCOLS);
+    let extensions = response.headers()["Sec-WebSocket-Extensions"]
+        .to_str()
+        .unwrap();
+    assert!(extensions.starts_with("permessage-deflate"));
+
+    // And compressed messages decompress into the usual feed messages:
+    let uri: http::Uri = format!("http://{}/feed", server.get_core().host())
+        .parse()
+        .unwrap();
+    let (feed_tx, feed_rx) = common::ws_client::connect_with_deflate(&uri)
+        .await
+        .unwrap()
+        .into_channels();
+    let feed_tx: test_utils::server::channels::FeedSender = feed_tx.into();
+    let mut feed_rx: FeedReceiver = feed_rx.into();
+    feed_tx
+        .send_command("subscribe", &format!("{:#x}", ghash(1)))
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        feed_messages,
+        FeedMessage::AddedChain { name, node_count: 1, .. } if name == "Local Testnet",
+        FeedMessage::SubscribedTo { genesis_hash } if genesis_hash == ghash(1),
+        FeedMessage::AddedNode { node_id: 0, .. }
+    );
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Feeds beyond the '--connection-limits' for '/feed' are turned away before they're
+/// upgraded to websocket connections, until an existing feed disconnects.
+#[tokio::test]
+async fn e2e_feed_connection_limit_turns_away_new_feeds() {
+    let server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            connection_limits: Some("feed=1".to_owned()),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+
+    let (old_feed_tx, old_feed_rx) = server.get_core().connect_feed_raw().await.unwrap();
+    assert!(server.get_core().connect_feed_raw().await.is_err());
+
+    // Once the first feed disconnects, there's room for another:
+    drop((old_feed_tx, old_feed_rx));
+    let mut connected = false;
+    for _ in 0..20 {
+        if server.get_core().connect_feed_raw().await.is_ok() {
+            connected = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(connected, "a new feed should be able to connect");
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Feeds beyond '--max-feeds-per-ip' from the same address are turned away, until one of the
+/// existing feeds from it disconnects.
+#[tokio::test]
+async fn e2e_feeds_are_limited_per_ip() {
+    let server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            max_feeds_per_ip: Some(2),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+
+    let (old_feed_tx, old_feed_rx) = server.get_core().connect_feed_raw().await.unwrap();
+    let _other_feed = server.get_core().connect_feed_raw().await.unwrap();
+    for _ in 0..3 {
+        assert!(server.get_core().connect_feed_raw().await.is_err());
+    }
+
+    // Once a feed disconnects, there's room for another:
+    drop((old_feed_tx, old_feed_rx));
+    let mut connected = false;
+    for _ in 0..20 {
+        if server.get_core().connect_feed_raw().await.is_ok() {
+            connected = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(connected, "a new feed should be able to connect");
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// If more than `--max-feeds` feeds connect, the "evict-oldest" policy closes the
+/// feed that was least recently active to make room for the new one. As with the "reject"
+/// policy, the limit applies across every aggregator rather than to each of them.
+#[tokio::test]
+async fn e2e_max_feeds_evict_oldest_closes_old_feed() {
+    let server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            num_aggregators: Some(2),
+            max_feeds: Some(3),
+            max_feeds_policy: Some("evict-oldest".to_owned()),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+
+    let mut old_feeds = Vec::new();
+    for _ in 0..3 {
+        old_feeds.push(server.get_core().connect_feed_raw().await.unwrap());
+        // Make sure that the feeds are active at distinct times:
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    let (_new_feed_tx, mut new_feed_rx) = server.get_core().connect_feed_raw().await.unwrap();
+
+    // Only the first feed to connect is closed to make room:
+    let (_, oldest_feed_rx) = &mut old_feeds[0];
+    assert!(raw_feed_is_closed(oldest_feed_rx).await);
+    for (_, old_feed_rx) in &mut old_feeds[1..] {
+        assert!(!raw_feed_is_closed(old_feed_rx).await);
+    }
+    assert!(!raw_feed_is_closed(&mut new_feed_rx).await);
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// With `--node-removal-grace-ms`, a node that disconnects is marked as stale rather than
+/// removed, and if it reconnects in time it's recovered rather than added again. If it
+/// doesn't reconnect in time, it's removed.
+#[tokio::test]
+async fn e2e_node_removal_is_deferred_for_grace_period() {
+    use FeedMessage::*;
+
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            node_removal_grace_ms: Some(3000),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+
+    let system_connected = json!(
+        {
+            "id":1,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload": {
+                "authority":true,
+                "chain":"Local Testnet",
+                "config":"",
+                "genesis_hash": ghash(1),
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name":"Alice",
+                "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                "startup_time":"1625565542717",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            },
+        }
+    );
+
+    // Connect a node, and subscribe a feed to its chain:
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+    node_tx.send_json_text(system_connected.clone()).unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    feed_tx
+        .send_command("subscribe", &format!("{:#x}", ghash(1)))
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(feed_messages, AddedNode { node_id: 0, .. });
+
+    // When the node disconnects, it's marked as stale rather than removed:
+    node_tx.close().await.unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(&feed_messages, StaleNode { node_id: 0 });
+    assert!(!feed_messages
+        .iter()
+        .any(|msg| matches!(msg, RemovedNode { .. })));
+
+    // When it reconnects, it's recovered rather than added again:
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+    node_tx.send_json_text(system_connected.clone()).unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(&feed_messages, RecoveredNode { node_id: 0 });
+    assert!(!feed_messages
+        .iter()
+        .any(|msg| matches!(msg, AddedNode { .. })));
+
+    // If it comes back with different details, feeds are sent it again with those details,
+    // in place of the node they know about:
+    node_tx.close().await.unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(&feed_messages, StaleNode { node_id: 0 });
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+    let mut renamed = system_connected;
+    renamed["payload"]["name"] = json!("Alice (restarted)");
+    node_tx.send_json_text(renamed).unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        &feed_messages,
+        RemovedNode { node_id: 0 },
+        AddedNode { node_id: 0, node: NodeDetails { name, .. }, .. } if name == "Alice (restarted)",
+    );
+
+    // If it disconnects and doesn't come back, it's removed once the grace period is over.
+    // It's the only node on the chain, so the chain is removed along with it:
+    node_tx.close().await.unwrap();
+    let mut feed_messages = Vec::new();
+    while !feed_messages
+        .iter()
+        .any(|msg| matches!(msg, RemovedChain { .. }))
+    {
+        let mut new_messages = feed_rx
+            .recv_feed_messages_timeout(Duration::from_secs(10))
+            .await
+            .unwrap();
+        assert!(!new_messages.is_empty(), "node should have been removed");
+        feed_messages.append(&mut new_messages);
+    }
+    assert_contains_matches!(
+        feed_messages,
+        StaleNode { node_id: 0 },
+        RemovedChain { genesis_hash } if genesis_hash == ghash(1)
+    );
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// With `--empty-chain-ttl-ms`, a chain whose last node goes is listed to feeds with a node
+/// count of 0 until the TTL has passed, and only then removed.
+#[tokio::test]
+async fn e2e_empty_chains_are_kept_until_their_ttl_has_passed() {
+    use FeedMessage::*;
+
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            empty_chain_ttl_ms: Some(3000),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+
+    // Connect a node, and subscribe a feed to its chain:
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+    node_tx
+        .send_json_text(json!({
+            "id":1,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload": {
+                "authority":true,
+                "chain":"Local Testnet",
+                "config":"",
+                "genesis_hash": ghash(1),
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name":"Alice",
+                "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                "startup_time":"1625565542717",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            },
+        }))
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    feed_tx
+        .send_command("subscribe", &format!("{:#x}", ghash(1)))
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(feed_messages, AddedNode { node_id: 0, .. });
+
+    // When the node disconnects, the chain stays listed with no nodes:
+    node_tx.close().await.unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(&feed_messages, RemovedNode { node_id: 0 });
+    assert_contains_matches!(
+        &feed_messages,
+        AddedChain {
+            genesis_hash,
+            node_count: 0,
+            ..
+        } if *genesis_hash == ghash(1)
+    );
+    assert!(!feed_messages
+        .iter()
+        .any(|msg| matches!(msg, RemovedChain { .. })));
+
+    // Once the TTL has passed, the chain is removed:
+    let feed_messages = feed_rx
+        .recv_feed_messages_timeout(Duration::from_secs(10))
+        .await
+        .expect("chain should be removed once the TTL has passed");
+    assert_contains_matches!(
+        feed_messages,
+        RemovedChain { genesis_hash } if genesis_hash == ghash(1)
+    );
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// If an allowlist is given, nodes on chains that aren't on it are muted, and so feeds
+/// never hear about those chains.
+#[tokio::test]
+async fn e2e_nodes_on_chains_not_in_allowlist_are_muted() {
+    use bincode::Options;
+    use common::internal_messages::{FromTelemetryCore, MuteReason, ShardNodeId};
+    use common::ws_client::RecvMessage;
+    use futures::StreamExt;
+    use FeedMessage::*;
+
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            allowlist: Some(vec!["Local Testnet".to_owned()]),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+
+    let (_feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+
+    // Connect a node on a chain that isn't on the allowlist, and one that is:
+    let mut nodes = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_multiple_nodes(2)
+        .await
+        .unwrap();
+    for (idx, (chain, (node_tx, _))) in ["Other Testnet", "Local Testnet"]
+        .iter()
+        .zip(nodes.iter_mut())
+        .enumerate()
+    {
+        node_tx
+            .send_json_text(json!({
+                "id":1,
+                "ts":"2021-07-12T10:37:47.714666+01:00",
+                "payload": {
+                    "authority":true,
+                    "chain":chain,
+                    "config":"",
+                    "genesis_hash": ghash(idx as u64 + 1),
+                    "implementation":"Substrate Node",
+                    "msg":"system.connected",
+                    "name":"Alice",
+                    "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                    "startup_time":"1625565542717",
+                    "version":"2.0.0-07a1af348-aarch64-macos"
+                },
+            }))
+            .unwrap();
+    }
+
+    // Only the allowlisted chain is added:
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        &feed_messages,
+        AddedChain {
+            name,
+            node_count: 1,
+            ..
+        } if name == "Local Testnet"
+    );
+    assert!(!feed_messages
+        .iter()
+        .any(|msg| matches!(msg, AddedChain { name, .. } if name == "Other Testnet")));
+
+    // And the shard is told to stop sending updates about the node which isn't allowed. Pretend
+    // to be a shard to see what the core sends back to it:
+    let uri: http::Uri = format!("http://{}/shard_submit", server.get_core().host())
+        .parse()
+        .unwrap();
+    let (shard_tx, mut shard_rx) = common::ws_client::connect(&uri)
+        .await
+        .unwrap()
+        .into_channels();
+    shard_tx
+        .unbounded_send(shard_add_node(7, "Bob", "Other Testnet", ghash(3)))
+        .unwrap();
+    let reply = tokio::time::timeout(Duration::from_secs(10), shard_rx.next())
+        .await
+        .expect("the core should reply to the shard")
+        .expect("the shard connection should stay open")
+        .unwrap();
+    let RecvMessage::Binary(bytes) = reply else {
+        panic!("expected a binary message, got {reply:?}");
+    };
+    let reply: FromTelemetryCore = bincode::options().deserialize(&bytes).unwrap();
+    assert!(
+        matches!(
+            reply,
+            FromTelemetryCore::Mute {
+                local_id,
+                reason: MuteReason::ChainNotAllowed
+            } if local_id == ShardNodeId::new(7)
+        ),
+        "expected the node to be muted, got {reply:?}"
+    );
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// '/health/detailed' reports how many feeds, shards, nodes and chains are connected.
+#[tokio::test]
+async fn e2e_detailed_health_reports_connection_counts() {
+    let mut server = start_server_debug().await;
+    let shard_id = server.add_shard().await.unwrap();
+
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .expect("can connect to shard");
+    node_tx
+        .send_json_text(json!({
+            "id":1,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload": {
+                "authority":true,
+                "chain":"Local Testnet",
+                "config":"",
+                "genesis_hash": ghash(1),
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name":"Alice",
+                "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                "startup_time":"1625565542717",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            },
+        }))
+        .unwrap();
+    let (_feed_tx, _feed_rx) = server.get_core().connect_feed().await.unwrap();
+
+    // Metrics are only gathered every few seconds, so keep asking until they're up to date:
+    let uri: hyper::Uri = format!("http://{}/health/detailed", server.get_core().host())
+        .parse()
+        .unwrap();
+    let client = hyper::Client::new();
+    let expected = json!({
+        "status": "ok",
+        "connected_feeds": 1,
+        "connected_shards": 1,
+        "connected_nodes": 1,
+        "chains": 1,
+    });
+    let mut health = serde_json::Value::Null;
+    for _ in 0..30 {
+        let res = client.get(uri.clone()).await.unwrap();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        health = serde_json::from_slice(&body).unwrap();
+        if health == expected {
+            break;
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+    assert_eq!(health, expected);
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// The number of nodes that have been added and removed is exposed via '/metrics', so that
+/// node churn can be tracked.
+#[tokio::test]
+async fn e2e_metrics_count_node_adds_and_removes() {
+    let mut server = start_server_debug().await;
+    let shard_id = server.add_shard().await.unwrap();
+
+    let mut nodes = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_multiple_nodes(3)
+        .await
+        .unwrap();
+    for (idx, (node_tx, _)) in nodes.iter_mut().enumerate() {
+        node_tx
+            .send_json_text(json!({
+                "id":1,
+                "ts":"2021-07-12T10:37:47.714666+01:00",
+                "payload": {
+                    "authority":true,
+                    "chain":"Local Testnet",
+                    "config":"",
+                    "genesis_hash": ghash(1),
+                    "implementation":"Substrate Node",
+                    "msg":"system.connected",
+                    "name": format!("Alice {}", idx),
+                    "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                    "startup_time":"1625565542717",
+                    "version":"2.0.0-07a1af348-aarch64-macos"
+                },
+            }))
+            .unwrap();
+    }
+
+    // Give the nodes a moment to be added before disconnecting two of them:
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    for (node_tx, _) in nodes.iter_mut().skip(1) {
+        node_tx.close().await.unwrap();
+    }
+
+    // Metrics are only gathered every few seconds, so keep scraping until they show up:
+    let uri: hyper::Uri = format!("http://{}/metrics", server.get_core().host())
+        .parse()
+        .unwrap();
+    let client = hyper::Client::new();
+    let has_value = |metrics: &str, name: &str, value: &str| {
+        metrics.lines().any(|l| {
+            let mut parts = l.split_whitespace();
+            let metric = parts.next().unwrap_or_default();
+            (metric == name || metric.starts_with(&format!("{name}{{")))
+                && parts.next() == Some(value)
+        })
+    };
+    let expected = [
+        ("telemetry_core_node_adds_total", "3"),
+        ("telemetry_core_node_removes_total", "2"),
+    ];
+    let mut metrics = String::new();
+    for _ in 0..30 {
+        let res = client.get(uri.clone()).await.unwrap();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        metrics = String::from_utf8(body.to_vec()).unwrap();
+        if expected
+            .iter()
+            .all(|(name, value)| has_value(&metrics, name, value))
+        {
+            break;
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+
+    for (name, value) in expected {
+        assert!(
+            has_value(&metrics, name, value),
+            "expected '{name}' to be {value} in metrics:\n{metrics}"
+        );
+    }
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// The number of nodes on each first party or pinned chain is exposed via '/metrics', labelled
+/// by chain. Other chains aren't, so that they can't give us an unbounded number of labels.
+#[tokio::test]
+async fn e2e_metrics_include_node_count_per_chain() {
+    let mut server = start_server_debug().await;
+    let shard_id = server.add_shard().await.unwrap();
+
+    // Connect two nodes to a first party chain and one node to another chain:
+    let mut nodes = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_multiple_nodes(3)
+        .await
+        .unwrap();
+    let chains = [
+        ("Polkadot", polkadot_genesis_hash()),
+        ("Polkadot", polkadot_genesis_hash()),
+        ("Local Testnet", ghash(2)),
+    ];
+    for (idx, ((chain, genesis_hash), (node_tx, _))) in
+        chains.iter().zip(nodes.iter_mut()).enumerate()
+    {
+        node_tx
+            .send_json_text(json!({
+                "id":1,
+                "ts":"2021-07-12T10:37:47.714666+01:00",
+                "payload": {
+                    "authority":true,
+                    "chain":chain,
+                    "config":"",
+                    "genesis_hash": genesis_hash,
+                    "implementation":"Substrate Node",
+                    "msg":"system.connected",
+                    "name": format!("Alice {}", idx),
+                    "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                    "startup_time":"1625565542717",
+                    "version":"2.0.0-07a1af348-aarch64-macos"
+                },
+            }))
+            .unwrap();
+    }
+
+    // Metrics are only gathered every few seconds, so keep scraping until they show up:
+    let uri: hyper::Uri = format!("http://{}/metrics", server.get_core().host())
+        .parse()
+        .unwrap();
+    let client = hyper::Client::new();
+    let expected = [r#"chain="Polkadot"} 2 "#];
+    let mut metrics = String::new();
+    for _ in 0..30 {
+        let res = client.get(uri.clone()).await.unwrap();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        metrics = String::from_utf8(body.to_vec()).unwrap();
+        if expected.iter().all(|e| metrics.contains(e)) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+
+    for e in expected {
+        assert!(
+            metrics
+                .lines()
+                .any(|l| l.starts_with("telemetry_core_chain_node_count{") && l.contains(e)),
+            "expected '{e}' in metrics:\n{metrics}"
+        );
+    }
+    assert!(
+        !metrics.contains("Local Testnet"),
+        "unexpected chain in metrics:\n{metrics}"
+    );
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// If the core is given a '--shard-secret', shards which present it can connect and send
+/// node data, and connections to '/shard_submit' without it are rejected with a 401.
+#[tokio::test]
+async fn e2e_shards_must_present_the_shard_secret() {
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            shard_secret: Some("open-sesame".to_owned()),
+            ..Default::default()
+        },
+        ShardOpts {
+            shard_secret: Some("open-sesame".to_owned()),
+            ..Default::default()
+        },
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+
+    // A shard with the right secret connects and its nodes show up on the feed:
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .expect("can connect to shard");
+    node_tx
+        .send_json_text(json!({
+            "id":1,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload": {
+                "authority":true,
+                "chain":"Local Testnet",
+                "config":"",
+                "genesis_hash": ghash(1),
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name":"Alice",
+                "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                "startup_time":"1625565542717",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            },
+        }))
+        .unwrap();
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        feed_messages,
+        FeedMessage::AddedChain { genesis_hash, node_count: 1, .. } if genesis_hash == ghash(1),
+    );
+    feed_tx
+        .send_command("subscribe", &format!("{:#x}", ghash(1)))
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        feed_messages,
+        FeedMessage::AddedNode { node: NodeDetails { name, .. }, ..} if name == "Alice",
+    );
+
+    // Connections with the wrong secret, or none at all, are turned away:
+    let uri: http::Uri = format!("http://{}/shard_submit", server.get_core().host())
+        .parse()
+        .unwrap();
+    let wrong_secret =
+        common::ws_client::connect_with_headers(&uri, &[("Authorization", "Bearer wrong")]).await;
+    assert!(matches!(
+        wrong_secret,
+        Err(ConnectError::ConnectionFailedRejected { status_code: 401 })
+    ));
+    let no_secret = common::ws_client::connect(&uri).await;
+    assert!(matches!(
+        no_secret,
+        Err(ConnectError::ConnectionFailedRejected { status_code: 401 })
+    ));
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// With '--tolerate-shard-parse-errors', a message from a shard that can't be deserialized is
+/// skipped and counted, rather than the whole shard (and all of its nodes) being disconnected.
+#[tokio::test]
+async fn e2e_shard_messages_that_cant_be_parsed_can_be_skipped() {
+    let server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            tolerate_shard_parse_errors: true,
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+
+    // Pretend to be a shard, so that we can send the core whatever bytes we like:
+    let uri: http::Uri = format!("http://{}/shard_submit", server.get_core().host())
+        .parse()
+        .unwrap();
+    let (shard_tx, _shard_rx) = common::ws_client::connect(&uri)
+        .await
+        .unwrap()
+        .into_channels();
+    let add_node = |local_id, name| shard_add_node(local_id, name, "Local Testnet", ghash(1));
+
+    // A corrupt message arrives between two valid ones:
+    shard_tx.unbounded_send(add_node(1, "Alice")).unwrap();
+    shard_tx
+        .unbounded_send(SentMessage::Binary(vec![0xff; 16]))
+        .unwrap();
+    shard_tx.unbounded_send(add_node(2, "Bob")).unwrap();
+
+    // Both valid messages are handled:
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(feed_messages, FeedMessage::AddedChain { node_count: 2, .. });
+    feed_tx
+        .send_command("subscribe", &format!("{:#x}", ghash(1)))
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        feed_messages,
+        FeedMessage::AddedNode { node: NodeDetails { name, .. }, ..} if name == "Alice",
+        FeedMessage::AddedNode { node: NodeDetails { name, .. }, ..} if name == "Bob",
+    );
+
+    // ...and the corrupt one is counted:
+    let uri: hyper::Uri = format!("http://{}/metrics", server.get_core().host())
+        .parse()
+        .unwrap();
+    let res = hyper::Client::new().get(uri).await.unwrap();
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    let metrics = String::from_utf8(body.to_vec()).unwrap();
+    assert!(
+        metrics
+            .lines()
+            .any(|l| l == "telemetry_core_shard_parse_errors_total 1"),
+        "expected one parse error in metrics:\n{metrics}"
+    );
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Feeds are told how long each node's process has been running for, going by the startup
+/// time that it reports, when they subscribe and then every '--node-uptime-broadcast-secs'.
+#[tokio::test]
+async fn e2e_feeds_are_told_how_long_nodes_have_been_up() {
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            node_uptime_broadcast_secs: Some(2),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+
+    // The node started up an hour ago:
+    let startup_time = common::time::now() - 60 * 60 * 1000;
+    node_tx
+        .send_json_text(json!({
+            "id":1,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload": {
+                "authority":true,
+                "chain":"Local Testnet",
+                "config":"",
+                "genesis_hash": ghash(1),
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name":"Alice",
+                "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                "startup_time": startup_time.to_string(),
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            },
+        }))
+        .unwrap();
+
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+    feed_tx
+        .send_command("subscribe", &format!("{:#x}", ghash(1)))
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        &feed_messages,
+        FeedMessage::AddedNode { node_id: 0, .. },
+        FeedMessage::NodeProcessUptime { node_id: 0, uptime_secs } if (3600..3660).contains(uptime_secs)
+    );
+    // Without an uptime database, there's no total uptime to tell feeds about:
+    assert!(!feed_messages
+        .iter()
+        .any(|msg| matches!(msg, FeedMessage::NodeUptime { .. })));
+
+    // It keeps being sent out while the feed is subscribed:
+    let feed_messages = feed_rx
+        .recv_feed_messages_timeout(Duration::from_secs(5))
+        .await
+        .unwrap();
+    assert_contains_matches!(
+        feed_messages,
+        FeedMessage::NodeProcessUptime { node_id: 0, uptime_secs } if (3600..3660).contains(&uptime_secs)
+    );
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// When the aggregator is flooded with messages, they spend some time queued up before being
+/// handled, and this shows up in the '/metrics' output.
+#[tokio::test]
+async fn e2e_aggregator_message_latency_is_reported() {
+    use FeedMessage::*;
+
+    let mut server = start_server(
+        ServerOpts::default(),
+        // Make sure that messages can be queued up while the aggregator is busy, even on
+        // machines with a single CPU:
+        CoreOpts {
+            worker_threads: Some(4),
+            ..Default::default()
+        },
+        lots_of_nodes_shard_opts(),
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+
+    // Add a load of nodes to a chain, so that sending the state of that chain to a feed
+    // takes the aggregator a while:
+    let num_nodes = 5_000;
+    add_polkadot_nodes(&mut node_tx, num_nodes);
+
+    // Wait until every node has been added:
+    let (_feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    loop {
+        let feed_messages = feed_rx
+            .recv_feed_messages_timeout(Duration::from_secs(30))
+            .await
+            .expect("all nodes should be added");
+        if feed_messages.iter().any(|msg| {
+            matches!(msg, AddedChain { genesis_hash, node_count, .. }
+                if *genesis_hash == polkadot_genesis_hash() && *node_count == num_nodes)
+        }) {
+            break;
+        }
+    }
+
+    // Subscribe a bunch of feeds to the chain all at once, so that the subscriptions back up
+    // behind each other:
+    let mut feeds = Vec::new();
+    for _ in 0..50 {
+        feeds.push(server.get_core().connect_feed().await.unwrap());
+    }
+    for (feed_tx, _feed_rx) in &feeds {
+        feed_tx
+            .send_command("subscribe", &format!("{:#x}", polkadot_genesis_hash()))
+            .unwrap();
+    }
+
+    // Metrics are only gathered every few seconds, so keep scraping until the latency shows up:
+    let uri: hyper::Uri = format!("http://{}/metrics", server.get_core().host())
+        .parse()
+        .unwrap();
+    let client = hyper::Client::new();
+    let mut latency_ms = 0;
+    for _ in 0..30 {
+        let res = client.get(uri.clone()).await.unwrap();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let metrics = String::from_utf8(body.to_vec()).unwrap();
+        latency_ms = metrics
+            .lines()
+            .filter(|l| l.starts_with("telemetry_core_aggregator_message_latency_ms"))
+            .filter_map(|l| l.split_whitespace().nth(1)?.parse::<u64>().ok())
+            .max()
+            .unwrap_or(0);
+        if latency_ms > 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+    assert!(latency_ms > 0, "expected a non-zero message latency");
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Chains can be added to the '--denylist-file' while the core is running. Nodes already on
+/// those chains are removed, and new nodes on them are muted.
+#[tokio::test]
+async fn e2e_denylist_file_is_reloaded() {
+    use FeedMessage::*;
+
+    let denylist_path =
+        std::env::temp_dir().join(format!("telemetry_denylist_test_{}", std::process::id()));
+    std::fs::write(&denylist_path, "# Nothing denied yet\n").unwrap();
+
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            denylist_file: Some(denylist_path.to_str().unwrap().to_owned()),
+            denylist_reload_secs: Some(1),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+
+    let (_feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+
+    let mut nodes = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_multiple_nodes(3)
+        .await
+        .unwrap();
+    let node_connected = |chain: &str, genesis_hash: BlockHash| {
+        json!({
+            "id":1,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload": {
+                "authority":true,
+                "chain":chain,
+                "config":"",
+                "genesis_hash": genesis_hash,
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name":"Alice",
+                "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                "startup_time":"1625565542717",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            },
+        })
+    };
+
+    // Nothing is denied to begin with, so the node is added:
+    nodes[0]
+        .0
+        .send_json_text(node_connected("Local Testnet", ghash(1)))
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        feed_messages,
+        AddedChain { name, node_count: 1, .. } if name == "Local Testnet"
+    );
+
+    // Deny the chain; once the file has been re-read, the node we added is removed:
+    std::fs::write(&denylist_path, "Local Testnet\n").unwrap();
+    let feed_messages = feed_rx
+        .recv_feed_messages_timeout(Duration::from_secs(10))
+        .await
+        .unwrap();
+    assert_contains_matches!(
+        feed_messages,
+        RemovedChain { genesis_hash } if genesis_hash == ghash(1)
+    );
+
+    // New nodes on the denied chain are muted, but nodes on other chains are still added:
+    nodes[1]
+        .0
+        .send_json_text(node_connected("Local Testnet", ghash(1)))
+        .unwrap();
+    nodes[2]
+        .0
+        .send_json_text(node_connected("Other Testnet", ghash(2)))
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        &feed_messages,
+        AddedChain { name, node_count: 1, .. } if name == "Other Testnet"
+    );
+    assert!(!feed_messages
+        .iter()
+        .any(|msg| matches!(msg, AddedChain { name, .. } if name == "Local Testnet")));
+
+    // Tidy up:
+    server.shutdown().await;
+    let _ = std::fs::remove_file(&denylist_path);
+}
+
+/// Chains given in '--pinned-chains' are listed to feeds before any nodes connect to them,
+/// and stay listed after the last node has gone.
+#[tokio::test]
+async fn e2e_pinned_chains_are_listed_without_nodes() {
+    use FeedMessage::*;
+
+    let pinned_path =
+        std::env::temp_dir().join(format!("telemetry_pinned_test_{}", std::process::id()));
+    std::fs::write(
+        &pinned_path,
+        format!(r#"{{"{:#x}":"Pinned Testnet"}}"#, ghash(1)),
+    )
+    .unwrap();
+
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            pinned_chains: Some(pinned_path.to_str().unwrap().to_owned()),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+
+    // The pinned chain is listed as soon as the feed connects, despite having no nodes:
+    let (_feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert!(feed_messages.contains(&AddedChain {
+        name: "Pinned Testnet".to_owned(),
+        genesis_hash: ghash(1),
+        node_count: 0,
+    }));
+
+    // A node connects to it, and the chain keeps its pinned label:
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+    node_tx
+        .send_json_text(json!({
+            "id":1,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload": {
+                "authority":true,
+                "chain":"Local Testnet",
+                "config":"",
+                "genesis_hash": ghash(1),
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name":"Alice",
+                "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                "startup_time":"1625565542717",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            },
+        }))
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert!(feed_messages.contains(&AddedChain {
+        name: "Pinned Testnet".to_owned(),
+        genesis_hash: ghash(1),
+        node_count: 1,
+    }));
+
+    // When the node goes, the chain is still listed, with no nodes:
+    node_tx.close().await.unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert!(feed_messages.contains(&AddedChain {
+        name: "Pinned Testnet".to_owned(),
+        genesis_hash: ghash(1),
+        node_count: 0,
+    }));
+    assert!(!feed_messages
+        .iter()
+        .any(|msg| matches!(msg, RemovedChain { .. })));
 
     // Tidy up:
     server.shutdown().await;
+    let _ = std::fs::remove_file(&pinned_path);
 }
 
-/// If something connects to the `/submit` endpoint, there is a limit to the number
-/// of different messages IDs it can send telemetry about, to prevent a malicious actor from
-/// spamming a load of message IDs and exhausting our memory.
+/// Feeds that are disconnected for being too slow are told why in the close frame.
 #[tokio::test]
-async fn e2e_max_nodes_per_connection_is_enforced() {
+async fn e2e_slow_feeds_are_told_why_they_were_closed() {
     let mut server = start_server(
         ServerOpts::default(),
-        CoreOpts::default(),
-        // Limit max nodes per connection to 2; any other msgs should be ignored.
-        ShardOpts {
-            max_nodes_per_connection: Some(2),
+        // Timeout faster so the test can be quicker:
+        CoreOpts {
+            feed_timeout: Some(1),
             ..Default::default()
         },
+        lots_of_nodes_shard_opts(),
     )
     .await;
-
-    // Connect to a shard
     let shard_id = server.add_shard().await.unwrap();
     let (mut node_tx, _node_rx) = server
         .get_shard(shard_id)
@@ -791,135 +3578,305 @@ async fn e2e_max_nodes_per_connection_is_enforced() {
         .await
         .unwrap();
 
-    // Connect a feed.
-    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    // Connect a raw feed that we won't read from for now, and add a load of nodes so that
+    // more data is sent to it than can be buffered up between us:
+    let (mut raw_feed_tx, mut raw_feed_rx) = server.get_core().connect_feed_raw().await.unwrap();
+    raw_feed_tx
+        .send_text(format!("subscribe:{:#x}", polkadot_genesis_hash()))
+        .await
+        .unwrap();
+    add_polkadot_nodes(&mut node_tx, 100_000);
 
-    // We'll send these messages from the node:
-    let json_msg = |n| {
-        json!({
-            "id":n,
-            "ts":"2021-07-12T10:37:47.714666+01:00",
-            "payload": {
-                "authority":true,
-                "chain":"Test Chain",
-                "config":"",
-                "genesis_hash": ghash(1),
-                "implementation":"Polkadot",
-                "msg":"system.connected",
-                "name": format!("Alice {}", n),
-                "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
-                "startup_time":"1625565542717",
-                "version":"2.0.0-07a1af348-aarch64-macos"
-            }
-        })
-    };
+    wait_for_slow_feed_to_be_closed(server.get_core()).await;
 
-    // First message ID should lead to feed messages:
-    node_tx.send_json_text(json_msg(1)).unwrap();
-    assert_ne!(
-        feed_rx
-            .recv_feed_messages_timeout(Duration::from_secs(1))
+    // Now, drain what we've been sent until we hit the close frame and see why we were closed:
+    let mut buf = Vec::new();
+    let reason = loop {
+        buf.clear();
+        let incoming = tokio::time::timeout(Duration::from_secs(5), raw_feed_rx.receive(&mut buf))
             .await
-            .unwrap()
-            .len(),
-        0
-    );
+            .expect("feed should be closed in a timely fashion")
+            .expect("feed should be closed with a close frame");
+        if let soketto::Incoming::Closed(reason) = incoming {
+            break reason;
+        }
+    };
+    assert_eq!(reason.code, 1011);
+    assert_eq!(reason.descr.as_deref(), Some("feed too slow"));
 
-    // Second message ID should lead to feed messages as well:
-    node_tx.send_json_text(json_msg(2)).unwrap();
-    assert_ne!(
-        feed_rx
-            .recv_feed_messages_timeout(Duration::from_secs(1))
-            .await
-            .unwrap()
-            .len(),
-        0
-    );
+    // Tidy up:
+    server.shutdown().await;
+}
 
-    // Third message ID should be ignored:
-    node_tx.send_json_text(json_msg(3)).unwrap();
-    assert_eq!(
-        feed_rx
-            .recv_feed_messages_timeout(Duration::from_secs(1))
-            .await
-            .unwrap()
-            .len(),
-        0
-    );
+/// How many feeds the core reports having closed for the reason given.
+async fn feeds_closed(core: &CoreProcess, reason: &str) -> u64 {
+    let uri: hyper::Uri = format!("http://{}/metrics", core.host()).parse().unwrap();
+    let res = hyper::Client::new().get(uri).await.unwrap();
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    let metrics = String::from_utf8(body.to_vec()).unwrap();
+    let prefix = format!("telemetry_core_feeds_closed_total{{reason=\"{reason}\"}} ");
+    metrics
+        .lines()
+        .find_map(|l| l.strip_prefix(&prefix))
+        .map(|n| n.parse().unwrap())
+        .unwrap_or(0)
+}
 
-    // Forth message ID should be ignored as well:
-    node_tx.send_json_text(json_msg(4)).unwrap();
-    assert_eq!(
-        feed_rx
-            .recv_feed_messages_timeout(Duration::from_secs(1))
-            .await
-            .unwrap()
-            .len(),
-        0
-    );
+/// Wait until the core reports that it's closed a feed for being too slow, panicking if it
+/// doesn't do so in a reasonable amount of time.
+async fn wait_for_slow_feed_to_be_closed(core: &CoreProcess) {
+    for _ in 0..300 {
+        if feeds_closed(core, "slow").await + feeds_closed(core, "stalled").await > 0 {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("feed should have been closed for being too slow");
+}
 
-    // (now that the chain "Test Chain" is known about, subscribe to it for update messages.
-    // This wasn't needed to receive messages re the above since everybody hears about node
-    // count changes)
-    feed_tx
-        .send_command(
-            "subscribe",
-            "0x0000000000000000000000000000000000000000000000000000000000000001",
+/// Our websocket clients hide anything sent after a close frame, and only hand back whole
+/// messages, so this connects a feed over a plain TCP connection instead, speaking just enough
+/// websocket to subscribe it to the chain given. The upgrade response is left to be read.
+async fn connect_tcp_feed(core: &CoreProcess, genesis_hash: BlockHash) -> tokio::net::TcpStream {
+    use tokio::io::AsyncWriteExt;
+
+    let mut stream = tokio::net::TcpStream::connect(core.host()).await.unwrap();
+    stream
+        .write_all(
+            b"GET /feed HTTP/1.1\r\n\
+              Host: localhost\r\n\
+              Upgrade: websocket\r\n\
+              Connection: Upgrade\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+              Sec-WebSocket-Version: 13\r\n\r\n",
         )
+        .await
         .unwrap();
-    feed_rx.recv_feed_messages().await.unwrap();
+    let subscribe = format!("subscribe:{:#x}", genesis_hash);
+    // A masked text frame, with a mask that leaves the payload as it is:
+    let mut frame = vec![0x81, 0x80 | subscribe.len() as u8, 0, 0, 0, 0];
+    frame.extend_from_slice(subscribe.as_bytes());
+    stream.write_all(&frame).await.unwrap();
+    stream
+}
 
-    // Update about non-ignored IDs should still lead to feed output:
+/// Count the close frames in some bytes sent from a websocket server.
+fn count_close_frames(mut bytes: &[u8]) -> usize {
+    let mut count = 0;
+    while bytes.len() >= 2 {
+        let opcode = bytes[0] & 0x0f;
+        let (len, header_len) = match bytes[1] & 0x7f {
+            126 => (u16::from_be_bytes([bytes[2], bytes[3]]) as usize, 4),
+            127 => (
+                u64::from_be_bytes(bytes[2..10].try_into().unwrap()) as usize,
+                10,
+            ),
+            n => (n as usize, 2),
+        };
+        if opcode == 0x8 {
+            count += 1;
+        }
+        bytes = &bytes[(header_len + len).min(bytes.len())..];
+    }
+    count
+}
 
-    node_tx.send_json_text(json!(
-        {"id":1, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":1},"ts":"2021-07-12T10:38:48.330433+01:00" }
-    )).unwrap();
-    assert_ne!(
-        feed_rx
-            .recv_feed_messages_timeout(Duration::from_secs(1))
-            .await
-            .unwrap()
-            .len(),
-        0
-    );
+/// Feeds that are closed for being too slow are sent exactly one close frame, and not another
+/// when the connection is torn down afterwards.
+#[tokio::test]
+async fn e2e_slow_feeds_are_sent_one_close_frame() {
+    use tokio::io::AsyncReadExt;
 
-    node_tx.send_json_text(json!(
-        {"id":2, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":1},"ts":"2021-07-12T10:38:48.330433+01:00" }
-    )).unwrap();
-    assert_ne!(
-        feed_rx
-            .recv_feed_messages_timeout(Duration::from_secs(1))
-            .await
-            .unwrap()
-            .len(),
-        0
-    );
+    let mut server = start_server(
+        ServerOpts::default(),
+        // Timeout faster so the test can be quicker:
+        CoreOpts {
+            feed_timeout: Some(1),
+            ..Default::default()
+        },
+        lots_of_nodes_shard_opts(),
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
 
-    // Updates about ignored IDs are still ignored:
+    let mut stream = connect_tcp_feed(server.get_core(), polkadot_genesis_hash()).await;
 
-    node_tx.send_json_text(json!(
-        {"id":3, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":1},"ts":"2021-07-12T10:38:48.330433+01:00" }
-    )).unwrap();
-    assert_eq!(
-        feed_rx
-            .recv_feed_messages_timeout(Duration::from_secs(1))
-            .await
-            .unwrap()
-            .len(),
-        0
-    );
+    // Send more data to the feed than can be buffered up between us, and don't read any of it
+    // until the core gives up on the feed:
+    add_polkadot_nodes(&mut node_tx, 100_000);
+    wait_for_slow_feed_to_be_closed(server.get_core()).await;
 
-    node_tx.send_json_text(json!(
-        {"id":4, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":1},"ts":"2021-07-12T10:38:48.330433+01:00" }
-    )).unwrap();
-    assert_eq!(
-        feed_rx
-            .recv_feed_messages_timeout(Duration::from_secs(1))
+    // Read everything that we were sent, without ever closing our end of the connection:
+    let mut bytes = Vec::new();
+    tokio::time::timeout(Duration::from_secs(30), stream.read_to_end(&mut bytes))
+        .await
+        .expect("feed should be closed in a timely fashion")
+        .expect("feed should be closed cleanly");
+    let header_end = bytes
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .expect("upgrade response")
+        + 4;
+    assert_eq!(count_close_frames(&bytes[header_end..]), 1);
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// With '--feed-stall-timeout-ms', a feed that stops reading altogether is closed as stalled,
+/// while a feed that keeps reading, however slowly, is given until '--feed-subscribe-timeout'
+/// to receive the chain that it subscribed to.
+#[tokio::test]
+async fn e2e_stalled_feeds_are_told_apart_from_slow_ones() {
+    use tokio::io::AsyncReadExt;
+    use FeedMessage::*;
+
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            feed_stall_timeout_ms: Some(1000),
+            feed_subscribe_timeout: Some(60),
+            ..Default::default()
+        },
+        lots_of_nodes_shard_opts(),
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+
+    // Add enough nodes that sending the state of the chain to a slow feed takes a while:
+    let num_nodes = 100_000;
+    add_polkadot_nodes(&mut node_tx, num_nodes);
+    let (_feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    loop {
+        let feed_messages = feed_rx
+            .recv_feed_messages_timeout(Duration::from_secs(30))
             .await
-            .unwrap()
-            .len(),
-        0
-    );
+            .expect("all nodes should be added");
+        if feed_messages.iter().any(|msg| {
+            matches!(msg, AddedChain { genesis_hash, node_count, .. }
+                if *genesis_hash == polkadot_genesis_hash() && *node_count == num_nodes)
+        }) {
+            break;
+        }
+    }
+
+    // One feed subscribes and never reads anything, and the other reads a little at a time:
+    let _stalled_feed = connect_tcp_feed(server.get_core(), polkadot_genesis_hash()).await;
+    let mut slow_feed = connect_tcp_feed(server.get_core(), polkadot_genesis_hash()).await;
+    let (stop_tx, mut stop_rx) = tokio::sync::oneshot::channel::<()>();
+    let slow_reader = tokio::spawn(async move {
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            tokio::select! {
+                n = slow_feed.read(&mut buf) => assert!(n.unwrap() > 0, "slow feed should not be closed"),
+                _ = &mut stop_rx => break,
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    });
+
+    // Only the feed that's stopped reading is closed, even though the slow feed is still busy
+    // receiving the chain well after the stall timeout:
+    for _ in 0..300 {
+        if feeds_closed(server.get_core(), "stalled").await > 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert_eq!(feeds_closed(server.get_core(), "stalled").await, 1);
+    assert_eq!(feeds_closed(server.get_core(), "slow").await, 0);
+
+    stop_tx.send(()).unwrap();
+    slow_reader.await.unwrap();
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Shards tell the core their ID, which the core tags their nodes with.
+#[tokio::test]
+async fn e2e_shards_tell_the_core_their_id() {
+    use FeedMessage::*;
+
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            admin_token: Some("letmein".to_string()),
+            ..Default::default()
+        },
+        ShardOpts {
+            shard_id: Some("eu-shard-1".to_owned()),
+            ..Default::default()
+        },
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+
+    let (_feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+    node_tx
+        .send_json_text(json!({
+            "id":1,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload": {
+                "authority":true,
+                "chain":"Local Testnet",
+                "config":"",
+                "genesis_hash": ghash(1),
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name":"Alice",
+                "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                "startup_time":"1625565542717",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            },
+        }))
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert!(feed_messages.contains(&AddedChain {
+        name: "Local Testnet".to_owned(),
+        genesis_hash: ghash(1),
+        node_count: 1,
+    }));
+
+    // The node is tagged with the ID of the shard that it connected through:
+    let uri: hyper::Uri = format!(
+        "http://{}/admin/node?chain={:#x}&id=0",
+        server.get_core().host(),
+        ghash(1)
+    )
+    .parse()
+    .unwrap();
+    let req = hyper::Request::get(uri)
+        .header("Authorization", "Bearer letmein")
+        .body(hyper::Body::empty())
+        .unwrap();
+    let res = hyper::Client::new().request(req).await.unwrap();
+    assert_eq!(res.status(), 200);
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    let info: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let shard = info["shard"].as_str().expect("a shard name");
+    assert!(shard.starts_with("eu-shard-1 ("), "shard name: {shard}");
 
     // Tidy up:
     server.shutdown().await;
@@ -0,0 +1,117 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+/*!
+Tests for recording node uptime in a postgres database. These need a database to connect to,
+and so are only compiled with the `uptime-db-tests` feature. The connection string is given by
+the `TELEMETRY_UPTIME_DB` environment variable. They can be run with:
+
+```sh
+TELEMETRY_UPTIME_DB=postgresql://postgres@localhost/telemetry \
+    cargo test --features uptime-db-tests uptime
+```
+*/
+
+#![cfg(feature = "uptime-db-tests")]
+
+use common::node_types::BlockHash;
+use serde_json::json;
+use std::time::Duration;
+use test_utils::{
+    assert_contains_matches,
+    feed_message_de::FeedMessage,
+    workspace::{start_server, CoreOpts, ServerOpts, ShardOpts},
+};
+
+fn uptime_db() -> String {
+    std::env::var("TELEMETRY_UPTIME_DB")
+        .expect("TELEMETRY_UPTIME_DB should be set to a postgres connection string")
+}
+
+/// A node's uptime carries on from where it left off when it reconnects, and feeds that
+/// subscribe to its chain are told about it.
+#[tokio::test]
+async fn uptime_is_kept_across_reconnects() {
+    use FeedMessage::*;
+
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            uptime_db: Some(uptime_db()),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+
+    // Use a network ID that previous runs won't have recorded any uptime for:
+    let network_id = format!("uptime-test-{}", common::time::now());
+    let system_connected = json!({
+        "id":1,
+        "ts":"2021-07-12T10:37:47.714666+01:00",
+        "payload": {
+            "authority":true,
+            "chain":"Local Testnet",
+            "config":"",
+            "genesis_hash": BlockHash::from_low_u64_be(1),
+            "implementation":"Substrate Node",
+            "msg":"system.connected",
+            "name":"Alice",
+            "network_id": network_id,
+            "startup_time":"1625565542717",
+            "version":"2.0.0-07a1af348-aarch64-macos"
+        },
+    });
+
+    // The node is up for a couple of seconds, and then disconnects:
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+    node_tx.send_json_text(system_connected.clone()).unwrap();
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    node_tx.close().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    // When it reconnects, feeds are told about the time it was up for before:
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+    node_tx.send_json_text(system_connected).unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    feed_tx
+        .send_command(
+            "subscribe",
+            &format!("{:#x}", BlockHash::from_low_u64_be(1)),
+        )
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        feed_messages,
+        NodeUptime { node_id: 0, uptime_secs } if uptime_secs >= 2
+    );
+
+    // Tidy up:
+    server.shutdown().await;
+}
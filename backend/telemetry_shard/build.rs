@@ -0,0 +1,2 @@
+// The build script is shared with the other binaries:
+include!("../common/build_script.rs");
@@ -19,12 +19,14 @@ use common::{
     internal_messages::{self, ShardNodeId},
     node_message,
     node_types::BlockHash,
-    AssignId,
+    ws_client, AssignId,
 };
 use futures::{Sink, SinkExt};
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::atomic::AtomicU64;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
 
 /// A unique Id is assigned per websocket connection (or more accurately,
 /// per thing-that-subscribes-to-the-aggregator). That connection might send
@@ -40,12 +42,15 @@ type ConnId = u64;
 enum ToAggregator {
     /// Sent when the telemetry core is disconnected.
     DisconnectedFromTelemetryCore,
-    /// Sent when the telemetry core (re)connects.
-    ConnectedToTelemetryCore,
+    /// Sent when the telemetry core (re)connects, with the protocol version that it speaks.
+    ConnectedToTelemetryCore { protocol_version: u32 },
     /// Sent when a message comes in from a substrate node.
     FromWebsocket(ConnId, FromWebsocket),
     /// Send when a message comes in from the telemetry core.
     FromTelemetryCore(internal_messages::FromTelemetryCore),
+    /// Handled once a connection with nodes muted because the core was full has waited long
+    /// enough to try again. The aggregator loop keeps track of when this is.
+    GlobalQuotaBackoffElapsed(ConnId),
 }
 
 /// An incoming socket connection can provide these messages.
@@ -101,20 +106,49 @@ struct AggregatorInternal {
 }
 
 impl Aggregator {
-    /// Spawn a new Aggregator. This connects to the telemetry backend
-    pub async fn spawn(telemetry_uri: http::Uri) -> anyhow::Result<Aggregator> {
+    /// Spawn a new Aggregator. This connects to the telemetry backend, identifying
+    /// ourselves to it with the `shard_id` given. If a `global_quota_backoff` is given,
+    /// connections whose nodes have all been muted because the core is full are closed
+    /// after it, so that they try again.
+    pub async fn spawn(
+        telemetry_uri: http::Uri,
+        shard_id: String,
+        init_ack_timeout: Duration,
+        shard_secret: Option<String>,
+        core_ca: ws_client::CaCertificates,
+        global_quota_backoff: Option<Duration>,
+    ) -> anyhow::Result<Aggregator> {
         let (tx_to_aggregator, rx_from_external) = flume::bounded(10);
 
         // Establish a resilient connection to the core (this retries as needed):
-        let (tx_to_telemetry_core, rx_from_telemetry_core) =
-            create_ws_connection_to_core(telemetry_uri).await;
+        let (tx_to_telemetry_core, rx_from_telemetry_core) = create_ws_connection_to_core(
+            telemetry_uri,
+            init_ack_timeout,
+            shard_secret,
+            core_ca,
+            |msg| match msg {
+                internal_messages::FromTelemetryCore::Initialized => Some(0),
+                internal_messages::FromTelemetryCore::Ready { protocol_version } => {
+                    Some(*protocol_version)
+                }
+                _ => None,
+            },
+            // Cores only know how to deserialize this from protocol version 1:
+            Some((
+                1,
+                internal_messages::FromShardAggregator::Identify { shard_id },
+            )),
+        )
+        .await;
 
         // Forward messages from the telemetry core into the aggregator:
         let tx_to_aggregator2 = tx_to_aggregator.clone();
         tokio::spawn(async move {
             while let Ok(msg) = rx_from_telemetry_core.recv_async().await {
                 let msg_to_aggregator = match msg {
-                    Message::Connected => ToAggregator::ConnectedToTelemetryCore,
+                    Message::Connected { protocol_version } => {
+                        ToAggregator::ConnectedToTelemetryCore { protocol_version }
+                    }
                     Message::Disconnected => ToAggregator::DisconnectedFromTelemetryCore,
                     Message::Data(data) => ToAggregator::FromTelemetryCore(data),
                 };
@@ -129,6 +163,7 @@ impl Aggregator {
         tokio::spawn(Aggregator::handle_messages(
             rx_from_external,
             tx_to_telemetry_core,
+            global_quota_backoff,
         ));
 
         // Return a handle to our aggregator so that we can send in messages to it:
@@ -144,13 +179,17 @@ impl Aggregator {
     async fn handle_messages(
         rx_from_external: flume::Receiver<ToAggregator>,
         tx_to_telemetry_core: flume::Sender<FromAggregator>,
+        global_quota_backoff: Option<Duration>,
     ) {
-        use internal_messages::{FromShardAggregator, FromTelemetryCore};
+        use internal_messages::{FromShardAggregator, FromTelemetryCore, MuteReason};
 
         // Just as an optimisation, we can keep track of whether we're connected to the backend
         // or not, and ignore incoming messages while we aren't.
         let mut connected_to_telemetry_core = false;
 
+        // The protocol version that the core we're connected to speaks:
+        let mut core_protocol_version = 0;
+
         // A list of close channels for the currently connected substrate nodes. Send an empty
         // tuple to these to ask the connections to be closed.
         let mut close_connections: HashMap<ConnId, flume::Sender<()>> = HashMap::new();
@@ -162,10 +201,32 @@ impl Aggregator {
         // Any messages coming from nodes that have been muted are ignored:
         let mut muted: HashSet<ShardNodeId> = HashSet::new();
 
+        // Connections with nodes that were muted because the core is full, and when to give
+        // them another go. The backoff is always the same, so these are in order:
+        let mut global_quota_backoffs: VecDeque<(Instant, ConnId)> = VecDeque::new();
+
         // Now, loop and receive messages to handle.
-        while let Ok(msg) = rx_from_external.recv_async().await {
+        loop {
+            let next_backoff = global_quota_backoffs.front().map(|&(at, _)| at);
+            let backoff_elapsed = async {
+                match next_backoff {
+                    Some(at) => tokio::time::sleep_until(at).await,
+                    None => std::future::pending().await,
+                }
+            };
+            let msg = tokio::select! {
+                msg = rx_from_external.recv_async() => match msg {
+                    Ok(msg) => msg,
+                    Err(_) => break,
+                },
+                _ = backoff_elapsed => {
+                    let (_, conn_id) = global_quota_backoffs.pop_front().expect("backoff exists");
+                    ToAggregator::GlobalQuotaBackoffElapsed(conn_id)
+                }
+            };
+
             match msg {
-                ToAggregator::ConnectedToTelemetryCore => {
+                ToAggregator::ConnectedToTelemetryCore { protocol_version } => {
                     // Take hold of the connection closers and run them all.
                     let closers = close_connections;
 
@@ -180,6 +241,7 @@ impl Aggregator {
                     muted.clear();
 
                     connected_to_telemetry_core = true;
+                    core_protocol_version = protocol_version;
                     log::info!("Connected to telemetry core");
                 }
                 ToAggregator::DisconnectedFromTelemetryCore => {
@@ -227,7 +289,7 @@ impl Aggregator {
                     conn_id,
                     FromWebsocket::Update {
                         message_id,
-                        payload,
+                        mut payload,
                     },
                 ) => {
                     // Ignore incoming messages if we're not connected to the backend:
@@ -246,6 +308,11 @@ impl Aggregator {
                         continue;
                     }
 
+                    // Cores older than protocol version 1 can't deserialize the newer interval fields:
+                    if core_protocol_version < 1 {
+                        payload.clear_v2_fields();
+                    }
+
                     // Send the message to the telemetry core with this local ID:
                     let _ = tx_to_telemetry_core
                         .send_async(FromShardAggregator::UpdateNode { local_id, payload })
@@ -295,19 +362,51 @@ impl Aggregator {
                         }
                     }
                 }
-                ToAggregator::FromTelemetryCore(FromTelemetryCore::Mute {
-                    local_id,
-                    reason: _,
-                }) => {
+                ToAggregator::FromTelemetryCore(FromTelemetryCore::Mute { local_id, reason }) => {
                     // Mute the local ID we've been told to:
                     muted.insert(local_id);
+
+                    // If the core is full, give the node another go later:
+                    let (Some(backoff), MuteReason::GlobalQuota) = (global_quota_backoff, reason)
+                    else {
+                        continue;
+                    };
+                    let Some(&(conn_id, _)) = to_local_id.get_details(local_id) else {
+                        continue;
+                    };
+                    global_quota_backoffs.push_back((Instant::now() + backoff, conn_id));
+                }
+                ToAggregator::GlobalQuotaBackoffElapsed(conn_id) => {
+                    // Only close connections that have nothing else to send, so that we don't
+                    // interrupt nodes on it which the core has accepted:
+                    let all_muted = to_local_id
+                        .iter()
+                        .filter(|(_, &(id, _))| id == conn_id)
+                        .all(|(local_id, _)| muted.contains(&local_id));
+                    if all_muted {
+                        if let Some(closer) = close_connections.get(&conn_id) {
+                            let _ = closer.send_async(()).await;
+                        }
+                    }
+                }
+                ToAggregator::FromTelemetryCore(
+                    FromTelemetryCore::Initialized | FromTelemetryCore::Ready { .. },
+                ) => {
+                    // Handled when connecting; each core aggregator acknowledges us,
+                    // so we may see a few more of these which we can ignore.
                 }
             }
         }
     }
 
-    /// Return a sink that a node can send messages into to be handled by the aggregator.
-    pub fn subscribe_node(&self) -> impl Sink<FromWebsocket, Error = anyhow::Error> + Unpin {
+    /// Return the ID of a new node connection, along with a sink that the node can send
+    /// messages into to be handled by the aggregator.
+    pub fn subscribe_node(
+        &self,
+    ) -> (
+        ConnId,
+        impl Sink<FromWebsocket, Error = anyhow::Error> + Unpin,
+    ) {
         // Assign a unique aggregator-local ID to each connection that subscribes, and pass
         // that along with every message to the aggregator loop:
         let conn_id: ConnId = self
@@ -318,10 +417,11 @@ impl Aggregator {
 
         // Calling `send` on this Sink requires Unpin. There may be a nicer way than this,
         // but pinning by boxing is the easy solution for now:
-        Box::pin(
+        let sink = Box::pin(
             tx_to_aggregator
                 .into_sink()
                 .with(move |msg| async move { Ok(ToAggregator::FromWebsocket(conn_id, msg)) }),
-        )
+        );
+        (conn_id, sink)
     }
 }
@@ -0,0 +1,223 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use common::node_message::NodeMessageId;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// What to do when a connection tells us about a new node but we're already
+/// tracking the maximum number of nodes allowed for that connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Ignore the new node until an existing one goes stale.
+    RejectNew,
+    /// Evict the node that we heard from least recently to make room for the new one.
+    EvictOldest,
+}
+
+impl std::str::FromStr for EvictionPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "reject-new" => Ok(EvictionPolicy::RejectNew),
+            "evict-oldest" => Ok(EvictionPolicy::EvictOldest),
+            other => Err(anyhow::anyhow!(
+                "Cannot parse eviction policy '{}'; expecting one of 'reject-new' or 'evict-oldest'",
+                other
+            )),
+        }
+    }
+}
+
+/// The result of trying to allow a new message ID.
+#[derive(Debug, PartialEq, Eq)]
+pub enum InsertResult {
+    /// The message ID is now allowed.
+    Added,
+    /// The message ID is now allowed, but to make room for it, this other one was evicted.
+    AddedAndEvicted(NodeMessageId),
+    /// The message ID was already allowed; we've just updated when it was last seen.
+    AlreadyAllowed,
+    /// We're at capacity, and so the message ID was not allowed.
+    Rejected,
+}
+
+/// Keep track of the message IDs on a single connection that have been "granted access",
+/// and when we last saw a message for each of them.
+pub struct AllowedMessageIds {
+    ids: HashMap<NodeMessageId, Instant>,
+    max_ids: usize,
+    policy: EvictionPolicy,
+}
+
+impl AllowedMessageIds {
+    /// Allow at most `max_ids` message IDs, using the policy given to decide
+    /// what to do once we hit that limit.
+    pub fn new(max_ids: usize, policy: EvictionPolicy) -> Self {
+        AllowedMessageIds {
+            ids: HashMap::new(),
+            max_ids,
+            policy,
+        }
+    }
+
+    /// Try to allow a new message ID.
+    pub fn insert(&mut self, message_id: NodeMessageId, now: Instant) -> InsertResult {
+        if let Some(last_seen) = self.ids.get_mut(&message_id) {
+            *last_seen = now;
+            return InsertResult::AlreadyAllowed;
+        }
+
+        if self.ids.len() < self.max_ids {
+            self.ids.insert(message_id, now);
+            return InsertResult::Added;
+        }
+
+        match self.policy {
+            EvictionPolicy::RejectNew => InsertResult::Rejected,
+            EvictionPolicy::EvictOldest => {
+                // We only do this when at capacity, and the number of IDs per connection is
+                // small in practice, so a linear scan is fine here:
+                let oldest_id = match self.ids.iter().min_by_key(|(_, &t)| t) {
+                    Some((&id, _)) => id,
+                    // A max of 0 means that nothing can be allowed:
+                    None => return InsertResult::Rejected,
+                };
+                self.ids.remove(&oldest_id);
+                self.ids.insert(message_id, now);
+                InsertResult::AddedAndEvicted(oldest_id)
+            }
+        }
+    }
+
+    /// Note that we've seen a message for this ID. Returns false if the ID is not allowed.
+    pub fn touch(&mut self, message_id: NodeMessageId, now: Instant) -> bool {
+        match self.ids.get_mut(&message_id) {
+            Some(last_seen) => {
+                *last_seen = now;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove and return any message IDs that we haven't seen for longer than the timeout given.
+    pub fn remove_stale(&mut self, stale_timeout: Duration) -> Vec<NodeMessageId> {
+        let stale_ids: Vec<NodeMessageId> = self
+            .ids
+            .iter()
+            .filter(|(_, last_seen)| last_seen.elapsed() > stale_timeout)
+            .map(|(&id, _)| id)
+            .collect();
+
+        for id in &stale_ids {
+            self.ids.remove(id);
+        }
+        stale_ids
+    }
+
+    /// Are there no allowed message IDs?
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reject_new_ignores_ids_when_full() {
+        let now = Instant::now();
+        let mut ids = AllowedMessageIds::new(2, EvictionPolicy::RejectNew);
+
+        assert_eq!(ids.insert(1, now), InsertResult::Added);
+        assert_eq!(ids.insert(2, now), InsertResult::Added);
+        assert_eq!(ids.insert(3, now), InsertResult::Rejected);
+
+        assert!(ids.touch(1, now));
+        assert!(ids.touch(2, now));
+        assert!(!ids.touch(3, now));
+    }
+
+    #[test]
+    fn evict_oldest_replaces_least_recently_seen() {
+        let now = Instant::now();
+        let mut ids = AllowedMessageIds::new(2, EvictionPolicy::EvictOldest);
+
+        assert_eq!(ids.insert(1, now), InsertResult::Added);
+        assert_eq!(
+            ids.insert(2, now + Duration::from_secs(1)),
+            InsertResult::Added
+        );
+
+        // 1 was added first, but seeing it again makes 2 the least recently seen:
+        assert!(ids.touch(1, now + Duration::from_secs(2)));
+        assert_eq!(
+            ids.insert(3, now + Duration::from_secs(3)),
+            InsertResult::AddedAndEvicted(2)
+        );
+
+        assert!(ids.touch(1, now));
+        assert!(!ids.touch(2, now));
+        assert!(ids.touch(3, now));
+    }
+
+    #[test]
+    fn duplicate_ids_are_not_added_twice() {
+        let now = Instant::now();
+        for policy in [EvictionPolicy::RejectNew, EvictionPolicy::EvictOldest] {
+            let mut ids = AllowedMessageIds::new(1, policy);
+            assert_eq!(ids.insert(1, now), InsertResult::Added);
+            assert_eq!(ids.insert(1, now), InsertResult::AlreadyAllowed);
+        }
+    }
+
+    #[test]
+    fn zero_capacity_allows_nothing() {
+        let now = Instant::now();
+        for policy in [EvictionPolicy::RejectNew, EvictionPolicy::EvictOldest] {
+            let mut ids = AllowedMessageIds::new(0, policy);
+            assert_eq!(ids.insert(1, now), InsertResult::Rejected);
+            assert!(ids.is_empty());
+        }
+    }
+
+    #[test]
+    fn stale_ids_are_removed() {
+        let mut ids = AllowedMessageIds::new(2, EvictionPolicy::RejectNew);
+        ids.insert(1, Instant::now() - Duration::from_secs(10));
+        ids.insert(2, Instant::now());
+
+        assert_eq!(ids.remove_stale(Duration::from_secs(5)), vec![1]);
+        assert!(!ids.touch(1, Instant::now()));
+        assert!(ids.touch(2, Instant::now()));
+    }
+
+    #[test]
+    fn eviction_policy_from_str() {
+        assert_eq!(
+            "reject-new".parse::<EvictionPolicy>().unwrap(),
+            EvictionPolicy::RejectNew
+        );
+        assert_eq!(
+            "evict-oldest".parse::<EvictionPolicy>().unwrap(),
+            EvictionPolicy::EvictOldest
+        );
+        assert!("foo".parse::<EvictionPolicy>().is_err());
+    }
+}
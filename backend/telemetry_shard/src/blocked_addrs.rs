@@ -15,10 +15,29 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 use std::collections::HashMap;
+use std::fmt;
 use std::net::IpAddr;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
+/// Why an address has been blocked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockReason {
+    /// The address sent us more than the allowed number of bytes per second, averaged
+    /// over a short window. `bps` is the rate that we saw.
+    TooMuchTraffic { bps: usize },
+}
+
+impl fmt::Display for BlockReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockReason::TooMuchTraffic { bps } => {
+                write!(f, "Too much traffic ({bps} bytes per second)")
+            }
+        }
+    }
+}
+
 /// Keep track of nodes that have been blocked.
 #[derive(Debug, Clone)]
 pub struct BlockedAddrs(Arc<BlockAddrsInner>);
@@ -26,7 +45,7 @@ pub struct BlockedAddrs(Arc<BlockAddrsInner>);
 #[derive(Debug)]
 struct BlockAddrsInner {
     block_duration: Duration,
-    inner: Mutex<HashMap<IpAddr, (&'static str, Instant)>>,
+    inner: Mutex<HashMap<IpAddr, (BlockReason, Instant)>>,
 }
 
 impl BlockedAddrs {
@@ -40,7 +59,7 @@ impl BlockedAddrs {
     }
 
     /// Block a new address
-    pub fn block_addr(&self, addr: IpAddr, reason: &'static str) {
+    pub fn block_addr(&self, addr: IpAddr, reason: BlockReason) {
         let now = Instant::now();
         self.0.inner.lock().unwrap().insert(addr, (reason, now));
     }
@@ -48,7 +67,7 @@ impl BlockedAddrs {
     /// Find out whether an address has been blocked. If it has, a reason
     /// will be returned. Else, we'll get None back. This function may also
     /// perform cleanup if the item was blocked and the block has expired.
-    pub fn blocked_reason(&self, addr: &IpAddr) -> Option<&'static str> {
+    pub fn blocked_reason(&self, addr: &IpAddr) -> Option<BlockReason> {
         let mut map = self.0.inner.lock().unwrap();
 
         let (reason, time) = match map.get(addr) {
@@ -64,3 +83,35 @@ impl BlockedAddrs {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn blocked_addrs_keep_the_reason_they_were_blocked() {
+        let blocked = BlockedAddrs::new(Duration::from_secs(60));
+        let addr: IpAddr = "1.2.3.4".parse().unwrap();
+        blocked.block_addr(addr, BlockReason::TooMuchTraffic { bps: 2048 });
+
+        assert_eq!(
+            blocked.blocked_reason(&addr),
+            Some(BlockReason::TooMuchTraffic { bps: 2048 })
+        );
+        assert_eq!(blocked.blocked_reason(&"1.2.3.5".parse().unwrap()), None);
+        assert_eq!(
+            BlockReason::TooMuchTraffic { bps: 2048 }.to_string(),
+            "Too much traffic (2048 bytes per second)"
+        );
+    }
+
+    #[test]
+    fn blocks_expire() {
+        let blocked = BlockedAddrs::new(Duration::ZERO);
+        let addr: IpAddr = "1.2.3.4".parse().unwrap();
+        blocked.block_addr(addr, BlockReason::TooMuchTraffic { bps: 2048 });
+        std::thread::sleep(Duration::from_millis(1));
+
+        assert_eq!(blocked.blocked_reason(&addr), None);
+    }
+}
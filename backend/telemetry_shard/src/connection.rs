@@ -17,10 +17,14 @@
 use bincode::Options;
 use common::ws_client;
 use futures::StreamExt;
+use std::time::Duration;
 
 #[derive(Clone, Debug)]
 pub enum Message<Out> {
-    Connected,
+    /// The core is connected, and speaks the given protocol version.
+    Connected {
+        protocol_version: u32,
+    },
     Disconnected,
     Data(Out),
 }
@@ -31,10 +35,29 @@ pub enum Message<Out> {
 /// - Messages are all encoded/decoded to/from bincode, and so need to support being (de)serialized from
 ///   a non self-describing encoding.
 ///
+/// - Once connected, we wait up to `init_ack_timeout` for a message that `init_ack_protocol` returns
+///   a protocol version for, before sending `Message::Connected` with that version. If the connection
+///   drops while waiting, we reconnect and try again. Older cores never send an ack, so if the timeout
+///   is reached we assume we're talking to one of those and carry on with protocol version 0.
+///
+/// - If a `hello` message is given along with the protocol version that introduced it, it's sent to the
+///   core as soon as the connection is acknowledged, before any other messages. It's only sent to cores
+///   which say that they speak at least that version, since others wouldn't be able to deserialize it.
+///
+/// - If a `shard_secret` is given, it's sent to the core in an `Authorization` header.
+///
+/// - When connecting over TLS, the core's certificate may be signed by one of `core_ca`, as well
+///   as by any of the usual web PKI certificate authorities.
+///
 /// Note: have a look at [`common::internal_messages`] to see the different message types exchanged
 /// between aggregator and core.
 pub async fn create_ws_connection_to_core<In, Out>(
     telemetry_uri: http::Uri,
+    init_ack_timeout: Duration,
+    shard_secret: Option<String>,
+    core_ca: ws_client::CaCertificates,
+    init_ack_protocol: fn(&Out) -> Option<u32>,
+    hello: Option<(u32, In)>,
 ) -> (flume::Sender<In>, flume::Receiver<Message<Out>>)
 where
     In: serde::Serialize + Send + 'static,
@@ -44,8 +67,15 @@ where
     let (tx_out, rx_out) = flume::bounded(10);
 
     let mut is_connected = false;
+    // If we've been given a secret, the core will want to see it before accepting us:
+    let auth_header = shard_secret.map(|secret| format!("Bearer {secret}"));
 
     tokio::spawn(async move {
+        let headers: Vec<(&str, &str)> = auth_header
+            .iter()
+            .map(|value| ("Authorization", value.as_str()))
+            .collect();
+
         loop {
             // Throw away any pending messages from the incoming channel so that it
             // doesn't get filled up and begin blocking while we're looping and waiting
@@ -55,13 +85,71 @@ where
             // Try to connect. If connection established, we serialize and forward messages
             // to/from the core. If the external channels break, we end for good. If the internal
             // channels break, we loop around and try connecting again.
-            match ws_client::connect(&telemetry_uri).await {
+            match ws_client::connect_with_headers_and_ca(&telemetry_uri, &headers, &core_ca).await {
                 Ok(connection) => {
                     let (tx_to_core, mut rx_from_core) = connection.into_channels();
+
+                    // Wait for the core to tell us that it's ready for node data:
+                    let wait_for_ack = async {
+                        while let Some(Ok(msg)) = rx_from_core.next().await {
+                            if let Some(version) = init_ack_protocol(&decode_message(msg)) {
+                                return Some(version);
+                            }
+                        }
+                        None
+                    };
+                    let acknowledged = match tokio::time::timeout(init_ack_timeout, wait_for_ack)
+                        .await
+                    {
+                        Ok(Some(version)) => {
+                            log::info!(
+                                "Connection to core acknowledged (protocol version {version})"
+                            );
+                            Some(version)
+                        }
+                        Ok(None) => {
+                            log::warn!("Connection to core closed before it was acknowledged (will reconnect)");
+                            tokio::time::sleep(Duration::from_secs(1)).await;
+                            continue;
+                        }
+                        Err(_) => {
+                            log::warn!(
+                                "No acknowledgement from core after {:?}; assuming an older core that doesn't send one",
+                                init_ack_timeout
+                            );
+                            None
+                        }
+                    };
+
+                    let core_protocol_version = acknowledged.unwrap_or(0);
+                    let hello = hello
+                        .as_ref()
+                        .filter(|(min_version, _)| core_protocol_version >= *min_version);
+                    if let Some((_, hello)) = hello {
+                        let bytes = bincode::options()
+                            .serialize(hello)
+                            .expect("internal messages must be serializable");
+                        if let Err(e) =
+                            tx_to_core.unbounded_send(ws_client::SentMessage::Binary(bytes))
+                        {
+                            log::warn!(
+                                "Unable to send hello message to core (will reconnect): {}",
+                                e
+                            );
+                            tokio::time::sleep(Duration::from_secs(1)).await;
+                            continue;
+                        }
+                    }
+
                     is_connected = true;
                     let tx_out = tx_out.clone();
 
-                    if let Err(e) = tx_out.send_async(Message::Connected).await {
+                    if let Err(e) = tx_out
+                        .send_async(Message::Connected {
+                            protocol_version: core_protocol_version,
+                        })
+                        .await
+                    {
                         // If receiving end is closed, bail now.
                         log::warn!("Aggregator is no longer receiving messages from core; disconnecting (permanently): {}", e);
                         return;
@@ -80,14 +168,7 @@ where
                                     }
                                 };
 
-                                let bytes = match msg {
-                                    ws_client::RecvMessage::Binary(bytes) => bytes,
-                                    ws_client::RecvMessage::Text(s) => s.into_bytes()
-                                };
-                                let msg = bincode::options()
-                                    .deserialize(&bytes)
-                                    .expect("internal messages must be deserializable");
-
+                                let msg = decode_message(msg);
                                 if let Err(e) = tx_out.send_async(Message::Data(msg)).await {
                                     log::error!("Aggregator is no longer receiving messages from core; disconnecting (permanently): {}", e);
                                     return;
@@ -133,9 +214,20 @@ where
             }
 
             // Wait a little before we try to connect again.
-            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            tokio::time::sleep(Duration::from_secs(1)).await;
         }
     });
 
     (tx_in, rx_out)
 }
+
+/// Decode a bincode encoded message received from the core.
+fn decode_message<Out: serde::de::DeserializeOwned>(msg: ws_client::RecvMessage) -> Out {
+    let bytes = match msg {
+        ws_client::RecvMessage::Binary(bytes) => bytes,
+        ws_client::RecvMessage::Text(s) => s.into_bytes(),
+    };
+    bincode::options()
+        .deserialize(&bytes)
+        .expect("internal messages must be deserializable")
+}
@@ -0,0 +1,210 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Nodes (or anything else acting on their behalf) can `POST` batches of telemetry messages to
+//! `/submit` rather than opening a websocket connection. The body of each request is newline
+//! delimited JSON; each line is a single message in exactly the same format that would be sent
+//! over a websocket connection.
+//!
+//! Each client is identified by its IP address and an optional `client` query parameter (eg
+//! `POST /submit?client=my-validators`), and its requests are handled as if they were all sent
+//! over one long lived connection; the same rate limits, per connection node limits and stale
+//! node timeouts apply. If we don't hear from a client for the stale node timeout, we forget
+//! about it and the nodes it told us about.
+//!
+//! Unlike websocket connections, there is no way to tell a client that it has been muted or
+//! disconnected (for instance if the chain is over quota, or the shard reconnects to the core).
+//! Clients should periodically re-send `system.connected` messages for their nodes to ensure
+//! that they are known about.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use hyper::body::HttpBody;
+
+/// Clients are identified by their IP address and an identifier they provide.
+pub type ClientKey = (IpAddr, Box<str>);
+
+/// How many messages from a client can be queued up waiting to be handled. Once this many
+/// are, requests from the client wait until there's room for their messages, so that a
+/// client sending messages faster than we handle them can't use up ever more memory.
+const CLIENT_QUEUE_LEN: usize = 1024;
+
+/// Keep track of the clients that are submitting messages via HTTP requests, and
+/// how to hand their messages on.
+#[derive(Clone, Default)]
+pub struct HttpSubmitClients(Arc<Mutex<HashMap<ClientKey, flume::Sender<Vec<u8>>>>>);
+
+impl HttpSubmitClients {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send some messages on to the client identified by `key`. If no such client exists (or it
+    /// has since gone away), `on_new_client` is called with a channel that this, and subsequent
+    /// messages for the client, will be sent to. This waits for there to be room in the channel
+    /// for the messages.
+    pub async fn send(
+        &self,
+        key: ClientKey,
+        msgs: Vec<Vec<u8>>,
+        on_new_client: impl FnOnce(flume::Receiver<Vec<u8>>),
+    ) {
+        let tx = {
+            let mut clients = self.0.lock().unwrap();
+            match clients.get(&key) {
+                Some(tx) if !tx.is_disconnected() => tx.clone(),
+                _ => {
+                    // Tidy up any clients that have gone away while we're here:
+                    clients.retain(|_, tx| !tx.is_disconnected());
+
+                    let (tx, rx) = flume::bounded(CLIENT_QUEUE_LEN);
+                    clients.insert(key, tx.clone());
+                    on_new_client(rx);
+                    tx
+                }
+            }
+        };
+
+        for msg in msgs {
+            if tx.send_async(msg).await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// Find the client identifier in the query string of a request, if one was given.
+pub fn client_id_from_query(query: Option<&str>) -> Box<str> {
+    query
+        .into_iter()
+        .flat_map(|q| q.split('&'))
+        .find_map(|param| param.strip_prefix("client="))
+        .unwrap_or("")
+        .into()
+}
+
+/// Read the body of a request, failing if it's larger than `max_bytes`.
+pub async fn read_body(mut body: hyper::Body, max_bytes: usize) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if bytes.len() + chunk.len() > max_bytes {
+            return Err(anyhow::anyhow!(
+                "Request body is larger than the maximum of {} bytes",
+                max_bytes
+            ));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
+/// Split a newline delimited body into separate messages, ignoring empty lines.
+pub fn split_messages(body: &[u8]) -> Vec<Vec<u8>> {
+    body.split(|&b| b == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .filter(|line| !line.iter().all(u8::is_ascii_whitespace))
+        .map(|line| line.to_vec())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn messages_are_split_by_line() {
+        let body = b"{\"a\":1}\n{\"b\":2}\r\n\n  \n{\"c\":3}";
+        assert_eq!(
+            split_messages(body),
+            vec![
+                b"{\"a\":1}".to_vec(),
+                b"{\"b\":2}".to_vec(),
+                b"{\"c\":3}".to_vec()
+            ]
+        );
+    }
+
+    #[test]
+    fn client_id_is_optional() {
+        assert_eq!(&*client_id_from_query(None), "");
+        assert_eq!(&*client_id_from_query(Some("foo=bar")), "");
+        assert_eq!(&*client_id_from_query(Some("foo=bar&client=abc")), "abc");
+    }
+
+    #[tokio::test]
+    async fn messages_for_the_same_client_go_to_the_same_channel() {
+        let clients = HttpSubmitClients::new();
+        let key: ClientKey = ("127.0.0.1".parse().unwrap(), "a".into());
+
+        let mut new_clients = Vec::new();
+        clients
+            .send(key.clone(), vec![b"1".to_vec()], |rx| new_clients.push(rx))
+            .await;
+        clients
+            .send(key.clone(), vec![b"2".to_vec()], |rx| new_clients.push(rx))
+            .await;
+        assert_eq!(new_clients.len(), 1);
+        assert_eq!(
+            new_clients[0].drain().collect::<Vec<_>>(),
+            vec![b"1".to_vec(), b"2".to_vec()]
+        );
+
+        // A different client gets a separate channel:
+        let other_key: ClientKey = ("127.0.0.1".parse().unwrap(), "b".into());
+        clients
+            .send(other_key, vec![b"3".to_vec()], |rx| new_clients.push(rx))
+            .await;
+        assert_eq!(new_clients.len(), 2);
+
+        // If a client goes away, we'll create a new channel for it next time:
+        new_clients.remove(0);
+        clients
+            .send(key, vec![b"4".to_vec()], |rx| new_clients.push(rx))
+            .await;
+        assert_eq!(new_clients.len(), 2);
+        assert_eq!(
+            new_clients[1].drain().collect::<Vec<_>>(),
+            vec![b"4".to_vec()]
+        );
+    }
+
+    #[tokio::test]
+    async fn clients_can_only_queue_up_so_many_messages() {
+        let clients = HttpSubmitClients::new();
+        let key: ClientKey = ("127.0.0.1".parse().unwrap(), "a".into());
+
+        let mut new_clients = Vec::new();
+        let msgs = vec![b"1".to_vec(); CLIENT_QUEUE_LEN];
+        clients
+            .send(key.clone(), msgs, |rx| new_clients.push(rx))
+            .await;
+
+        // The queue is full, so sending another message waits until there's room for it:
+        let send = clients.send(key, vec![b"2".to_vec()], |_| panic!("not a new client"));
+        tokio::pin!(send);
+        assert!(
+            tokio::time::timeout(std::time::Duration::from_millis(100), &mut send)
+                .await
+                .is_err()
+        );
+        assert_eq!(new_clients[0].recv().unwrap(), b"1".to_vec());
+        send.await;
+        assert_eq!(new_clients[0].len(), CLIENT_QUEUE_LEN);
+    }
+}
@@ -0,0 +1,93 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! How a shard identifies itself, both in its own logs and to the core, so that logs
+//! from several shards can be told apart.
+
+/// The ID to use if none is given; the hostname of the machine if we can find it, or else
+/// a random UUID.
+pub fn default_shard_id() -> String {
+    hostname::get()
+        .ok()
+        .and_then(|hostname| hostname.into_string().ok())
+        .map(|hostname| hostname.trim().to_owned())
+        .filter(|hostname| !hostname.is_empty())
+        .unwrap_or_else(random_uuid)
+}
+
+/// A random (version 4) UUID.
+fn random_uuid() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+/// A logger which prefixes every message with the shard ID before handing it on.
+pub struct PrefixedLogger<L> {
+    prefix: String,
+    inner: L,
+}
+
+impl<L: log::Log + 'static> PrefixedLogger<L> {
+    /// Prefix messages logged with `[shard_id]`.
+    pub fn new(shard_id: &str, inner: L) -> Self {
+        PrefixedLogger {
+            prefix: format!("[{shard_id}]"),
+            inner,
+        }
+    }
+
+    /// Install this as the global logger.
+    pub fn init(self, level: log::LevelFilter) -> Result<(), log::SetLoggerError> {
+        log::set_boxed_logger(Box::new(self))?;
+        log::set_max_level(level);
+        Ok(())
+    }
+}
+
+impl<L: log::Log> log::Log for PrefixedLogger<L> {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        self.inner.log(
+            &log::Record::builder()
+                .args(format_args!("{} {}", self.prefix, record.args()))
+                .metadata(record.metadata().clone())
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line())
+                .build(),
+        );
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn random_uuids_look_like_uuids() {
+        let uuid = random_uuid();
+        let lens: Vec<usize> = uuid.split('-').map(str::len).collect();
+        assert_eq!(lens, vec![8, 4, 4, 4, 12]);
+        assert_eq!(uuid.chars().nth(14), Some('4'));
+        assert_ne!(uuid, random_uuid());
+    }
+}
@@ -0,0 +1,273 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Nodes are untrusted, so we feed lots of mangled and arbitrary input into the node message
+//! deserializer (and the conversion into our internal message format which follows it) to
+//! check that it only ever hands back `Ok` or `Err`, and never panics. Large adversarial
+//! inputs are also checked to not allocate much more memory than their own size.
+//!
+//! By default the same seed is used each run, so that the test behaves the same everywhere.
+//! A different seed can be given with `TELEMETRY_FUZZ_SEED`, and the seed is printed if the
+//! test fails so that the failure can be reproduced. The number of inputs tried can be raised
+//! with `TELEMETRY_FUZZ_ITERATIONS` for a longer run, for instance:
+//!
+//! ```text
+//! TELEMETRY_FUZZ_SEED=$RANDOM TELEMETRY_FUZZ_ITERATIONS=10000000 cargo test --release -p telemetry_shard fuzz
+//! ```
+
+use super::NodeMessage;
+use common::node_message as internal;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+const DEFAULT_SEED: u64 = 0x5eed_7e1e_3e7e_5eed;
+const DEFAULT_ITERATIONS: usize = 5_000;
+
+/// Valid messages, which we mangle to produce most of our inputs.
+const SEED_MESSAGES: &[&str] = &[
+    r#"{"id":1,"ts":"2021-01-13T12:22:20.053527101+01:00","payload":{"msg":"system.connected","genesis_hash":"0x91b171bb158e2d3848fa23a9f1c25182fb8e20313b2c1eb49219da7a70ce90c3","chain":"Polkadot","name":"Alice","implementation":"Parity Polkadot","version":"0.9.17-75dd6c7d0-x86_64-linux-gnu","validator":"1abc","network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp","startup_time":"1625565542717","sysinfo":{"cpu":"AMD Ryzen","memory":17179869184,"core_count":8,"linux_kernel":"5.10.0","linux_distro":"Ubuntu","is_virtual_machine":false}}}"#,
+    r#"{"id":1,"ts":"2021-01-13T12:22:20.053527101+01:00","payload":{"msg":"system.interval","peers":12,"txcount":0,"bandwidth_upload":1.5,"bandwidth_download":2.5,"finalized_height":10,"finalized_hash":"0xcc41708573f2acaded9dd75e07dac2d4163d136ca35b3061c558d7a35a09dd8d","best":"0xcc41708573f2acaded9dd75e07dac2d4163d136ca35b3061c558d7a35a09dd8d","height":12,"used_state_cache_size":1.0,"database_size":1024,"error_count":1,"warning_count":2,"major_syncing":true}}"#,
+    r#"{"id":1,"ts":"2021-01-13T12:22:20.053527101+01:00","payload":{"msg":"block.import","best":"0xcc41708573f2acaded9dd75e07dac2d4163d136ca35b3061c558d7a35a09dd8d","height":1234}}"#,
+    r#"{"id":1,"ts":"2021-01-13T12:22:20.053527101+01:00","payload":{"msg":"notify.finalized","best":"0xcc41708573f2acaded9dd75e07dac2d4163d136ca35b3061c558d7a35a09dd8d","height":"209"}}"#,
+    r#"{"id":1,"ts":"2021-01-13T12:22:20.053527101+01:00","payload":{"msg":"afg.authority_set","authority_id":"foo"}}"#,
+    r#"{"id":1,"ts":"2021-01-13T12:22:20.053527101+01:00","payload":{"msg":"sysinfo.hwbench","cpu_hashrate_score":1,"memory_memcpy_score":2,"disk_sequential_write_score":3,"disk_random_write_score":4}}"#,
+    r#"{"msg":"notify.finalized","level":"INFO","ts":"2021-01-13T12:38:25.410794650+01:00","best":"0x031c3521ca2f9c673812d692fc330b9a18e18a2781e3f9976992f861fd3ea0cb","height":"50"}"#,
+    r#"{"msg":"system.connected","genesis_hash":[1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,25,26,27,28,29,30,31,32],"chain":"Kusama","name":"Bob","implementation":"Substrate Node","version":"2.0.0-alpha.5-da487d19d-x86_64-linux","network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp"}"#,
+];
+
+/// Snippets which are likely to find their way into awkward corners of the deserializer.
+const INTERESTING_TOKENS: &[&str] = &[
+    "-1",
+    "0",
+    "1e400",
+    "-1e400",
+    "18446744073709551616",
+    "340282366920938463463374607431768211456",
+    "3.4e39",
+    "null",
+    "true",
+    "\"\"",
+    "\"0x\"",
+    "\"0x0\"",
+    "\"0xzz\"",
+    "[]",
+    "{}",
+    "[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[",
+    "{\"msg\":",
+    "\"msg\":\"system.connected\",",
+    "\"version\":\"x86_64-linux-gnu\",",
+    "\"version\":\"-\",",
+    "\"version\":\"--\",",
+    "\"height\":-5,",
+    "\"payload\":",
+    "\"\\ud800\"",
+    "\"\\u0000\"",
+    "\u{feff}",
+    ",",
+    ":",
+    "\"",
+    "{",
+    "}",
+    "[",
+    "]",
+];
+
+/// Pieces that we glue together to produce version strings, since splitting up old style
+/// versions is the fiddliest bit of converting a message.
+const VERSION_PARTS: &[&str] = &[
+    "",
+    "0.9.17",
+    "75dd6c7d0",
+    "x86_64",
+    "aarch64",
+    "linux",
+    "macos",
+    "gnu",
+    "alpha.5",
+    "abc",
+    "-",
+    "é",
+];
+
+/// A small, fast and deterministic pseudo random number generator (xorshift64*).
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn pick<'a>(&mut self, items: &[&'a str]) -> &'a str {
+        items[self.below(items.len())]
+    }
+}
+
+/// Keeps track of how much memory is allocated by the current thread, so that we can check
+/// how much deserializing a message needs without other tests getting in the way.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATED: Cell<usize> = const { Cell::new(0) };
+    static PEAK_ALLOCATED: Cell<usize> = const { Cell::new(0) };
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATED.try_with(|allocated| {
+            let now = allocated.get() + layout.size();
+            allocated.set(now);
+            let _ = PEAK_ALLOCATED.try_with(|peak| peak.set(peak.get().max(now)));
+        });
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // Memory may be freed by a different thread to the one which allocated it:
+        let _ = ALLOCATED
+            .try_with(|allocated| allocated.set(allocated.get().saturating_sub(layout.size())));
+        System.dealloc(ptr, layout)
+    }
+}
+
+/// Run the function, returning the most memory that it had allocated at once.
+fn peak_allocation(f: impl FnOnce()) -> usize {
+    let start = ALLOCATED.with(|allocated| allocated.get());
+    PEAK_ALLOCATED.with(|peak| peak.set(start));
+    f();
+    PEAK_ALLOCATED.with(|peak| peak.get()) - start
+}
+
+fn deserialize(input: &[u8]) {
+    if let Ok(msg) = NodeMessage::from_json(input) {
+        let _ = internal::NodeMessage::from(msg);
+    }
+}
+
+fn env_var<T: std::str::FromStr>(name: &str) -> Option<T> {
+    std::env::var(name).ok().and_then(|val| val.parse().ok())
+}
+
+/// Produce the next input to try.
+fn generate_input(rng: &mut Rng) -> Vec<u8> {
+    match rng.below(10) {
+        // Entirely arbitrary bytes:
+        0 => {
+            let len = rng.below(256);
+            (0..len).map(|_| rng.next() as u8).collect()
+        }
+        // A valid message with an arbitrary version string:
+        1 => {
+            let parts = rng.below(7);
+            let version: Vec<_> = (0..parts).map(|_| rng.pick(VERSION_PARTS)).collect();
+            let version = serde_json::to_string(&version.join("-")).unwrap();
+            SEED_MESSAGES[0]
+                .replace("\"0.9.17-75dd6c7d0-x86_64-linux-gnu\"", &version)
+                .into_bytes()
+        }
+        // A valid message that's been mangled in a few places:
+        _ => {
+            let mut input = rng.pick(SEED_MESSAGES).as_bytes().to_vec();
+            for _ in 0..=rng.below(4) {
+                let idx = rng.below(input.len() + 1);
+                match rng.below(4) {
+                    0 => input.truncate(idx),
+                    1 if idx < input.len() => input[idx] = rng.next() as u8,
+                    2 if idx < input.len() => {
+                        let end = idx + rng.below(input.len() - idx + 1);
+                        input.drain(idx..end);
+                    }
+                    _ => {
+                        let token = rng.pick(INTERESTING_TOKENS).as_bytes();
+                        input.splice(idx..idx, token.iter().copied());
+                    }
+                }
+            }
+            input
+        }
+    }
+}
+
+#[test]
+fn node_message_deserialization_never_panics() {
+    let seed = env_var("TELEMETRY_FUZZ_SEED")
+        .unwrap_or(DEFAULT_SEED)
+        // xorshift never leaves a state of 0:
+        .max(1);
+    let iterations = env_var("TELEMETRY_FUZZ_ITERATIONS").unwrap_or(DEFAULT_ITERATIONS);
+
+    let mut rng = Rng(seed);
+    for _ in 0..iterations {
+        let input = generate_input(&mut rng);
+        let result = std::panic::catch_unwind(|| deserialize(&input));
+        if result.is_err() {
+            panic!(
+                "Deserializing this node message panicked (TELEMETRY_FUZZ_SEED={seed}): {}",
+                String::from_utf8_lossy(&input)
+            );
+        }
+    }
+}
+
+#[test]
+fn large_inputs_dont_need_much_more_memory_than_their_size() {
+    const SIZE: usize = 1024 * 1024;
+    let connected = |field: &str| {
+        SEED_MESSAGES[0]
+            .replace("\"msg\":", &format!("{field},\"msg\":"))
+            .into_bytes()
+    };
+    let inputs = [
+        // Deeply nested, which would also overflow the stack if nesting wasn't limited:
+        "[".repeat(SIZE).into_bytes(),
+        connected(&format!("\"nested\":{}", "[".repeat(SIZE))),
+        // Huge values in fields we hold onto:
+        connected(&format!("\"name\":\"{}\"", "a".repeat(SIZE))),
+        connected(&format!("\"name\":\"{}\"", "\\u00e9".repeat(SIZE / 6))),
+        connected(&format!("\"peers\":{}", "9".repeat(SIZE))),
+        connected(&format!("\"genesis_hash\":[{}1]", "1,".repeat(SIZE / 2))),
+        // Lots of fields that we ignore:
+        connected(&"\"unknown\":[1,2,3],".repeat(SIZE / 20)),
+        connected(&"\"x\":{\"y\":\"z\"},".repeat(SIZE / 14)),
+    ];
+
+    for input in inputs {
+        let peak = peak_allocation(|| deserialize(&input));
+        assert!(
+            peak <= 2 * input.len() + 64 * 1024,
+            "Deserializing a {} byte message allocated {peak} bytes: {}...",
+            input.len(),
+            String::from_utf8_lossy(&input[..100])
+        );
+    }
+}
+
+#[test]
+fn seed_messages_are_valid() {
+    // If these don't deserialize, we aren't fuzzing much beyond the JSON parser:
+    for msg in SEED_MESSAGES {
+        serde_json::from_str::<NodeMessage>(msg).expect(msg);
+    }
+}
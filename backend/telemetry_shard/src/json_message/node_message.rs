@@ -22,8 +22,15 @@
 use super::hash::Hash;
 use common::node_message as internal;
 use common::node_types;
+use serde::de::Error as _;
 use serde::Deserialize;
 
+/// The most values (array elements and object fields, at any depth) that we'll accept in a
+/// message. Genuine messages have far fewer than this. Deserializing buffers every value
+/// (see [`NodeMessage`]), which can take many times the size of the message itself, so this
+/// bounds how much memory a message can make us allocate.
+const MAX_VALUES: usize = 4096;
+
 /// This struct represents a telemetry message sent from a node as
 /// a JSON payload. Since JSON is self describing, we can use attributes
 /// like serde(untagged) and serde(flatten) without issue.
@@ -49,6 +56,44 @@ pub enum NodeMessage {
     },
 }
 
+impl NodeMessage {
+    /// Deserialize a message from the JSON that a node sent, refusing messages with too many
+    /// values in them.
+    pub fn from_json(bytes: &[u8]) -> serde_json::Result<NodeMessage> {
+        if count_values(bytes) > MAX_VALUES {
+            return Err(serde_json::Error::custom(format!(
+                "more than {MAX_VALUES} values in message"
+            )));
+        }
+        serde_json::from_slice(bytes)
+    }
+}
+
+/// Roughly count the values in some JSON, by counting the separators and opening brackets
+/// outside of strings. This doesn't care whether the JSON is valid.
+fn count_values(bytes: &[u8]) -> usize {
+    let mut count = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for &b in bytes {
+        if in_string {
+            match b {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+        } else {
+            match b {
+                b'"' => in_string = true,
+                b'[' | b'{' | b',' => count += 1,
+                _ => {}
+            }
+        }
+    }
+    count
+}
+
 impl From<NodeMessage> for internal::NodeMessage {
     fn from(msg: NodeMessage) -> Self {
         match msg {
@@ -120,6 +165,14 @@ pub struct SystemInterval {
     #[serde(flatten)]
     pub block: Option<Block>,
     pub used_state_cache_size: Option<f32>,
+    /// The size of the node's database on disk, in bytes. Not all nodes report this.
+    pub database_size: Option<u64>,
+    /// How many errors the node has logged since it started. Not all nodes report this.
+    pub error_count: Option<u64>,
+    /// How many warnings the node has logged since it started. Not all nodes report this.
+    pub warning_count: Option<u64>,
+    /// Is the node doing a major sync? Not all nodes report this.
+    pub major_syncing: Option<bool>,
 }
 
 impl From<SystemInterval> for internal::SystemInterval {
@@ -133,6 +186,10 @@ impl From<SystemInterval> for internal::SystemInterval {
             finalized_hash: msg.finalized_hash.map(|h| h.into()),
             block: msg.block.map(|b| b.into()),
             used_state_cache_size: msg.used_state_cache_size,
+            database_size: msg.database_size,
+            error_count: msg.error_count,
+            warning_count: msg.warning_count,
+            major_syncing: msg.major_syncing,
         }
     }
 }
@@ -282,6 +339,7 @@ impl From<NodeDetails> for node_types::NodeDetails {
             target_env: details.target_env,
             sysinfo: details.sysinfo.map(|sysinfo| sysinfo.into()),
             ip: details.ip,
+            shard: None,
         }
     }
 }
@@ -326,7 +384,10 @@ fn split_old_style_version(version_and_target: &str) -> Option<(&str, &str, &str
         item.as_ptr() as usize
     } - version_and_target.as_ptr() as usize;
 
-    let version = version_and_target.get(0..target_offset - 1)?;
+    // There's no version if the target is all we were given (eg "x86_64-linux-gnu" or "--"):
+    let version = version_and_target
+        .get(0..target_offset.checked_sub(1)?)
+        .filter(|version| !version.is_empty())?;
     let mut target = version_and_target.get(target_offset..)?.split('-');
     let target_arch = target.next()?;
     let target_os = target.next()?;
@@ -339,6 +400,23 @@ fn split_old_style_version(version_and_target: &str) -> Option<(&str, &str, &str
 mod tests {
     use super::*;
 
+    #[test]
+    fn values_are_counted_outside_of_strings() {
+        assert_eq!(count_values(br#"{"a":[1,2,3],"b":{"c":"d"}}"#), 6);
+        assert_eq!(count_values(br#"{"a":"[1,2,{3}]"}"#), 1);
+        assert_eq!(count_values(br#"{"a":"\"[,","b":1}"#), 2);
+    }
+
+    #[test]
+    fn messages_with_too_many_values_are_refused() {
+        let genesis_hash = format!("[{}1]", "1,".repeat(MAX_VALUES));
+        let json = format!(
+            r#"{{"msg":"system.connected","genesis_hash":{genesis_hash},"chain":"Kusama"}}"#
+        );
+        let err = NodeMessage::from_json(json.as_bytes()).unwrap_err();
+        assert!(err.to_string().contains("values in message"));
+    }
+
     #[test]
     fn message_v1() {
         let json = r#"{
@@ -404,6 +482,74 @@ mod tests {
         );
     }
 
+    #[test]
+    fn system_interval_major_syncing_is_optional() {
+        let major_syncing = |json: &str| match serde_json::from_str::<NodeMessage>(json).unwrap() {
+            NodeMessage::V2 {
+                payload: Payload::SystemInterval(interval),
+                ..
+            } => interval.major_syncing,
+            msg => panic!("unexpected message: {msg:?}"),
+        };
+
+        let without_flag = r#"{
+            "id":1,
+            "ts":"2021-01-13T12:22:20.053527101+01:00",
+            "payload":{
+                "msg":"system.interval",
+                "peers":12,
+                "txcount":0
+            }
+        }"#;
+        assert_eq!(major_syncing(without_flag), None);
+
+        let with_flag = r#"{
+            "id":1,
+            "ts":"2021-01-13T12:22:20.053527101+01:00",
+            "payload":{
+                "msg":"system.interval",
+                "peers":12,
+                "txcount":0,
+                "major_syncing":true
+            }
+        }"#;
+        assert_eq!(major_syncing(with_flag), Some(true));
+    }
+
+    #[test]
+    fn system_connected_sysinfo_fields_are_optional() {
+        let json = r#"{
+            "id":1,
+            "ts":"2021-01-13T12:22:20.053527101+01:00",
+            "payload":{
+                "msg":"system.connected",
+                "genesis_hash":"0x91b171bb158e2d3848fa23a9f1c25182fb8e20313b2c1eb49219da7a70ce90c3",
+                "chain":"Polkadot",
+                "name":"Alice",
+                "implementation":"Parity Polkadot",
+                "version":"0.9.17-75dd6c7d0-x86_64-linux-gnu",
+                "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                "sysinfo":{
+                    "core_count":8,
+                    "memory":17179869184,
+                    "linux_distro":"Ubuntu 20.04"
+                }
+            }
+        }"#;
+        let sysinfo = match serde_json::from_str::<NodeMessage>(json).unwrap() {
+            NodeMessage::V2 {
+                payload: Payload::SystemConnected(connected),
+                ..
+            } => connected.node.sysinfo.expect("sysinfo"),
+            msg => panic!("unexpected message: {msg:?}"),
+        };
+        assert_eq!(sysinfo.core_count, Some(8));
+        assert_eq!(sysinfo.memory, Some(17179869184));
+        assert_eq!(sysinfo.linux_distro.as_deref(), Some("Ubuntu 20.04"));
+        assert_eq!(sysinfo.cpu, None);
+        assert_eq!(sysinfo.is_virtual_machine, None);
+    }
+
     #[test]
     fn split_old_style_version_works() {
         let (version, target_arch, target_os, target_env) =
@@ -445,4 +591,11 @@ mod tests {
         assert_eq!(split_old_style_version("a"), None);
         assert_eq!(split_old_style_version("a-b"), None);
     }
+
+    #[test]
+    fn split_old_style_version_handles_targets_without_a_version() {
+        // These used to panic when working out where the version ends:
+        assert_eq!(split_old_style_version("x86_64-linux-gnu"), None);
+        assert_eq!(split_old_style_version("--"), None);
+    }
 }
@@ -16,38 +16,58 @@
 
 #[warn(missing_docs)]
 mod aggregator;
+mod allowed_message_ids;
 mod blocked_addrs;
 mod connection;
+mod http_submit;
+mod identity;
 mod json_message;
-mod real_ip;
+mod node_version;
+mod self_test;
 
 use std::{
-    collections::HashMap,
+    collections::HashSet,
     net::IpAddr,
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+    sync::Arc,
     time::{Duration, Instant},
 };
 
 use aggregator::{Aggregator, FromWebsocket};
-use blocked_addrs::BlockedAddrs;
+use allowed_message_ids::{AllowedMessageIds, EvictionPolicy, InsertResult};
+use bincode::Options;
+use blocked_addrs::{BlockReason, BlockedAddrs};
+use common::build_info::BuildInfo;
 use common::byte_size::ByteSize;
+use common::connection_limits::{at_capacity_response, ConnectionLimits, Endpoint};
 use common::http_utils;
+use common::internal_messages;
 use common::node_message;
-use common::node_message::NodeMessageId;
+use common::real_ip;
 use common::rolling_total::RollingTotalBuilder;
-use futures::{SinkExt, StreamExt};
+use common::shutdown::{self, Shutdown, ShutdownHandle};
+use common::ws_client;
+use futures::{SinkExt, Stream, StreamExt};
 use http::Uri;
-use hyper::{Method, Response};
+use http_submit::HttpSubmitClients;
+use hyper::{header::HeaderName, Method, Response};
+use identity::PrefixedLogger;
+use node_version::NodeVersion;
 use simple_logger::SimpleLogger;
 use structopt::StructOpt;
 
-#[cfg(not(target_env = "msvc"))]
+#[cfg(all(not(target_env = "msvc"), not(test)))]
 use jemallocator::Jemalloc;
 
-#[cfg(not(target_env = "msvc"))]
+// Tests keep track of how much memory is allocated (see `json_message::fuzz`):
+#[cfg(all(not(target_env = "msvc"), not(test)))]
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// How this shard was built, which is served from '/version'.
+const BUILD_INFO: BuildInfo = common::build_info!();
 const AUTHORS: &str = env!("CARGO_PKG_AUTHORS");
 const NAME: &str = "Substrate Telemetry Backend Shard";
 const ABOUT: &str = "This is the Telemetry Backend Shard that forwards the \
@@ -72,6 +92,26 @@ struct Opts {
         default_value = "ws://127.0.0.1:8000/shard_submit/"
     )]
     core_url: Uri,
+    /// A file containing PEM encoded certificate authorities to trust when connecting to the
+    /// core over TLS (ie when '--core' is a 'wss://' URL), on top of the usual web PKI ones.
+    /// Use this if the core's certificate is signed by a private CA.
+    #[structopt(long)]
+    core_ca: Option<PathBuf>,
+    /// How many seconds to wait for the core to acknowledge a new connection before we start
+    /// sending node data to it anyway. Older cores never acknowledge connections, and so this
+    /// is how long we'll wait before assuming that we're connected to one of those.
+    #[structopt(long, default_value = "2")]
+    core_init_ack_timeout: u64,
+    /// A secret to give to the core (as 'Authorization: Bearer <secret>') when connecting to it.
+    /// This should match the '--shard-secret' that the core was started with. Prefer setting
+    /// the environment variable to passing the option, which other users can see in 'ps'.
+    #[structopt(long, env = "TELEMETRY_SHARD_SECRET", hide_env_values = true)]
+    shard_secret: Option<String>,
+    /// An ID for this shard, which prefixes everything that it logs and is given to the core
+    /// so that it can tell which shard nodes came from. If no ID is given, the hostname of the
+    /// machine is used if it can be found, or else a random UUID.
+    #[structopt(long)]
+    shard_id: Option<String>,
     /// How many different nodes is a given connection to the /submit endpoint allowed to
     /// tell us about before we ignore the rest?
     ///
@@ -79,6 +119,12 @@ struct Opts {
     /// RAM by suggesting that it accounts for billions of nodes.
     #[structopt(long, default_value = "20")]
     max_nodes_per_connection: usize,
+    /// What to do when a connection to the /submit endpoint tells us about a new node, but
+    /// it's already at '--max-nodes-per-connection'. Either 'reject-new', to ignore the new
+    /// node until an existing one goes stale, or 'evict-oldest', to forget about the node we
+    /// heard from least recently in order to make room for the new one.
+    #[structopt(long, default_value = "reject-new")]
+    node_eviction_policy: EvictionPolicy,
     /// What is the maximum number of bytes per second, on average, that a connection from a
     /// node is allowed to send to a shard before it gets booted. This is averaged over a
     /// rolling window of 10 seconds, and so spikes beyond this limit are allowed as long as
@@ -92,6 +138,26 @@ struct Opts {
     /// value prevented from reconnecting to this shard for, in seconds.
     #[structopt(long, default_value = "600")]
     node_block_seconds: u64,
+    /// How many seconds we'll wait for the next message from a node to arrive in full before
+    /// closing the connection. We can't tell when a message starts to arrive, so this is counted
+    /// from when the previous message was received (or the connection was opened). This guards
+    /// against connections that trickle in a message (for instance as an endless stream of
+    /// continuation frames) that never completes. Nodes send telemetry every few seconds, so
+    /// this can be generous.
+    #[structopt(long, default_value = "120")]
+    node_message_timeout: u64,
+    /// If given, close websocket connections from nodes that haven't sent us any bytes at all
+    /// (including pings and partial messages) for this many seconds. Unlike
+    /// '--stale-node-timeout', which is about whether nodes are still sending telemetry, this
+    /// is about tidying up connections which appear to have died without being closed.
+    #[structopt(long)]
+    idle_socket_timeout: Option<u64>,
+    /// The maximum size of a single message from a node, which may be split across many
+    /// websocket frames. If a message exceeds this size, the connection is closed without
+    /// waiting for the rest of it to arrive (and so without holding onto it). Messages sent
+    /// to 'POST /submit' which exceed this size are ignored.
+    #[structopt(long, default_value = "16m")]
+    max_node_message_size: ByteSize,
     /// Number of worker threads to spawn. If "0" is given, use the number of CPUs available
     /// on the machine. If no value is given, use an internal default that we have deemed sane.
     #[structopt(long)]
@@ -102,17 +168,72 @@ struct Opts {
     /// dropped.
     #[structopt(long, default_value = "60")]
     stale_node_timeout: u64,
+    /// Also accept batches of node messages via 'POST /submit' requests, as an alternative to
+    /// websocket connections. The body of each request should be newline delimited JSON, one
+    /// message per line. Requests from the same IP address and '?client=' query parameter are
+    /// treated as though they came from a single connection. Note that there is no way to tell
+    /// HTTP clients that they have been muted.
+    #[structopt(long)]
+    http_submit: bool,
+    /// The maximum size of the body of a 'POST /submit' request.
+    #[structopt(long, default_value = "1m")]
+    http_submit_max_body_size: ByteSize,
+    /// A header to obtain the real IP address of connecting nodes from. This can be given
+    /// multiple times, and the headers will be checked in the order given, falling back to
+    /// the socket address of the connection if none of them are present. This overrides the
+    /// default behaviour of checking the 'Forwarded', 'X-Forwarded-For' and 'X-Real-IP'
+    /// headers. Since anybody can set these headers, they should only be trusted if this
+    /// shard sits behind a proxy that sets them (eg 'CF-Connecting-IP' for Cloudflare).
+    #[structopt(long = "real-ip-header")]
+    real_ip_headers: Vec<HeaderName>,
+    /// Ignore nodes that report a version older than this (eg '1.2.0') when they connect.
+    /// Only the 'major.minor.patch' part of the version is compared, so a node reporting
+    /// '2.0.0-07a1af348-aarch64-macos' is treated as version '2.0.0'. Nodes whose version
+    /// can't be parsed are ignored too. Other nodes sharing a connection with an ignored
+    /// node are unaffected. If no version is given, nodes of any version are accepted.
+    #[structopt(long)]
+    min_node_version: Option<NodeVersion>,
+    /// The maximum number of connections allowed to each endpoint at once, as a comma separated
+    /// list of 'endpoint=max' pairs (eg 'submit=20000'). Shards serve the 'submit' endpoint
+    /// ('feed' and 'shard_submit' are limited on the core). Websocket connections beyond the
+    /// limit are turned away with a '503 Service Unavailable' response. If no limit is given,
+    /// there is no limit. The number of open connections is exposed on '/metrics'.
+    #[structopt(long)]
+    connection_limits: Option<ConnectionLimits>,
+    /// Rather than running normally, start up, connect a fake node to '/submit', check that it
+    /// shows up on the '/feed' endpoint of the core given by '--core', and then exit. The exit
+    /// code is 0 if the check passes and 1 if it fails, so this can be used to check a
+    /// deployment's configuration.
+    #[structopt(long)]
+    self_test: bool,
+    /// How many seconds to wait for the '--self-test' check to pass before failing it.
+    #[structopt(long, default_value = "10")]
+    self_test_timeout: u64,
+    /// On receiving SIGTERM or SIGINT, we stop accepting new connections and close the existing
+    /// node connections, waiting up to this many seconds for them to close before exiting.
+    #[structopt(long, default_value = "10")]
+    shutdown_grace_seconds: u64,
+    /// When the core is tracking as many nodes as it's allowed to (see its '--max-total-nodes'),
+    /// it mutes any more that connect. Connections whose nodes have all been muted for this
+    /// reason are closed after this many seconds, so that they reconnect and try again once
+    /// there may be room. Nodes wait longer before each reconnection attempt, and so back off
+    /// further while the core remains full. If "0" is given, these nodes are left muted.
+    #[structopt(long, default_value = "300")]
+    global_quota_backoff_seconds: u64,
 }
 
 fn main() {
     let opts = Opts::from_args();
+    let shard_id = opts
+        .shard_id
+        .clone()
+        .unwrap_or_else(identity::default_shard_id);
 
-    SimpleLogger::new()
-        .with_level(opts.log_level)
-        .init()
+    PrefixedLogger::new(&shard_id, SimpleLogger::new().with_level(opts.log_level))
+        .init(opts.log_level)
         .expect("Must be able to start a logger");
 
-    log::info!("Starting Telemetry Shard version: {}", VERSION);
+    log::info!("Starting Telemetry Shard version: {}", shard_version());
 
     let worker_threads = match opts.worker_threads {
         Some(0) => num_cpus::get(),
@@ -129,59 +250,156 @@ fn main() {
         .build()
         .unwrap()
         .block_on(async {
-            if let Err(e) = start_server(opts).await {
+            if opts.self_test {
+                if let Err(e) = run_self_test(opts, shard_id).await {
+                    log::error!("Self-test failed: {}", e);
+                    std::process::exit(1);
+                }
+                log::info!("Self-test passed");
+            } else if let Err(e) = start_server(opts, shard_id).await {
                 log::error!("Error starting server: {}", e);
             }
         });
 }
 
+/// Start the server, and check that it's working by sending a fake node through it.
+async fn run_self_test(opts: Opts, shard_id: String) -> anyhow::Result<()> {
+    let socket_addr = opts.socket;
+    let core_url = opts.core_url.clone();
+    let timeout = Duration::from_secs(opts.self_test_timeout);
+    tokio::select! {
+        res = start_server(opts, shard_id) => {
+            res?;
+            Err(anyhow::anyhow!("Server stopped before the self-test finished"))
+        }
+        res = self_test::run(socket_addr, &core_url, timeout) => res,
+    }
+}
+
 /// Declare our routes and start the server.
-async fn start_server(opts: Opts) -> anyhow::Result<()> {
+async fn start_server(opts: Opts, shard_id: String) -> anyhow::Result<()> {
     let block_list = BlockedAddrs::new(Duration::from_secs(opts.node_block_seconds));
-    let aggregator = Aggregator::spawn(opts.core_url).await?;
+    let core_ca = match &opts.core_ca {
+        Some(path) => ws_client::CaCertificates::from_pem_file(path)?,
+        None => ws_client::CaCertificates::default(),
+    };
+    let aggregator = Aggregator::spawn(
+        core_url_with_params(opts.core_url)?,
+        shard_id,
+        Duration::from_secs(opts.core_init_ack_timeout),
+        opts.shard_secret,
+        core_ca,
+        match opts.global_quota_backoff_seconds {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        },
+    )
+    .await?;
     let socket_addr = opts.socket;
     let max_nodes_per_connection = opts.max_nodes_per_connection;
+    let node_eviction_policy = opts.node_eviction_policy;
     let bytes_per_second = opts.max_node_data_per_second;
     let stale_node_timeout = Duration::from_secs(opts.stale_node_timeout);
+    let min_node_version = opts.min_node_version;
+    let http_submit = opts.http_submit;
+    let http_submit_max_body_size = opts.http_submit_max_body_size.num_bytes();
+    let http_submit_clients = HttpSubmitClients::new();
+    let real_ip_headers: Arc<[HeaderName]> = opts.real_ip_headers.into();
+    let node_message_timeout = Duration::from_secs(opts.node_message_timeout);
+    let idle_socket_timeout = opts.idle_socket_timeout.map(Duration::from_secs);
+    let max_node_message_size = opts.max_node_message_size.num_bytes();
+    let closed_for_incomplete_messages = Arc::new(AtomicU64::new(0));
+    let connection_limits = Arc::new(opts.connection_limits.unwrap_or_default());
+    if [Endpoint::Feed, Endpoint::ShardSubmit]
+        .into_iter()
+        .any(|e| connection_limits.limit(e).is_some())
+    {
+        log::warn!("The 'feed' and 'shard_submit' connection limits are ignored by shards; give them to the core instead");
+    }
+    let shutdown_grace = Duration::from_secs(opts.shutdown_grace_seconds);
+    let shutdown = Shutdown::new();
+    let shutdown_handle = shutdown.handle();
+    let server_shutdown = shutdown.handle();
+    let server_stopped = async move { server_shutdown.wait().await };
 
-    let server = http_utils::start_server(socket_addr, move |addr, req| {
+    let server = http_utils::start_server(socket_addr, server_stopped, move |addr, req| {
         let aggregator = aggregator.clone();
         let block_list = block_list.clone();
+        let http_submit_clients = http_submit_clients.clone();
+        let real_ip_headers = Arc::clone(&real_ip_headers);
+        let closed_for_incomplete_messages = Arc::clone(&closed_for_incomplete_messages);
+        let connection_limits = Arc::clone(&connection_limits);
+        let shutdown = shutdown_handle.clone();
         async move {
             match (req.method(), req.uri().path().trim_end_matches('/')) {
                 // Check that the server is up and running:
                 (&Method::GET, "/health") => Ok(Response::new("OK".into())),
-                // Nodes send messages here:
-                (&Method::GET, "/submit") => {
-                    let (real_addr, real_addr_source) = real_ip::real_ip(addr, req.headers());
+                // Hand out details about how this shard was built:
+                (&Method::GET, "/version") => Ok(Response::builder()
+                    .header(http::header::CONTENT_TYPE, "application/json")
+                    .body(BUILD_INFO.to_json().into())
+                    .unwrap()),
+                // Return metrics in a prometheus-friendly text based format:
+                (&Method::GET, "/metrics") => Ok(return_prometheus_metrics(
+                    &closed_for_incomplete_messages,
+                    &connection_limits,
+                )),
+                // Nodes send messages here. On "/submit_bin", binary messages are expected
+                // to be bincode encoded rather than JSON:
+                (&Method::GET, path @ ("/submit" | "/submit_bin")) => {
+                    let path = path.to_owned();
+                    let bincode_binary_messages = path == "/submit_bin";
+                    let (real_addr, real_addr_source) =
+                        real_ip::real_ip(addr, req.headers(), &real_ip_headers);
 
                     if let Some(reason) = block_list.blocked_reason(&real_addr) {
-                        return Ok(Response::builder().status(403).body(reason.into()).unwrap());
+                        return Ok(Response::builder()
+                            .status(403)
+                            .body(reason.to_string().into())
+                            .unwrap());
                     }
 
-                    Ok(http_utils::upgrade_to_websocket(
+                    let Some(connection) = connection_limits.try_acquire(Endpoint::Submit) else {
+                        return Ok(at_capacity_response(Endpoint::Submit));
+                    };
+
+                    Ok(http_utils::upgrade_to_websocket_with_max_message_size(
                         req,
-                        move |ws_send, ws_recv| async move {
+                        Some(max_node_message_size),
+                        move |ws_send, ws_recv, ws_activity| async move {
+                            // Hold onto this until the connection closes:
+                            let _connection = connection;
+                            // Each connection is given an ID, which prefixes every log line
+                            // about it so that they can be told apart:
+                            let (conn_id, tx_to_aggregator) = aggregator.subscribe_node();
                             log::info!(
-                                "Opening /submit connection from {:?} (address source: {})",
+                                "[conn {conn_id}] Opening {path} connection from {:?} (address source: {})",
                                 real_addr,
                                 real_addr_source
                             );
-                            let tx_to_aggregator = aggregator.subscribe_node();
                             let (mut tx_to_aggregator, mut ws_send) =
                                 handle_node_websocket_connection(
+                                    conn_id,
                                     real_addr,
                                     ws_send,
                                     ws_recv,
+                                    ws_activity,
+                                    bincode_binary_messages,
                                     tx_to_aggregator,
                                     max_nodes_per_connection,
+                                    node_eviction_policy,
                                     bytes_per_second,
                                     block_list,
                                     stale_node_timeout,
+                                    node_message_timeout,
+                                    idle_socket_timeout,
+                                    min_node_version,
+                                    closed_for_incomplete_messages,
+                                    shutdown.clone(),
                                 )
                                 .await;
                             log::info!(
-                                "Closing /submit connection from {:?} (address source: {})",
+                                "[conn {conn_id}] Closing {path} connection from {:?} (address source: {})",
                                 real_addr,
                                 real_addr_source
                             );
@@ -191,6 +409,66 @@ async fn start_server(opts: Opts) -> anyhow::Result<()> {
                         },
                     ))
                 }
+                // Nodes can also send batches of messages here, if enabled:
+                (&Method::POST, "/submit") if http_submit => {
+                    let (real_addr, _) = real_ip::real_ip(addr, req.headers(), &real_ip_headers);
+
+                    if let Some(reason) = block_list.blocked_reason(&real_addr) {
+                        return Ok(Response::builder()
+                            .status(403)
+                            .body(reason.to_string().into())
+                            .unwrap());
+                    }
+
+                    let client_id = http_submit::client_id_from_query(req.uri().query());
+                    let body =
+                        match http_submit::read_body(req.into_body(), http_submit_max_body_size)
+                            .await
+                        {
+                            Ok(body) => body,
+                            Err(e) => {
+                                return Ok(Response::builder()
+                                    .status(413)
+                                    .body(e.to_string().into())
+                                    .unwrap())
+                            }
+                        };
+
+                    let messages = http_submit::split_messages(&body)
+                        .into_iter()
+                        .filter(|msg| {
+                            let too_large = msg.len() > max_node_message_size;
+                            if too_large {
+                                log::debug!("Ignoring {} byte message from {real_addr:?}: larger than the maximum of {max_node_message_size} bytes", msg.len());
+                            }
+                            !too_large
+                        })
+                        .collect();
+                    http_submit_clients
+                        .send((real_addr, client_id), messages, |rx| {
+                            let (conn_id, tx_to_aggregator) = aggregator.subscribe_node();
+                            log::info!(
+                                "[conn {conn_id}] New HTTP /submit client from {:?}",
+                                real_addr
+                            );
+                            tokio::spawn(handle_node_http_client(
+                                conn_id,
+                                real_addr,
+                                rx,
+                                tx_to_aggregator,
+                                max_nodes_per_connection,
+                                node_eviction_policy,
+                                bytes_per_second,
+                                block_list,
+                                stale_node_timeout,
+                                min_node_version,
+                                shutdown.clone(),
+                            ));
+                        })
+                        .await;
+
+                    Ok(Response::new("OK".into()))
+                }
                 // 404 for anything else:
                 _ => Ok(Response::builder()
                     .status(404)
@@ -200,36 +478,115 @@ async fn start_server(opts: Opts) -> anyhow::Result<()> {
         }
     });
 
-    server.await?;
+    // Serve until we're asked to stop, and then give open connections a chance to close:
+    let mut server = tokio::spawn(server);
+    tokio::select! {
+        res = &mut server => return res?,
+        res = shutdown::signal() => res?,
+    }
+    log::info!("Shutting down; waiting up to {shutdown_grace:?} for connections to close");
+    if !shutdown.shutdown(shutdown_grace).await {
+        log::warn!("Some connections were still open after {shutdown_grace:?}; exiting anyway");
+    }
     Ok(())
 }
 
-/// This takes care of handling messages from an established socket connection.
+/// Return our metrics in the text based format that prometheus expects. See the
+/// core's equivalent for more on this format.
+fn return_prometheus_metrics(
+    closed_for_incomplete_messages: &AtomicU64,
+    connection_limits: &ConnectionLimits,
+) -> Response<hyper::Body> {
+    let mut s = format!(
+        "telemetry_shard_connections_closed_for_incomplete_messages {}\n",
+        closed_for_incomplete_messages.load(Ordering::Relaxed)
+    );
+    s.push_str(&connection_limits.prometheus_metrics("telemetry_shard", &[Endpoint::Submit]));
+    s.push_str(&BUILD_INFO.prometheus_metric("telemetry_shard_build_info"));
+
+    Response::builder()
+        // The version number here tells prometheus which version of the text format we're using:
+        .header(http::header::CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(s.into())
+        .unwrap()
+}
+
+/// The version we report to the core. This includes the git hash if it was
+/// known at build time.
+fn shard_version() -> String {
+    match BUILD_INFO.git_hash {
+        Some(hash) => format!("{VERSION}-{hash}"),
+        None => VERSION.to_owned(),
+    }
+}
+
+/// Append some query parameters to the core URL; our version, so that the core knows which
+/// version of the shard is connecting, a request for the core to acknowledge when it's
+/// ready to receive node data from us, and the protocol version that we speak. Cores that
+/// don't know about these will just ignore them.
+fn core_url_with_params(core_url: Uri) -> anyhow::Result<Uri> {
+    let separator = match core_url.query() {
+        Some(_) => '&',
+        None => '?',
+    };
+    let uri = format!(
+        "{core_url}{separator}version={}&init_ack=true&protocol={}",
+        shard_version(),
+        internal_messages::PROTOCOL_VERSION
+    )
+    .parse()?;
+    Ok(uri)
+}
+
+/// How the bytes of some message sent from a node are encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MessageFormat {
+    /// The JSON format that nodes send by default.
+    Json,
+    /// Bincode, using the same options that we use to talk to the core, encoding
+    /// the internal [`node_message::NodeMessage`] representation.
+    Bincode,
+}
+
+/// Decode the bytes of a message sent from a node.
+fn decode_node_message(
+    format: MessageFormat,
+    bytes: &[u8],
+) -> anyhow::Result<node_message::NodeMessage> {
+    match format {
+        MessageFormat::Json => {
+            let node_message = json_message::NodeMessage::from_json(bytes)?;
+            Ok(node_message.into())
+        }
+        MessageFormat::Bincode => Ok(bincode::options().deserialize(bytes)?),
+    }
+}
+
+/// This takes care of handling messages from an established socket connection. If
+/// `bincode_binary_messages` is true, binary websocket messages are decoded using bincode,
+/// and text ones as JSON. Otherwise, all messages are decoded as JSON.
 async fn handle_node_websocket_connection<S>(
+    conn_id: u64,
     real_addr: IpAddr,
     ws_send: http_utils::WsSender,
     mut ws_recv: http_utils::WsReceiver,
+    ws_activity: http_utils::WsActivity,
+    bincode_binary_messages: bool,
     mut tx_to_aggregator: S,
     max_nodes_per_connection: usize,
+    node_eviction_policy: EvictionPolicy,
     bytes_per_second: ByteSize,
     block_list: BlockedAddrs,
     stale_node_timeout: Duration,
+    node_message_timeout: Duration,
+    idle_socket_timeout: Option<Duration>,
+    min_node_version: Option<NodeVersion>,
+    closed_for_incomplete_messages: Arc<AtomicU64>,
+    shutdown: ShutdownHandle,
 ) -> (S, http_utils::WsSender)
 where
     S: futures::Sink<FromWebsocket, Error = anyhow::Error> + Unpin + Send + 'static,
 {
-    // Keep track of the message Ids that have been "granted access". We allow a maximum of
-    // `max_nodes_per_connection` before ignoring others.
-    let mut allowed_message_ids = HashMap::<NodeMessageId, Instant>::new();
-
-    // Limit the number of bytes based on a rolling total and the incoming bytes per second
-    // that has been configured via the CLI opts.
-    let bytes_per_second = bytes_per_second.num_bytes();
-    let mut rolling_total_bytes = RollingTotalBuilder::new()
-        .granularity(Duration::from_secs(1))
-        .window_size_multiple(10)
-        .start();
-
     // This could be a oneshot channel, but it's useful to be able to clone
     // messages, and we can't clone oneshot channel senders.
     let (close_connection_tx, close_connection_rx) = flume::bounded(1);
@@ -239,7 +596,7 @@ where
         close_connection: close_connection_tx.clone(),
     };
     if let Err(e) = tx_to_aggregator.send(init_msg).await {
-        log::error!("Shutting down websocket connection from {real_addr:?}: Error sending message to aggregator: {e}");
+        log::error!("[conn {conn_id}] Shutting down websocket connection from {real_addr:?}: Error sending message to aggregator: {e}");
         return (tx_to_aggregator, ws_send);
     }
 
@@ -254,19 +611,42 @@ where
                 // The close channel has fired, so end the loop. `ws_recv.receive_data` is
                 // *not* cancel safe, but since we're closing the connection we don't care.
                 _ = close_connection_rx.recv_async() => {
-                    log::info!("connection to {real_addr:?} being closed");
+                    log::info!("[conn {conn_id}] connection to {real_addr:?} being closed");
+                    break
+                },
+                // No bytes at all have arrived for a while, so the connection is probably dead.
+                // As above, we don't care that we're cancelling `ws_recv.receive_data`.
+                _ = idle_for(&ws_activity, idle_socket_timeout) => {
+                    log::info!("[conn {conn_id}] Shutting down websocket connection from {real_addr:?}: No bytes received within {idle_socket_timeout:?}");
                     break
                 },
                 // Receive data and relay it on to our main select loop below.
-                msg_info = ws_recv.receive_data(&mut bytes) => {
+                msg_info = tokio::time::timeout(node_message_timeout, ws_recv.receive_data(&mut bytes)) => {
+                    let msg_info = match msg_info {
+                        Ok(msg_info) => msg_info,
+                        Err(_) => {
+                            log::info!("[conn {conn_id}] Shutting down websocket connection from {real_addr:?}: No complete message received within {node_message_timeout:?}");
+                            closed_for_incomplete_messages.fetch_add(1, Ordering::Relaxed);
+                            break;
+                        }
+                    };
                     if let Err(soketto::connection::Error::Closed) = msg_info {
                         break;
                     }
-                    if let Err(e) = msg_info {
-                        log::error!("Shutting down websocket connection from {real_addr:?}: Failed to receive data: {e}");
+                    if let Err(e @ soketto::connection::Error::MessageTooLarge { .. }) = &msg_info {
+                        log::info!("[conn {conn_id}] Shutting down websocket connection from {real_addr:?}: {e}");
+                        closed_for_incomplete_messages.fetch_add(1, Ordering::Relaxed);
                         break;
                     }
-                    if ws_tx_atomic.unbounded_send(bytes).is_err() {
+                    let format = match msg_info {
+                        Ok(soketto::Data::Binary(_)) if bincode_binary_messages => MessageFormat::Bincode,
+                        Ok(_) => MessageFormat::Json,
+                        Err(e) => {
+                            log::error!("[conn {conn_id}] Shutting down websocket connection from {real_addr:?}: Failed to receive data: {e}");
+                            break;
+                        }
+                    };
+                    if ws_tx_atomic.unbounded_send((format, bytes)).is_err() {
                         // The other end closed; end this loop.
                         break;
                     }
@@ -275,6 +655,142 @@ where
         }
     });
 
+    handle_node_messages(
+        conn_id,
+        real_addr,
+        &mut ws_rx_atomic,
+        &mut tx_to_aggregator,
+        max_nodes_per_connection,
+        node_eviction_policy,
+        bytes_per_second,
+        &block_list,
+        stale_node_timeout,
+        min_node_version,
+        &shutdown,
+    )
+    .await;
+
+    // Make sure to kill off the receive-messages task if the main select loop ends:
+    let _ = close_connection_tx.send(());
+
+    // Return what we need to close the connection gracefully:
+    (tx_to_aggregator, ws_send)
+}
+
+/// Resolves once no bytes have been read from a connection for the duration given, or never
+/// if no duration is given.
+async fn idle_for(ws_activity: &http_utils::WsActivity, duration: Option<Duration>) {
+    match duration {
+        Some(duration) => ws_activity.idle_for(duration).await,
+        None => futures::future::pending().await,
+    }
+}
+
+/// Handle node messages sent via HTTP requests from a single client, as though they were sent over a
+/// single connection. This ends if we don't hear from the client for `stale_node_timeout`.
+async fn handle_node_http_client<S>(
+    conn_id: u64,
+    real_addr: IpAddr,
+    rx_from_client: flume::Receiver<Vec<u8>>,
+    mut tx_to_aggregator: S,
+    max_nodes_per_connection: usize,
+    node_eviction_policy: EvictionPolicy,
+    bytes_per_second: ByteSize,
+    block_list: BlockedAddrs,
+    stale_node_timeout: Duration,
+    min_node_version: Option<NodeVersion>,
+    shutdown: ShutdownHandle,
+) where
+    S: futures::Sink<FromWebsocket, Error = anyhow::Error> + Unpin + Send + 'static,
+{
+    let (close_connection_tx, close_connection_rx) = flume::bounded(1);
+
+    // Tell the aggregator about this new client, and give it a way to forget about it:
+    let init_msg = FromWebsocket::Initialize {
+        close_connection: close_connection_tx.clone(),
+    };
+    if let Err(e) = tx_to_aggregator.send(init_msg).await {
+        log::error!("[conn {conn_id}] Ignoring HTTP /submit client from {real_addr:?}: Error sending message to aggregator: {e}");
+        return;
+    }
+
+    // Forward messages from the client until we're asked to stop, or the client goes quiet.
+    // Messages wait in the client's channel until we're ready for them, rather than here:
+    let (mut msgs_tx, mut msgs_rx) = futures::channel::mpsc::channel(0);
+    tokio::task::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = close_connection_rx.recv_async() => break,
+                msg = tokio::time::timeout(stale_node_timeout, rx_from_client.recv_async()) => {
+                    let bytes = match msg {
+                        Ok(Ok(bytes)) => bytes,
+                        // Timed out or no more messages; forget about this client.
+                        _ => break,
+                    };
+                    if msgs_tx.send((MessageFormat::Json, bytes)).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+
+    handle_node_messages(
+        conn_id,
+        real_addr,
+        &mut msgs_rx,
+        &mut tx_to_aggregator,
+        max_nodes_per_connection,
+        node_eviction_policy,
+        bytes_per_second,
+        &block_list,
+        stale_node_timeout,
+        min_node_version,
+        &shutdown,
+    )
+    .await;
+
+    log::info!(
+        "[conn {conn_id}] Forgetting about HTTP /submit client from {:?}",
+        real_addr
+    );
+    let _ = close_connection_tx.send(());
+    let _ = tx_to_aggregator.send(FromWebsocket::Disconnected).await;
+}
+
+/// Handle the messages sent from some node connection until the stream of messages ends, we
+/// stop hearing about any nodes, the connection sends too much data, or we're shutting down.
+async fn handle_node_messages<S>(
+    conn_id: u64,
+    real_addr: IpAddr,
+    msgs: &mut (impl Stream<Item = (MessageFormat, Vec<u8>)> + Unpin),
+    tx_to_aggregator: &mut S,
+    max_nodes_per_connection: usize,
+    node_eviction_policy: EvictionPolicy,
+    bytes_per_second: ByteSize,
+    block_list: &BlockedAddrs,
+    stale_node_timeout: Duration,
+    min_node_version: Option<NodeVersion>,
+    shutdown: &ShutdownHandle,
+) where
+    S: futures::Sink<FromWebsocket, Error = anyhow::Error> + Unpin + Send + 'static,
+{
+    // Keep track of the message Ids that have been "granted access". We allow a maximum of
+    // `max_nodes_per_connection` before ignoring others (or evicting old ones to make room).
+    let mut allowed_message_ids =
+        AllowedMessageIds::new(max_nodes_per_connection, node_eviction_policy);
+
+    // Keep track of the message IDs of nodes that are too old, so that we ignore their messages.
+    let mut too_old_message_ids = HashSet::new();
+
+    // Limit the number of bytes based on a rolling total and the incoming bytes per second
+    // that has been configured via the CLI opts.
+    let bytes_per_second = bytes_per_second.num_bytes();
+    let mut rolling_total_bytes = RollingTotalBuilder::new()
+        .granularity(Duration::from_secs(1))
+        .window_size_multiple(10)
+        .start();
+
     // A periodic interval to check for stale nodes.
     let mut stale_interval = tokio::time::interval(stale_node_timeout / 2);
 
@@ -282,32 +798,33 @@ where
     // and periodically checks for stale connections to keep our node state tidy.
     loop {
         tokio::select! {
+            // Stop handling messages if we're shutting down; the connection will be closed.
+            _ = shutdown.wait() => {
+                log::info!("[conn {conn_id}] Closing connection from {real_addr:?}; shutting down");
+                break;
+            },
             // We periodically check for stale message IDs and remove nodes associated with
             // them, to prevent a buildup. We boot the whole connection if no interpretable
             // messages have been sent at all in the time period.
             _ = stale_interval.tick() => {
-                let stale_ids: Vec<NodeMessageId> = allowed_message_ids.iter()
-                    .filter(|(_, last_seen)| last_seen.elapsed() > stale_node_timeout)
-                    .map(|(&id, _)| id)
-                    .collect();
+                let stale_ids = allowed_message_ids.remove_stale(stale_node_timeout);
 
                 for &message_id in &stale_ids {
-                    log::info!("Removing stale node with message ID {message_id} from {real_addr:?}");
-                    allowed_message_ids.remove(&message_id);
+                    log::info!("[conn {conn_id}] Removing stale node with message ID {message_id} from {real_addr:?}");
                     let _ = tx_to_aggregator.send(FromWebsocket::Remove { message_id } ).await;
                 }
 
                 if !stale_ids.is_empty() && allowed_message_ids.is_empty() {
                     // End the entire connection if no recent messages came in for any ID.
-                    log::info!("Closing stale connection from {real_addr:?}");
+                    log::info!("[conn {conn_id}] Closing stale connection from {real_addr:?}");
                     break;
                 }
             },
             // Handle messages received by the connected node.
-            msg = ws_rx_atomic.next() => {
+            msg = msgs.next() => {
                 // No more messages? break.
-                let bytes = match msg {
-                    Some(bytes) => bytes,
+                let (format, bytes) = match msg {
+                    Some(msg) => msg,
                     None => { break; }
                 };
 
@@ -315,19 +832,19 @@ where
                 rolling_total_bytes.push(bytes.len());
                 let this_bytes_per_second = rolling_total_bytes.total() / 10;
                 if this_bytes_per_second > bytes_per_second {
-                    block_list.block_addr(real_addr, "Too much traffic");
-                    log::error!("Shutting down websocket connection: Too much traffic ({this_bytes_per_second}bps averaged over last 10s)");
+                    block_list.block_addr(real_addr, BlockReason::TooMuchTraffic { bps: this_bytes_per_second });
+                    log::error!("[conn {conn_id}] Shutting down websocket connection: Too much traffic ({this_bytes_per_second}bps averaged over last 10s)");
                     break;
                 }
 
-                // Deserialize from JSON, warning in debug mode if deserialization fails:
-                let node_message: json_message::NodeMessage = match serde_json::from_slice(&bytes) {
+                // Deserialize the message, warning in debug mode if deserialization fails:
+                let node_message = match decode_node_message(format, &bytes) {
                     Ok(node_message) => node_message,
                     #[cfg(debug)]
                     Err(e) => {
                         let bytes: &[u8] = bytes.get(..512).unwrap_or_else(|| &bytes);
                         let msg_start = std::str::from_utf8(bytes).unwrap_or_else(|_| "INVALID UTF8");
-                        log::warn!("Failed to parse node message ({msg_start}): {e}");
+                        log::warn!("[conn {conn_id}] Failed to parse {format:?} node message ({msg_start}): {e}");
                         continue;
                     },
                     #[cfg(not(debug))]
@@ -337,7 +854,6 @@ where
                 };
 
                 // Pull relevant details from the message:
-                let node_message: node_message::NodeMessage = node_message.into();
                 let message_id = node_message.id();
                 let payload = node_message.into_payload();
 
@@ -345,21 +861,45 @@ where
                 // we see one of these SystemConnected ones, it will ignore messages with
                 // the corresponding message_id.
                 if let node_message::Payload::SystemConnected(info) = payload {
-                    // Too many nodes seen on this connection? Ignore this one.
-                    if allowed_message_ids.len() >= max_nodes_per_connection {
-                        log::info!("Ignoring new node with ID {message_id} from {real_addr:?} (we've hit the max of {max_nodes_per_connection} nodes per connection)");
-                        continue;
+                    // Ignore nodes that are too old (or whose version we can't make sense of):
+                    if let Some(min_version) = min_node_version {
+                        if !node_version::is_allowed(&info.node.version, min_version) {
+                            if !too_old_message_ids.contains(&message_id) {
+                                // Don't hold onto more of these than we would nodes. Messages from
+                                // any that we forget are ignored all the same, since they're never
+                                // allowed:
+                                if too_old_message_ids.len() >= max_nodes_per_connection {
+                                    too_old_message_ids.clear();
+                                }
+                                too_old_message_ids.insert(message_id);
+                                log::info!("[conn {conn_id}] Ignoring node with ID {message_id} from {real_addr:?} (version '{}' is older than {min_version})", info.node.version);
+                            }
+                            continue;
+                        }
                     }
+                    too_old_message_ids.remove(&message_id);
 
                     // Note of the message ID, allowing telemetry for it.
-                    let prev_join_time = allowed_message_ids.insert(message_id, Instant::now());
-                    if prev_join_time.is_some() {
-                        log::info!("Ignoring duplicate new node with ID {message_id} from {real_addr:?}");
-                        continue;
+                    match allowed_message_ids.insert(message_id, Instant::now()) {
+                        InsertResult::Added => {},
+                        InsertResult::AddedAndEvicted(evicted_id) => {
+                            // Too many nodes seen on this connection? Forget the least recently seen one.
+                            log::info!("[conn {conn_id}] Evicting node with ID {evicted_id} from {real_addr:?} to make room for node with ID {message_id} (we've hit the max of {max_nodes_per_connection} nodes per connection)");
+                            let _ = tx_to_aggregator.send(FromWebsocket::Remove { message_id: evicted_id }).await;
+                        },
+                        InsertResult::AlreadyAllowed => {
+                            log::info!("[conn {conn_id}] Ignoring duplicate new node with ID {message_id} from {real_addr:?}");
+                            continue;
+                        },
+                        InsertResult::Rejected => {
+                            // Too many nodes seen on this connection? Ignore this one.
+                            log::info!("[conn {conn_id}] Ignoring new node with ID {message_id} from {real_addr:?} (we've hit the max of {max_nodes_per_connection} nodes per connection)");
+                            continue;
+                        }
                     }
 
                     // Tell the aggregator loop about the new node.
-                    log::info!("Adding node with message ID {message_id} from {real_addr:?}");
+                    log::info!("[conn {conn_id}] Adding node with message ID {message_id} from {real_addr:?}");
                     let _ = tx_to_aggregator.send(FromWebsocket::Add {
                         message_id,
                         ip: real_addr,
@@ -370,24 +910,20 @@ where
                 // Anything that's not an "Add" is an Update. The aggregator will ignore
                 // updates against a message_id that hasn't first been Added, above.
                 else {
-                    if let Some(last_seen) = allowed_message_ids.get_mut(&message_id) {
-                        *last_seen = Instant::now();
+                    if too_old_message_ids.contains(&message_id) {
+                        continue;
+                    }
+                    if allowed_message_ids.touch(message_id, Instant::now()) {
                         if let Err(e) = tx_to_aggregator.send(FromWebsocket::Update { message_id, payload } ).await {
-                            log::error!("Failed to send node message to aggregator: {e}");
+                            log::error!("[conn {conn_id}] Failed to send node message to aggregator: {e}");
                             continue;
                         }
                     } else {
-                        log::info!("Ignoring message with ID {message_id} from {real_addr:?} (we've hit the max of {max_nodes_per_connection} nodes per connection)");
+                        log::info!("[conn {conn_id}] Ignoring message with ID {message_id} from {real_addr:?} (we've hit the max of {max_nodes_per_connection} nodes per connection)");
                         continue;
                     }
                 }
             }
         }
     }
-
-    // Make sure to kill off the receive-messages task if the main select loop ends:
-    let _ = close_connection_tx.send(());
-
-    // Return what we need to close the connection gracefully:
-    (tx_to_aggregator, ws_send)
 }
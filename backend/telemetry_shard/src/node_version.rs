@@ -0,0 +1,105 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Nodes report their version when they connect, and the shard can be told to ignore nodes
+//! older than some minimum version. This module parses and compares those versions.
+
+use anyhow::{anyhow, Error};
+
+/// The `major.minor.patch` version that a node reports when it connects. Anything after this
+/// (for instance the `-commithash-arch-os` suffix in `2.0.0-07a1af348-aarch64-macos`) is ignored.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct NodeVersion {
+    major: u64,
+    minor: u64,
+    patch: u64,
+}
+
+impl std::str::FromStr for NodeVersion {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let version = s.split(|c| c == '-' || c == '+').next().unwrap_or(s);
+        let mut parts = version.split('.').map(|n| n.parse::<u64>());
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(Ok(major)), Some(Ok(minor)), Some(Ok(patch)), None) => Ok(NodeVersion {
+                major,
+                minor,
+                patch,
+            }),
+            _ => Err(anyhow!(
+                "Cannot parse '{}' as a version; expecting something like '1.2.3'",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for NodeVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Is a node reporting the version given new enough to be allowed? Nodes whose version
+/// can't be parsed aren't allowed.
+pub fn is_allowed(version: &str, min_version: NodeVersion) -> bool {
+    match version.parse::<NodeVersion>() {
+        Ok(version) => version >= min_version,
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn v(s: &str) -> NodeVersion {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_versions_with_suffixes() {
+        assert_eq!(v("2.0.0-07a1af348-aarch64-macos"), v("2.0.0"));
+        assert_eq!(v("0.9.42+abc"), v("0.9.42"));
+        assert_eq!(
+            v("1.10.3-dev"),
+            NodeVersion {
+                major: 1,
+                minor: 10,
+                patch: 3
+            }
+        );
+        assert!("2.0".parse::<NodeVersion>().is_err());
+        assert!("2.0.0.1".parse::<NodeVersion>().is_err());
+        assert!("version-2".parse::<NodeVersion>().is_err());
+    }
+
+    #[test]
+    fn versions_are_compared_against_minimum() {
+        let min = v("1.2.0");
+        // Just below:
+        assert!(!is_allowed("1.1.9-07a1af348-x86_64-linux-gnu", min));
+        // Exactly at:
+        assert!(is_allowed("1.2.0-07a1af348-x86_64-linux-gnu", min));
+        // Above:
+        assert!(is_allowed("1.2.1-07a1af348-x86_64-linux-gnu", min));
+        assert!(is_allowed("1.10.0", min));
+        // Unparseable:
+        assert!(!is_allowed("unknown", min));
+    }
+}
@@ -0,0 +1,119 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! A self-test, to check that a freshly started shard is working properly. We connect a fake
+//! node to our own '/submit' endpoint, and then make sure that it shows up on the '/feed/v2'
+//! endpoint of the core that we're connected to.
+
+use common::node_types::BlockHash;
+use common::ws_client;
+use http::Uri;
+use std::net::{Ipv4Addr, SocketAddr};
+use tokio::time::{Duration, Instant};
+
+/// The name of the fake node that we look for on the feed.
+const NODE_NAME: &str = "Telemetry Shard Self-Test";
+
+/// Run the self-test against a shard listening on the address given and connected to the core
+/// at the URL given, failing if the fake node doesn't show up on the feed before the timeout.
+pub async fn run(socket_addr: SocketAddr, core_url: &Uri, timeout: Duration) -> anyhow::Result<()> {
+    let deadline = Instant::now() + timeout;
+    let addr = local_addr(socket_addr);
+    let genesis_hash = BlockHash::repeat_byte(0x5e);
+
+    // Keep hold of the node connection until we're done, so that the node isn't removed:
+    let submit_uri: Uri = format!("ws://{addr}/submit").parse()?;
+    let (node_tx, _node_rx) = common::self_test::connect(&submit_uri, deadline).await?;
+    let connected = serde_json::to_vec(&connect_message(genesis_hash))?;
+    node_tx.unbounded_send(ws_client::SentMessage::Binary(connected))?;
+
+    let feed_uri = core_feed_url(core_url)?;
+    common::self_test::wait_for_node_on_feed(&feed_uri, genesis_hash, NODE_NAME, deadline).await
+}
+
+/// The `system.connected` message that the fake node sends to add itself.
+fn connect_message(genesis_hash: BlockHash) -> serde_json::Value {
+    serde_json::json!({
+        "id": 1,
+        "payload": {
+            "authority": false,
+            "chain": "Self-Test",
+            "config": "",
+            "genesis_hash": genesis_hash,
+            "implementation": "telemetry_shard",
+            "msg": "system.connected",
+            "name": NODE_NAME,
+            "network_id": "",
+            "startup_time": null,
+            "version": env!("CARGO_PKG_VERSION")
+        }
+    })
+}
+
+/// If we're listening on every interface, connect to ourselves via localhost.
+fn local_addr(socket_addr: SocketAddr) -> SocketAddr {
+    match socket_addr.ip() {
+        ip if ip.is_unspecified() => {
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), socket_addr.port())
+        }
+        _ => socket_addr,
+    }
+}
+
+/// The '/feed/v2' endpoint of the core that we're connected to.
+fn core_feed_url(core_url: &Uri) -> anyhow::Result<Uri> {
+    let mut parts = core_url.clone().into_parts();
+    parts.path_and_query = Some("/feed/v2".parse()?);
+    Ok(Uri::from_parts(parts)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::json_message::{NodeMessage, Payload};
+
+    #[test]
+    fn fake_node_connects_with_a_valid_message() {
+        let bytes = serde_json::to_vec(&connect_message(BlockHash::repeat_byte(0x5e))).unwrap();
+        let msg: NodeMessage = serde_json::from_slice(&bytes).unwrap();
+        assert!(matches!(
+            msg,
+            NodeMessage::V2 {
+                payload: Payload::SystemConnected(_),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn feed_url_is_on_the_same_host_as_the_core() {
+        let core_url: Uri = "ws://127.0.0.1:8000/shard_submit/".parse().unwrap();
+        assert_eq!(
+            core_feed_url(&core_url).unwrap(),
+            "ws://127.0.0.1:8000/feed/v2".parse::<Uri>().unwrap()
+        );
+
+        let core_url: Uri = "wss://telemetry.example.com/shard_submit?foo=bar"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            core_feed_url(&core_url).unwrap(),
+            "wss://telemetry.example.com/feed/v2"
+                .parse::<Uri>()
+                .unwrap()
+        );
+    }
+}
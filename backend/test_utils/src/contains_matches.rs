@@ -39,6 +39,15 @@ let does_contain: bool = test_utils::contains_matches!(
 );
 
 assert!(does_contain);
+
+// The same items in a different order don't match:
+let does_contain: bool = test_utils::contains_matches!(
+    vec![Foo { a: 2 }, Wibble, Bar(true), Foo { a: 100 }],
+    Bar(true),
+    Foo { a: 2 }
+);
+
+assert!(!does_contain);
 ```
 */
 #[macro_export]
@@ -79,7 +88,8 @@ match items given, in the order that they are given in (but not necessarily
 contiguous, ie other items may be interspersed between the ones we're looking
 to match).
 
-Panics if this is not the case.
+Panics if this is not the case. This makes it useful for checking that messages arrive
+in a specific order, as well as for checking that they arrive at all.
 ```
 enum Item {
     Foo { a: usize },
@@ -96,6 +106,23 @@ test_utils::assert_contains_matches!(
     Foo {..}
 );
 ```
+
+Items that are all present, but not in the order given, cause a panic:
+```should_panic
+enum Item {
+    Foo { a: usize },
+    Bar(bool),
+    Wibble
+}
+
+use Item::*;
+
+test_utils::assert_contains_matches!(
+    vec![Foo { a: 2 }, Wibble, Bar(true), Foo { a: 100 }],
+    Bar(true),
+    Foo { a: 2 }
+);
+```
 */
 #[macro_export]
 macro_rules! assert_contains_matches {
@@ -105,6 +132,10 @@ macro_rules! assert_contains_matches {
             $( $pattern $( if $guard )? ),+
         );
 
-        assert!(does_contain_matches);
+        assert!(
+            does_contain_matches,
+            "expected items matching these patterns, in this order: {}",
+            stringify!($( $pattern $( if $guard )? ),+)
+        );
     }
 }
@@ -28,9 +28,6 @@ impl FakeTelemetry {
         E: Into<anyhow::Error>,
     {
         let id = self.message_id;
-        let name = self.node_name;
-        let chain = self.chain;
-        let genesis_hash = self.genesis_hash;
         let block_time = self.block_time;
 
         // Our "state". These numbers can be hashed to give a block hash,
@@ -49,35 +46,11 @@ impl FakeTelemetry {
         }
 
         // Send system connected immediately
-        send_msg!({
-            "id":id,
-            "payload": {
-                "authority":true,
-                "chain":chain,
-                "config":"",
-                "genesis_hash":genesis_hash,
-                "implementation":"Substrate Node",
-                "msg":"system.connected",
-                "name":name,
-                "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
-                "startup_time":"1627986634759",
-                "version":"2.0.0-07a1af348-aarch64-macos"
-            },
-            "ts":now_iso()
-        })?;
+        send_msg!(self.connect_message(&now_iso()))?;
         best_block_n += 1;
 
         // First block import immediately (height 1)
-        send_msg!({
-            "id":id,
-            "payload":{
-                "best":block_hash(best_block_n),
-                "height":best_block_n,
-                "msg":"block.import",
-                "origin":"Own"
-            },
-            "ts":now_iso()
-        })?;
+        send_msg!(self.block_import_message(best_block_n, &now_iso()))?;
         best_block_n += 1;
 
         let now = tokio::time::Instant::now();
@@ -109,16 +82,7 @@ impl FakeTelemetry {
                         },
                         "ts":now_iso()
                     })?;
-                    send_msg!({
-                        "id":id,
-                        "payload":{
-                            "best":block_hash(best_block_n),
-                            "height":best_block_n,
-                            "msg":"block.import",
-                            "origin":"Own"
-                        },
-                        "ts":now_iso()
-                    })?;
+                    send_msg!(self.block_import_message(best_block_n, &now_iso()))?;
                     best_block_n += 1;
 
                 },
@@ -177,6 +141,43 @@ impl FakeTelemetry {
             };
         }
     }
+
+    /// The `system.connected` message that this node sends when it starts, timestamped
+    /// with the RFC3339 time given. This (and [`FakeTelemetry::block_import_message`]) can
+    /// be used to deterministically generate realistic messages without starting the node.
+    pub fn connect_message(&self, ts: &str) -> serde_json::Value {
+        json!({
+            "id":self.message_id,
+            "payload": {
+                "authority":true,
+                "chain":self.chain,
+                "config":"",
+                "genesis_hash":self.genesis_hash,
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name":self.node_name,
+                "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                "startup_time":"1627986634759",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            },
+            "ts":ts
+        })
+    }
+
+    /// The `block.import` message that this node sends when it imports the block
+    /// at the height given, timestamped with the RFC3339 time given.
+    pub fn block_import_message(&self, height: u64, ts: &str) -> serde_json::Value {
+        json!({
+            "id":self.message_id,
+            "payload":{
+                "best":block_hash(height),
+                "height":height,
+                "msg":"block.import",
+                "origin":"Own"
+            },
+            "ts":ts
+        })
+    }
 }
 
 fn now_iso() -> String {
@@ -43,6 +43,7 @@ pub enum FeedMessage {
         location: Option<NodeLocation>,
         startup_time: Option<Timestamp>,
         hwbench: Option<NodeHwBench>,
+        finality_lag: Option<BlockNumber>,
     },
     RemovedNode {
         node_id: usize,
@@ -78,6 +79,10 @@ pub enum FeedMessage {
         genesis_hash: BlockHash,
         node_count: usize,
     },
+    /// The name, genesis hash and node count of each chain.
+    AddedChains {
+        chains: Vec<(String, BlockHash, usize)>,
+    },
     RemovedChain {
         genesis_hash: BlockHash,
     },
@@ -118,6 +123,42 @@ pub enum FeedMessage {
     StaleNode {
         node_id: usize,
     },
+    RecoveredNode {
+        node_id: usize,
+    },
+    NodeFinalityLag {
+        node_id: usize,
+        lag: BlockNumber,
+    },
+    LocationFailed {
+        node_id: usize,
+    },
+    NodeUptime {
+        node_id: usize,
+        uptime_secs: u64,
+    },
+    BlockTimePercentiles {
+        median: u64,
+        p95: u64,
+    },
+    NodeSyncState {
+        node_id: usize,
+        major_syncing: bool,
+    },
+    Seq {
+        seq: u64,
+    },
+    ResumeFailed {
+        genesis_hash: BlockHash,
+    },
+    PeerCountHistogram {
+        genesis_hash: BlockHash,
+        buckets: Vec<((u64, Option<u64>), u64)>,
+    },
+    NodeProcessUptime {
+        node_id: usize,
+        uptime_secs: u64,
+    },
     NodeIOUpdate {
         node_id: usize,
         // details: NodeIO, // can't losslessly deserialize
@@ -141,6 +182,7 @@ pub struct NodeDetails {
     pub target_env: String,
     pub ip: Option<String>,
     pub sysinfo: Option<NodeSysInfo>,
+    pub shard: Option<String>,
 }
 
 impl FeedMessage {
@@ -205,6 +247,7 @@ impl FeedMessage {
                         ip,
                         sysinfo,
                         hwbench,
+                        shard,
                     ),
                     stats,
                     io,
@@ -212,6 +255,7 @@ impl FeedMessage {
                     block_details,
                     location,
                     startup_time,
+                    finality_lag,
                 ) = serde_json::from_str(raw_val.get())?;
 
                 // Give these two types but don't use the results:
@@ -230,12 +274,14 @@ impl FeedMessage {
                         target_env,
                         ip,
                         sysinfo,
+                        shard,
                     },
                     stats,
                     block_details,
                     location,
                     startup_time,
                     hwbench,
+                    finality_lag,
                 }
             }
             // RemoveNode
@@ -367,6 +413,73 @@ impl FeedMessage {
                 let (node_id, _node_io): (_, &RawValue) = serde_json::from_str(raw_val.get())?;
                 FeedMessage::NodeIOUpdate { node_id }
             }
+            // RecoveredNode
+            26 => {
+                let node_id = serde_json::from_str(raw_val.get())?;
+                FeedMessage::RecoveredNode { node_id }
+            }
+            // NodeFinalityLag
+            27 => {
+                let (node_id, lag) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::NodeFinalityLag { node_id, lag }
+            }
+            // AddedChains
+            28 => {
+                let chains = serde_json::from_str(raw_val.get())?;
+                FeedMessage::AddedChains { chains }
+            }
+            // LocationFailed
+            29 => {
+                let node_id = serde_json::from_str(raw_val.get())?;
+                FeedMessage::LocationFailed { node_id }
+            }
+            // NodeUptime
+            30 => {
+                let (node_id, uptime_secs) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::NodeUptime {
+                    node_id,
+                    uptime_secs,
+                }
+            }
+            // BlockTimePercentiles
+            31 => {
+                let (median, p95) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::BlockTimePercentiles { median, p95 }
+            }
+            // NodeSyncState
+            32 => {
+                let (node_id, major_syncing) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::NodeSyncState {
+                    node_id,
+                    major_syncing,
+                }
+            }
+            // Seq
+            33 => {
+                let seq = serde_json::from_str(raw_val.get())?;
+                FeedMessage::Seq { seq }
+            }
+            // ResumeFailed
+            34 => {
+                let genesis_hash = serde_json::from_str(raw_val.get())?;
+                FeedMessage::ResumeFailed { genesis_hash }
+            }
+            // PeerCountHistogram
+            35 => {
+                let (genesis_hash, buckets) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::PeerCountHistogram {
+                    genesis_hash,
+                    buckets,
+                }
+            }
+            // NodeProcessUptime
+            36 => {
+                let (node_id, uptime_secs) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::NodeProcessUptime {
+                    node_id,
+                    uptime_secs,
+                }
+            }
             // A catchall for messages we don't know/care about yet:
             _ => {
                 let value = raw_val.to_string();
@@ -0,0 +1,67 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::feed_message_de::FeedMessage;
+use anyhow::Context;
+use common::node_types::BlockHash;
+use std::path::Path;
+
+/// A set of feed messages that were broadcast together, read from a recording.
+#[derive(Debug)]
+pub struct RecordedFeedMessages {
+    /// When the messages were broadcast, in unix MS from epoch.
+    pub timestamp: u64,
+    /// The chain whose subscribers the messages were broadcast to,
+    /// or `None` if they were broadcast to every feed.
+    pub chain: Option<BlockHash>,
+    /// The messages that were broadcast.
+    pub messages: Vec<FeedMessage>,
+}
+
+/// Read back the feed messages recorded by running the core with `--record-feed <file>`,
+/// in the order that they were broadcast.
+pub fn read_feed_recording(
+    path: impl AsRef<Path>,
+) -> Result<Vec<RecordedFeedMessages>, anyhow::Error> {
+    let path = path.as_ref();
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Could not read feed recording {path:?}"))?;
+
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(idx, line)| {
+            parse_line(line)
+                .with_context(|| format!("Could not parse line {} of {path:?}", idx + 1))
+        })
+        .collect()
+}
+
+fn parse_line(line: &str) -> Result<RecordedFeedMessages, anyhow::Error> {
+    let mut value: serde_json::Value = serde_json::from_str(line)?;
+    let timestamp = value["ts"]
+        .as_u64()
+        .ok_or_else(|| anyhow::anyhow!("Expected a numeric 'ts'"))?;
+    let chain = serde_json::from_value(value["chain"].take())?;
+    let messages = FeedMessage::from_bytes(&serde_json::to_vec(&value["messages"])?)?;
+
+    Ok(RecordedFeedMessages {
+        timestamp,
+        chain,
+        messages,
+    })
+}
@@ -31,3 +31,6 @@ pub mod workspace;
 
 /// A utility to generate fake telemetry messages at realistic intervals.
 pub mod fake_telemetry;
+
+/// Read back feed messages that were recorded by the core, to replay them in tests.
+pub mod feed_recording;
@@ -20,8 +20,9 @@ use std::{
 };
 
 use crate::feed_message_de::FeedMessage;
-use common::ws_client;
-use futures::{channel, Stream, StreamExt};
+use bincode::Options;
+use common::{node_message::NodeMessage, ws_client};
+use futures::{Stream, StreamExt};
 
 /// Wrap a `ws_client::Sender` with convenient utility methods for shard connections
 pub struct ShardSender(ws_client::Sender);
@@ -37,18 +38,21 @@ impl ShardSender {
     pub fn send_json_binary(
         &mut self,
         json: serde_json::Value,
-    ) -> Result<(), channel::mpsc::SendError> {
+    ) -> Result<(), ws_client::SendError> {
         let bytes = serde_json::to_vec(&json).expect("valid bytes");
         self.unbounded_send(ws_client::SentMessage::Binary(bytes))
     }
     /// Send JSON as a textual websocket message
-    pub fn send_json_text(
-        &mut self,
-        json: serde_json::Value,
-    ) -> Result<(), channel::mpsc::SendError> {
+    pub fn send_json_text(&mut self, json: serde_json::Value) -> Result<(), ws_client::SendError> {
         let s = serde_json::to_string(&json).expect("valid string");
         self.unbounded_send(ws_client::SentMessage::Text(s))
     }
+    /// Send a bincode encoded node message as a binary websocket message. Only
+    /// connections to `/submit_bin` will understand this.
+    pub fn send_bincode(&mut self, msg: &NodeMessage) -> Result<(), ws_client::SendError> {
+        let bytes = bincode::options().serialize(msg).expect("valid bytes");
+        self.unbounded_send(ws_client::SentMessage::Binary(bytes))
+    }
 }
 
 impl Deref for ShardSender {
@@ -123,7 +127,7 @@ impl FeedSender {
         &self,
         command: S,
         param: S,
-    ) -> Result<(), channel::mpsc::SendError> {
+    ) -> Result<(), ws_client::SendError> {
         self.unbounded_send(ws_client::SentMessage::Text(format!(
             "{}:{}",
             command.as_ref(),
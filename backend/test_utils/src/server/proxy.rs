@@ -0,0 +1,106 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+/// Forwards TCP connections on to some target address. It can be told to drop every
+/// connection going through it and turn new ones away, which looks to either side like
+/// the other has become unreachable, until it's told to let connections through again.
+pub struct Proxy {
+    addr: SocketAddr,
+    partitioned: watch::Sender<bool>,
+    handle: JoinHandle<()>,
+}
+
+impl Proxy {
+    /// Start proxying connections to the target address (eg `127.0.0.1:8000`) given.
+    pub async fn start(target: String) -> io::Result<Proxy> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let (partitioned, partitioned_rx) = watch::channel(false);
+
+        let handle = tokio::spawn(async move {
+            while let Ok((inbound, _)) = listener.accept().await {
+                // Dropping the connection straight away turns it away:
+                if *partitioned_rx.borrow() {
+                    continue;
+                }
+                let target = target.clone();
+                let partitioned_rx = partitioned_rx.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = forward(inbound, &target, partitioned_rx).await {
+                        log::warn!("Error proxying connection to {}: {}", target, e);
+                    }
+                });
+            }
+        });
+
+        Ok(Proxy {
+            addr,
+            partitioned,
+            handle,
+        })
+    }
+
+    /// The address to connect to in order to be proxied to the target.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Drop every connection going through the proxy, and turn new ones away.
+    pub fn partition(&self) {
+        let _ = self.partitioned.send(true);
+    }
+
+    /// Let connections through the proxy again.
+    pub fn heal(&self) {
+        let _ = self.partitioned.send(false);
+    }
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        // Connections already going through the proxy notice that it's gone and close:
+        self.handle.abort();
+    }
+}
+
+/// Forward bytes between a connection and the target until either side closes, or until
+/// we're partitioned.
+async fn forward(
+    mut inbound: TcpStream,
+    target: &str,
+    mut partitioned: watch::Receiver<bool>,
+) -> io::Result<()> {
+    let mut outbound = TcpStream::connect(target).await?;
+    tokio::select! {
+        res = tokio::io::copy_bidirectional(&mut inbound, &mut outbound) => res.map(|_| ()),
+        _ = until_partitioned(&mut partitioned) => Ok(()),
+    }
+}
+
+/// Resolves once we're partitioned, or once the proxy has gone away.
+async fn until_partitioned(partitioned: &mut watch::Receiver<bool>) {
+    while !*partitioned.borrow_and_update() {
+        if partitioned.changed().await.is_err() {
+            return;
+        }
+    }
+}
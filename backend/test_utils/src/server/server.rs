@@ -14,7 +14,7 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-use super::{channels, utils};
+use super::{channels, proxy::Proxy, utils};
 use common::ws_client;
 use common::{id_type, DenseMap};
 use std::ffi::OsString;
@@ -106,6 +106,8 @@ pub enum Error {
     ErrorObtainingPort(anyhow::Error),
     #[error("Whoops; attempt to kill a process we didn't start (and so have no handle to)")]
     CannotKillNoHandle,
+    #[error("Failed to send SIGTERM to the process")]
+    CannotTerminate,
     #[error(
         "Can't add a shard: command not provided, or we are not in charge of spawning processes"
     )]
@@ -127,6 +129,14 @@ impl Server {
         }
     }
 
+    pub fn get_shard_mut(&mut self, id: ProcessId) -> Option<&mut ShardProcess> {
+        match &mut self.mode {
+            ServerMode::SingleProcessMode { virtual_shard, .. } => Some(virtual_shard),
+            ServerMode::ShardAndCoreMode { shards, .. } => shards.get_mut(id),
+            ServerMode::ConnectToExistingMode { shards, .. } => shards.get_mut(id),
+        }
+    }
+
     pub async fn kill_shard(&mut self, id: ProcessId) -> bool {
         let shard = match &mut self.mode {
             // Can't remove the pretend shard:
@@ -150,6 +160,38 @@ impl Server {
         true
     }
 
+    /// Cut a shard off from the core without killing either of them, to simulate a network
+    /// partition between the two. The shard's connection to the core is dropped, and it can't
+    /// reconnect until [`Server::heal_shard`] is called. Returns false if there's no such
+    /// shard, or it's not one that we started ourselves.
+    pub fn partition_shard(&self, id: ProcessId) -> bool {
+        match self
+            .get_shard(id)
+            .and_then(|shard| shard.core_link.as_ref())
+        {
+            Some(core_link) => {
+                core_link.partition();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Let a shard that was cut off from the core by [`Server::partition_shard`] reconnect
+    /// to it. Returns false if there's no such shard, or it's not one that we started ourselves.
+    pub fn heal_shard(&self, id: ProcessId) -> bool {
+        match self
+            .get_shard(id)
+            .and_then(|shard| shard.core_link.as_ref())
+        {
+            Some(core_link) => {
+                core_link.heal();
+                true
+            }
+            None => false,
+        }
+    }
+
     /// Kill everything and tidy up
     pub async fn shutdown(self) {
         // Spawn so we don't need to await cleanup if we don't care.
@@ -193,6 +235,7 @@ impl Server {
                     id,
                     host: format!("{}", host),
                     handle: None,
+                    core_link: None,
                     _channel_type: PhantomData,
                 });
 
@@ -203,8 +246,10 @@ impl Server {
                 shard_command,
                 shards,
             } => {
-                // Where is the URI we'll want to submit things to?
-                let core_shard_submit_uri = format!("http://{}/shard_submit", self.core.host);
+                // Where is the URI we'll want to submit things to? We go via a proxy so that
+                // the shard can be cut off from the core to simulate a network partition.
+                let core_link = Proxy::start(self.core.host.clone()).await?;
+                let core_shard_submit_uri = format!("http://{}/shard_submit", core_link.addr());
 
                 let mut shard_cmd: TokioCommand = shard_command.clone().into();
                 shard_cmd
@@ -247,6 +292,7 @@ impl Server {
                     id,
                     host: format!("127.0.0.1:{}", shard_port),
                     handle: Some(shard_process),
+                    core_link: Some(core_link),
                     _channel_type: PhantomData,
                 });
 
@@ -272,6 +318,7 @@ impl Server {
                             id: ProcessId(0),
                             host: virtual_shard_host,
                             handle: None,
+                            core_link: None,
                             _channel_type: PhantomData,
                         },
                     },
@@ -302,6 +349,7 @@ impl Server {
                     id: ProcessId(0),
                     host: feed_host,
                     handle: None,
+                    core_link: None,
                     _channel_type: PhantomData,
                 },
                 mode: ServerMode::ConnectToExistingMode {
@@ -346,6 +394,7 @@ impl Server {
             id: ProcessId(0),
             host: format!("127.0.0.1:{}", core_port),
             handle: Some(child),
+            core_link: None,
             _channel_type: PhantomData,
         };
 
@@ -362,6 +411,9 @@ pub struct Process<Channel> {
     /// If we started the processes ourselves, we'll have a handle to
     /// them which we can use to kill them. Else, we may not.
     handle: Option<process::Child>,
+    /// If this is a shard that we started, its connection to the core goes
+    /// through this, which lets us cut the two off from each other.
+    core_link: Option<Proxy>,
     /// The kind of the process (lets us add methods specific to shard/core).
     _channel_type: PhantomData<Channel>,
 }
@@ -383,6 +435,30 @@ impl<Channel> Process<Channel> {
         &self.host
     }
 
+    /// Ask the process to shut down gracefully by sending it a SIGTERM. This
+    /// doesn't wait for the process to exit.
+    pub async fn terminate(&self) -> Result<(), Error> {
+        let pid = self
+            .handle
+            .as_ref()
+            .and_then(|handle| handle.id())
+            .ok_or(Error::CannotKillNoHandle)?;
+        // Safety: this just sends a signal to the process we started.
+        match unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } {
+            0 => Ok(()),
+            _ => Err(Error::CannotTerminate),
+        }
+    }
+
+    /// Wait up to `timeout` for the process to exit, returning whether it did.
+    pub async fn exited_within(&mut self, timeout: std::time::Duration) -> Result<bool, Error> {
+        let handle = self.handle.as_mut().ok_or(Error::CannotKillNoHandle)?;
+        match tokio::time::timeout(timeout, handle.wait()).await {
+            Ok(status) => status.map(|_| true).map_err(Into::into),
+            Err(_) => Ok(false),
+        }
+    }
+
     /// Kill the process and wait for this to complete
     /// Not public: Klling done via Server.
     async fn kill(self) -> Result<(), Error> {
@@ -442,6 +518,28 @@ impl ShardProcess {
         Process::connect_to_uri(&uri).await
     }
 
+    /// Establish a connection to the process, sending the given `(name, value)` headers
+    /// along with the request (for instance, to connect as though from another IP address).
+    pub async fn connect_node_with_headers(
+        &self,
+        headers: &[(&str, &str)],
+    ) -> Result<(channels::ShardSender, channels::ShardReceiver), Error> {
+        let uri = format!("http://{}/submit", self.host).parse()?;
+        ws_client::connect_with_headers(&uri, headers)
+            .await
+            .map(|c| c.into_channels())
+            .map(|(s, r)| (s.into(), r.into()))
+            .map_err(|e| e.into())
+    }
+
+    /// Establish a connection to the process which accepts bincode encoded binary messages
+    pub async fn connect_node_bin(
+        &self,
+    ) -> Result<(channels::ShardSender, channels::ShardReceiver), Error> {
+        let uri = format!("http://{}/submit_bin", self.host).parse()?;
+        Process::connect_to_uri(&uri).await
+    }
+
     /// Establish multiple connections to the process
     pub async fn connect_multiple_nodes(
         &self,
@@ -486,6 +584,7 @@ impl CoreProcess {
 pub struct Command {
     command: OsString,
     args: Vec<OsString>,
+    envs: Vec<(OsString, OsString)>,
 }
 
 impl Command {
@@ -493,6 +592,7 @@ impl Command {
         Command {
             command: command.into(),
             args: Vec::new(),
+            envs: Vec::new(),
         }
     }
 
@@ -500,12 +600,18 @@ impl Command {
         self.args.push(arg.into());
         self
     }
+
+    pub fn env<K: Into<OsString>, V: Into<OsString>>(mut self, key: K, val: V) -> Command {
+        self.envs.push((key.into(), val.into()));
+        self
+    }
 }
 
 impl Into<TokioCommand> for Command {
     fn into(self) -> TokioCommand {
         let mut cmd = TokioCommand::new(self.command);
         cmd.args(self.args);
+        cmd.envs(self.envs);
         cmd
     }
 }
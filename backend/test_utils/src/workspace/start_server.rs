@@ -35,16 +35,62 @@ impl Default for ServerOpts {
 /// Additional options to pass to the core command.
 pub struct CoreOpts {
     pub feed_timeout: Option<u64>,
+    pub feed_subscribe_timeout: Option<u64>,
+    pub feed_stall_timeout_ms: Option<u64>,
     pub worker_threads: Option<usize>,
     pub num_aggregators: Option<usize>,
+    pub max_feeds: Option<usize>,
+    pub max_feeds_policy: Option<String>,
+    pub node_removal_grace_ms: Option<u64>,
+    pub empty_chain_ttl_ms: Option<u64>,
+    pub location_broadcast_interval_ms: Option<u64>,
+    pub allowlist: Option<Vec<String>>,
+    pub connection_limits: Option<String>,
+    pub max_feeds_per_ip: Option<usize>,
+    pub uptime_db: Option<String>,
+    pub feed_permessage_deflate: bool,
+    pub shutdown_grace_seconds: Option<u64>,
+    pub shard_secret: Option<String>,
+    pub denylist_file: Option<String>,
+    pub denylist_reload_secs: Option<u64>,
+    pub admin_token: Option<String>,
+    pub feed_resume_buffer_len: Option<usize>,
+    pub pinned_chains: Option<String>,
+    pub load_snapshot: Option<String>,
+    pub tolerate_shard_parse_errors: bool,
+    pub node_uptime_broadcast_secs: Option<u64>,
+    pub max_total_nodes: Option<usize>,
 }
 
 impl Default for CoreOpts {
     fn default() -> Self {
         Self {
             feed_timeout: None,
+            feed_subscribe_timeout: None,
+            feed_stall_timeout_ms: None,
             worker_threads: None,
             num_aggregators: None,
+            max_feeds: None,
+            max_feeds_policy: None,
+            node_removal_grace_ms: None,
+            empty_chain_ttl_ms: None,
+            location_broadcast_interval_ms: None,
+            allowlist: None,
+            connection_limits: None,
+            max_feeds_per_ip: None,
+            uptime_db: None,
+            feed_permessage_deflate: false,
+            shutdown_grace_seconds: None,
+            shard_secret: None,
+            denylist_file: None,
+            denylist_reload_secs: None,
+            admin_token: None,
+            feed_resume_buffer_len: None,
+            pinned_chains: None,
+            load_snapshot: None,
+            tolerate_shard_parse_errors: false,
+            node_uptime_broadcast_secs: None,
+            max_total_nodes: None,
         }
     }
 }
@@ -55,6 +101,12 @@ pub struct ShardOpts {
     pub max_node_data_per_second: Option<usize>,
     pub node_block_seconds: Option<u64>,
     pub worker_threads: Option<usize>,
+    pub shard_secret: Option<String>,
+    pub max_node_message_size: Option<usize>,
+    pub idle_socket_timeout: Option<u64>,
+    pub shard_id: Option<String>,
+    pub shutdown_grace_seconds: Option<u64>,
+    pub global_quota_backoff_seconds: Option<u64>,
 }
 
 impl Default for ShardOpts {
@@ -64,6 +116,12 @@ impl Default for ShardOpts {
             max_node_data_per_second: None,
             node_block_seconds: None,
             worker_threads: None,
+            shard_secret: None,
+            max_node_message_size: None,
+            idle_socket_timeout: None,
+            shard_id: None,
+            shutdown_grace_seconds: None,
+            global_quota_backoff_seconds: None,
         }
     }
 }
@@ -142,6 +200,33 @@ pub async fn start_server(
     if let Some(val) = shard_opts.worker_threads {
         shard_command = shard_command.arg("--worker-threads").arg(val.to_string());
     }
+    // Secrets are handed over in the environment, so that they aren't visible in `ps`:
+    if let Some(val) = shard_opts.shard_secret {
+        shard_command = shard_command.env("TELEMETRY_SHARD_SECRET", val);
+    }
+    if let Some(val) = shard_opts.max_node_message_size {
+        shard_command = shard_command
+            .arg("--max-node-message-size")
+            .arg(val.to_string());
+    }
+    if let Some(val) = shard_opts.idle_socket_timeout {
+        shard_command = shard_command
+            .arg("--idle-socket-timeout")
+            .arg(val.to_string());
+    }
+    if let Some(val) = shard_opts.shard_id {
+        shard_command = shard_command.arg("--shard-id").arg(val);
+    }
+    if let Some(val) = shard_opts.shutdown_grace_seconds {
+        shard_command = shard_command
+            .arg("--shutdown-grace-seconds")
+            .arg(val.to_string());
+    }
+    if let Some(val) = shard_opts.global_quota_backoff_seconds {
+        shard_command = shard_command
+            .arg("--global-quota-backoff-seconds")
+            .arg(val.to_string());
+    }
 
     // Build the core command
     let mut core_command = std::env::var("TELEMETRY_CORE_BIN")
@@ -155,12 +240,101 @@ pub async fn start_server(
     if let Some(val) = core_opts.feed_timeout {
         core_command = core_command.arg("--feed-timeout").arg(val.to_string());
     }
+    if let Some(val) = core_opts.feed_subscribe_timeout {
+        core_command = core_command
+            .arg("--feed-subscribe-timeout")
+            .arg(val.to_string());
+    }
+    if let Some(val) = core_opts.feed_stall_timeout_ms {
+        core_command = core_command
+            .arg("--feed-stall-timeout-ms")
+            .arg(val.to_string());
+    }
     if let Some(val) = core_opts.worker_threads {
         core_command = core_command.arg("--worker-threads").arg(val.to_string());
     }
     if let Some(val) = core_opts.num_aggregators {
         core_command = core_command.arg("--num-aggregators").arg(val.to_string());
     }
+    if let Some(val) = core_opts.max_feeds {
+        core_command = core_command.arg("--max-feeds").arg(val.to_string());
+    }
+    if let Some(val) = core_opts.max_feeds_policy {
+        core_command = core_command.arg("--max-feeds-policy").arg(val);
+    }
+    if let Some(val) = core_opts.node_removal_grace_ms {
+        core_command = core_command
+            .arg("--node-removal-grace-ms")
+            .arg(val.to_string());
+    }
+    if let Some(val) = core_opts.empty_chain_ttl_ms {
+        core_command = core_command
+            .arg("--empty-chain-ttl-ms")
+            .arg(val.to_string());
+    }
+    if let Some(val) = core_opts.location_broadcast_interval_ms {
+        core_command = core_command
+            .arg("--location-broadcast-interval-ms")
+            .arg(val.to_string());
+    }
+    if let Some(chains) = core_opts.allowlist {
+        for chain in chains {
+            core_command = core_command.arg("--allowlist").arg(chain);
+        }
+    }
+    if let Some(val) = core_opts.connection_limits {
+        core_command = core_command.arg("--connection-limits").arg(val);
+    }
+    if let Some(val) = core_opts.max_feeds_per_ip {
+        core_command = core_command.arg("--max-feeds-per-ip").arg(val.to_string());
+    }
+    if let Some(val) = core_opts.uptime_db {
+        core_command = core_command.arg("--uptime-db").arg(val);
+    }
+    if core_opts.feed_permessage_deflate {
+        core_command = core_command.arg("--feed-permessage-deflate");
+    }
+    if let Some(val) = core_opts.shutdown_grace_seconds {
+        core_command = core_command
+            .arg("--shutdown-grace-seconds")
+            .arg(val.to_string());
+    }
+    if let Some(val) = core_opts.shard_secret {
+        core_command = core_command.env("TELEMETRY_SHARD_SECRET", val);
+    }
+    if let Some(val) = core_opts.denylist_file {
+        core_command = core_command.arg("--denylist-file").arg(val);
+    }
+    if let Some(val) = core_opts.denylist_reload_secs {
+        core_command = core_command
+            .arg("--denylist-reload-secs")
+            .arg(val.to_string());
+    }
+    if let Some(val) = core_opts.admin_token {
+        core_command = core_command.arg("--admin-token").arg(val);
+    }
+    if let Some(val) = core_opts.feed_resume_buffer_len {
+        core_command = core_command
+            .arg("--feed-resume-buffer-len")
+            .arg(val.to_string());
+    }
+    if let Some(val) = core_opts.pinned_chains {
+        core_command = core_command.arg("--pinned-chains").arg(val);
+    }
+    if let Some(val) = core_opts.load_snapshot {
+        core_command = core_command.arg("--load-snapshot").arg(val);
+    }
+    if core_opts.tolerate_shard_parse_errors {
+        core_command = core_command.arg("--tolerate-shard-parse-errors");
+    }
+    if let Some(val) = core_opts.node_uptime_broadcast_secs {
+        core_command = core_command
+            .arg("--node-uptime-broadcast-secs")
+            .arg(val.to_string());
+    }
+    if let Some(val) = core_opts.max_total_nodes {
+        core_command = core_command.arg("--max-total-nodes").arg(val.to_string());
+    }
 
     // Start the server
     Server::start(server::StartOpts::ShardAndCore {
@@ -70,6 +70,7 @@ export default class App extends React.Component {
         blockpropagation: true,
         blocklasttime: false,
         uptime: false,
+        totaluptime: false,
         version: false,
         target_os: false,
         target_arch: false,
@@ -117,6 +118,8 @@ export default class App extends React.Component {
       finalized: 0 as Types.BlockNumber,
       blockTimestamp: 0 as Types.Timestamp,
       blockAverage: null,
+      blockMedian: null,
+      blockP95: null,
       timeDiff: 0 as Types.Milliseconds,
       subscribed: null,
       chains: new Map(),
@@ -127,6 +130,7 @@ export default class App extends React.Component {
       selectedColumns: this.selectedColumns(this.settings.raw()),
       tab,
       chainStats: null,
+      peerCountHistogram: null,
     });
     this.appState = this.appUpdate({});
 
@@ -181,6 +181,14 @@ export class Connection {
           break;
         }
 
+        case ACTIONS.BlockTimePercentiles: {
+          const [blockMedian, blockP95] = message.payload;
+
+          this.appUpdate({ blockMedian, blockP95 });
+
+          break;
+        }
+
         case ACTIONS.BestFinalized: {
           const [finalized /*, hash */] = message.payload;
 
@@ -234,6 +242,14 @@ export class Connection {
           break;
         }
 
+        case ACTIONS.RecoveredNode: {
+          const id = message.payload;
+
+          nodes.mutAndSort(id, (node) => node.setStale(false));
+
+          break;
+        }
+
         case ACTIONS.LocatedNode: {
           const [id, lat, lon, city] = message.payload;
 
@@ -246,6 +262,42 @@ export class Connection {
           break;
         }
 
+        case ACTIONS.LocationFailed: {
+          const id = message.payload;
+
+          nodes.mutAndMaybeSort(
+            id,
+            (node) => node.setLocationFailed(),
+            sortByColumn === LocationColumn
+          );
+
+          break;
+        }
+
+        case ACTIONS.NodeUptime: {
+          const [id, uptimeSecs] = message.payload;
+
+          nodes.mut(id, (node) => node.updateUptime(uptimeSecs));
+
+          break;
+        }
+
+        case ACTIONS.NodeProcessUptime: {
+          const [id, uptimeSecs] = message.payload;
+
+          nodes.mut(id, (node) => node.updateProcessUptime(uptimeSecs));
+
+          break;
+        }
+
+        case ACTIONS.NodeSyncState: {
+          const [id, majorSyncing] = message.payload;
+
+          nodes.mut(id, (node) => node.updateSyncState(majorSyncing));
+
+          break;
+        }
+
         case ACTIONS.ImportedBlock: {
           const [id, blockDetails] = message.payload;
 
@@ -311,13 +363,37 @@ export class Connection {
         }
 
         case ACTIONS.AddedChain: {
-          const [label, genesisHash, nodeCount] = message.payload;
+          const [label, genesisHash, nodeCount, metadata] = message.payload;
           const chain = chains.get(genesisHash);
 
           if (chain) {
             chain.nodeCount = nodeCount;
+            chain.metadata = metadata;
           } else {
-            chains.set(genesisHash, { label, genesisHash, nodeCount });
+            chains.set(genesisHash, { label, genesisHash, nodeCount, metadata });
+          }
+
+          this.appUpdate({ chains });
+
+          break;
+        }
+
+        case ACTIONS.AddedChains: {
+          for (const addedChain of message.payload) {
+            const [label, genesisHash, nodeCount, metadata] = addedChain;
+            const chain = chains.get(genesisHash);
+
+            if (chain) {
+              chain.nodeCount = nodeCount;
+              chain.metadata = metadata;
+            } else {
+              chains.set(genesisHash, {
+                label,
+                genesisHash,
+                nodeCount,
+                metadata,
+              });
+            }
           }
 
           this.appUpdate({ chains });
@@ -365,6 +441,16 @@ export class Connection {
           break;
         }
 
+        case ACTIONS.PeerCountHistogram: {
+          const [genesisHash, peerCountHistogram] = message.payload;
+
+          if (this.appState.subscribed === genesisHash) {
+            this.appUpdate({ peerCountHistogram });
+          }
+
+          break;
+        }
+
         default: {
           break;
         }
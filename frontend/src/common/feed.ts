@@ -24,6 +24,7 @@ import {
   City,
   NodeId,
   NodeCount,
+  ChainMetadata,
   NodeDetails,
   NodeStats,
   NodeIO,
@@ -38,6 +39,7 @@ import {
   GenesisHash,
   AuthoritySetInfo,
   ChainStats,
+  Range,
 } from './types';
 
 export const ACTIONS = {
@@ -64,6 +66,20 @@ export const ACTIONS = {
   StaleNode: 0x14 as const,
   NodeIO: 0x15 as const,
   ChainStatsUpdate: 0x16 as const,
+  NodeQualityScore: 0x17 as const,
+  MaxClaimedBlock: 0x18 as const,
+  NodeLogCounts: 0x19 as const,
+  RecoveredNode: 0x1a as const,
+  NodeFinalityLag: 0x1b as const,
+  AddedChains: 0x1c as const,
+  LocationFailed: 0x1d as const,
+  NodeUptime: 0x1e as const,
+  BlockTimePercentiles: 0x1f as const,
+  NodeSyncState: 0x20 as const,
+  Seq: 0x21 as const,
+  ResumeFailed: 0x22 as const,
+  PeerCountHistogram: 0x23 as const,
+  NodeProcessUptime: 0x24 as const,
 };
 
 export type Action = typeof ACTIONS[keyof typeof ACTIONS];
@@ -98,7 +114,8 @@ interface AddedNodeMessage extends MessageBase {
     NodeHardware,
     BlockDetails,
     Maybe<NodeLocation>,
-    Maybe<Timestamp>
+    Maybe<Timestamp>,
+    Maybe<BlockNumber>
   ];
 }
 
@@ -144,7 +161,12 @@ interface TimeSyncMessage extends MessageBase {
 
 interface AddedChainMessage extends MessageBase {
   action: typeof ACTIONS.AddedChain;
-  payload: [ChainLabel, GenesisHash, NodeCount];
+  payload: [ChainLabel, GenesisHash, NodeCount, ChainMetadata?];
+}
+
+interface AddedChainsMessage extends MessageBase {
+  action: typeof ACTIONS.AddedChains;
+  payload: Array<[ChainLabel, GenesisHash, NodeCount, ChainMetadata?]>;
 }
 
 interface RemovedChainMessage extends MessageBase {
@@ -192,11 +214,76 @@ interface StaleNodeMessage extends MessageBase {
   payload: NodeId;
 }
 
+interface RecoveredNodeMessage extends MessageBase {
+  action: typeof ACTIONS.RecoveredNode;
+  payload: NodeId;
+}
+
 interface ChainStatsUpdate extends MessageBase {
   action: typeof ACTIONS.ChainStatsUpdate;
   payload: ChainStats;
 }
 
+interface NodeQualityScoreMessage extends MessageBase {
+  action: typeof ACTIONS.NodeQualityScore;
+  payload: [NodeId, number];
+}
+
+interface MaxClaimedBlockMessage extends MessageBase {
+  action: typeof ACTIONS.MaxClaimedBlock;
+  payload: BlockNumber;
+}
+
+interface NodeLogCountsMessage extends MessageBase {
+  action: typeof ACTIONS.NodeLogCounts;
+  payload: [NodeId, number, number];
+}
+
+interface NodeFinalityLagMessage extends MessageBase {
+  action: typeof ACTIONS.NodeFinalityLag;
+  payload: [NodeId, BlockNumber];
+}
+
+interface LocationFailedMessage extends MessageBase {
+  action: typeof ACTIONS.LocationFailed;
+  payload: NodeId;
+}
+
+interface NodeUptimeMessage extends MessageBase {
+  action: typeof ACTIONS.NodeUptime;
+  payload: [NodeId, number];
+}
+
+interface BlockTimePercentilesMessage extends MessageBase {
+  action: typeof ACTIONS.BlockTimePercentiles;
+  payload: [Milliseconds, Milliseconds];
+}
+
+interface NodeSyncStateMessage extends MessageBase {
+  action: typeof ACTIONS.NodeSyncState;
+  payload: [NodeId, boolean];
+}
+
+interface SeqMessage extends MessageBase {
+  action: typeof ACTIONS.Seq;
+  payload: number;
+}
+
+interface ResumeFailedMessage extends MessageBase {
+  action: typeof ACTIONS.ResumeFailed;
+  payload: GenesisHash;
+}
+
+interface PeerCountHistogramMessage extends MessageBase {
+  action: typeof ACTIONS.PeerCountHistogram;
+  payload: [GenesisHash, Array<[Range, NodeCount]>];
+}
+
+interface NodeProcessUptimeMessage extends MessageBase {
+  action: typeof ACTIONS.NodeProcessUptime;
+  payload: [NodeId, number];
+}
+
 export type Message =
   | FeedVersionMessage
   | BestBlockMessage
@@ -210,6 +297,7 @@ export type Message =
   | NodeHardwareMessage
   | TimeSyncMessage
   | AddedChainMessage
+  | AddedChainsMessage
   | RemovedChainMessage
   | SubscribedToMessage
   | UnsubscribedFromMessage
@@ -218,9 +306,22 @@ export type Message =
   | AfgReceivedPrecommit
   | AfgAuthoritySet
   | StaleNodeMessage
+  | RecoveredNodeMessage
   | PongMessage
   | NodeIOMessage
-  | ChainStatsUpdate;
+  | ChainStatsUpdate
+  | NodeQualityScoreMessage
+  | MaxClaimedBlockMessage
+  | NodeLogCountsMessage
+  | NodeFinalityLagMessage
+  | LocationFailedMessage
+  | NodeUptimeMessage
+  | BlockTimePercentilesMessage
+  | NodeSyncStateMessage
+  | SeqMessage
+  | ResumeFailedMessage
+  | PeerCountHistogramMessage
+  | NodeProcessUptimeMessage;
 
 /**
  * Data type to be sent to the feed. Passing through strings means we can only serialize once,
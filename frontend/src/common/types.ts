@@ -41,6 +41,8 @@ export type Milliseconds = Opaque<number, 'Milliseconds'>;
 export type Timestamp = Opaque<Milliseconds, 'Timestamp'>;
 export type PropagationTime = Opaque<Milliseconds, 'PropagationTime'>;
 export type NodeCount = Opaque<number, 'NodeCount'>;
+// Display metadata for a chain; this is passed through from the server's config as-is.
+export type ChainMetadata = unknown;
 export type PeerCount = Opaque<number, 'PeerCount'>;
 export type TransactionCount = Opaque<number, 'TransactionCount'>;
 export type Latitude = Opaque<number, 'Latitude'>;
@@ -124,8 +126,11 @@ export type ChainStats = {
   version: Maybe<Ranking<string>>;
   target_os: Maybe<Ranking<string>>;
   target_arch: Maybe<Ranking<string>>;
+  target_platform: Maybe<Ranking<string>>;
   cpu: Maybe<Ranking<string>>;
   core_count: Maybe<Ranking<number>>;
+  total_core_count: Maybe<number>;
+  total_memory: Maybe<Bytes>;
   memory: Maybe<Ranking<Range>>;
   is_virtual_machine: Maybe<Ranking<boolean>>;
   linux_distro: Maybe<Ranking<string>>;
@@ -135,4 +140,22 @@ export type ChainStats = {
   disk_sequential_write_score: Maybe<Ranking<Range>>;
   disk_random_write_score: Maybe<Ranking<Range>>;
   cpu_vendor: Maybe<Ranking<string>>;
+  database_size: Maybe<DatabaseSizeStats>;
+  log_counts: Maybe<LogCountStats>;
+  nodes_with_excessive_finality_lag: Maybe<number>;
+  last_block_at: Maybe<Timestamp>;
+};
+
+export type DatabaseSizeStats = {
+  min: Bytes;
+  avg: Bytes;
+  max: Bytes;
+  count: number;
+};
+
+export type LogCountStats = {
+  errors: number;
+  warnings: number;
+  nodes_with_errors: number;
+  nodes_with_warnings: number;
 };
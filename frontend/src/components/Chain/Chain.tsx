@@ -66,7 +66,14 @@ export class Chain extends React.Component<ChainProps, ChainState> {
 
   public render() {
     const { appState } = this.props;
-    const { best, finalized, blockTimestamp, blockAverage } = appState;
+    const {
+      best,
+      finalized,
+      blockTimestamp,
+      blockAverage,
+      blockMedian,
+      blockP95,
+    } = appState;
     const { display: currentTab } = this.state;
 
     return (
@@ -75,6 +82,8 @@ export class Chain extends React.Component<ChainProps, ChainState> {
           best={best}
           finalized={finalized}
           blockAverage={blockAverage}
+          blockMedian={blockMedian}
+          blockP95={blockP95}
           blockTimestamp={blockTimestamp}
           currentTab={currentTab}
           setDisplay={this.setDisplay}
@@ -36,6 +36,8 @@ interface HeaderProps {
   finalized: Types.BlockNumber;
   blockTimestamp: Types.Timestamp;
   blockAverage: Maybe<Types.Milliseconds>;
+  blockMedian: Maybe<Types.Milliseconds>;
+  blockP95: Maybe<Types.Milliseconds>;
   currentTab: ChainDisplay;
   setDisplay: (display: ChainDisplay) => void;
 }
@@ -47,12 +49,21 @@ export class Header extends React.Component<HeaderProps> {
       this.props.finalized !== nextProps.finalized ||
       this.props.blockTimestamp !== nextProps.blockTimestamp ||
       this.props.blockAverage !== nextProps.blockAverage ||
+      this.props.blockMedian !== nextProps.blockMedian ||
+      this.props.blockP95 !== nextProps.blockP95 ||
       this.props.currentTab !== nextProps.currentTab
     );
   }
 
   public render() {
-    const { best, finalized, blockTimestamp, blockAverage } = this.props;
+    const {
+      best,
+      finalized,
+      blockTimestamp,
+      blockAverage,
+      blockMedian,
+      blockP95,
+    } = this.props;
     const { currentTab, setDisplay } = this.props;
 
     return (
@@ -68,6 +79,12 @@ export class Header extends React.Component<HeaderProps> {
             ? '-'
             : secondsWithPrecision(blockAverage / 1000)}
         </Tile>
+        <Tile icon={blockTimeIcon} title="Median Time">
+          {blockMedian == null ? '-' : secondsWithPrecision(blockMedian / 1000)}
+        </Tile>
+        <Tile icon={blockTimeIcon} title="95th Percentile Time">
+          {blockP95 == null ? '-' : secondsWithPrecision(blockP95 / 1000)}
+        </Tile>
         <Tile icon={lastTimeIcon} title="Last Block">
           <Ago when={blockTimestamp} />
         </Tile>
@@ -29,17 +29,31 @@ export class LocationColumn extends React.Component<ColumnProps> {
   public static readonly sortBy = ({ city }: Node) => city || '';
 
   private data: Maybe<string>;
+  private locationFailed = false;
 
   public shouldComponentUpdate(nextProps: ColumnProps) {
-    return this.data !== nextProps.node.city;
+    return (
+      this.data !== nextProps.node.city ||
+      this.locationFailed !== nextProps.node.locationFailed
+    );
   }
 
   render() {
-    const { city } = this.props.node;
+    const { city, locationFailed } = this.props.node;
 
     this.data = city;
+    this.locationFailed = locationFailed;
 
     if (!city) {
+      if (locationFailed) {
+        return (
+          <td className="Column">
+            <Tooltip text="This node couldn't be located" position="left" />
+            Unknown
+          </td>
+        );
+      }
+
       return <td className="Column">-</td>;
     }
 
@@ -0,0 +1,63 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2023 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+import * as React from 'react';
+import { ColumnProps } from './';
+import { Maybe, Types } from '../../../common';
+import { Node } from '../../../state';
+import { Ago } from '../../';
+import icon from '../../../icons/history.svg';
+
+export class TotalUptimeColumn extends React.Component<ColumnProps> {
+  public static readonly label = 'Total Uptime (across reconnects)';
+  public static readonly icon = icon;
+  public static readonly width = 58;
+  public static readonly setting = 'totaluptime';
+  public static readonly sortBy = (node: Node) => upSince(node) || 0;
+
+  private data: Maybe<Types.Timestamp>;
+
+  public shouldComponentUpdate(nextProps: ColumnProps) {
+    return this.data !== upSince(nextProps.node);
+  }
+
+  render() {
+    const since = upSince(this.props.node);
+
+    this.data = since;
+
+    if (!since) {
+      return <td className="Column">-</td>;
+    }
+
+    return (
+      <td className="Column">
+        <Ago when={since} justTime={true} />
+      </td>
+    );
+  }
+}
+
+// When the node would have started, had it been up for its total uptime without a break.
+function upSince({
+  uptimeSecs,
+  uptimeReceivedAt,
+}: Node): Maybe<Types.Timestamp> {
+  if (uptimeSecs == null || uptimeReceivedAt == null) {
+    return null;
+  }
+  return (uptimeReceivedAt - uptimeSecs * 1000) as Types.Timestamp;
+}
@@ -16,6 +16,7 @@
 
 import * as React from 'react';
 import { ColumnProps } from './';
+import { Maybe, Types } from '../../../common';
 import { Node } from '../../../state';
 import { Ago } from '../../';
 import icon from '../../../icons/pulse.svg';
@@ -25,24 +26,41 @@ export class UptimeColumn extends React.Component<ColumnProps> {
   public static readonly icon = icon;
   public static readonly width = 58;
   public static readonly setting = 'uptime';
-  public static readonly sortBy = ({ startupTime }: Node) => startupTime || 0;
+  public static readonly sortBy = (node: Node) => startedAt(node) || 0;
+
+  private data: Maybe<Types.Timestamp>;
 
   public shouldComponentUpdate(nextProps: ColumnProps) {
-    // Uptime only changes when the node does
-    return this.props.node !== nextProps.node;
+    return this.data !== startedAt(nextProps.node);
   }
 
   render() {
-    const { startupTime } = this.props.node;
+    const since = startedAt(this.props.node);
+
+    this.data = since;
 
-    if (!startupTime) {
+    if (!since) {
       return <td className="Column">-</td>;
     }
 
     return (
       <td className="Column">
-        <Ago when={startupTime} justTime={true} />
+        <Ago when={since} justTime={true} />
       </td>
     );
   }
 }
+
+// When the node's process started. We prefer the uptime the server works out,
+// since the node's own clock may be off, and fall back to what the node sent.
+function startedAt({
+  startupTime,
+  processUptimeSecs,
+  processUptimeReceivedAt,
+}: Node): Maybe<Types.Timestamp> {
+  if (processUptimeSecs == null || processUptimeReceivedAt == null) {
+    return startupTime;
+  }
+  const uptimeMs = processUptimeSecs * 1000;
+  return (processUptimeReceivedAt - uptimeMs) as Types.Timestamp;
+}
@@ -33,6 +33,7 @@ export * from './BlockTimeColumn';
 export * from './BlockPropagationColumn';
 export * from './LastBlockColumn';
 export * from './UptimeColumn';
+export * from './TotalUptimeColumn';
 export * from './CpuArchitectureColumn'; //extra columns added
 export * from './CpuCoresColumn';
 export * from './LinuxDistroColumn';
@@ -39,6 +39,7 @@ import {
   BlockPropagationColumn,
   LastBlockColumn,
   UptimeColumn,
+  TotalUptimeColumn,
   CpuArchitectureColumn,
   CpuColumn,
   CpuCoresColumn,
@@ -82,6 +83,7 @@ export class Row extends React.Component<RowProps, RowState> {
     BlockPropagationColumn,
     LastBlockColumn,
     UptimeColumn,
+    TotalUptimeColumn,
     VersionColumn,
     OperatingSystemColumn,
     CpuArchitectureColumn,
@@ -17,7 +17,9 @@
 import * as React from 'react';
 import { Maybe } from '../../common';
 import { State as AppState } from '../../state';
-import { Ranking, Range } from '../../common/types';
+import { Ranking, Range, Bytes } from '../../common/types';
+import { formatNumber } from '../../utils';
+import { formatBytes } from '../List/Column';
 
 import './Stats.css';
 
@@ -95,6 +97,44 @@ function generateRankingTable<T>(
   );
 }
 
+function generateTotalsTable(coreCount: Maybe<number>, memory: Maybe<Bytes>) {
+  const entries: React.ReactNode[] = [];
+  if (coreCount) {
+    entries.push(
+      <tr key="core_count">
+        <td className="Stats-total">{formatNumber(coreCount)}</td>
+        <td className="Stats-value">CPU Cores</td>
+      </tr>
+    );
+  }
+  if (memory) {
+    entries.push(
+      <tr key="memory">
+        <td className="Stats-total">{formatBytes(memory, null)}</td>
+        <td className="Stats-value">Memory</td>
+      </tr>
+    );
+  }
+
+  if (entries.length === 0) {
+    return null;
+  }
+
+  return (
+    <div className="Stats-category" key="totals">
+      <table>
+        <thead>
+          <tr>
+            <th className="Stats-total" />
+            <th className="Stats-value">Total</th>
+          </tr>
+        </thead>
+        <tbody>{entries}</tbody>
+      </table>
+    </div>
+  );
+}
+
 function identity(value: string | number): string {
   return value + '';
 }
@@ -110,6 +150,17 @@ function formatMemory(value: Range): string {
   return min + ' GB';
 }
 
+function formatPeers(value: Range): string {
+  const [min, max] = value;
+  if (max === null) {
+    return 'At least ' + min;
+  }
+  if (max === min + 1) {
+    return min + '';
+  }
+  return min + ' to ' + (max - 1);
+}
+
 function formatYesNo(value: boolean): string {
   if (value) {
     return 'Yes';
@@ -151,11 +202,28 @@ export class Stats extends React.Component<StatsProps> {
       }
     }
 
+    const peerCounts = appState.peerCountHistogram;
+    if (peerCounts) {
+      add('peers', 'Peers', formatPeers, {
+        list: peerCounts.filter(([_, count]) => count > 0),
+        other: 0,
+        unknown: 0,
+      });
+    }
+
     const stats = appState.chainStats;
     if (stats) {
+      const totals = generateTotalsTable(
+        stats.total_core_count,
+        stats.total_memory
+      );
+      if (totals !== null) {
+        children.push(totals);
+      }
       add('version', 'Version', identity, stats.version);
       add('target_os', 'Operating System', identity, stats.target_os);
       add('target_arch', 'CPU Architecture', identity, stats.target_arch);
+      add('target_platform', 'Platform', identity, stats.target_platform);
       add('cpu', 'CPU', identity, stats.cpu);
       add('core_count', 'CPU Cores', identity, stats.core_count);
       add('cpu_vendor', 'CPU Vendor', identity, stats.cpu_vendor);
@@ -15,7 +15,7 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 import * as React from 'react';
-import { Types, Maybe, SortedCollection } from './common';
+import { Types, Maybe, SortedCollection, timestamp } from './common';
 import { Column } from './components/List';
 
 export const PINNED_CHAINS = {
@@ -98,6 +98,18 @@ export class Node {
   public lat: Maybe<Types.Latitude>;
   public lon: Maybe<Types.Longitude>;
   public city: Maybe<Types.City>;
+  public locationFailed = false;
+
+  // Seconds that the node has been up for in total (across reconnects), and when we were told.
+  public uptimeSecs: Maybe<number>;
+  public uptimeReceivedAt: Maybe<Types.Timestamp>;
+
+  // Seconds that the node's process has been running for, and when we were told.
+  public processUptimeSecs: Maybe<number>;
+  public processUptimeReceivedAt: Maybe<Types.Timestamp>;
+
+  // Whether the node is doing a major sync, if it tells us.
+  public majorSyncing: Maybe<boolean>;
 
   private _changeRef = 0;
   private readonly subscriptionsConsensus = new Set<(node: Node) => void>();
@@ -228,6 +240,33 @@ export class Node {
     this.lat = lat;
     this.lon = lon;
     this.city = city;
+    this.locationFailed = false;
+
+    this.trigger();
+  }
+
+  public updateUptime(uptimeSecs: number) {
+    this.uptimeSecs = uptimeSecs;
+    this.uptimeReceivedAt = timestamp();
+
+    this.trigger();
+  }
+
+  public updateProcessUptime(uptimeSecs: number) {
+    this.processUptimeSecs = uptimeSecs;
+    this.processUptimeReceivedAt = timestamp();
+
+    this.trigger();
+  }
+
+  public updateSyncState(majorSyncing: boolean) {
+    this.majorSyncing = majorSyncing;
+
+    this.trigger();
+  }
+
+  public setLocationFailed() {
+    this.locationFailed = true;
 
     this.trigger();
   }
@@ -301,6 +340,7 @@ export interface StateSettings {
   blockpropagation: boolean;
   blocklasttime: boolean;
   uptime: boolean;
+  totaluptime: boolean;
   version: boolean;
   target_os: boolean;
   target_arch: boolean;
@@ -324,6 +364,8 @@ export interface State {
   tab: string;
   blockTimestamp: Types.Timestamp;
   blockAverage: Maybe<Types.Milliseconds>;
+  blockMedian: Maybe<Types.Milliseconds>;
+  blockP95: Maybe<Types.Milliseconds>;
   timeDiff: Types.Milliseconds;
   subscribed: Maybe<Types.GenesisHash>;
   chains: Map<Types.GenesisHash, ChainData>;
@@ -333,6 +375,7 @@ export interface State {
   sortBy: Readonly<Maybe<number>>;
   selectedColumns: Column[];
   chainStats: Maybe<Types.ChainStats>;
+  peerCountHistogram: Maybe<Array<[Types.Range, Types.NodeCount]>>;
 }
 
 export type Update = <K extends keyof State>(
@@ -343,4 +386,5 @@ export interface ChainData {
   label: Types.ChainLabel;
   genesisHash: Types.GenesisHash;
   nodeCount: Types.NodeCount;
+  metadata?: Types.ChainMetadata;
 }